@@ -0,0 +1,111 @@
+//! Resumable apply checkpoints.
+//!
+//! A checkpoint captures where the applier stands in the instruction stream
+//! and how much target it has produced, together with the FNV-1a hash of
+//! that target prefix. After an interruption, the caller keeps the written
+//! output, and `PatchApplier::resume()` checks the prefix against the hash
+//! before continuing from the recorded patch offset.
+//!
+//! Serialized layout (44 bytes, little-endian):
+//!
+//! | Offset | Size | Field                          |
+//! |--------|------|--------------------------------|
+//! | 0      | 4    | Magic `PTCK`                   |
+//! | 4      | 8    | Patch offset (after header)    |
+//! | 12     | 8    | Target offset                  |
+//! | 20     | 8    | Target prefix hash (FNV-1a)    |
+//! | 28     | 8    | INSERT bytes still to copy     |
+//! | 36     | 8    | COPY bytes still to copy       |
+
+use crate::error::{PatchlyError, Result};
+
+/// Magic bytes for serialized checkpoints.
+const CHECKPOINT_MAGIC: &[u8; 4] = b"PTCK";
+
+/// Size of a serialized checkpoint.
+pub const CHECKPOINT_SIZE: usize = 44;
+
+/// Applier position from which applying can resume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApplyCheckpoint {
+    /// Instruction bytes consumed, counted from the end of the header.
+    pub patch_offset: u64,
+    /// Target bytes produced up to this point.
+    pub target_offset: u64,
+    /// FNV-1a hash of the first `target_offset` target bytes.
+    pub target_hash: u64,
+    /// Bytes left in an INSERT that the checkpoint falls inside of.
+    pub insert_remaining: u64,
+    /// Bytes left in a COPY that the checkpoint falls inside of. The patch
+    /// offset then points at the COPY instruction itself.
+    pub copy_remaining: u64,
+}
+
+impl ApplyCheckpoint {
+    /// Serializes the checkpoint.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(CHECKPOINT_SIZE);
+        out.extend_from_slice(CHECKPOINT_MAGIC);
+        out.extend_from_slice(&self.patch_offset.to_le_bytes());
+        out.extend_from_slice(&self.target_offset.to_le_bytes());
+        out.extend_from_slice(&self.target_hash.to_le_bytes());
+        out.extend_from_slice(&self.insert_remaining.to_le_bytes());
+        out.extend_from_slice(&self.copy_remaining.to_le_bytes());
+        out
+    }
+
+    /// Parses a checkpoint produced by `to_bytes()`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() != CHECKPOINT_SIZE || &data[0..4] != CHECKPOINT_MAGIC {
+            return Err(PatchlyError::InvalidData(
+                "Invalid apply checkpoint".to_string(),
+            ));
+        }
+
+        let field = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+
+        Ok(Self {
+            patch_offset: field(4),
+            target_offset: field(12),
+            target_hash: field(20),
+            insert_remaining: field(28),
+            copy_remaining: field(36),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_roundtrip() {
+        let checkpoint = ApplyCheckpoint {
+            patch_offset: 1 << 40,
+            target_offset: 12345,
+            target_hash: 0xDEAD_BEEF_0BAD_F00D,
+            insert_remaining: 7,
+            copy_remaining: 1 << 33,
+        };
+
+        let bytes = checkpoint.to_bytes();
+        assert_eq!(bytes.len(), CHECKPOINT_SIZE);
+        assert_eq!(ApplyCheckpoint::from_bytes(&bytes).unwrap(), checkpoint);
+    }
+
+    #[test]
+    fn test_checkpoint_rejects_garbage() {
+        let mut bytes = ApplyCheckpoint {
+            patch_offset: 0,
+            target_offset: 0,
+            target_hash: 0,
+            insert_remaining: 0,
+            copy_remaining: 0,
+        }
+        .to_bytes();
+
+        assert!(ApplyCheckpoint::from_bytes(&bytes[..20]).is_err());
+        bytes[0] = b'X';
+        assert!(ApplyCheckpoint::from_bytes(&bytes).is_err());
+    }
+}
@@ -0,0 +1,978 @@
+//! Streaming patch applier.
+//!
+//! Rebuilds the target file from a source file and the patch instructions.
+//! Patch bytes can be fed in arbitrary chunk sizes; COPY instructions read
+//! from the source through the [`SourceAccess`] trait so large sources don't
+//! need to be held in memory.
+//!
+//! COPY data is produced in pieces of at most `COPY_PIECE_SIZE` bytes, and
+//! `apply_chunk()` pauses once that much output is waiting, so a multi-GB
+//! COPY never needs a buffer of its size. With a checkpoint interval set,
+//! the applier periodically records an [`ApplyCheckpoint`], also between
+//! the pieces of a COPY, so an interrupted apply can resume mid-patch.
+
+use crate::apply::checkpoint::ApplyCheckpoint;
+use crate::container::deflate::{self, DeflateRecipe};
+use crate::error::{PatchlyError, Result};
+use crate::filter::bcj::{BcjFilter, FilterKind};
+use crate::format::patch_format::{
+    calculate_hash, HashBuilder, PatchHeader, PatchTrailer, EXT_DEFLATE, EXT_FILTER, TRAILER_SIZE,
+    TYPE_COPY, TYPE_END, TYPE_INSERT,
+};
+use std::io;
+use wasm_bindgen::{JsCast, JsValue};
+
+/// Random-access reader for source data.
+pub trait SourceAccess {
+    /// Returns the source length in bytes.
+    fn len(&self) -> u64;
+
+    /// Returns whether the source is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fills `buf` with source bytes starting at `offset`.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+}
+
+impl SourceAccess for &[u8] {
+    fn len(&self) -> u64 {
+        <[u8]>::len(self) as u64
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let start = offset as usize;
+        let bytes = start
+            .checked_add(buf.len())
+            .and_then(|end| self.get(start..end))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "Read past end of source")
+            })?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl SourceAccess for Vec<u8> {
+    fn len(&self) -> u64 {
+        <[u8]>::len(self) as u64
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.as_slice().read_at(offset, buf)
+    }
+}
+
+/// Source read through a JS object with the `read(buffer, { at })` and
+/// `getSize()` methods of an OPFS `FileSystemSyncAccessHandle`.
+pub struct JsSyncAccessSource {
+    /// The handle object.
+    handle: JsValue,
+    /// Its `read` method.
+    read: js_sys::Function,
+    /// Source length from `getSize()`.
+    len: u64,
+    /// Transfer buffer, reused while reads have the same length.
+    buffer: Option<js_sys::Uint8Array>,
+}
+
+impl JsSyncAccessSource {
+    /// Wraps `handle`, reading its size once.
+    pub fn new(handle: JsValue) -> Result<Self> {
+        let method = |name: &str| {
+            js_sys::Reflect::get(&handle, &name.into())
+                .ok()
+                .and_then(|method| method.dyn_into::<js_sys::Function>().ok())
+                .ok_or(PatchlyError::Misuse(
+                    "Source handle needs read() and getSize() methods",
+                ))
+        };
+        let read = method("read")?;
+        let len = method("getSize")?
+            .call0(&handle)
+            .ok()
+            .and_then(|size| size.as_f64())
+            .ok_or(PatchlyError::Misuse("Source handle getSize() failed"))?;
+
+        Ok(Self {
+            handle,
+            read,
+            len: len as u64,
+            buffer: None,
+        })
+    }
+}
+
+impl SourceAccess for JsSyncAccessSource {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let js_error = |_| io::Error::other("Source handle read() failed");
+
+        let array = match self.buffer.take() {
+            Some(array) if array.length() as usize == buf.len() => array,
+            _ => js_sys::Uint8Array::new_with_length(buf.len() as u32),
+        };
+        let options = js_sys::Object::new();
+        js_sys::Reflect::set(&options, &"at".into(), &(offset as f64).into()).map_err(js_error)?;
+
+        let read = self
+            .read
+            .call2(&self.handle, &array, &options)
+            .map_err(js_error)?;
+        if read.as_f64() != Some(buf.len() as f64) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Read past end of source",
+            ));
+        }
+
+        array.copy_to(buf);
+        self.buffer = Some(array);
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SourceAccess for std::fs::File {
+    fn len(&self) -> u64 {
+        self.metadata().map(|m| m.len()).unwrap_or(0)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+}
+
+/// Size of a serialized COPY instruction.
+const COPY_SIZE: usize = 13;
+
+/// Size of a serialized INSERT instruction before its data.
+const INSERT_HEADER_SIZE: usize = 5;
+
+/// Read size used when verifying a written target prefix.
+const VERIFY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Most COPY bytes read from the source at once; `apply_chunk()` pauses
+/// once this much output is waiting to be taken.
+const COPY_PIECE_SIZE: usize = 1024 * 1024;
+
+/// Instruction parser state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApplyState {
+    /// Expecting (the rest of) an instruction header.
+    Instruction,
+    /// Copying INSERT data from the patch to the output.
+    Insert { remaining: u64 },
+    /// Copying source data to the output, one piece at a time.
+    Copy { offset: u64, remaining: u64 },
+    /// Trailer read; the patch is complete.
+    Done,
+}
+
+/// Streaming applier for patch instructions.
+///
+/// Feed the instruction bytes that follow the header with `apply_chunk()`,
+/// collect the rebuilt target with `take_output()`, and call `finish()` at
+/// the end to detect truncated patches. When `apply_chunk()` returns `true`
+/// it paused inside a COPY: take the output and call it again, with an
+/// empty chunk or the next one, to continue. A trailer ending the instructions
+/// is returned by `trailer()`; its hash covers the final target, so with
+/// deflate or filter extensions it differs from `target_hash()`.
+///
+/// Call `set_checkpoint_interval()` to have the applier record a checkpoint
+/// every N target bytes; `take_checkpoint()` returns the latest one. A
+/// checkpoint can cover output still waiting in the applier, so after each
+/// `apply_chunk()` call `take_output()`, persist it, and only then call
+/// `take_checkpoint()`.
+pub struct PatchApplier<S: SourceAccess> {
+    /// Source data read by COPY instructions.
+    source: S,
+    /// Current parser state.
+    state: ApplyState,
+    /// Partial instruction header carried over between chunks.
+    pending: Vec<u8>,
+    /// Instruction bytes left unprocessed by a paused `apply_chunk()`.
+    unprocessed: Vec<u8>,
+    /// COPY bytes left at a resumed checkpoint, applied to the first COPY.
+    resume_copy: u64,
+    /// Rebuilt target bytes ready to be consumed.
+    output: Vec<u8>,
+    /// Total target bytes produced.
+    target_written: u64,
+    /// Instruction bytes consumed.
+    patch_offset: u64,
+    /// Running hash of all target bytes produced.
+    target_hasher: HashBuilder,
+    /// Target bytes between checkpoints (0 = disabled).
+    checkpoint_interval: u64,
+    /// Target offset at which the next checkpoint is due.
+    next_checkpoint: u64,
+    /// Most recent checkpoint not yet taken.
+    checkpoint: Option<ApplyCheckpoint>,
+    /// Trailer read at the end of the instructions.
+    trailer: Option<PatchTrailer>,
+}
+
+impl<S: SourceAccess> PatchApplier<S> {
+    /// Creates an applier reading COPY data from `source`.
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            state: ApplyState::Instruction,
+            pending: Vec::with_capacity(COPY_SIZE),
+            unprocessed: Vec::new(),
+            resume_copy: 0,
+            output: Vec::new(),
+            target_written: 0,
+            patch_offset: 0,
+            target_hasher: HashBuilder::new(),
+            checkpoint_interval: 0,
+            next_checkpoint: 0,
+            checkpoint: None,
+            trailer: None,
+        }
+    }
+
+    /// Resumes applying from a checkpoint.
+    ///
+    /// Verifies that `written` starts with the target prefix the checkpoint
+    /// describes. On success, feed the instruction bytes starting at
+    /// `checkpoint.patch_offset` (counted after the header) and append the
+    /// output to the target truncated to `checkpoint.target_offset` bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Source data read by COPY instructions.
+    /// * `checkpoint` - Checkpoint recorded by an earlier run.
+    /// * `written` - Target output written by the earlier run.
+    pub fn resume<T: SourceAccess>(
+        source: S,
+        checkpoint: &ApplyCheckpoint,
+        written: &mut T,
+    ) -> Result<Self> {
+        if written.len() < checkpoint.target_offset {
+            return Err(PatchlyError::CheckpointMismatch(format!(
+                "Written target too short: checkpoint at {} bytes, found {} bytes",
+                checkpoint.target_offset,
+                written.len()
+            )));
+        }
+
+        let mut hasher = HashBuilder::new();
+        let mut buf = vec![0u8; VERIFY_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < checkpoint.target_offset {
+            let len = (checkpoint.target_offset - offset).min(VERIFY_CHUNK_SIZE as u64) as usize;
+            written.read_at(offset, &mut buf[..len])?;
+            hasher.update(&buf[..len]);
+            offset += len as u64;
+        }
+
+        if hasher.finalize() != checkpoint.target_hash {
+            return Err(PatchlyError::CheckpointMismatch(
+                "Written target does not match checkpoint".to_string(),
+            ));
+        }
+
+        if checkpoint.insert_remaining > 0 && checkpoint.copy_remaining > 0 {
+            return Err(PatchlyError::CheckpointMismatch(
+                "Checkpoint is inside both an INSERT and a COPY".to_string(),
+            ));
+        }
+
+        let mut applier = Self::new(source);
+        applier.target_written = checkpoint.target_offset;
+        applier.patch_offset = checkpoint.patch_offset;
+        applier.target_hasher = hasher;
+        applier.resume_copy = checkpoint.copy_remaining;
+        if checkpoint.insert_remaining > 0 {
+            applier.state = ApplyState::Insert {
+                remaining: checkpoint.insert_remaining,
+            };
+        }
+        Ok(applier)
+    }
+
+    /// Records a checkpoint every `interval` target bytes (0 disables).
+    pub fn set_checkpoint_interval(&mut self, interval: u64) {
+        self.checkpoint_interval = interval;
+        self.next_checkpoint = self.target_written.saturating_add(interval);
+    }
+
+    /// Returns a checkpoint for the current position.
+    ///
+    /// Returns `None` while an instruction header is split across chunks.
+    pub fn checkpoint(&self) -> Option<ApplyCheckpoint> {
+        if !self.pending.is_empty() {
+            return None;
+        }
+
+        // Inside a COPY, resuming re-reads its instruction
+        let (patch_offset, insert_remaining, copy_remaining) = match self.state {
+            ApplyState::Insert { remaining } => (self.patch_offset, remaining, 0),
+            ApplyState::Copy { remaining, .. } => {
+                (self.patch_offset - COPY_SIZE as u64, 0, remaining)
+            }
+            ApplyState::Instruction | ApplyState::Done => (self.patch_offset, 0, 0),
+        };
+
+        Some(ApplyCheckpoint {
+            patch_offset,
+            target_offset: self.target_written,
+            target_hash: self.target_hasher.finalize(),
+            insert_remaining,
+            copy_remaining,
+        })
+    }
+
+    /// Takes the most recent periodic checkpoint, if one was recorded.
+    ///
+    /// It may cover output not yet taken; call `take_output()` and persist
+    /// that output first.
+    pub fn take_checkpoint(&mut self) -> Option<ApplyCheckpoint> {
+        self.checkpoint.take()
+    }
+
+    /// Records a periodic checkpoint once enough target was produced.
+    fn update_checkpoint(&mut self) {
+        if self.checkpoint_interval == 0 || self.target_written < self.next_checkpoint {
+            return;
+        }
+
+        if let Some(checkpoint) = self.checkpoint() {
+            self.checkpoint = Some(checkpoint);
+            self.next_checkpoint = self.target_written.saturating_add(self.checkpoint_interval);
+        }
+    }
+
+    /// Applies a chunk of instruction bytes.
+    ///
+    /// Returns `true` if it paused inside a COPY with about
+    /// `COPY_PIECE_SIZE` bytes of output waiting. The rest of `chunk` is
+    /// kept; take the output and call again to continue.
+    pub fn apply_chunk(&mut self, chunk: &[u8]) -> Result<bool> {
+        if self.unprocessed.is_empty() {
+            return self.process(chunk);
+        }
+
+        let mut input = std::mem::take(&mut self.unprocessed);
+        input.extend_from_slice(chunk);
+        self.process(&input)
+    }
+
+    /// Runs the parser over `chunk`, keeping what's left if a COPY pauses.
+    fn process(&mut self, chunk: &[u8]) -> Result<bool> {
+        let mut pos = 0;
+
+        while pos < chunk.len() || matches!(self.state, ApplyState::Copy { .. }) {
+            match self.state {
+                ApplyState::Copy { offset, remaining } => {
+                    if self.output.len() >= COPY_PIECE_SIZE {
+                        self.unprocessed.extend_from_slice(&chunk[pos..]);
+                        return Ok(true);
+                    }
+
+                    let piece = remaining.min(COPY_PIECE_SIZE as u64);
+                    self.copy_piece(offset, piece as usize)?;
+                    self.state = if piece == remaining {
+                        ApplyState::Instruction
+                    } else {
+                        ApplyState::Copy {
+                            offset: offset + piece,
+                            remaining: remaining - piece,
+                        }
+                    };
+                }
+                ApplyState::Insert { remaining } => {
+                    let take = remaining.min((chunk.len() - pos) as u64) as usize;
+                    self.write_output(&chunk[pos..pos + take]);
+                    pos += take;
+                    self.patch_offset += take as u64;
+
+                    let remaining = remaining - take as u64;
+                    self.state = if remaining == 0 {
+                        ApplyState::Instruction
+                    } else {
+                        ApplyState::Insert { remaining }
+                    };
+                }
+                ApplyState::Instruction => {
+                    if self.pending.is_empty() {
+                        let instr_type = chunk[pos];
+                        if ![TYPE_COPY, TYPE_INSERT, TYPE_END].contains(&instr_type) {
+                            return Err(PatchlyError::UnknownInstruction {
+                                offset: self.patch_offset,
+                                kind: instr_type,
+                            });
+                        }
+                    }
+
+                    let needed = match self.pending.first().copied().unwrap_or(chunk[pos]) {
+                        TYPE_COPY => COPY_SIZE,
+                        TYPE_END => TRAILER_SIZE,
+                        _ => INSERT_HEADER_SIZE,
+                    };
+                    let take = (needed - self.pending.len()).min(chunk.len() - pos);
+                    self.pending.extend_from_slice(&chunk[pos..pos + take]);
+                    pos += take;
+                    self.patch_offset += take as u64;
+
+                    if self.pending.len() == needed {
+                        self.execute_pending()?;
+                    }
+                }
+                ApplyState::Done => {
+                    return Err(PatchlyError::InvalidData(format!(
+                        "Data after the patch trailer at offset {}",
+                        self.patch_offset
+                    )));
+                }
+            }
+
+            self.update_checkpoint();
+        }
+
+        Ok(false)
+    }
+
+    /// Executes the complete instruction header in `pending`.
+    fn execute_pending(&mut self) -> Result<()> {
+        let header = std::mem::take(&mut self.pending);
+
+        if self.resume_copy > 0 && header[0] != TYPE_COPY {
+            return Err(PatchlyError::CheckpointMismatch(
+                "Checkpoint inside a COPY doesn't point at one".to_string(),
+            ));
+        }
+
+        if header[0] == TYPE_COPY {
+            let offset = u64::from_le_bytes(header[1..9].try_into().unwrap());
+            let length = u32::from_le_bytes(header[9..13].try_into().unwrap()) as u64;
+            self.start_copy(offset, length)?;
+        } else if header[0] == TYPE_END {
+            self.trailer = Some(PatchTrailer::parse(&header)?);
+            self.state = ApplyState::Done;
+        } else {
+            let length = u32::from_le_bytes(header[1..5].try_into().unwrap());
+            if length > 0 {
+                self.state = ApplyState::Insert {
+                    remaining: length as u64,
+                };
+            }
+        }
+
+        self.pending = header;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Checks a COPY's source range and starts copying it.
+    fn start_copy(&mut self, mut offset: u64, mut length: u64) -> Result<()> {
+        let end = offset.checked_add(length);
+        if end.is_none_or(|end| end > self.source.len()) {
+            return Err(PatchlyError::CopyOutOfRange {
+                offset,
+                length,
+                source_size: self.source.len(),
+            });
+        }
+
+        // A checkpoint inside this COPY already covers its first bytes
+        if self.resume_copy > 0 {
+            if self.resume_copy > length {
+                return Err(PatchlyError::CheckpointMismatch(
+                    "Checkpoint COPY remainder exceeds the COPY".to_string(),
+                ));
+            }
+            offset += length - self.resume_copy;
+            length = self.resume_copy;
+            self.resume_copy = 0;
+        }
+
+        if length > 0 {
+            self.state = ApplyState::Copy {
+                offset,
+                remaining: length,
+            };
+        }
+        Ok(())
+    }
+
+    /// Appends one piece of a COPY to the output.
+    fn copy_piece(&mut self, offset: u64, length: usize) -> Result<()> {
+        let start = self.output.len();
+        self.output.resize(start + length, 0);
+        self.source.read_at(offset, &mut self.output[start..])?;
+        self.target_hasher.update(&self.output[start..]);
+        self.target_written += length as u64;
+        Ok(())
+    }
+
+    /// Appends literal bytes to the output.
+    fn write_output(&mut self, data: &[u8]) {
+        self.output.extend_from_slice(data);
+        self.target_hasher.update(data);
+        self.target_written += data.len() as u64;
+    }
+
+    /// Takes the rebuilt target bytes produced so far.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Returns the total number of target bytes produced.
+    pub fn target_written(&self) -> u64 {
+        self.target_written
+    }
+
+    /// Returns the number of instruction bytes consumed.
+    pub fn patch_offset(&self) -> u64 {
+        self.patch_offset
+    }
+
+    /// Returns the FNV-1a hash of all target bytes produced.
+    pub fn target_hash(&self) -> u64 {
+        self.target_hasher.finalize()
+    }
+
+    /// Returns the trailer, once the instructions ended with one.
+    pub fn trailer(&self) -> Option<PatchTrailer> {
+        self.trailer
+    }
+
+    /// Checks that the patch didn't end inside an instruction.
+    pub fn finish(&self) -> Result<()> {
+        if matches!(self.state, ApplyState::Copy { .. }) {
+            return Err(PatchlyError::Misuse(
+                "A COPY is still paused; call apply_chunk() until it returns false",
+            ));
+        }
+        if matches!(self.state, ApplyState::Insert { .. }) || !self.pending.is_empty() {
+            return Err(PatchlyError::TruncatedInstruction {
+                offset: self.patch_offset,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Applies a complete in-memory patch to a source buffer.
+///
+/// Validates the source against the header, applies header extensions
+/// (deflate preprocessing, executable filters) and checks the rebuilt
+/// target size, and for trailer patches also its hash.
+///
+/// # Arguments
+///
+/// * `source` - Original file contents.
+/// * `patch` - Complete patch file contents.
+pub fn apply_patch(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let header = PatchHeader::parse(patch)?;
+    let instructions = &patch[header.encoded_len()..];
+
+    header.validate_source(source.len() as u64, calculate_hash(source))?;
+
+    let recipe = header
+        .extension(EXT_DEFLATE)
+        .map(DeflateRecipe::from_bytes)
+        .transpose()?;
+
+    let filter = match header.extension(EXT_FILTER) {
+        Some(&[id]) => FilterKind::from_id(id)
+            .ok_or_else(|| PatchlyError::InvalidHeader(format!("Unsupported filter ID: {}", id)))?,
+        Some(_) => {
+            return Err(PatchlyError::InvalidHeader(
+                "Invalid filter extension".to_string(),
+            ))
+        }
+        None => FilterKind::None,
+    };
+
+    // Rebuild the source view the diff was computed against
+    let mut prepared_source = match &recipe {
+        Some(recipe) => deflate::expand(source, &recipe.source)?,
+        None => source.to_vec(),
+    };
+    if filter != FilterKind::None {
+        let mut encoder = BcjFilter::encoder(filter);
+        let mut filtered = encoder.process(&prepared_source);
+        filtered.extend(encoder.finish());
+        prepared_source = filtered;
+    }
+
+    let mut applier = PatchApplier::new(prepared_source);
+    let mut target = Vec::new();
+    let mut chunk = instructions;
+    while applier.apply_chunk(chunk)? {
+        target.extend(applier.take_output());
+        chunk = &[];
+    }
+    target.extend(applier.take_output());
+    applier.finish()?;
+
+    let (target_size, target_hash) = match (header.has_trailer(), applier.trailer()) {
+        (false, None) => (header.target_size, None),
+        (true, Some(trailer)) => (trailer.target_size, Some(trailer.target_hash)),
+        _ => return Err(PatchlyError::MissingTrailer),
+    };
+
+    if filter != FilterKind::None {
+        let mut decoder = BcjFilter::decoder(filter);
+        let mut decoded = decoder.process(&target);
+        decoded.extend(decoder.finish());
+        target = decoded;
+    }
+    if let Some(recipe) = &recipe {
+        target = deflate::restore(&target, &recipe.target)?;
+    }
+
+    if target.len() as u64 != target_size {
+        return Err(PatchlyError::TargetSizeMismatch {
+            expected: target_size,
+            actual: target.len() as u64,
+        });
+    }
+    if let Some(expected) = target_hash {
+        let actual = calculate_hash(&target);
+        if actual != expected {
+            return Err(PatchlyError::TargetHashMismatch { expected, actual });
+        }
+    }
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::patch_format::{serialize_header, FLAG_FILTER, FLAG_TRAILER};
+
+    /// Serializes a COPY instruction.
+    fn copy(offset: u64, length: u32) -> Vec<u8> {
+        let mut out = vec![TYPE_COPY];
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&length.to_le_bytes());
+        out
+    }
+
+    /// Serializes an INSERT instruction.
+    fn insert(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![TYPE_INSERT];
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    #[test]
+    fn test_apply_instructions() {
+        let source = b"hello world";
+        let mut patch = copy(6, 5);
+        patch.extend(insert(b", "));
+        patch.extend(copy(0, 5));
+
+        let mut applier = PatchApplier::new(&source[..]);
+        applier.apply_chunk(&patch).unwrap();
+        applier.finish().unwrap();
+
+        assert_eq!(applier.take_output(), b"world, hello");
+        assert_eq!(applier.target_written(), 12);
+    }
+
+    #[test]
+    fn test_apply_byte_by_byte() {
+        let source = b"abcdefgh".to_vec();
+        let mut patch = insert(b"xyz");
+        patch.extend(copy(2, 4));
+        patch.extend(insert(b""));
+
+        let mut applier = PatchApplier::new(source);
+        for byte in &patch {
+            applier.apply_chunk(std::slice::from_ref(byte)).unwrap();
+        }
+        applier.finish().unwrap();
+
+        assert_eq!(applier.take_output(), b"xyzcdef");
+    }
+
+    #[test]
+    fn test_copy_out_of_range() {
+        let mut applier = PatchApplier::new(&b"abc"[..]);
+        let err = applier.apply_chunk(&copy(2, 5)).unwrap_err();
+
+        assert!(matches!(
+            err,
+            PatchlyError::CopyOutOfRange {
+                offset: 2,
+                length: 5,
+                source_size: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn test_unknown_instruction() {
+        let mut applier = PatchApplier::new(&b"abc"[..]);
+        let mut patch = copy(0, 3);
+        patch.push(0x7F);
+
+        assert!(matches!(
+            applier.apply_chunk(&patch),
+            Err(PatchlyError::UnknownInstruction {
+                offset: 13,
+                kind: 0x7F
+            })
+        ));
+    }
+
+    #[test]
+    fn test_truncated_patch() {
+        let mut applier = PatchApplier::new(&b"abc"[..]);
+        applier.apply_chunk(&insert(b"data")[..7]).unwrap();
+
+        assert!(matches!(
+            applier.finish(),
+            Err(PatchlyError::TruncatedInstruction { offset: 7 })
+        ));
+    }
+
+    /// Instructions exercising COPY and long INSERT data.
+    fn mixed_patch() -> (Vec<u8>, Vec<u8>) {
+        let source: Vec<u8> = (0..200u8).collect();
+        let mut patch = Vec::new();
+        for i in 0..20u8 {
+            patch.extend(copy(i as u64 * 7, 50));
+            patch.extend(insert(&[i; 37]));
+        }
+        (source, patch)
+    }
+
+    #[test]
+    fn test_periodic_checkpoints() {
+        let (source, patch) = mixed_patch();
+        let mut applier = PatchApplier::new(&source[..]);
+        applier.set_checkpoint_interval(100);
+
+        let mut target = Vec::new();
+        let mut checkpoints = Vec::new();
+        for chunk in patch.chunks(9) {
+            applier.apply_chunk(chunk).unwrap();
+            target.extend(applier.take_output());
+            checkpoints.extend(applier.take_checkpoint());
+        }
+
+        assert!(checkpoints.len() >= 10);
+        for checkpoint in &checkpoints {
+            let prefix = &target[..checkpoint.target_offset as usize];
+            assert_eq!(checkpoint.target_hash, calculate_hash(prefix));
+        }
+        assert!(checkpoints.iter().any(|c| c.insert_remaining > 0));
+        assert_eq!(applier.patch_offset(), patch.len() as u64);
+        assert_eq!(applier.target_hash(), calculate_hash(&target));
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let (source, patch) = mixed_patch();
+
+        let mut expected_applier = PatchApplier::new(&source[..]);
+        expected_applier.apply_chunk(&patch).unwrap();
+        let expected = expected_applier.take_output();
+
+        // Interrupted run: keep the last checkpoint and all written output
+        let mut applier = PatchApplier::new(&source[..]);
+        applier.set_checkpoint_interval(64);
+        let mut written = Vec::new();
+        let mut last = None;
+        for chunk in patch[..patch.len() / 2].chunks(11) {
+            applier.apply_chunk(chunk).unwrap();
+            written.extend(applier.take_output());
+            last = applier.take_checkpoint().or(last);
+        }
+        let checkpoint = ApplyCheckpoint::from_bytes(&last.unwrap().to_bytes()).unwrap();
+
+        let mut resumed = PatchApplier::resume(&source[..], &checkpoint, &mut written).unwrap();
+        written.truncate(checkpoint.target_offset as usize);
+        resumed
+            .apply_chunk(&patch[checkpoint.patch_offset as usize..])
+            .unwrap();
+        resumed.finish().unwrap();
+        written.extend(resumed.take_output());
+
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn test_resume_inside_large_copy() {
+        let source = crate::test_util::noise(3 * COPY_PIECE_SIZE, 7);
+        let length = 5 * COPY_PIECE_SIZE / 2;
+        let mut patch = insert(b"head");
+        patch.extend(copy(100, length as u32));
+        patch.extend(insert(b"tail"));
+        let expected = [b"head", &source[100..100 + length], b"tail"].concat();
+
+        // Interrupted run: killed after the second pause
+        let mut applier = PatchApplier::new(&source[..]);
+        applier.set_checkpoint_interval(COPY_PIECE_SIZE as u64 / 2);
+        let mut written = Vec::new();
+        let mut last = None;
+        let mut chunk = &patch[..];
+        for _ in 0..2 {
+            assert!(applier.apply_chunk(chunk).unwrap());
+            let output = applier.take_output();
+            assert!(output.len() <= 2 * COPY_PIECE_SIZE);
+            written.extend(output);
+            last = applier.take_checkpoint().or(last);
+            chunk = &[];
+        }
+        assert!(matches!(applier.finish(), Err(PatchlyError::Misuse(_))));
+
+        let checkpoint = last.unwrap();
+        assert!(checkpoint.copy_remaining > 0);
+        assert_eq!(checkpoint.patch_offset, insert(b"head").len() as u64);
+
+        let mut resumed = PatchApplier::resume(&source[..], &checkpoint, &mut written).unwrap();
+        written.truncate(checkpoint.target_offset as usize);
+        let mut chunk = &patch[checkpoint.patch_offset as usize..];
+        while resumed.apply_chunk(chunk).unwrap() {
+            written.extend(resumed.take_output());
+            chunk = &[];
+        }
+        resumed.finish().unwrap();
+        written.extend(resumed.take_output());
+        assert_eq!(written, expected);
+
+        // The remainder must fit the COPY it points at
+        let mut bad = checkpoint;
+        bad.copy_remaining = length as u64 + 1;
+        let mut resumed = PatchApplier::resume(&source[..], &bad, &mut written).unwrap();
+        assert!(matches!(
+            resumed.apply_chunk(&patch[bad.patch_offset as usize..]),
+            Err(PatchlyError::CheckpointMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_resume_rejects_modified_prefix() {
+        let (source, patch) = mixed_patch();
+        let mut applier = PatchApplier::new(&source[..]);
+        applier.apply_chunk(&patch[..300]).unwrap();
+        let checkpoint = applier.checkpoint().unwrap();
+        let mut written = applier.take_output();
+
+        written[5] ^= 1;
+        assert!(matches!(
+            PatchApplier::resume(&source[..], &checkpoint, &mut written),
+            Err(PatchlyError::CheckpointMismatch(_))
+        ));
+
+        written.truncate(10);
+        assert!(matches!(
+            PatchApplier::resume(&source[..], &checkpoint, &mut written),
+            Err(PatchlyError::CheckpointMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_patch_validates_source() {
+        let source = b"source";
+        let mut patch = serialize_header(4096, 6, calculate_hash(source), 3).unwrap();
+        patch.extend(copy(0, 3));
+
+        assert_eq!(apply_patch(source, &patch).unwrap(), b"sou");
+        assert!(matches!(
+            apply_patch(b"other!", &patch),
+            Err(PatchlyError::SourceHashMismatch { .. })
+        ));
+        assert!(matches!(
+            apply_patch(b"short", &patch),
+            Err(PatchlyError::SourceSizeMismatch {
+                expected: 6,
+                actual: 5
+            })
+        ));
+    }
+
+    #[test]
+    fn test_apply_patch_rejects_unknown_filter() {
+        let source = b"source";
+        let mut header = PatchHeader::new(4096, 6, calculate_hash(source), 0);
+        header.flags = FLAG_FILTER;
+        header.set_extension(EXT_FILTER, vec![0x7F]);
+
+        let err = apply_patch(source, &header.to_bytes()).unwrap_err();
+        assert!(matches!(err, PatchlyError::InvalidHeader(_)));
+        assert!(err.to_string().contains("filter"));
+    }
+
+    #[test]
+    fn test_trailer_ends_instructions() {
+        let source = b"source";
+        let trailer = PatchTrailer {
+            target_size: 7,
+            target_hash: calculate_hash(b"sou-new"),
+        };
+        let mut header = PatchHeader::new(4096, 6, calculate_hash(source), 0);
+        header.flags = FLAG_TRAILER;
+
+        let mut instructions = copy(0, 3);
+        instructions.extend(insert(b"-new"));
+        instructions.extend(trailer.to_bytes());
+
+        let mut patch = header.to_bytes();
+        patch.extend(&instructions);
+        assert_eq!(apply_patch(source, &patch).unwrap(), b"sou-new");
+
+        // Trailer split across chunks, and data after it
+        let mut applier = PatchApplier::new(&source[..]);
+        for chunk in instructions.chunks(4) {
+            applier.apply_chunk(chunk).unwrap();
+        }
+        applier.finish().unwrap();
+        assert_eq!(applier.trailer(), Some(trailer));
+        assert_eq!(applier.target_hash(), trailer.target_hash);
+        assert!(applier.apply_chunk(&copy(0, 1)).is_err());
+
+        // Missing, unannounced and mismatching trailers
+        let without = &patch[..patch.len() - TRAILER_SIZE];
+        assert!(matches!(
+            apply_patch(source, without),
+            Err(PatchlyError::MissingTrailer)
+        ));
+
+        let mut unannounced = serialize_header(4096, 6, calculate_hash(source), 7).unwrap();
+        unannounced.extend(&instructions);
+        assert!(matches!(
+            apply_patch(source, &unannounced),
+            Err(PatchlyError::MissingTrailer)
+        ));
+
+        let last = patch.len() - 1;
+        patch[last] ^= 1;
+        assert!(matches!(
+            apply_patch(source, &patch),
+            Err(PatchlyError::TargetHashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_apply_patch_checks_target_size() {
+        let source = b"source";
+        let mut patch = serialize_header(4096, 6, calculate_hash(source), 10).unwrap();
+        patch.extend(copy(0, 3));
+
+        assert!(matches!(
+            apply_patch(source, &patch),
+            Err(PatchlyError::TargetSizeMismatch {
+                expected: 10,
+                actual: 3
+            })
+        ));
+    }
+}
@@ -0,0 +1,522 @@
+//! Deflate-aware preprocessing for gzip and zip containers.
+//!
+//! Compression scrambles content, so two slightly different `.gz` or `.zip`
+//! files share almost no blocks. This module finds deflate streams inside
+//! gzip members and zip local entries, and replaces each one with its
+//! decompressed bytes ("expanded" form). Diffing happens on the expanded
+//! forms; the applier rebuilds the exact target bytes by recompressing.
+//!
+//! A target stream is only expanded when recompressing its contents with a
+//! known level reproduces the original bytes exactly. Streams produced by a
+//! different compressor stay compressed and are diffed as raw bytes.
+//!
+//! Source streams only need to be decompressed on apply, so for them just
+//! the location is recorded. Target streams also record the hash of their
+//! compressed bytes, which `restore()` checks after recompressing.
+//!
+//! Whole files are held in memory, so raw and expanded files are limited to
+//! [`MAX_DEFLATE_SIZE`] bytes. Streams that would expand past the limit stay
+//! compressed.
+
+use crate::error::PatchlyError;
+use crate::format::patch_format::calculate_hash;
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZFlush, MZStatus};
+use std::io;
+
+/// Compression levels tried when reproducing a stream, most common first.
+const LEVEL_ORDER: [u8; 11] = [6, 9, 1, 5, 4, 7, 8, 3, 2, 0, 10];
+
+/// Serialized size of a `DeflateStream` entry.
+const STREAM_ENTRY_SIZE: usize = 33;
+
+/// Largest raw or expanded file handled in deflate mode (256MB).
+pub const MAX_DEFLATE_SIZE: usize = 256 * 1024 * 1024;
+
+/// gzip magic bytes followed by the deflate method.
+const GZIP_SIGNATURE: [u8; 3] = [0x1f, 0x8b, 0x08];
+
+/// zip local file header signature.
+const ZIP_LOCAL_SIGNATURE: [u8; 4] = *b"PK\x03\x04";
+
+/// zip compression method for deflate.
+const ZIP_METHOD_DEFLATE: u16 = 8;
+
+/// A deflate stream located inside a container file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateStream {
+    /// Offset of the compressed stream in the raw file.
+    pub raw_offset: u64,
+    /// Length of the compressed stream.
+    pub raw_len: u64,
+    /// Length of the decompressed contents.
+    pub expanded_len: u64,
+    /// Compression level that reproduces the stream (ignored for source streams).
+    pub level: u8,
+    /// FNV-1a hash of the compressed stream (0 for source streams).
+    pub raw_hash: u64,
+}
+
+/// Streams to expand in the source and to recompress in the target.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeflateRecipe {
+    /// Streams decompressed from the source before COPY instructions read it.
+    pub source: Vec<DeflateStream>,
+    /// Streams recompressed after the expanded target has been rebuilt.
+    pub target: Vec<DeflateStream>,
+}
+
+impl DeflateRecipe {
+    /// Serializes the recipe for the patch header.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer =
+            Vec::with_capacity(8 + (self.source.len() + self.target.len()) * STREAM_ENTRY_SIZE);
+
+        for streams in [&self.source, &self.target] {
+            buffer.extend_from_slice(&(streams.len() as u32).to_le_bytes());
+            for stream in streams {
+                buffer.extend_from_slice(&stream.raw_offset.to_le_bytes());
+                buffer.extend_from_slice(&stream.raw_len.to_le_bytes());
+                buffer.extend_from_slice(&stream.expanded_len.to_le_bytes());
+                buffer.push(stream.level);
+                buffer.extend_from_slice(&stream.raw_hash.to_le_bytes());
+            }
+        }
+
+        buffer
+    }
+
+    /// Parses a recipe serialized by `to_bytes()`.
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let mut pos = 0;
+        let source = read_streams(data, &mut pos)?;
+        let target = read_streams(data, &mut pos)?;
+
+        if pos != data.len() {
+            return Err(invalid("Trailing bytes in deflate recipe"));
+        }
+
+        Ok(Self { source, target })
+    }
+}
+
+/// Reads a count-prefixed list of stream entries.
+fn read_streams(data: &[u8], pos: &mut usize) -> io::Result<Vec<DeflateStream>> {
+    let count_bytes = data
+        .get(*pos..*pos + 4)
+        .ok_or_else(|| invalid("Truncated deflate recipe"))?;
+    let count = u32::from_le_bytes(count_bytes.try_into().unwrap()) as usize;
+    *pos += 4;
+
+    let entries = data
+        .get(*pos..)
+        .filter(|rest| rest.len() / STREAM_ENTRY_SIZE >= count)
+        .ok_or_else(|| invalid("Truncated deflate recipe"))?;
+
+    let streams = entries
+        .chunks_exact(STREAM_ENTRY_SIZE)
+        .take(count)
+        .map(|entry| DeflateStream {
+            raw_offset: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+            raw_len: u64::from_le_bytes(entry[8..16].try_into().unwrap()),
+            expanded_len: u64::from_le_bytes(entry[16..24].try_into().unwrap()),
+            level: entry[24],
+            raw_hash: u64::from_le_bytes(entry[25..33].try_into().unwrap()),
+        })
+        .collect();
+    *pos += count * STREAM_ENTRY_SIZE;
+
+    Ok(streams)
+}
+
+/// Creates an error for malformed recipes or streams.
+fn invalid(message: &str) -> io::Error {
+    PatchlyError::InvalidData(message.to_string()).into()
+}
+
+/// Finds deflate streams that can be expanded.
+///
+/// Scans for gzip members and zip local entries. When `require_reproducible`
+/// is set (target side), a stream is kept only if some compression level
+/// reproduces it byte-for-byte, and that level and the stream hash are
+/// recorded. Streams are skipped once the expanded file would exceed
+/// `MAX_DEFLATE_SIZE`.
+///
+/// # Arguments
+///
+/// * `data` - Complete raw file contents.
+/// * `require_reproducible` - Whether streams must recompress exactly.
+pub fn find_streams(data: &[u8], require_reproducible: bool) -> Vec<DeflateStream> {
+    let mut streams = Vec::new();
+    let mut expanded_size = data.len();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let start = gzip_stream_start(data, pos).or_else(|| zip_stream_start(data, pos));
+
+        let found = start.and_then(|start| {
+            let (expanded, raw_len) = inflate_raw(&data[start..], MAX_DEFLATE_SIZE)?;
+            if expanded_size - raw_len + expanded.len() > MAX_DEFLATE_SIZE {
+                return None;
+            }
+            let compressed = &data[start..start + raw_len];
+
+            let (level, raw_hash) = if require_reproducible {
+                (
+                    find_level(&expanded, compressed)?,
+                    calculate_hash(compressed),
+                )
+            } else {
+                (0, 0)
+            };
+
+            Some(DeflateStream {
+                raw_offset: start as u64,
+                raw_len: raw_len as u64,
+                expanded_len: expanded.len() as u64,
+                level,
+                raw_hash,
+            })
+        });
+
+        match found {
+            Some(stream) => {
+                expanded_size =
+                    expanded_size - stream.raw_len as usize + stream.expanded_len as usize;
+                pos = (stream.raw_offset + stream.raw_len) as usize;
+                streams.push(stream);
+            }
+            None => pos += 1,
+        }
+    }
+
+    streams
+}
+
+/// Replaces each stream in `data` with its decompressed contents.
+///
+/// Fails if a stream doesn't match the recipe or the expanded file would
+/// exceed `MAX_DEFLATE_SIZE`.
+///
+/// # Arguments
+///
+/// * `data` - Complete raw file contents.
+/// * `streams` - Non-overlapping streams in ascending offset order.
+pub fn expand(data: &[u8], streams: &[DeflateStream]) -> io::Result<Vec<u8>> {
+    let mut expanded = Vec::with_capacity(data.len());
+    let mut pos = 0usize;
+
+    for stream in streams {
+        let start = stream.raw_offset as usize;
+        let end = start
+            .checked_add(stream.raw_len as usize)
+            .filter(|&end| start >= pos && end <= data.len())
+            .ok_or_else(|| invalid("Deflate stream out of range"))?;
+
+        expanded.extend_from_slice(&data[pos..start]);
+
+        let limit = MAX_DEFLATE_SIZE.saturating_sub(expanded.len());
+        let (contents, raw_len) = inflate_raw(&data[start..end], limit)
+            .ok_or_else(|| invalid("Corrupt or oversized deflate stream in source"))?;
+        if raw_len != end - start || contents.len() as u64 != stream.expanded_len {
+            return Err(invalid("Deflate stream doesn't match recipe"));
+        }
+        expanded.extend_from_slice(&contents);
+
+        pos = end;
+    }
+
+    if expanded.len() + (data.len() - pos) > MAX_DEFLATE_SIZE {
+        return Err(invalid("Expanded file exceeds the deflate size limit"));
+    }
+    expanded.extend_from_slice(&data[pos..]);
+    Ok(expanded)
+}
+
+/// Rebuilds raw file contents by recompressing expanded streams.
+///
+/// Inverse of `expand()` for streams found with `require_reproducible`.
+/// Fails unless each recompressed stream has the recorded length and hash.
+///
+/// # Arguments
+///
+/// * `expanded` - Expanded file contents.
+/// * `streams` - Streams in ascending raw offset order.
+pub fn restore(expanded: &[u8], streams: &[DeflateStream]) -> io::Result<Vec<u8>> {
+    let mut raw = Vec::with_capacity(expanded.len());
+    let mut pos = 0usize;
+
+    for stream in streams {
+        // Raw bytes before the stream are copied verbatim
+        let gap = (stream.raw_offset as usize)
+            .checked_sub(raw.len())
+            .ok_or_else(|| invalid("Deflate streams overlap"))?;
+        let start = pos
+            .checked_add(gap)
+            .filter(|&start| start <= expanded.len())
+            .ok_or_else(|| invalid("Deflate stream out of range"))?;
+        let end = start
+            .checked_add(stream.expanded_len as usize)
+            .filter(|&end| end <= expanded.len())
+            .ok_or_else(|| invalid("Deflate stream out of range"))?;
+
+        raw.extend_from_slice(&expanded[pos..start]);
+
+        let compressed = compress_to_vec(&expanded[start..end], stream.level);
+        if compressed.len() as u64 != stream.raw_len
+            || calculate_hash(&compressed) != stream.raw_hash
+        {
+            return Err(invalid("Deflate recompression is not reproducible"));
+        }
+        raw.extend_from_slice(&compressed);
+
+        pos = end;
+    }
+
+    raw.extend_from_slice(&expanded[pos..]);
+    Ok(raw)
+}
+
+/// Returns the deflate stream offset if a gzip member header starts at `pos`.
+fn gzip_stream_start(data: &[u8], pos: usize) -> Option<usize> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    let header = data.get(pos..pos + 10)?;
+    if header[0..3] != GZIP_SIGNATURE || header[3] & 0xE0 != 0 {
+        return None;
+    }
+
+    let flags = header[3];
+    let mut offset = pos + 10;
+
+    if flags & FEXTRA != 0 {
+        let len = data.get(offset..offset + 2)?;
+        offset += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            offset += data.get(offset..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        offset += 2;
+    }
+
+    (offset < data.len()).then_some(offset)
+}
+
+/// Returns the deflate stream offset if a zip local entry starts at `pos`.
+fn zip_stream_start(data: &[u8], pos: usize) -> Option<usize> {
+    let header = data.get(pos..pos + 30)?;
+    if header[0..4] != ZIP_LOCAL_SIGNATURE {
+        return None;
+    }
+
+    let method = u16::from_le_bytes([header[8], header[9]]);
+    if method != ZIP_METHOD_DEFLATE {
+        return None;
+    }
+
+    let name_len = u16::from_le_bytes([header[26], header[27]]) as usize;
+    let extra_len = u16::from_le_bytes([header[28], header[29]]) as usize;
+    let offset = pos + 30 + name_len + extra_len;
+
+    (offset < data.len()).then_some(offset)
+}
+
+/// Decompresses a raw deflate stream at the start of `input`.
+///
+/// # Returns
+///
+/// The decompressed bytes and the compressed length, or `None` if `input`
+/// doesn't start with a complete, valid stream of at most `limit` bytes.
+fn inflate_raw(input: &[u8], limit: usize) -> Option<(Vec<u8>, usize)> {
+    let mut state = InflateState::new_boxed(DataFormat::Raw);
+    let mut output = Vec::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut consumed = 0;
+
+    loop {
+        let result = inflate(&mut state, &input[consumed..], &mut buffer, MZFlush::None);
+        consumed += result.bytes_consumed;
+        output.extend_from_slice(&buffer[..result.bytes_written]);
+        if output.len() > limit {
+            return None;
+        }
+
+        match result.status {
+            Ok(MZStatus::StreamEnd) => return Some((output, consumed)),
+            Ok(_) if result.bytes_consumed > 0 || result.bytes_written > 0 => {}
+            _ => return None,
+        }
+    }
+}
+
+/// Finds a compression level that reproduces `compressed` from `expanded`.
+fn find_level(expanded: &[u8], compressed: &[u8]) -> Option<u8> {
+    LEVEL_ORDER
+        .into_iter()
+        .find(|&level| compress_to_vec(expanded, level) == compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compressible text with some variety.
+    fn text(lines: usize, seed: usize) -> Vec<u8> {
+        (0..lines)
+            .flat_map(|i| format!("line {} of document {}\n", i * 7 % 13 + i, seed).into_bytes())
+            .collect()
+    }
+
+    /// Builds a single-member gzip file with an original file name.
+    fn gzip(contents: &[u8], level: u8) -> Vec<u8> {
+        let mut out = vec![0x1f, 0x8b, 0x08, 0x08, 0, 0, 0, 0, 0, 0xff];
+        out.extend_from_slice(b"file.txt\0");
+        out.extend_from_slice(&compress_to_vec(contents, level));
+        // CRC32 is not checked by the preprocessing layer
+        out.extend_from_slice(&[0xAA; 4]);
+        out.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        out
+    }
+
+    /// Builds a zip local entry (no central directory needed for scanning).
+    fn zip_entry(name: &str, contents: &[u8]) -> Vec<u8> {
+        let compressed = compress_to_vec(contents, 6);
+        let mut out = ZIP_LOCAL_SIGNATURE.to_vec();
+        out.extend_from_slice(&[20, 0, 0, 0]);
+        out.extend_from_slice(&ZIP_METHOD_DEFLATE.to_le_bytes());
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(&compressed);
+        out
+    }
+
+    #[test]
+    fn test_gzip_expand_restore() {
+        let contents = text(500, 1);
+        let file = gzip(&contents, 9);
+
+        let streams = find_streams(&file, true);
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].raw_offset, 19);
+        assert_eq!(streams[0].expanded_len, contents.len() as u64);
+        assert_eq!(
+            compress_to_vec(&contents, streams[0].level),
+            compress_to_vec(&contents, 9)
+        );
+
+        let expanded = expand(&file, &streams).unwrap();
+        assert!(expanded.windows(contents.len()).any(|w| w == contents));
+        assert_eq!(restore(&expanded, &streams).unwrap(), file);
+    }
+
+    #[test]
+    fn test_zip_entries() {
+        let mut file = zip_entry("a.txt", &text(200, 2));
+        file.extend_from_slice(&zip_entry("b.txt", &text(300, 3)));
+        file.extend_from_slice(b"PK\x01\x02 central directory stays raw");
+
+        let streams = find_streams(&file, true);
+        assert_eq!(streams.len(), 2);
+
+        let expanded = expand(&file, &streams).unwrap();
+        assert_eq!(restore(&expanded, &streams).unwrap(), file);
+    }
+
+    #[test]
+    fn test_unreproducible_stream_stays_raw() {
+        // Two stored blocks: valid deflate, but no level emits this layout
+        let mut stream = vec![0x00, 0x02, 0x00, 0xFD, 0xFF, b'h', b'i'];
+        stream.extend_from_slice(&[0x01, 0x01, 0x00, 0xFE, 0xFF, b'!']);
+        let mut file = vec![0x1f, 0x8b, 0x08, 0, 0, 0, 0, 0, 0, 0xff];
+        file.extend_from_slice(&stream);
+        file.extend_from_slice(&[0; 8]);
+
+        assert!(find_streams(&file, true).is_empty());
+
+        // Source side only needs the stream to decompress
+        let streams = find_streams(&file, false);
+        assert_eq!(streams.len(), 1);
+        let expanded = expand(&file, &streams).unwrap();
+        assert_eq!(&expanded[10..13], b"hi!");
+        assert_eq!(expanded.len(), 10 + 3 + 8);
+    }
+
+    #[test]
+    fn test_no_streams_in_plain_data() {
+        let data = text(100, 4);
+        assert!(find_streams(&data, true).is_empty());
+        assert_eq!(expand(&data, &[]).unwrap(), data);
+        assert_eq!(restore(&data, &[]).unwrap(), data);
+    }
+
+    #[test]
+    fn test_recipe_roundtrip() {
+        let recipe = DeflateRecipe {
+            source: vec![DeflateStream {
+                raw_offset: 10,
+                raw_len: 20,
+                expanded_len: 30,
+                level: 0,
+                raw_hash: 0,
+            }],
+            target: vec![
+                DeflateStream {
+                    raw_offset: 1,
+                    raw_len: 2,
+                    expanded_len: 3,
+                    level: 9,
+                    raw_hash: 0x0123_4567_89ab_cdef,
+                },
+                DeflateStream {
+                    raw_offset: 100,
+                    raw_len: 5,
+                    expanded_len: 50,
+                    level: 6,
+                    raw_hash: u64::MAX,
+                },
+            ],
+        };
+
+        let bytes = recipe.to_bytes();
+        assert_eq!(DeflateRecipe::from_bytes(&bytes).unwrap(), recipe);
+        assert!(DeflateRecipe::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_expand_rejects_mismatched_recipe() {
+        let file = gzip(&text(50, 5), 6);
+        let mut streams = find_streams(&file, true);
+        streams[0].expanded_len += 1;
+
+        assert!(expand(&file, &streams).is_err());
+    }
+
+    #[test]
+    fn test_restore_checks_recompressed_bytes() {
+        let file = gzip(&text(50, 6), 6);
+        let mut streams = find_streams(&file, true);
+        let expanded = expand(&file, &streams).unwrap();
+
+        // Same length, different bytes
+        streams[0].raw_hash ^= 1;
+        assert!(restore(&expanded, &streams).is_err());
+    }
+
+    #[test]
+    fn test_inflate_limit() {
+        let compressed = compress_to_vec(&[0u8; 100_000], 6);
+        assert!(inflate_raw(&compressed, 100_000).is_some());
+        assert!(inflate_raw(&compressed, 99_999).is_none());
+    }
+}
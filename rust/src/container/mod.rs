@@ -0,0 +1,2 @@
+pub mod deflate;
+pub mod tar;
@@ -0,0 +1,1123 @@
+//! Tar-aware diffing that aligns matching on member boundaries.
+//!
+//! Plain block matching works poorly on tar archives: every member is
+//! preceded by a 512-byte header, so member contents land at arbitrary
+//! offsets relative to the fixed source blocks, and header fields such as
+//! mtime change between builds. This module parses both archives and:
+//!
+//! - Indexes each source member's contents in blocks starting at the
+//!   member's data offset
+//! - Diffs each target member against the source member with the same path
+//! - Copies unchanged headers and short member tails by hash
+//!
+//! The output is ordinary COPY/INSERT instructions, so the patch reproduces
+//! the target archive byte-for-byte with the regular applier. Anything that
+//! does not parse as tar (or follows the end-of-archive marker) falls back
+//! to plain streaming diff.
+
+use crate::apply::patch_applier::SourceAccess;
+use crate::diff::block_index::BlockIndex;
+use crate::diff::rolling_hash::RollingHashKind;
+use crate::diff::streaming_diff::StreamingDiff;
+use crate::format::patch_format::{calculate_hash, HashBuilder};
+use crate::format::sink::PatchSink;
+use crate::format::state::{invalid, StateReader, StateWriter};
+use std::collections::HashMap;
+use std::io;
+
+/// Size of a tar header and of the padding unit.
+pub const TAR_BLOCK_SIZE: usize = 512;
+
+/// Upper bound for buffered extended header payloads (GNU long names, pax).
+const MAX_EXTENDED_SIZE: u64 = 64 * 1024;
+
+/// Event produced while parsing a tar stream.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TarEvent<'a> {
+    /// Archive structure that is not member content: extended headers and padding.
+    Meta(&'a [u8]),
+    /// Start of a member; `header` is its 512-byte header block.
+    Member {
+        path: &'a str,
+        size: u64,
+        header: &'a [u8],
+    },
+    /// Member content bytes.
+    Data(&'a [u8]),
+    /// End of the current member's content.
+    MemberEnd,
+    /// Bytes after the end-of-archive marker, or after the stream stopped parsing as tar.
+    Raw(&'a [u8]),
+}
+
+/// Kind of extended header whose payload applies to the next member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExtendedKind {
+    /// GNU long name ('L'): payload is the next member's path.
+    LongName,
+    /// Pax extended header ('x'): payload may contain a `path` record.
+    Pax,
+    /// Other payloads (global pax, GNU long link) that carry no path.
+    Other,
+}
+
+/// Parser state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Collecting a 512-byte header block.
+    Header,
+    /// Collecting an extended header payload.
+    Extended { remaining: u64, kind: ExtendedKind },
+    /// Passing member content through.
+    Data { remaining: u64 },
+    /// Skipping zero padding up to the next 512-byte boundary.
+    Padding { remaining: u64 },
+    /// Everything else is raw data.
+    Raw,
+}
+
+/// Incremental tar stream parser.
+///
+/// Accepts arbitrary chunk sizes and reports structure through [`TarEvent`]s.
+/// Only header blocks and extended header payloads are buffered; member
+/// content is passed through as slices of the input.
+pub struct TarParser {
+    /// Current parser state.
+    state: State,
+    /// Partially collected header block.
+    header: Vec<u8>,
+    /// Partially collected extended header payload.
+    extended: Vec<u8>,
+    /// Path override from a preceding extended header.
+    next_path: Option<String>,
+    /// Path of the member being parsed.
+    path: String,
+    /// Size field of the header that owns the current data or payload.
+    size: u64,
+}
+
+impl TarParser {
+    /// Creates a parser positioned at the start of an archive.
+    pub fn new() -> Self {
+        Self {
+            state: State::Header,
+            header: Vec::with_capacity(TAR_BLOCK_SIZE),
+            extended: Vec::new(),
+            next_path: None,
+            path: String::new(),
+            size: 0,
+        }
+    }
+
+    /// Feeds a chunk of the archive, invoking `on_event` for each event.
+    pub fn feed<F>(&mut self, chunk: &[u8], mut on_event: F)
+    where
+        F: FnMut(TarEvent<'_>),
+    {
+        let mut pos = 0;
+
+        while pos < chunk.len() {
+            let rest = &chunk[pos..];
+
+            match self.state {
+                State::Header => {
+                    let take = (TAR_BLOCK_SIZE - self.header.len()).min(rest.len());
+                    self.header.extend_from_slice(&rest[..take]);
+                    pos += take;
+
+                    if self.header.len() == TAR_BLOCK_SIZE {
+                        self.parse_header(&mut on_event);
+                    }
+                }
+                State::Extended { remaining, kind } => {
+                    let take = remaining.min(rest.len() as u64) as usize;
+                    on_event(TarEvent::Meta(&rest[..take]));
+                    if self.extended.len() as u64 + (take as u64) <= MAX_EXTENDED_SIZE {
+                        self.extended.extend_from_slice(&rest[..take]);
+                    }
+                    pos += take;
+
+                    let remaining = remaining - take as u64;
+                    if remaining == 0 {
+                        self.finish_extended(kind);
+                    } else {
+                        self.state = State::Extended { remaining, kind };
+                    }
+                }
+                State::Data { remaining } => {
+                    let take = remaining.min(rest.len() as u64) as usize;
+                    on_event(TarEvent::Data(&rest[..take]));
+                    pos += take;
+
+                    let remaining = remaining - take as u64;
+                    self.state = State::Data { remaining };
+                    if remaining == 0 {
+                        on_event(TarEvent::MemberEnd);
+                        self.state = State::Padding {
+                            remaining: padding_for(self.size),
+                        };
+                        self.skip_empty_padding();
+                    }
+                }
+                State::Padding { remaining } => {
+                    let take = remaining.min(rest.len() as u64) as usize;
+                    on_event(TarEvent::Meta(&rest[..take]));
+                    pos += take;
+
+                    self.state = State::Padding {
+                        remaining: remaining - take as u64,
+                    };
+                    self.skip_empty_padding();
+                }
+                State::Raw => {
+                    on_event(TarEvent::Raw(rest));
+                    pos = chunk.len();
+                }
+            }
+        }
+    }
+
+    /// Ends the stream, reporting a partially collected header block as
+    /// `Meta`. Extended payloads are reported while they are collected.
+    pub fn finish<F>(&mut self, mut on_event: F)
+    where
+        F: FnMut(TarEvent<'_>),
+    {
+        if self.state == State::Header && !self.header.is_empty() {
+            on_event(TarEvent::Meta(&self.header));
+            self.header.clear();
+        }
+    }
+
+    /// Parses a complete header block and transitions to the next state.
+    fn parse_header<F>(&mut self, on_event: &mut F)
+    where
+        F: FnMut(TarEvent<'_>),
+    {
+        let header = std::mem::take(&mut self.header);
+
+        // End-of-archive marker or garbage: the rest of the stream is raw
+        let parsed = if header.iter().all(|&b| b == 0) {
+            None
+        } else {
+            parse_header_block(&header)
+        };
+
+        let Some((path, size, typeflag)) = parsed else {
+            on_event(TarEvent::Raw(&header));
+            self.state = State::Raw;
+            return;
+        };
+
+        let extended_kind = match typeflag {
+            b'L' => Some(ExtendedKind::LongName),
+            b'x' => Some(ExtendedKind::Pax),
+            b'g' | b'K' => Some(ExtendedKind::Other),
+            _ => None,
+        };
+
+        self.size = size;
+
+        if let Some(kind) = extended_kind {
+            on_event(TarEvent::Meta(&header));
+            self.extended.clear();
+            if size == 0 {
+                self.finish_extended(kind);
+            } else {
+                self.state = State::Extended {
+                    remaining: size,
+                    kind,
+                };
+            }
+            self.header = header;
+            self.header.clear();
+            return;
+        }
+
+        self.path = self.next_path.take().unwrap_or(path);
+        on_event(TarEvent::Member {
+            path: &self.path,
+            size,
+            header: &header,
+        });
+
+        if size == 0 {
+            on_event(TarEvent::MemberEnd);
+            self.state = State::Header;
+        } else {
+            self.state = State::Data { remaining: size };
+        }
+        self.header = header;
+        self.header.clear();
+    }
+
+    /// Applies a completed extended header payload and moves to its padding.
+    fn finish_extended(&mut self, kind: ExtendedKind) {
+        match kind {
+            ExtendedKind::LongName => {
+                let end = self
+                    .extended
+                    .iter()
+                    .position(|&b| b == 0)
+                    .unwrap_or(self.extended.len());
+                self.next_path = Some(String::from_utf8_lossy(&self.extended[..end]).into_owned());
+            }
+            ExtendedKind::Pax => {
+                if let Some(path) = parse_pax_path(&self.extended) {
+                    self.next_path = Some(path);
+                }
+            }
+            ExtendedKind::Other => {}
+        }
+
+        self.extended.clear();
+        self.state = State::Padding {
+            remaining: padding_for(self.size),
+        };
+        self.skip_empty_padding();
+    }
+
+    /// Moves from a finished padding state to the next header.
+    fn skip_empty_padding(&mut self) {
+        if self.state == (State::Padding { remaining: 0 }) {
+            self.state = State::Header;
+        }
+    }
+
+    /// Writes the parser position to suspended builder state.
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        match self.state {
+            State::Header => writer.put_u8(0),
+            State::Extended { remaining, kind } => {
+                writer.put_u8(1);
+                writer.put_u64(remaining);
+                writer.put_u8(match kind {
+                    ExtendedKind::LongName => 0,
+                    ExtendedKind::Pax => 1,
+                    ExtendedKind::Other => 2,
+                });
+            }
+            State::Data { remaining } => {
+                writer.put_u8(2);
+                writer.put_u64(remaining);
+            }
+            State::Padding { remaining } => {
+                writer.put_u8(3);
+                writer.put_u64(remaining);
+            }
+            State::Raw => writer.put_u8(4),
+        }
+
+        writer.put_bytes(&self.header);
+        writer.put_bytes(&self.extended);
+        match &self.next_path {
+            Some(path) => {
+                writer.put_bool(true);
+                writer.put_str(path);
+            }
+            None => writer.put_bool(false),
+        }
+        writer.put_str(&self.path);
+        writer.put_u64(self.size);
+    }
+
+    /// Reads a parser written by `write_state()`.
+    pub fn read_state(reader: &mut StateReader) -> io::Result<Self> {
+        let state = match reader.u8()? {
+            0 => State::Header,
+            1 => State::Extended {
+                remaining: reader.u64()?,
+                kind: match reader.u8()? {
+                    0 => ExtendedKind::LongName,
+                    1 => ExtendedKind::Pax,
+                    2 => ExtendedKind::Other,
+                    _ => return Err(invalid("Invalid tar parser state")),
+                },
+            },
+            2 => State::Data {
+                remaining: reader.u64()?,
+            },
+            3 => State::Padding {
+                remaining: reader.u64()?,
+            },
+            4 => State::Raw,
+            _ => return Err(invalid("Invalid tar parser state")),
+        };
+
+        let header = reader.bytes()?;
+        let extended = reader.bytes()?;
+        let next_path = if reader.bool()? {
+            Some(reader.string()?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            state,
+            header,
+            extended,
+            next_path,
+            path: reader.string()?,
+            size: reader.u64()?,
+        })
+    }
+}
+
+impl Default for TarParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Number of zero bytes that pad `size` up to a 512-byte boundary.
+fn padding_for(size: u64) -> u64 {
+    let block = TAR_BLOCK_SIZE as u64;
+    (block - size % block) % block
+}
+
+/// Parses a 512-byte header block.
+///
+/// # Returns
+///
+/// `(path, size, typeflag)`, or `None` if the checksum doesn't verify.
+fn parse_header_block(header: &[u8]) -> Option<(String, u64, u8)> {
+    if header.len() != TAR_BLOCK_SIZE {
+        return None;
+    }
+
+    // Checksum is computed with the checksum field itself read as spaces
+    let expected = parse_octal(&header[148..156])?;
+    let actual: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                b' ' as u64
+            } else {
+                b as u64
+            }
+        })
+        .sum();
+    if expected != actual {
+        return None;
+    }
+
+    let size = parse_size(&header[124..136])?;
+    let typeflag = header[156];
+
+    let name = field_str(&header[0..100]);
+    let path = if &header[257..263] == b"ustar\0" && header[345] != 0 {
+        format!("{}/{}", field_str(&header[345..500]), name)
+    } else {
+        name
+    };
+
+    Some((path, size, typeflag))
+}
+
+/// Reads a NUL-terminated header string field.
+fn field_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Parses an octal numeric field terminated by NUL or space.
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != 0 && b != b' ');
+
+    let mut value: u64 = 0;
+    let mut any = false;
+    for &b in digits {
+        if !(b'0'..=b'7').contains(&b) {
+            return None;
+        }
+        value = value.checked_mul(8)?.checked_add((b - b'0') as u64)?;
+        any = true;
+    }
+
+    any.then_some(value)
+}
+
+/// Parses the size field, which is octal or GNU base-256 for large members.
+fn parse_size(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut value: u64 = (field[0] & 0x7F) as u64;
+        for &b in &field[1..] {
+            value = value.checked_mul(256)?.checked_add(b as u64)?;
+        }
+        return Some(value);
+    }
+    parse_octal(field)
+}
+
+/// Extracts the `path` record from a pax extended header payload.
+fn parse_pax_path(payload: &[u8]) -> Option<String> {
+    let mut path = None;
+    let mut rest = payload;
+
+    // Records are "<len> <key>=<value>\n" where len covers the whole record
+    while !rest.is_empty() {
+        let space = rest.iter().position(|&b| b == b' ')?;
+        let len: usize = std::str::from_utf8(&rest[..space]).ok()?.parse().ok()?;
+        if len <= space + 1 || len > rest.len() {
+            return path;
+        }
+
+        let record = &rest[space + 1..len - 1];
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).into_owned());
+        }
+        rest = &rest[len..];
+    }
+
+    path
+}
+
+/// Location and hashes of a member in the source archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceMember {
+    /// Offset of the member's header block.
+    pub header_offset: u64,
+    /// Strong hash of the header block.
+    pub header_hash: u64,
+    /// Offset of the member's first content byte.
+    pub data_offset: u64,
+    /// Content size in bytes.
+    pub size: u64,
+    /// Strong hash of the content after the last complete block.
+    pub tail_hash: u64,
+}
+
+impl SourceMember {
+    /// Length of the trailing partial block that the block index doesn't cover.
+    pub fn tail_len(&self, block_size: usize) -> u64 {
+        self.size % block_size as u64
+    }
+
+    /// Source offset of the trailing partial block.
+    pub fn tail_offset(&self, block_size: usize) -> u64 {
+        self.data_offset + self.size - self.tail_len(block_size)
+    }
+
+    /// Writes the member to suspended builder state.
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.put_u64(self.header_offset);
+        writer.put_u64(self.header_hash);
+        writer.put_u64(self.data_offset);
+        writer.put_u64(self.size);
+        writer.put_u64(self.tail_hash);
+    }
+
+    /// Reads a member written by `write_state()`.
+    fn read_state(reader: &mut StateReader) -> io::Result<Self> {
+        Ok(Self {
+            header_offset: reader.u64()?,
+            header_hash: reader.u64()?,
+            data_offset: reader.u64()?,
+            size: reader.u64()?,
+            tail_hash: reader.u64()?,
+        })
+    }
+}
+
+/// Source-side indexer that aligns blocks on member boundaries.
+pub struct TarSourceIndex {
+    /// Member-aligned block index.
+    index: BlockIndex,
+    /// Source archive parser.
+    parser: TarParser,
+    /// Members by path.
+    members: HashMap<String, SourceMember>,
+    /// Member being indexed with its path.
+    current: Option<(String, SourceMember)>,
+    /// Hasher for the current member's tail.
+    tail_hasher: HashBuilder,
+    /// Bytes of the current member's content seen so far.
+    member_pos: u64,
+    /// Absolute source offset of the parser.
+    offset: u64,
+    /// Whether raw data has started (index is aligned for it already).
+    in_raw: bool,
+}
+
+impl TarSourceIndex {
+    /// Creates a tar-aware indexer with the given block size.
+    pub fn with_block_size(block_size: usize) -> Self {
+        Self {
+            index: BlockIndex::with_block_size(block_size),
+            parser: TarParser::new(),
+            members: HashMap::new(),
+            current: None,
+            tail_hasher: HashBuilder::new(),
+            member_pos: 0,
+            offset: 0,
+            in_raw: false,
+        }
+    }
+
+    /// Adds a chunk of the source archive.
+    pub fn add_chunk(&mut self, chunk: &[u8]) {
+        let Self {
+            index,
+            parser,
+            members,
+            current,
+            tail_hasher,
+            member_pos,
+            offset,
+            in_raw,
+        } = self;
+        let block_size = index.block_size();
+
+        parser.feed(chunk, |event| match event {
+            TarEvent::Meta(bytes) => *offset += bytes.len() as u64,
+            TarEvent::Member { path, size, header } => {
+                let data_offset = *offset + header.len() as u64;
+                *current = Some((
+                    path.to_string(),
+                    SourceMember {
+                        header_offset: *offset,
+                        header_hash: calculate_hash(header),
+                        data_offset,
+                        size,
+                        tail_hash: 0,
+                    },
+                ));
+                *tail_hasher = HashBuilder::new();
+                *member_pos = 0;
+                *offset = data_offset;
+                index.align_to(data_offset);
+            }
+            TarEvent::Data(bytes) => {
+                if let Some((_, member)) = current {
+                    // Hash the part of this slice that falls into the tail
+                    let tail_start = member.size - member.tail_len(block_size);
+                    let end = *member_pos + bytes.len() as u64;
+                    if end > tail_start {
+                        let skip = tail_start.saturating_sub(*member_pos) as usize;
+                        tail_hasher.update(&bytes[skip..]);
+                    }
+                    *member_pos = end;
+                }
+                index.add_chunk(bytes);
+                *offset += bytes.len() as u64;
+            }
+            TarEvent::MemberEnd => {
+                if let Some((path, mut member)) = current.take() {
+                    member.tail_hash = tail_hasher.finalize();
+                    members.insert(path, member);
+                }
+                index.align_to(*offset);
+            }
+            TarEvent::Raw(bytes) => {
+                if !*in_raw {
+                    index.align_to(*offset);
+                    *in_raw = true;
+                }
+                index.add_chunk(bytes);
+                *offset += bytes.len() as u64;
+            }
+        });
+    }
+
+    /// Finalizes the underlying block index.
+    pub fn finalize(&mut self) {
+        self.index.finalize();
+    }
+
+    /// Returns the number of members indexed.
+    pub fn member_count(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Looks up a source member by path.
+    pub fn member(&self, path: &str) -> Option<&SourceMember> {
+        self.members.get(path)
+    }
+
+    /// Caps the block index memory (see `BlockIndex::set_memory_budget`).
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        self.index.set_memory_budget(bytes);
+    }
+
+    /// Selects the weak hash algorithm (see `BlockIndex::set_rolling_hash`).
+    pub fn set_rolling_hash(&mut self, kind: RollingHashKind) {
+        self.index.set_rolling_hash(kind);
+    }
+
+    /// Returns the member-aligned block index.
+    pub fn index(&self) -> &BlockIndex {
+        &self.index
+    }
+}
+
+/// Target-side diff that processes a tar archive member by member.
+pub struct TarDiff {
+    /// Underlying block-matching diff.
+    diff: StreamingDiff,
+    /// Target archive parser.
+    parser: TarParser,
+    /// Source members by path.
+    members: HashMap<String, SourceMember>,
+    /// Source member matching the target member being diffed.
+    current: Option<SourceMember>,
+    /// Block size of the source index.
+    block_size: usize,
+}
+
+impl TarDiff {
+    /// Creates a `TarDiff` from a finalized tar source index.
+    pub fn new(source: TarSourceIndex) -> Self {
+        let block_size = source.index.block_size();
+
+        Self {
+            diff: StreamingDiff::new(source.index),
+            parser: TarParser::new(),
+            members: source.members,
+            current: None,
+            block_size,
+        }
+    }
+
+    /// Processes a chunk of the target archive, writing finished
+    /// instructions to `out`.
+    pub fn process_target_chunk(
+        &mut self,
+        chunk: &[u8],
+        out: &mut dyn PatchSink,
+    ) -> io::Result<()> {
+        let Self {
+            diff,
+            parser,
+            members,
+            current,
+            block_size,
+        } = self;
+        let block_size = *block_size;
+
+        // The parser callback can't fail, so keep the first error and skip
+        // the remaining events
+        let mut result = Ok(());
+        parser.feed(chunk, |event| {
+            if result.is_err() {
+                return;
+            }
+            result = match event {
+                TarEvent::Meta(bytes) => diff.push_insert(bytes, out),
+                TarEvent::Member {
+                    path,
+                    size: _,
+                    header,
+                } => {
+                    let source = members.get(path).cloned();
+
+                    match &source {
+                        Some(src)
+                            if calculate_hash(header) == src.header_hash
+                                && diff.verify(src.header_offset, header) =>
+                        {
+                            diff.push_copy(src.header_offset, header.len() as u32, out)
+                        }
+                        _ => diff.push_insert(header, out),
+                    }
+                    .map(|()| {
+                        diff.set_preferred_range(
+                            source
+                                .as_ref()
+                                .map(|src| src.data_offset..src.data_offset + src.size),
+                        );
+                        *current = source;
+                    })
+                }
+                TarEvent::Data(bytes) => diff.process_target_chunk(bytes, out),
+                TarEvent::MemberEnd => {
+                    let tail = diff.take_pending();
+
+                    match current.take() {
+                        Some(src)
+                            if !tail.is_empty()
+                                && tail.len() as u64 == src.tail_len(block_size)
+                                && calculate_hash(&tail) == src.tail_hash
+                                && diff.verify(src.tail_offset(block_size), &tail) =>
+                        {
+                            diff.push_copy(src.tail_offset(block_size), tail.len() as u32, out)
+                        }
+                        _ => diff.push_insert(&tail, out),
+                    }
+                    .map(|()| diff.set_preferred_range(None))
+                }
+                TarEvent::Raw(bytes) => diff.process_target_chunk(bytes, out),
+            };
+        });
+        result
+    }
+
+    /// Refines INSERT data against a finalized fine-grained index over the
+    /// whole source archive, or `None` to disable.
+    pub fn set_fine_index(&mut self, index: Option<BlockIndex>) {
+        self.diff.set_fine_index(index);
+    }
+
+    /// Sets the pending INSERT size at which it is emitted
+    /// (see `InstructionWriter::set_insert_limit()`).
+    pub fn set_insert_limit(&mut self, bytes: usize) {
+        self.diff.set_insert_limit(bytes);
+    }
+
+    /// Returns the fine-grained index, if set.
+    pub fn fine_index(&self) -> Option<&BlockIndex> {
+        self.diff.fine_index()
+    }
+
+    /// Verifies matches, including header and tail COPYs, against source
+    /// bytes read from `source` (see `StreamingDiff::set_source_access()`).
+    pub fn set_source_access(&mut self, source: Option<Box<dyn SourceAccess>>) {
+        self.diff.set_source_access(source);
+    }
+
+    /// Returns the number of candidates rejected by byte verification.
+    pub fn rejected_matches(&self) -> u64 {
+        self.diff.rejected_matches()
+    }
+
+    /// Finalizes processing, writing the remaining instructions to `out`.
+    pub fn finalize(&mut self, out: &mut dyn PatchSink) -> io::Result<()> {
+        let diff = &mut self.diff;
+        let mut result = Ok(());
+        self.parser.finish(|event| {
+            if let TarEvent::Meta(bytes) = event {
+                if result.is_ok() {
+                    result = diff.push_insert(bytes, out);
+                }
+            }
+        });
+        result?;
+        self.diff.finalize(out)
+    }
+
+    /// Returns the source block index.
+    pub fn index(&self) -> &BlockIndex {
+        self.diff.index()
+    }
+
+    /// Writes the diff, parser and member table to suspended builder state.
+    pub fn write_state(&self, writer: &mut StateWriter) -> io::Result<()> {
+        self.diff.write_state(writer)?;
+        self.parser.write_state(writer);
+
+        let mut paths: Vec<&String> = self.members.keys().collect();
+        paths.sort();
+
+        writer.put_u64(paths.len() as u64);
+        for path in paths {
+            writer.put_str(path);
+            self.members[path].write_state(writer);
+        }
+
+        match &self.current {
+            Some(member) => {
+                writer.put_bool(true);
+                member.write_state(writer);
+            }
+            None => writer.put_bool(false),
+        }
+        Ok(())
+    }
+
+    /// Reads a tar diff written by `write_state()`.
+    pub fn read_state(reader: &mut StateReader) -> io::Result<Self> {
+        let diff = StreamingDiff::read_state(reader)?;
+        let parser = TarParser::read_state(reader)?;
+
+        let member_count = reader.u64()?;
+        let mut members = HashMap::new();
+        for _ in 0..member_count {
+            let path = reader.string()?;
+            members.insert(path, SourceMember::read_state(reader)?);
+        }
+
+        let current = if reader.bool()? {
+            Some(SourceMember::read_state(reader)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            block_size: diff.block_size(),
+            diff,
+            parser,
+            members,
+            current,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::patch_format::TYPE_COPY;
+    use crate::test_util::{apply, noise};
+
+    /// Builds a ustar header block.
+    fn header(path: &str, size: u64, mtime: u64) -> Vec<u8> {
+        let mut h = vec![0u8; TAR_BLOCK_SIZE];
+        h[..path.len()].copy_from_slice(path.as_bytes());
+        h[100..107].copy_from_slice(b"0000644");
+        h[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        h[136..147].copy_from_slice(format!("{:011o}", mtime).as_bytes());
+        h[156] = b'0';
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+
+        h[148..156].fill(b' ');
+        let sum: u64 = h.iter().map(|&b| b as u64).sum();
+        h[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        h
+    }
+
+    /// Builds a tar archive from (path, content) pairs.
+    fn archive(members: &[(&str, Vec<u8>)], mtime: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (path, content) in members {
+            out.extend_from_slice(&header(path, content.len() as u64, mtime));
+            out.extend_from_slice(content);
+            out.resize(out.len() + padding_for(content.len() as u64) as usize, 0);
+        }
+        out.resize(out.len() + 2 * TAR_BLOCK_SIZE, 0);
+        out
+    }
+
+    /// Runs a tar-aware diff and returns the serialized instructions.
+    fn tar_diff(source: &[u8], target: &[u8], block_size: usize, chunk: usize) -> Vec<u8> {
+        let mut index = TarSourceIndex::with_block_size(block_size);
+        for piece in source.chunks(chunk) {
+            index.add_chunk(piece);
+        }
+        index.finalize();
+
+        let mut diff = TarDiff::new(index);
+        let mut output = Vec::new();
+        for piece in target.chunks(chunk) {
+            diff.process_target_chunk(piece, &mut output).unwrap();
+        }
+        diff.finalize(&mut output).unwrap();
+        output
+    }
+
+    /// Sums INSERT payload bytes.
+    fn inserted_bytes(patch: &[u8]) -> usize {
+        let mut total = 0;
+        let mut pos = 0;
+        while pos < patch.len() {
+            if patch[pos] == TYPE_COPY {
+                pos += 13;
+            } else {
+                let len = u32::from_le_bytes(patch[pos + 1..pos + 5].try_into().unwrap()) as usize;
+                total += len;
+                pos += 5 + len;
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn test_parser_events() {
+        let tar = archive(&[("a.txt", b"hello".to_vec()), ("empty", Vec::new())], 0);
+
+        let mut members = Vec::new();
+        let mut data = Vec::new();
+        let mut total = 0;
+        let mut parser = TarParser::new();
+        for piece in tar.chunks(7) {
+            parser.feed(piece, |event| match event {
+                TarEvent::Member { path, size, header } => {
+                    total += header.len();
+                    members.push((path.to_string(), size));
+                }
+                TarEvent::Data(bytes) => {
+                    total += bytes.len();
+                    data.extend_from_slice(bytes);
+                }
+                TarEvent::Meta(bytes) | TarEvent::Raw(bytes) => total += bytes.len(),
+                TarEvent::MemberEnd => {}
+            });
+        }
+
+        assert_eq!(
+            members,
+            vec![("a.txt".to_string(), 5), ("empty".to_string(), 0)]
+        );
+        assert_eq!(data, b"hello");
+        assert_eq!(total, tar.len());
+    }
+
+    #[test]
+    fn test_gnu_long_name() {
+        let long = "d/".repeat(80) + "file";
+        let mut tar = header("././@LongLink", long.len() as u64 + 1, 0);
+        tar[156] = b'L';
+        tar[148..156].fill(b' ');
+        let sum: u64 = tar.iter().map(|&b| b as u64).sum();
+        tar[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        tar.extend_from_slice(long.as_bytes());
+        tar.push(0);
+        tar.resize(TAR_BLOCK_SIZE * 2, 0);
+        tar.extend_from_slice(&archive(&[("short", b"x".to_vec())], 0));
+
+        let mut paths = Vec::new();
+        TarParser::new().feed(&tar, |event| {
+            if let TarEvent::Member { path, .. } = event {
+                paths.push(path.to_string());
+            }
+        });
+
+        assert_eq!(paths, vec![long]);
+    }
+
+    #[test]
+    fn test_pax_path() {
+        let payload = b"18 path=dir/a.bin\n10 foo=ba\n";
+        assert_eq!(parse_pax_path(payload), Some("dir/a.bin".to_string()));
+    }
+
+    #[test]
+    fn test_source_members_recorded() {
+        let content = noise(5000, 1);
+        let tar = archive(&[("a.bin", content.clone())], 0);
+
+        let mut index = TarSourceIndex::with_block_size(1024);
+        index.add_chunk(&tar);
+        index.finalize();
+
+        let member = index.member("a.bin").unwrap();
+        assert_eq!(member.header_offset, 0);
+        assert_eq!(member.data_offset, 512);
+        assert_eq!(member.size, 5000);
+        assert_eq!(member.tail_len(1024), 5000 % 1024);
+        assert_eq!(
+            member.tail_hash,
+            calculate_hash(&content[5000 - 5000 % 1024..])
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_with_shifted_members() {
+        let a = noise(9000, 1);
+        let b = noise(3000, 2);
+        let c = noise(700, 3);
+        let source = archive(&[("a", a.clone()), ("b", b.clone()), ("c", c.clone())], 100);
+
+        // New member shifts everything; mtimes change in every header
+        let mut b2 = b.clone();
+        b2[1500] ^= 0xFF;
+        let target = archive(
+            &[("new", noise(1234, 9)), ("a", a), ("b", b2), ("c", c)],
+            200,
+        );
+
+        for chunk in [1, 511, 4096, target.len()] {
+            let patch = tar_diff(&source, &target, 1024, chunk);
+            assert_eq!(apply(&source, &patch), target);
+        }
+    }
+
+    #[test]
+    fn test_state_roundtrip_mid_archive() {
+        let source = archive(&[("a", noise(9000, 1)), ("b", noise(3000, 2))], 100);
+        let target = archive(
+            &[
+                ("new", noise(1234, 9)),
+                ("a", noise(9000, 1)),
+                ("b", noise(3000, 2)),
+            ],
+            200,
+        );
+        let expected = tar_diff(&source, &target, 1024, 333);
+
+        for split in [100, 700, 2000, 9000] {
+            let mut index = TarSourceIndex::with_block_size(1024);
+            index.add_chunk(&source);
+            index.finalize();
+
+            let mut diff = TarDiff::new(index);
+            let mut output = Vec::new();
+            for piece in target[..split].chunks(333) {
+                diff.process_target_chunk(piece, &mut output).unwrap();
+            }
+
+            let mut writer = StateWriter::new();
+            diff.write_state(&mut writer).unwrap();
+            let bytes = writer.into_bytes();
+            let mut reader = StateReader::new(&bytes);
+            let mut resumed = TarDiff::read_state(&mut reader).unwrap();
+            reader.finish().unwrap();
+
+            for piece in target[split..].chunks(333) {
+                resumed.process_target_chunk(piece, &mut output).unwrap();
+            }
+            resumed.finalize(&mut output).unwrap();
+
+            assert_eq!(apply(&source, &output), target, "split {}", split);
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_unchanged_members_are_copied() {
+        let members = [("x", noise(10_000, 4)), ("y", noise(300, 5))];
+        let source = archive(&members, 7);
+        let target = source.clone();
+
+        let patch = tar_diff(&source, &target, 1024, 1000);
+
+        assert_eq!(apply(&source, &patch), target);
+        // Only padding and the end-of-archive zeros stay literal
+        assert!(inserted_bytes(&patch) < 2 * TAR_BLOCK_SIZE + 1024);
+    }
+
+    #[test]
+    fn test_member_aligned_matching_beats_plain() {
+        let a = noise(20_000, 6);
+        let source = archive(&[("a", a.clone())], 1);
+        let target = archive(&[("pad", noise(100, 7)), ("a", a)], 2);
+
+        let patch = tar_diff(&source, &target, 4096, 4096);
+        assert_eq!(apply(&source, &patch), target);
+
+        // Content plus tail is copied; only headers, padding and the small member are literal
+        assert!(inserted_bytes(&patch) < 4 * TAR_BLOCK_SIZE + 100 + 2 * TAR_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_partial_header_at_end_is_kept() {
+        let source = archive(&[("a.txt", noise(3000, 9))], 0);
+
+        // Shorter than one header block
+        let short = b"not a tar archive at all".to_vec();
+        let patch = tar_diff(&source, &short, 1024, 7);
+        assert_eq!(apply(&source, &patch), short);
+
+        // Ends inside the header after the first member
+        let mut truncated = archive(&[("a.txt", noise(3000, 9))], 0);
+        truncated.truncate(TAR_BLOCK_SIZE + 3072 + 100);
+        truncated[TAR_BLOCK_SIZE + 3072..].copy_from_slice(&header("b.txt", 10, 0)[..100]);
+        for chunk in [64, 1000, truncated.len()] {
+            let patch = tar_diff(&source, &truncated, 1024, chunk);
+            assert_eq!(apply(&source, &patch), truncated);
+        }
+    }
+
+    #[test]
+    fn test_non_tar_falls_back_to_raw() {
+        let source = noise(8192, 8);
+        let mut target = source.clone();
+        target[100] ^= 1;
+
+        let patch = tar_diff(&source, &target, 1024, 1000);
+        assert_eq!(apply(&source, &patch), target);
+        assert!(inserted_bytes(&patch) <= 2048);
+    }
+}
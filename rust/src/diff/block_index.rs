@@ -4,11 +4,41 @@
 //! O(1) lookups during diff generation. Uses two-level hashing:
 //! - Weak hash (32-bit rolling hash) for fast candidate lookup
 //! - Strong hash (64-bit FNV-1a) for collision verification
+//!
+//! Blocks are stored in flat arrays indexed by block number; offsets are
+//! derived from the block number instead of being stored. `finalize()`
+//! sorts block numbers by weak hash and builds a bucket directory over the
+//! top bits of the hash, so a lookup is one directory read plus a short
+//! binary search. Candidates for one weak hash are in source order, so the
+//! one nearest a given offset is found by another binary search. A
+//! `HashFilter` built alongside rejects most weak hashes that are not in
+//! the index before the directory is touched; on unrelated data nearly
+//! every target position is such a miss.
+//!
+//! With a memory budget set, the index switches to sampled indexing once
+//! the budget would be exceeded: only blocks whose strong hash has its top
+//! `sample_level` bits clear are kept, and the level rises until the index
+//! fits. Sampling is content-defined, so any kept block can still be found
+//! at any target position; coverage (and patch quality) degrades
+//! gracefully instead of memory growing without bound.
+//!
+//! An index built by `DiskIndexBuilder` keeps its entries in external
+//! storage instead (see `disk_index`); lookups go through the same methods.
 
-use super::rolling_hash::RollingHash;
+use super::disk_index::{DiskEntries, DiskLookup};
+use super::hash_filter::HashFilter;
+use super::rolling_hash::{RollingHashKind, RollingHasher, WeakHasher};
+use crate::error::PatchlyError;
 use crate::format::patch_format::calculate_hash;
+use crate::format::state::{invalid, StateReader, StateWriter};
+use crate::simd;
 use crate::DEFAULT_CHUNK_SIZE;
-use std::collections::HashMap;
+use std::io;
+use std::mem::size_of;
+use std::ops::Range;
+
+/// Estimated bytes per indexed block when sampling, including lookup tables.
+const SAMPLED_ENTRY_BYTES: usize = 24;
 
 /// Entry storing block metadata for verification.
 #[derive(Clone, Debug)]
@@ -19,21 +49,97 @@ pub struct BlockEntry {
     pub strong_hash: u64,
 }
 
+/// Iterator over the entries of one weak hash, returned by
+/// `BlockIndex::lookup()`.
+pub struct Lookup<'a> {
+    /// Index being searched.
+    index: &'a BlockIndex,
+    /// Remaining candidates.
+    entries: LookupEntries<'a>,
+}
+
+/// Candidates of a `Lookup`, by where the index keeps its entries.
+enum LookupEntries<'a> {
+    /// Positions in the in-memory `sorted` table.
+    Memory(Range<usize>),
+    /// Entries read from external storage.
+    Disk(DiskEntries<'a>),
+}
+
+impl Iterator for Lookup<'_> {
+    type Item = BlockEntry;
+
+    fn next(&mut self) -> Option<BlockEntry> {
+        match &mut self.entries {
+            LookupEntries::Memory(candidates) => {
+                let entry = self.index.sorted[candidates.next()?];
+                Some(BlockEntry {
+                    offset: self.index.entry_offset(entry),
+                    strong_hash: self.index.strong[entry as usize],
+                })
+            }
+            LookupEntries::Disk(entries) => entries.next(),
+        }
+    }
+}
+
+/// Run of consecutively indexed blocks starting at a source offset.
+///
+/// Plain indexing has a single run; `align_to()` starts a new one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BlockRun {
+    /// Number of the first block in the run.
+    first_block: u32,
+    /// Source offset of the first block.
+    offset: u64,
+}
+
 /// Memory-efficient block index that stores hash-to-offset mappings.
 ///
 /// # Memory Usage
 ///
-/// - Per block: ~20 bytes (u32 weak_hash key + u64 offset + u64 strong_hash)
+/// - Per block: ~21 bytes (u32 weak hash + u64 strong hash + u32 sorted
+///   block number + up to 4 bytes of bucket directory + 1-2 bytes of
+///   prefilter)
 /// - 1GB file with 4KB blocks = ~250k blocks = ~5MB index
+///
+/// Sampled indexing adds a u32 block number per kept block. Use
+/// `memory_usage()` / `bytes_per_block()` for measured figures.
 pub struct BlockIndex {
     /// Block size used for chunking.
     block_size: usize,
-    /// Hash table: weak_hash -> list of block entries.
-    index: HashMap<u32, Vec<BlockEntry>>,
+    /// Weak hash algorithm.
+    hash_kind: RollingHashKind,
+    /// Weak hash of each kept block, by entry.
+    weak: Vec<u32>,
+    /// Strong hash of each kept block, by entry.
+    strong: Vec<u64>,
+    /// Block number of each entry; empty while every block is kept.
+    block_numbers: Vec<u32>,
+    /// Number of blocks seen, kept or not.
+    blocks_seen: u32,
+    /// Top strong hash bits that must be zero for a block to be kept.
+    sample_level: u32,
+    /// Entry limit derived from the memory budget.
+    max_entries: usize,
+    /// Block runs in block order (never empty).
+    runs: Vec<BlockRun>,
+    /// Entries sorted by (weak hash, entry); built by `finalize()`.
+    sorted: Vec<u32>,
+    /// Start of each bucket in `sorted`, plus an end marker.
+    buckets: Vec<u32>,
+    /// Number of weak hash bits used to select a bucket.
+    bucket_bits: u32,
+    /// Number of distinct weak hashes; counted by `finalize()`.
+    unique_hashes: usize,
+    /// Prefilter over the weak hashes; built by `finalize()`.
+    filter: Option<HashFilter>,
     /// Total bytes indexed so far.
     bytes_indexed: u64,
     /// Buffer for incomplete block from previous chunk.
     pending: Vec<u8>,
+    /// Entries in external storage; replaces the in-memory tables.
+    disk: Option<Box<DiskLookup>>,
 }
 
 impl BlockIndex {
@@ -50,74 +156,275 @@ impl BlockIndex {
     pub fn with_block_size(block_size: usize) -> Self {
         Self {
             block_size,
-            index: HashMap::new(),
+            hash_kind: RollingHashKind::default(),
+            weak: Vec::new(),
+            strong: Vec::new(),
+            block_numbers: Vec::new(),
+            blocks_seen: 0,
+            sample_level: 0,
+            max_entries: usize::MAX,
+            runs: vec![BlockRun {
+                first_block: 0,
+                offset: 0,
+            }],
+            sorted: Vec::new(),
+            buckets: Vec::new(),
+            bucket_bits: 0,
+            unique_hashes: 0,
+            filter: None,
             bytes_indexed: 0,
             pending: Vec::with_capacity(block_size),
+            disk: None,
+        }
+    }
+
+    /// Creates a finalized index over entries in external storage.
+    pub(crate) fn from_disk(
+        block_size: usize,
+        hash_kind: RollingHashKind,
+        bytes_indexed: u64,
+        disk: DiskLookup,
+    ) -> Self {
+        let mut index = Self::with_block_size(block_size);
+        index.hash_kind = hash_kind;
+        index.bytes_indexed = bytes_indexed;
+        index.pending = Vec::new();
+        index.disk = Some(Box::new(disk));
+        index
+    }
+
+    /// Caps index memory at roughly `bytes` (0 = unlimited).
+    ///
+    /// Once the cap would be exceeded, the index samples blocks by content
+    /// (see `coverage()`). Best set before adding data.
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        self.max_entries = match bytes {
+            0 => usize::MAX,
+            _ => (bytes / SAMPLED_ENTRY_BYTES).max(1),
+        };
+        self.enforce_budget();
+    }
+
+    /// Selects the weak hash algorithm.
+    ///
+    /// Ignored once blocks have been added.
+    pub fn set_rolling_hash(&mut self, kind: RollingHashKind) {
+        if self.blocks_seen == 0 && self.pending.is_empty() {
+            self.hash_kind = kind;
         }
     }
 
+    /// Returns the weak hash algorithm.
+    pub fn rolling_hash(&self) -> RollingHashKind {
+        self.hash_kind
+    }
+
+    /// Creates a hasher matching the indexed weak hashes.
+    pub fn hasher(&self) -> WeakHasher {
+        self.hash_kind.hasher(self.block_size)
+    }
+
     /// Adds a chunk of source data to the index.
     ///
     /// Stores both weak hash (for lookup) and strong hash (for verification).
+    /// At most `u32::MAX` blocks are indexed; later blocks are skipped.
     ///
     /// # Arguments
     ///
     /// * `chunk` - Raw bytes to index.
     pub fn add_chunk(&mut self, chunk: &[u8]) {
-        let mut hasher = RollingHash::new(self.block_size);
+        let mut hasher = self.hasher();
 
         // Combine pending bytes with new chunk
         let mut data = std::mem::take(&mut self.pending);
         data.extend_from_slice(chunk);
 
+        // Strong hashes of all complete blocks, several blocks per vector
+        let complete = data.len() - data.len() % self.block_size;
+        let mut strong_hashes = Vec::with_capacity(complete / self.block_size);
+        simd::block_hashes(&data[..complete], self.block_size, &mut strong_hashes);
+
         // Process complete blocks
-        let mut offset = 0;
-        while offset + self.block_size <= data.len() {
-            let block = &data[offset..offset + self.block_size];
-            let weak_hash = hasher.hash_chunk(block);
-            let strong_hash = calculate_hash(block);
-
-            // Store weak_hash -> (offset, strong_hash) mapping
-            self.index
-                .entry(weak_hash)
-                .or_insert_with(Vec::new)
-                .push(BlockEntry {
-                    offset: self.bytes_indexed,
-                    strong_hash,
-                });
+        for (block, &strong_hash) in data[..complete]
+            .chunks_exact(self.block_size)
+            .zip(&strong_hashes)
+        {
+            if self.blocks_seen < u32::MAX {
+                if self.is_sampled(strong_hash) {
+                    if self.sample_level > 0 {
+                        self.block_numbers.push(self.blocks_seen);
+                    }
+                    self.weak.push(hasher.hash_chunk(block));
+                    self.strong.push(strong_hash);
+                    self.enforce_budget();
+                }
+                self.blocks_seen += 1;
+            }
 
             self.bytes_indexed += self.block_size as u64;
-            offset += self.block_size;
         }
 
         // Save remaining bytes for next chunk
-        if offset < data.len() {
-            self.pending = data[offset..].to_vec();
+        if complete < data.len() {
+            self.pending = data[complete..].to_vec();
+        }
+    }
+
+    /// Restarts block boundaries at an absolute source offset.
+    ///
+    /// Discards any incomplete pending block so the next indexed block
+    /// starts exactly at `offset`. Used by container-aware indexing to align
+    /// blocks on member boundaries instead of fixed file offsets.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Source offset where the next block begins.
+    pub fn align_to(&mut self, offset: u64) {
+        self.pending.clear();
+        self.bytes_indexed = offset;
+
+        let run = BlockRun {
+            first_block: self.blocks_seen,
+            offset,
+        };
+        let last = self.runs.last_mut().expect("block runs are never empty");
+        if last.first_block == run.first_block {
+            *last = run;
+        } else {
+            self.runs.push(run);
+        }
+    }
+
+    /// Checks whether a block is kept at the current sample level.
+    fn is_sampled(&self, strong_hash: u64) -> bool {
+        self.sample_level == 0 || strong_hash >> (64 - self.sample_level) == 0
+    }
+
+    /// Raises the sample level until the entries fit the budget.
+    fn enforce_budget(&mut self) {
+        if self.weak.len() < self.max_entries {
+            return;
+        }
+
+        if self.sample_level == 0 {
+            self.block_numbers = (0..self.weak.len() as u32).collect();
+        }
+
+        while self.weak.len() >= self.max_entries && self.sample_level < 64 {
+            self.sample_level += 1;
+
+            let mut kept = 0;
+            for entry in 0..self.weak.len() {
+                if self.is_sampled(self.strong[entry]) {
+                    self.weak[kept] = self.weak[entry];
+                    self.strong[kept] = self.strong[entry];
+                    self.block_numbers[kept] = self.block_numbers[entry];
+                    kept += 1;
+                }
+            }
+            self.weak.truncate(kept);
+            self.strong.truncate(kept);
+            self.block_numbers.truncate(kept);
         }
     }
 
     /// Finalizes indexing after all source chunks have been added.
     ///
+    /// Builds the lookup tables; lookups before this find nothing.
     /// Note: Partial blocks at the end are NOT indexed.
     ///
     /// # Returns
     ///
     /// Total bytes indexed.
     pub fn finalize(&mut self) -> u64 {
-        self.pending.clear();
+        self.pending = Vec::new();
+        self.weak.shrink_to_fit();
+        self.strong.shrink_to_fit();
+        self.block_numbers.shrink_to_fit();
+        self.build_lookup();
         self.bytes_indexed
     }
 
+    /// Sorts block numbers by weak hash and builds the bucket directory
+    /// and prefilter.
+    fn build_lookup(&mut self) {
+        let weak = &self.weak;
+        let mut filter = HashFilter::with_capacity(weak.len());
+        for &hash in weak {
+            filter.insert(hash);
+        }
+
+        let mut sorted: Vec<u32> = (0..weak.len() as u32).collect();
+        sorted.sort_unstable_by_key(|&entry| (weak[entry as usize], entry));
+
+        // About one entry per bucket: 2^bits <= entries
+        let bits = (usize::BITS - 1).saturating_sub(weak.len().leading_zeros());
+        let mut buckets = vec![0u32; (1usize << bits) + 1];
+        let mut unique_hashes = 0;
+        let mut previous = None;
+        for &entry in &sorted {
+            let hash = weak[entry as usize];
+            buckets[bucket_of(hash, bits) + 1] += 1;
+            if previous != Some(hash) {
+                unique_hashes += 1;
+                previous = Some(hash);
+            }
+        }
+        for i in 1..buckets.len() {
+            buckets[i] += buckets[i - 1];
+        }
+
+        self.sorted = sorted;
+        self.buckets = buckets;
+        self.bucket_bits = bits;
+        self.unique_hashes = unique_hashes;
+        self.filter = Some(filter);
+    }
+
+    /// Returns the positions in `sorted` of blocks with a weak hash.
+    fn candidates(&self, weak_hash: u32) -> Range<usize> {
+        match &self.filter {
+            Some(filter) if filter.may_contain(weak_hash) => {}
+            _ => return 0..0,
+        }
+
+        let bucket = bucket_of(weak_hash, self.bucket_bits);
+        let start = self.buckets[bucket] as usize;
+        let end = self.buckets[bucket + 1] as usize;
+        let slots = &self.sorted[start..end];
+
+        let first = slots.partition_point(|&entry| self.weak[entry as usize] < weak_hash);
+        let last = slots.partition_point(|&entry| self.weak[entry as usize] <= weak_hash);
+        start + first..start + last
+    }
+
+    /// Returns the source offset of an entry's block.
+    fn entry_offset(&self, entry: u32) -> u64 {
+        let block = if self.block_numbers.is_empty() {
+            entry
+        } else {
+            self.block_numbers[entry as usize]
+        };
+
+        let run = self.runs[self.runs.partition_point(|run| run.first_block <= block) - 1];
+        run.offset + (block - run.first_block) as u64 * self.block_size as u64
+    }
+
     /// Looks up block entries where a given weak hash appears.
     ///
     /// # Returns
     ///
-    /// Slice of block entries, or empty slice if hash not found.
-    pub fn lookup(&self, weak_hash: u32) -> &[BlockEntry] {
-        self.index
-            .get(&weak_hash)
-            .map(|v| v.as_slice())
-            .unwrap_or(&[])
+    /// Iterator over the block entries in source order; empty if the hash
+    /// is not found.
+    pub fn lookup(&self, weak_hash: u32) -> Lookup<'_> {
+        let entries = match &self.disk {
+            Some(disk) => LookupEntries::Disk(disk.entries(weak_hash)),
+            None => LookupEntries::Memory(self.candidates(weak_hash)),
+        };
+        Lookup {
+            index: self,
+            entries,
+        }
     }
 
     /// Finds a matching block by weak hash AND strong hash verification.
@@ -131,22 +438,205 @@ impl BlockIndex {
     ///
     /// Source offset if a verified match is found, None otherwise.
     pub fn find_verified_match(&self, weak_hash: u32, target_block: &[u8]) -> Option<u64> {
-        let entries = self.lookup(weak_hash);
-        if entries.is_empty() {
+        // Strong hash is only computed once a candidate exists
+        let mut target_strong_hash = None;
+        self.lookup(weak_hash)
+            .find(|entry| {
+                entry.strong_hash
+                    == *target_strong_hash.get_or_insert_with(|| calculate_hash(target_block))
+            })
+            .map(|entry| entry.offset)
+    }
+
+    /// Finds a verified match, preferring entries inside a source range.
+    ///
+    /// Falls back to any verified entry when none lies within `preferred`.
+    ///
+    /// # Arguments
+    ///
+    /// * `weak_hash` - Rolling hash of target block.
+    /// * `target_block` - Actual bytes of target block for strong hash verification.
+    /// * `preferred` - Source byte range whose entries are tried first.
+    pub fn find_verified_match_in(
+        &self,
+        weak_hash: u32,
+        target_block: &[u8],
+        preferred: &Range<u64>,
+    ) -> Option<u64> {
+        let mut target_strong_hash = None;
+        let mut fallback = None;
+
+        for entry in self.lookup(weak_hash) {
+            let target = *target_strong_hash.get_or_insert_with(|| calculate_hash(target_block));
+            if entry.strong_hash != target {
+                continue;
+            }
+            if preferred.contains(&entry.offset) {
+                return Some(entry.offset);
+            }
+            fallback = fallback.or(Some(entry.offset));
+        }
+
+        fallback
+    }
+
+    /// Finds a verified match, choosing among duplicates by locality.
+    ///
+    /// Entries inside `preferred` win over the rest. Within that, the entry
+    /// nearest `near` is chosen: an entry at exactly `near` continues the
+    /// previous COPY, and nearby ones keep apply-time reads sequential.
+    /// Without `near` the first entry in source order is chosen, as in
+    /// `find_verified_match_in()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `weak_hash` - Rolling hash of target block.
+    /// * `target_block` - Actual bytes of target block for strong hash verification.
+    /// * `preferred` - Source byte range whose entries are tried first.
+    /// * `near` - Source offset just past the previous COPY.
+    pub fn find_best_match(
+        &self,
+        weak_hash: u32,
+        target_block: &[u8],
+        preferred: Option<&Range<u64>>,
+        near: Option<u64>,
+    ) -> Option<u64> {
+        let near = match (near, preferred) {
+            (Some(near), _) => near,
+            (None, Some(range)) => {
+                return self.find_verified_match_in(weak_hash, target_block, range)
+            }
+            (None, None) => return self.find_verified_match(weak_hash, target_block),
+        };
+
+        if self.disk.is_some() {
+            let rank = |offset: u64| {
+                (
+                    !preferred.is_some_and(|r| r.contains(&offset)),
+                    offset.abs_diff(near),
+                )
+            };
+            let mut best: Option<u64> = None;
+            let mut target_strong_hash = None;
+
+            for entry in self.lookup(weak_hash) {
+                let target =
+                    *target_strong_hash.get_or_insert_with(|| calculate_hash(target_block));
+                if entry.strong_hash == target && best.is_none_or(|b| rank(entry.offset) < rank(b))
+                {
+                    best = Some(entry.offset);
+                }
+            }
+            return best;
+        }
+
+        let candidates = self.candidates(weak_hash);
+        if candidates.is_empty() {
             return None;
         }
 
-        // Compute strong hash of target block
         let target_strong_hash = calculate_hash(target_block);
+        if let Some(range) = preferred {
+            let inside = self.offset_subrange(candidates.clone(), range);
+            if let Some(offset) = self.nearest(inside, target_strong_hash, near) {
+                return Some(offset);
+            }
+        }
+        self.nearest(candidates, target_strong_hash, near)
+    }
 
-        // Find entry with matching strong hash
-        for entry in entries {
-            if entry.strong_hash == target_strong_hash {
-                return Some(entry.offset);
+    /// Returns the candidates whose source offsets lie within `range`.
+    fn offset_subrange(&self, candidates: Range<usize>, range: &Range<u64>) -> Range<usize> {
+        let slots = &self.sorted[candidates.clone()];
+        let start = slots.partition_point(|&entry| self.entry_offset(entry) < range.start);
+        let end = slots.partition_point(|&entry| self.entry_offset(entry) < range.end);
+        candidates.start + start..candidates.start + end.max(start)
+    }
+
+    /// Finds the candidate with a strong hash nearest `near`, probing
+    /// outward from `near` in both directions. Ties go to the later offset.
+    fn nearest(&self, candidates: Range<usize>, strong_hash: u64, near: u64) -> Option<u64> {
+        let slots = &self.sorted[candidates];
+        let split = slots.partition_point(|&entry| self.entry_offset(entry) < near);
+        let mut after = slots[split..].iter();
+        let mut before = slots[..split].iter().rev();
+
+        let offset_of = |entry: Option<&u32>| entry.map(|&e| (e, self.entry_offset(e)));
+        let mut next_after = offset_of(after.next());
+        let mut next_before = offset_of(before.next());
+
+        loop {
+            let (entry, offset) = match (next_after, next_before) {
+                (Some(a), Some(b)) if a.1 - near <= near - b.1 => {
+                    next_after = offset_of(after.next());
+                    a
+                }
+                (Some(a), None) => {
+                    next_after = offset_of(after.next());
+                    a
+                }
+                (_, Some(b)) => {
+                    next_before = offset_of(before.next());
+                    b
+                }
+                (None, None) => return None,
+            };
+
+            if self.strong[entry as usize] == strong_hash {
+                return Some(offset);
             }
         }
+    }
+
+    /// Finds a strong-hash match that also passes `check`.
+    ///
+    /// The candidate `find_best_match()` picks is tried first, so results
+    /// only differ from it when `check` rejects a candidate. The rest are
+    /// then tried inside `preferred` first, then by distance to `near` (or
+    /// in source order without it), until `check(offset)` accepts one. Lets
+    /// callers compare actual source bytes and fall back to another
+    /// duplicate when a candidate is rejected.
+    ///
+    /// # Arguments
+    ///
+    /// * `weak_hash` - Rolling hash of target block.
+    /// * `target_block` - Actual bytes of target block for strong hash verification.
+    /// * `preferred` - Source byte range whose entries are tried first.
+    /// * `near` - Source offset just past the previous COPY.
+    /// * `check` - Returns whether the block at a source offset is accepted.
+    pub fn find_checked_match<F>(
+        &self,
+        weak_hash: u32,
+        target_block: &[u8],
+        preferred: Option<&Range<u64>>,
+        near: Option<u64>,
+        mut check: F,
+    ) -> Option<u64>
+    where
+        F: FnMut(u64) -> bool,
+    {
+        let first = self.find_best_match(weak_hash, target_block, preferred, near)?;
+        if check(first) {
+            return Some(first);
+        }
+
+        // Rejected: collect the other candidates (rare, so allocating is fine)
+        let target_strong_hash = calculate_hash(target_block);
+        let mut candidates: Vec<u64> = self
+            .lookup(weak_hash)
+            .filter(|entry| entry.strong_hash == target_strong_hash && entry.offset != first)
+            .map(|entry| entry.offset)
+            .collect();
+
+        // Stable: source order breaks ties
+        candidates.sort_by_key(|offset| {
+            (
+                !preferred.is_some_and(|range| range.contains(offset)),
+                near.map_or(0, |near| offset.abs_diff(near)),
+            )
+        });
 
-        None
+        candidates.into_iter().find(|&offset| check(offset))
     }
 
     /// Returns the block size.
@@ -154,9 +644,170 @@ impl BlockIndex {
         self.block_size
     }
 
-    /// Returns the number of unique hashes in the index.
+    /// Returns the number of indexed blocks.
+    pub fn block_count(&self) -> usize {
+        match &self.disk {
+            Some(disk) => disk.entry_count() as usize,
+            None => self.weak.len(),
+        }
+    }
+
+    /// Checks whether the entries live in external storage.
+    pub fn is_disk_backed(&self) -> bool {
+        self.disk.is_some()
+    }
+
+    /// Returns the number of failed storage reads during lookups.
+    ///
+    /// Failed reads are treated as misses; always 0 for in-memory indexes.
+    pub fn storage_errors(&self) -> u64 {
+        self.disk.as_ref().map_or(0, |disk| disk.read_errors())
+    }
+
+    /// Returns the fraction of source blocks kept in the index (0.0..=1.0).
+    ///
+    /// 1.0 unless a memory budget forced sampling.
+    pub fn coverage(&self) -> f64 {
+        if self.blocks_seen == 0 {
+            return 1.0;
+        }
+        self.weak.len() as f64 / self.blocks_seen as f64
+    }
+
+    /// Returns the current sample level (0 = every block is kept).
+    pub fn sample_level(&self) -> u32 {
+        self.sample_level
+    }
+
+    /// Returns the number of unique weak hashes in the in-memory index.
+    ///
+    /// Counted by `finalize()`; 0 before that and for disk-backed indexes.
     pub fn unique_hash_count(&self) -> usize {
-        self.index.len()
+        self.unique_hashes
+    }
+
+    /// Returns the heap memory held by the index in bytes.
+    ///
+    /// For a disk-backed index this is the filter and fence tables only.
+    pub fn memory_usage(&self) -> usize {
+        self.disk.as_ref().map_or(0, |disk| disk.memory_usage())
+            + self.weak.capacity() * size_of::<u32>()
+            + self.strong.capacity() * size_of::<u64>()
+            + self.block_numbers.capacity() * size_of::<u32>()
+            + self.runs.capacity() * size_of::<BlockRun>()
+            + self.sorted.capacity() * size_of::<u32>()
+            + self.buckets.capacity() * size_of::<u32>()
+            + self
+                .filter
+                .as_ref()
+                .map_or(0, |filter| filter.memory_usage())
+            + self.pending.capacity()
+    }
+
+    /// Returns the measured memory per indexed block in bytes.
+    ///
+    /// Returns 0 for an empty index.
+    pub fn bytes_per_block(&self) -> f64 {
+        match self.block_count() {
+            0 => 0.0,
+            blocks => self.memory_usage() as f64 / blocks as f64,
+        }
+    }
+
+    /// Writes the index to suspended builder state.
+    ///
+    /// Lookup tables are not written; they are rebuilt on read. Disk-backed
+    /// indexes can't be saved.
+    pub fn write_state(&self, writer: &mut StateWriter) -> io::Result<()> {
+        if self.disk.is_some() {
+            return Err(PatchlyError::Misuse("Disk-backed block index cannot be saved").into());
+        }
+
+        writer.put_u64(self.block_size as u64);
+        writer.put_u8(self.hash_kind.id());
+        writer.put_u64(self.bytes_indexed);
+        writer.put_bytes(&self.pending);
+        writer.put_bool(!self.buckets.is_empty());
+        writer.put_u32(self.blocks_seen);
+        writer.put_u32(self.sample_level);
+        writer.put_u64(self.max_entries as u64);
+
+        writer.put_u64(self.runs.len() as u64);
+        for run in &self.runs {
+            writer.put_u32(run.first_block);
+            writer.put_u64(run.offset);
+        }
+
+        writer.put_u64(self.weak.len() as u64);
+        for (&weak, &strong) in self.weak.iter().zip(&self.strong) {
+            writer.put_u32(weak);
+            writer.put_u64(strong);
+        }
+        for &block in &self.block_numbers {
+            writer.put_u32(block);
+        }
+        Ok(())
+    }
+
+    /// Reads an index written by `write_state()`.
+    pub fn read_state(reader: &mut StateReader) -> io::Result<Self> {
+        let block_size = reader.u64()? as usize;
+        if block_size == 0 {
+            return Err(invalid("Invalid block size in builder state"));
+        }
+
+        let mut index = Self::with_block_size(block_size);
+        index.hash_kind = RollingHashKind::from_id(reader.u8()?)
+            .ok_or_else(|| invalid("Invalid rolling hash in builder state"))?;
+        index.bytes_indexed = reader.u64()?;
+        index.pending = reader.bytes()?;
+        let finalized = reader.bool()?;
+        index.blocks_seen = reader.u32()?;
+        index.sample_level = reader.u32()?;
+        index.max_entries = usize::try_from(reader.u64()?).unwrap_or(usize::MAX);
+        if index.sample_level > 64 {
+            return Err(invalid("Invalid sample level in builder state"));
+        }
+
+        let run_count = reader.u64()?;
+        index.runs.clear();
+        for _ in 0..run_count {
+            index.runs.push(BlockRun {
+                first_block: reader.u32()?,
+                offset: reader.u64()?,
+            });
+        }
+        if index.runs.first().is_none_or(|run| run.first_block != 0) {
+            return Err(invalid("Invalid block runs in builder state"));
+        }
+
+        let block_count = reader.u64()?;
+        if block_count > u32::MAX as u64 {
+            return Err(invalid("Invalid block count in builder state"));
+        }
+        for _ in 0..block_count {
+            index.weak.push(reader.u32()?);
+            index.strong.push(reader.u64()?);
+        }
+        if index.sample_level > 0 {
+            for _ in 0..block_count {
+                index.block_numbers.push(reader.u32()?);
+            }
+        }
+
+        if finalized {
+            index.build_lookup();
+        }
+        Ok(index)
+    }
+}
+
+/// Selects the bucket for a weak hash from its top `bits` bits.
+fn bucket_of(weak_hash: u32, bits: u32) -> usize {
+    if bits == 0 {
+        0
+    } else {
+        (weak_hash >> (32 - bits)) as usize
     }
 }
 
@@ -169,6 +820,185 @@ impl Default for BlockIndex {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::diff::rolling_hash::RollingHash;
+    use crate::test_util::noise;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut index = BlockIndex::with_block_size(512);
+        index.add_chunk(&data[..6000]);
+
+        let mut writer = StateWriter::new();
+        index.write_state(&mut writer).unwrap();
+        let bytes = writer.into_bytes();
+
+        let mut reader = StateReader::new(&bytes);
+        let mut restored = BlockIndex::read_state(&mut reader).unwrap();
+        reader.finish().unwrap();
+
+        // Pending bytes carry over into the next chunk
+        index.add_chunk(&data[6000..]);
+        restored.add_chunk(&data[6000..]);
+        index.finalize();
+        restored.finalize();
+
+        let mut hasher = RollingHash::new(512);
+        for block in data.chunks_exact(512) {
+            let weak = hasher.hash_chunk(block);
+            assert_eq!(
+                index.find_verified_match(weak, block),
+                restored.find_verified_match(weak, block)
+            );
+        }
+        assert_eq!(index.unique_hash_count(), restored.unique_hash_count());
+    }
+
+    #[test]
+    fn test_buzhash_index_lookup() {
+        let data: Vec<u8> = (0..64 * 300u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 9) as u8)
+            .collect();
+        let mut index = BlockIndex::with_block_size(64);
+        index.set_rolling_hash(RollingHashKind::Buzhash);
+        index.add_chunk(&data);
+        index.set_rolling_hash(RollingHashKind::Adler);
+        index.finalize();
+        assert_eq!(index.rolling_hash(), RollingHashKind::Buzhash);
+
+        let mut hasher = index.hasher();
+        for (n, block) in data.chunks_exact(64).enumerate() {
+            let weak = hasher.hash_chunk(block);
+            assert_eq!(index.find_verified_match(weak, block), Some(n as u64 * 64));
+        }
+
+        let mut writer = StateWriter::new();
+        index.write_state(&mut writer).unwrap();
+        let bytes = writer.into_bytes();
+        let restored = BlockIndex::read_state(&mut StateReader::new(&bytes)).unwrap();
+        assert_eq!(restored.rolling_hash(), RollingHashKind::Buzhash);
+    }
+
+    #[test]
+    fn test_lookup_matches_every_block() {
+        // Pseudo-random blocks with some duplicates
+        let mut data = noise(64 * 5000, 12345);
+        data.copy_within(0..64 * 10, 64 * 4000);
+
+        let mut index = BlockIndex::with_block_size(64);
+        index.add_chunk(&data);
+        index.finalize();
+        assert_eq!(index.block_count(), 5000);
+
+        let mut hasher = RollingHash::new(64);
+        for (n, block) in data.chunks_exact(64).enumerate() {
+            let weak = hasher.hash_chunk(block);
+            let entries: Vec<_> = index.lookup(weak).collect();
+            assert!(entries.iter().any(|e| e.offset == n as u64 * 64));
+            assert!(entries.windows(2).all(|w| w[0].offset < w[1].offset));
+
+            let expected = if (4000..4010).contains(&n) {
+                n - 4000
+            } else {
+                n
+            };
+            assert_eq!(
+                index.find_verified_match(weak, block),
+                Some(expected as u64 * 64)
+            );
+        }
+    }
+
+    #[test]
+    fn test_memory_budget_samples_blocks() {
+        let data = noise(64 * 20_000, 99);
+
+        let mut index = BlockIndex::with_block_size(64);
+        index.set_memory_budget(100_000);
+        for chunk in data.chunks(10_000) {
+            index.add_chunk(chunk);
+        }
+        index.finalize();
+
+        assert!(index.sample_level() >= 2);
+        assert!(index.block_count() < 100_000 / SAMPLED_ENTRY_BYTES);
+        assert!(index.memory_usage() <= 100_000 + 1024);
+        let coverage = index.coverage();
+        assert!(coverage > 0.1 && coverage < 0.3, "{}", coverage);
+
+        // Every kept block is still found at its own offset
+        let mut hasher = RollingHash::new(64);
+        let mut found = 0;
+        for (n, block) in data.chunks_exact(64).enumerate() {
+            let weak = hasher.hash_chunk(block);
+            if let Some(offset) = index.find_verified_match(weak, block) {
+                assert_eq!(offset, n as u64 * 64);
+                found += 1;
+            }
+        }
+        assert_eq!(found, index.block_count());
+    }
+
+    #[test]
+    fn test_sampling_keeps_run_offsets() {
+        let blocks: Vec<Vec<u8>> = (0..200u32)
+            .map(|i| {
+                (0..16)
+                    .map(|j| (i * 31 + j * 7) as u8 ^ (i >> 3) as u8)
+                    .collect()
+            })
+            .collect();
+
+        let mut index = BlockIndex::with_block_size(16);
+        index.set_memory_budget(50 * SAMPLED_ENTRY_BYTES);
+        for (i, block) in blocks.iter().enumerate() {
+            index.align_to(1000 + i as u64 * 21);
+            index.add_chunk(block);
+        }
+        index.finalize();
+        assert!(index.coverage() < 1.0);
+
+        let mut hasher = RollingHash::new(16);
+        for (i, block) in blocks.iter().enumerate() {
+            if let Some(offset) = index.find_verified_match(hasher.hash_chunk(block), block) {
+                assert_eq!(offset, 1000 + i as u64 * 21);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bytes_per_block() {
+        let data: Vec<u8> = (0..1_000_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let mut index = BlockIndex::with_block_size(64);
+        index.add_chunk(&data);
+        index.finalize();
+
+        let per_block = index.bytes_per_block();
+        assert!(per_block > 16.0 && per_block <= 20.0, "{}", per_block);
+        assert_eq!(BlockIndex::new().bytes_per_block(), 0.0);
+    }
+
+    #[test]
+    fn test_prefilter_rejects_most_misses() {
+        let data: Vec<u8> = (0..64 * 10_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 11) as u8)
+            .collect();
+        let mut index = BlockIndex::with_block_size(64);
+        index.add_chunk(&data);
+        assert!(index.filter.is_none());
+        index.finalize();
+
+        // Every indexed hash passes; unrelated hashes mostly don't
+        let filter = index.filter.as_ref().unwrap();
+        assert!(index.weak.iter().all(|&hash| filter.may_contain(hash)));
+        let passed = (0..100_000u32)
+            .map(|i| i.wrapping_mul(0x2545_F491) ^ 0x5bd1_e995)
+            .filter(|&hash| filter.may_contain(hash))
+            .count();
+        assert!(passed < 20_000, "{}", passed);
+    }
 
     #[test]
     fn test_index_and_lookup() {
@@ -181,10 +1011,24 @@ mod tests {
         let mut hasher = RollingHash::new(4096);
         let hash = hasher.hash_chunk(&data[0..4096]);
 
-        let entries = index.lookup(hash);
+        let entries: Vec<_> = index.lookup(hash).collect();
         assert_eq!(entries.len(), 2);
         assert_eq!(entries[0].offset, 0);
         assert_eq!(entries[1].offset, 4096);
+        assert_eq!(index.unique_hash_count(), 1);
+    }
+
+    #[test]
+    fn test_unique_hashes_counted_by_finalize() {
+        let data = noise(64 * 100, 5);
+        let mut index = BlockIndex::with_block_size(64);
+        index.add_chunk(&data);
+        index.add_chunk(&data[..64 * 10]);
+        assert_eq!(index.unique_hash_count(), 0);
+
+        index.finalize();
+        assert_eq!(index.block_count(), 110);
+        assert_eq!(index.unique_hash_count(), 100);
     }
 
     #[test]
@@ -229,10 +1073,110 @@ mod tests {
         assert_eq!(index.bytes_indexed, 4096); // One block indexed
     }
 
+    #[test]
+    fn test_align_to_restarts_blocks() {
+        let mut index = BlockIndex::with_block_size(4);
+        index.add_chunk(b"ab");
+        index.align_to(10);
+        index.add_chunk(b"wxyz");
+        index.finalize();
+
+        let mut hasher = RollingHash::new(4);
+        let entries: Vec<_> = index.lookup(hasher.hash_chunk(b"wxyz")).collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].offset, 10);
+    }
+
+    #[test]
+    fn test_verified_match_in_prefers_range() {
+        let data = vec![7u8; 12];
+        let mut index = BlockIndex::with_block_size(4);
+        index.add_chunk(&data);
+        index.finalize();
+
+        let mut hasher = RollingHash::new(4);
+        let weak_hash = hasher.hash_chunk(&data[..4]);
+
+        assert_eq!(
+            index.find_verified_match_in(weak_hash, &data[..4], &(8..12)),
+            Some(8)
+        );
+        assert_eq!(
+            index.find_verified_match_in(weak_hash, &data[..4], &(100..200)),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_checked_match_tries_every_candidate() {
+        let data = vec![7u8; 16];
+        let mut index = BlockIndex::with_block_size(4);
+        index.add_chunk(&data);
+        index.finalize();
+
+        let weak_hash = RollingHash::new(4).hash_chunk(&data[..4]);
+        let mut tried = Vec::new();
+        let found =
+            index.find_checked_match(weak_hash, &data[..4], Some(&(8..12)), None, |offset| {
+                tried.push(offset);
+                offset == 4
+            });
+
+        assert_eq!(found, Some(4));
+        assert_eq!(tried, vec![8, 0, 4]);
+        assert_eq!(
+            index.find_checked_match(weak_hash, &data[..4], None, None, |_| false),
+            None
+        );
+
+        tried.clear();
+        index.find_checked_match(weak_hash, &data[..4], None, Some(11), |offset| {
+            tried.push(offset);
+            false
+        });
+        assert_eq!(tried, vec![12, 8, 4, 0]);
+    }
+
+    #[test]
+    fn test_best_match_prefers_nearest() {
+        // Block "abcd" at 0, 12, 24 and 36; "wxyz" elsewhere
+        let data = b"abcdwxyzEFGHabcdIJKLMNOPabcdQRSTUVWXabcd";
+        let mut index = BlockIndex::with_block_size(4);
+        index.add_chunk(data);
+        index.finalize();
+
+        let block = &data[..4];
+        let weak_hash = RollingHash::new(4).hash_chunk(block);
+        let best = |preferred: Option<&Range<u64>>, near| {
+            index.find_best_match(weak_hash, block, preferred, near)
+        };
+
+        assert_eq!(best(None, None), Some(0));
+        assert_eq!(best(None, Some(24)), Some(24));
+        assert_eq!(best(None, Some(27)), Some(24));
+        assert_eq!(best(None, Some(31)), Some(36));
+        assert_eq!(best(None, Some(1000)), Some(36));
+        assert_eq!(best(Some(&(10..20)), Some(36)), Some(12));
+        assert_eq!(best(Some(&(100..200)), Some(29)), Some(24));
+        // Ties go forward, matching sequential reads
+        assert_eq!(best(None, Some(30)), Some(36));
+
+        let other = &data[4..8];
+        let other_weak = RollingHash::new(4).hash_chunk(other);
+        assert_eq!(
+            index.find_best_match(other_weak, other, None, Some(40)),
+            Some(4)
+        );
+        assert_eq!(
+            index.find_best_match(other_weak, block, None, Some(40)),
+            None
+        );
+    }
+
     #[test]
     fn test_empty_lookup() {
         let index = BlockIndex::new();
-        assert!(index.lookup(12345).is_empty());
+        assert_eq!(index.lookup(12345).count(), 0);
         assert_eq!(index.find_verified_match(12345, &[0u8; 4096]), None);
     }
 }
@@ -0,0 +1,857 @@
+//! Out-of-core block index for sources too large to index in memory.
+//!
+//! Entries are partitioned by the top bits of the weak hash and appended to
+//! per-partition storage while the source streams in. `finish()` sorts each
+//! partition in bounded runs, merges them, builds a sparse fence table for
+//! it and a [`HashFilter`] over all weak hashes, and returns a disk-backed
+//! [`BlockIndex`]. A lookup is then:
+//!
+//! 1. Filter check in memory (most misses stop here)
+//! 2. Binary search in the partition's fence table
+//! 3. One read of up to `FENCE_STRIDE` entries from storage
+//!
+//! Storage is pluggable through [`IndexStorage`]: [`DirStorage`] keeps one
+//! file per partition on native builds, [`MemoryStorage`] keeps partitions
+//! in memory, and a wasm build can implement the trait on top of OPFS sync
+//! access handles.
+//!
+//! Entry layout (20 bytes, little-endian): weak hash u32, source offset u64,
+//! strong hash u64.
+
+use super::block_index::{BlockEntry, BlockIndex};
+use super::hash_filter::HashFilter;
+use super::rolling_hash::{RollingHashKind, RollingHasher};
+use crate::simd;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Size of a stored entry.
+const ENTRY_SIZE: usize = 20;
+
+/// Entries between fence table samples.
+const FENCE_STRIDE: usize = 128;
+
+/// Default number of weak hash bits selecting a partition (256 partitions).
+pub const DEFAULT_PARTITION_BITS: u32 = 8;
+
+/// Buffered bytes per partition before they are appended to storage.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Entries sorted in memory at once by `finish()` (5 MiB).
+const SORT_RUN_ENTRIES: usize = 256 * 1024;
+
+/// Partitioned byte storage backing a disk index.
+///
+/// Reads take `&self` so one index can serve several diff threads;
+/// implementations synchronize internally.
+pub trait IndexStorage: Send + Sync {
+    /// Appends bytes to a partition.
+    fn append(&mut self, partition: usize, data: &[u8]) -> io::Result<()>;
+
+    /// Replaces a partition's contents.
+    fn replace(&mut self, partition: usize, data: &[u8]) -> io::Result<()>;
+
+    /// Returns the length of a partition in bytes.
+    fn len(&self, partition: usize) -> io::Result<u64>;
+
+    /// Fills `buf` from a partition starting at `offset`.
+    fn read_at(&self, partition: usize, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// In-memory partition storage.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    /// Partition contents.
+    partitions: Vec<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    /// Creates empty storage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a partition, creating empty partitions up to it.
+    fn partition_mut(&mut self, partition: usize) -> &mut Vec<u8> {
+        if self.partitions.len() <= partition {
+            self.partitions.resize_with(partition + 1, Vec::new);
+        }
+        &mut self.partitions[partition]
+    }
+}
+
+impl IndexStorage for MemoryStorage {
+    fn append(&mut self, partition: usize, data: &[u8]) -> io::Result<()> {
+        self.partition_mut(partition).extend_from_slice(data);
+        Ok(())
+    }
+
+    fn replace(&mut self, partition: usize, data: &[u8]) -> io::Result<()> {
+        *self.partition_mut(partition) = data.to_vec();
+        Ok(())
+    }
+
+    fn len(&self, partition: usize) -> io::Result<u64> {
+        Ok(self.partitions.get(partition).map_or(0, |p| p.len() as u64))
+    }
+
+    fn read_at(&self, partition: usize, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let data = self
+            .partitions
+            .get(partition)
+            .map_or(&[][..], |p| p.as_slice());
+        let start = offset as usize;
+        let bytes = start
+            .checked_add(buf.len())
+            .and_then(|end| data.get(start..end))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Read past partition"))?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Partition storage with one file per partition in a directory.
+///
+/// The partition files are deleted when the storage is dropped; the
+/// directory itself belongs to the caller.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct DirStorage {
+    /// Directory holding the partition files.
+    dir: std::path::PathBuf,
+    /// Open partition files, created on first append.
+    files: Vec<Option<std::sync::Mutex<std::fs::File>>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl DirStorage {
+    /// Uses `dir` for partition files, creating it if needed.
+    ///
+    /// Existing partition files are truncated when first written.
+    pub fn new(dir: impl Into<std::path::PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            files: Vec::new(),
+        })
+    }
+
+    /// Returns the file path of a partition.
+    fn path(&self, partition: usize) -> std::path::PathBuf {
+        self.dir.join(format!("part-{:05}.idx", partition))
+    }
+
+    /// Returns the open file of a partition, creating it if needed.
+    fn file_mut(&mut self, partition: usize) -> io::Result<&mut std::fs::File> {
+        if self.files.len() <= partition {
+            self.files.resize_with(partition + 1, || None);
+        }
+
+        if self.files[partition].is_none() {
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(self.path(partition))?;
+            self.files[partition] = Some(std::sync::Mutex::new(file));
+        }
+
+        let file = self.files[partition]
+            .as_mut()
+            .expect("file was just opened");
+        Ok(file.get_mut().unwrap_or_else(|err| err.into_inner()))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for DirStorage {
+    fn drop(&mut self) {
+        for partition in 0..self.files.len() {
+            if self.files[partition].take().is_some() {
+                // Best effort: a leftover file is only wasted space
+                let _ = std::fs::remove_file(self.path(partition));
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl IndexStorage for DirStorage {
+    fn append(&mut self, partition: usize, data: &[u8]) -> io::Result<()> {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let file = self.file_mut(partition)?;
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(data)
+    }
+
+    fn replace(&mut self, partition: usize, data: &[u8]) -> io::Result<()> {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let file = self.file_mut(partition)?;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(data)
+    }
+
+    fn len(&self, partition: usize) -> io::Result<u64> {
+        match self.files.get(partition) {
+            Some(Some(file)) => {
+                let file = file.lock().unwrap_or_else(|err| err.into_inner());
+                Ok(file.metadata()?.len())
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn read_at(&self, partition: usize, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        use crate::apply::patch_applier::SourceAccess;
+
+        match self.files.get(partition) {
+            Some(Some(file)) => {
+                let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+                SourceAccess::read_at(&mut *file, offset, buf)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Read past partition",
+            )),
+        }
+    }
+}
+
+/// Stored index entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct DiskEntry {
+    /// Weak (rolling) hash.
+    weak: u32,
+    /// Source offset of the block.
+    offset: u64,
+    /// Strong hash for verification.
+    strong: u64,
+}
+
+impl DiskEntry {
+    /// Appends the serialized entry to `out`.
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.weak.to_le_bytes());
+        out.extend_from_slice(&self.offset.to_le_bytes());
+        out.extend_from_slice(&self.strong.to_le_bytes());
+    }
+
+    /// Parses a serialized entry.
+    fn read_from(bytes: &[u8]) -> Self {
+        Self {
+            weak: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            offset: u64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+            strong: u64::from_le_bytes(bytes[12..20].try_into().unwrap()),
+        }
+    }
+}
+
+/// Returns the partition for a weak hash.
+fn partition_of(weak_hash: u32, bits: u32) -> usize {
+    if bits == 0 {
+        0
+    } else {
+        (weak_hash >> (32 - bits)) as usize
+    }
+}
+
+/// Streams source blocks into partitioned storage.
+pub struct DiskIndexBuilder {
+    /// Partition storage.
+    storage: Box<dyn IndexStorage>,
+    /// Block size used for chunking.
+    block_size: usize,
+    /// Weak hash algorithm.
+    hash_kind: RollingHashKind,
+    /// Weak hash bits selecting a partition.
+    partition_bits: u32,
+    /// Entries not yet appended, per partition.
+    buffers: Vec<Vec<u8>>,
+    /// Source offset of the next block (total bytes indexed).
+    offset: u64,
+    /// Buffer for incomplete block from previous chunk.
+    pending: Vec<u8>,
+    /// Entries `finish()` sorts in memory at once.
+    run_entries: usize,
+}
+
+impl DiskIndexBuilder {
+    /// Creates a builder writing to `storage` with the default partitioning.
+    pub fn new(storage: Box<dyn IndexStorage>, block_size: usize) -> Self {
+        Self::with_partition_bits(storage, block_size, DEFAULT_PARTITION_BITS)
+    }
+
+    /// Creates a builder using `2^partition_bits` partitions (at most 16 bits).
+    pub fn with_partition_bits(
+        storage: Box<dyn IndexStorage>,
+        block_size: usize,
+        partition_bits: u32,
+    ) -> Self {
+        let partition_bits = partition_bits.min(16);
+
+        Self {
+            storage,
+            block_size,
+            hash_kind: RollingHashKind::default(),
+            partition_bits,
+            buffers: vec![Vec::new(); 1 << partition_bits],
+            offset: 0,
+            pending: Vec::with_capacity(block_size),
+            run_entries: SORT_RUN_ENTRIES,
+        }
+    }
+
+    /// Selects the weak hash algorithm (see `BlockIndex::set_rolling_hash`).
+    ///
+    /// Ignored once data has been added.
+    pub fn set_rolling_hash(&mut self, kind: RollingHashKind) {
+        if self.offset == 0 && self.pending.is_empty() {
+            self.hash_kind = kind;
+        }
+    }
+
+    /// Adds a chunk of source data.
+    pub fn add_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let mut hasher = self.hash_kind.hasher(self.block_size);
+
+        let mut data = std::mem::take(&mut self.pending);
+        data.extend_from_slice(chunk);
+
+        let complete = data.len() - data.len() % self.block_size;
+        let mut strong_hashes = Vec::with_capacity(complete / self.block_size);
+        simd::block_hashes(&data[..complete], self.block_size, &mut strong_hashes);
+
+        for (block, &strong) in data[..complete]
+            .chunks_exact(self.block_size)
+            .zip(&strong_hashes)
+        {
+            let entry = DiskEntry {
+                weak: hasher.hash_chunk(block),
+                offset: self.offset,
+                strong,
+            };
+
+            let partition = partition_of(entry.weak, self.partition_bits);
+            entry.write_to(&mut self.buffers[partition]);
+            if self.buffers[partition].len() >= WRITE_BUFFER_SIZE {
+                self.storage.append(partition, &self.buffers[partition])?;
+                self.buffers[partition].clear();
+            }
+
+            self.offset += self.block_size as u64;
+        }
+
+        if complete < data.len() {
+            self.pending = data[complete..].to_vec();
+        }
+        Ok(())
+    }
+
+    /// Restarts block boundaries at an absolute source offset
+    /// (see `BlockIndex::align_to`).
+    pub fn align_to(&mut self, offset: u64) {
+        self.pending.clear();
+        self.offset = offset;
+    }
+
+    /// Sorts the partitions and returns the finalized index.
+    ///
+    /// Partial blocks at the end are not indexed.
+    pub fn finish(mut self) -> io::Result<BlockIndex> {
+        let partitions = self.buffers.len();
+        for partition in 0..partitions {
+            let buffered = std::mem::take(&mut self.buffers[partition]);
+            if !buffered.is_empty() {
+                self.storage.append(partition, &buffered)?;
+            }
+        }
+
+        let mut counts = Vec::with_capacity(partitions);
+        for partition in 0..partitions {
+            counts.push(self.storage.len(partition)? / ENTRY_SIZE as u64);
+        }
+
+        let total: u64 = counts.iter().sum();
+        let mut filter = HashFilter::with_capacity(total as usize);
+        let mut fences = Vec::with_capacity(partitions);
+        for (partition, &count) in counts.iter().enumerate() {
+            fences.push(self.sort_partition(partition, count, &mut filter)?);
+        }
+
+        let lookup = DiskLookup {
+            storage: self.storage,
+            partition_bits: self.partition_bits,
+            counts,
+            fences,
+            filter,
+            read_errors: AtomicU64::new(0),
+        };
+        Ok(BlockIndex::from_disk(
+            self.block_size,
+            self.hash_kind,
+            self.offset,
+            lookup,
+        ))
+    }
+
+    /// Sorts one partition in place and returns its fence table.
+    ///
+    /// Partitions of up to `run_entries` entries are sorted in memory.
+    /// Larger ones are sorted in runs of that size into a scratch partition
+    /// past the last one and merged back, so memory stays bounded by the
+    /// run size plus one read buffer per run.
+    fn sort_partition(
+        &mut self,
+        partition: usize,
+        count: u64,
+        filter: &mut HashFilter,
+    ) -> io::Result<Vec<u32>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+
+        let run_entries = self.run_entries as u64;
+        let mut writer = SortedWriter::new(partition);
+
+        if count <= run_entries {
+            let mut entries = read_entries(&*self.storage, partition, 0, count as usize)?;
+            entries.sort_unstable();
+            self.storage.replace(partition, &[])?;
+            for entry in entries {
+                filter.insert(entry.weak);
+                writer.push(&mut *self.storage, entry)?;
+            }
+            return writer.finish(&mut *self.storage);
+        }
+
+        let scratch = self.buffers.len();
+        self.storage.replace(scratch, &[])?;
+        let mut runs = Vec::new();
+        let mut bytes = Vec::with_capacity(self.run_entries * ENTRY_SIZE);
+        let mut start = 0;
+        while start < count {
+            let len = run_entries.min(count - start);
+            let mut entries = read_entries(&*self.storage, partition, start, len as usize)?;
+            entries.sort_unstable();
+
+            bytes.clear();
+            for entry in &entries {
+                entry.write_to(&mut bytes);
+            }
+            self.storage.append(scratch, &bytes)?;
+            runs.push(RunCursor::new(start, start + len));
+            start += len;
+        }
+        drop(bytes);
+
+        self.storage.replace(partition, &[])?;
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (run, cursor) in runs.iter_mut().enumerate() {
+            if let Some(entry) = cursor.next(&*self.storage, scratch)? {
+                heap.push(Reverse((entry, run)));
+            }
+        }
+        while let Some(Reverse((entry, run))) = heap.pop() {
+            filter.insert(entry.weak);
+            writer.push(&mut *self.storage, entry)?;
+            if let Some(entry) = runs[run].next(&*self.storage, scratch)? {
+                heap.push(Reverse((entry, run)));
+            }
+        }
+
+        self.storage.replace(scratch, &[])?;
+        writer.finish(&mut *self.storage)
+    }
+
+    /// Returns the block size.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+}
+
+/// Reads `count` entries of a partition starting at entry `start`.
+fn read_entries(
+    storage: &dyn IndexStorage,
+    partition: usize,
+    start: u64,
+    count: usize,
+) -> io::Result<Vec<DiskEntry>> {
+    let mut bytes = vec![0u8; count * ENTRY_SIZE];
+    storage.read_at(partition, start * ENTRY_SIZE as u64, &mut bytes)?;
+    Ok(bytes
+        .chunks_exact(ENTRY_SIZE)
+        .map(DiskEntry::read_from)
+        .collect())
+}
+
+/// Appends sorted entries to a partition and samples its fence table.
+struct SortedWriter {
+    /// Partition being written.
+    partition: usize,
+    /// Serialized entries not yet appended.
+    buffer: Vec<u8>,
+    /// Entries pushed so far.
+    written: usize,
+    /// Weak hash of every `FENCE_STRIDE`th entry.
+    fences: Vec<u32>,
+}
+
+impl SortedWriter {
+    /// Creates a writer appending to `partition`.
+    fn new(partition: usize) -> Self {
+        Self {
+            partition,
+            buffer: Vec::with_capacity(WRITE_BUFFER_SIZE + ENTRY_SIZE),
+            written: 0,
+            fences: Vec::new(),
+        }
+    }
+
+    /// Appends the next entry in sort order.
+    fn push(&mut self, storage: &mut dyn IndexStorage, entry: DiskEntry) -> io::Result<()> {
+        if self.written.is_multiple_of(FENCE_STRIDE) {
+            self.fences.push(entry.weak);
+        }
+        self.written += 1;
+
+        entry.write_to(&mut self.buffer);
+        if self.buffer.len() >= WRITE_BUFFER_SIZE {
+            storage.append(self.partition, &self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Appends the remaining entries and returns the fence table.
+    fn finish(self, storage: &mut dyn IndexStorage) -> io::Result<Vec<u32>> {
+        if !self.buffer.is_empty() {
+            storage.append(self.partition, &self.buffer)?;
+        }
+        Ok(self.fences)
+    }
+}
+
+/// Read position in one sorted run of the scratch partition.
+struct RunCursor {
+    /// Index of the next entry to read from storage.
+    next: u64,
+    /// Index one past the run's last entry.
+    end: u64,
+    /// Entries read but not yet returned, in reverse order.
+    buffered: Vec<DiskEntry>,
+}
+
+impl RunCursor {
+    /// Creates a cursor over the run of entries `start..end`.
+    fn new(start: u64, end: u64) -> Self {
+        Self {
+            next: start,
+            end,
+            buffered: Vec::new(),
+        }
+    }
+
+    /// Returns the run's next entry, reading `WRITE_BUFFER_SIZE` bytes of
+    /// entries at a time.
+    fn next(
+        &mut self,
+        storage: &dyn IndexStorage,
+        scratch: usize,
+    ) -> io::Result<Option<DiskEntry>> {
+        if self.buffered.is_empty() && self.next < self.end {
+            let len = ((WRITE_BUFFER_SIZE / ENTRY_SIZE) as u64).min(self.end - self.next);
+            self.buffered = read_entries(storage, scratch, self.next, len as usize)?;
+            self.buffered.reverse();
+            self.next += len;
+        }
+        Ok(self.buffered.pop())
+    }
+}
+
+/// Iterator over the entries of one weak hash, returned by
+/// `DiskLookup::entries()`.
+///
+/// Reads one fence stride of entries at a time into a buffer that is only
+/// allocated once storage is touched.
+pub struct DiskEntries<'a> {
+    /// Lookup being read.
+    lookup: &'a DiskLookup,
+    /// Weak hash being looked up.
+    weak_hash: u32,
+    /// Partition holding the hash.
+    partition: usize,
+    /// Entries in the partition.
+    count: usize,
+    /// Next fence stride to read.
+    chunk: usize,
+    /// Entries read from storage.
+    buf: Vec<u8>,
+    /// Read position in `buf`.
+    pos: usize,
+    /// Set once no more entries can match.
+    done: bool,
+}
+
+impl Iterator for DiskEntries<'_> {
+    type Item = BlockEntry;
+
+    fn next(&mut self) -> Option<BlockEntry> {
+        while !self.done {
+            if self.pos == self.buf.len() && !self.read_chunk() {
+                break;
+            }
+
+            let entry = DiskEntry::read_from(&self.buf[self.pos..self.pos + ENTRY_SIZE]);
+            self.pos += ENTRY_SIZE;
+            if entry.weak > self.weak_hash {
+                break;
+            }
+            if entry.weak == self.weak_hash {
+                return Some(BlockEntry {
+                    offset: entry.offset,
+                    strong_hash: entry.strong,
+                });
+            }
+        }
+
+        self.done = true;
+        None
+    }
+}
+
+impl DiskEntries<'_> {
+    /// Reads the next fence stride of entries into `buf`.
+    ///
+    /// Returns false at the end of the partition or on a storage error.
+    fn read_chunk(&mut self) -> bool {
+        let first = self.chunk * FENCE_STRIDE;
+        if first >= self.count {
+            return false;
+        }
+
+        let len = (self.count - first).min(FENCE_STRIDE) * ENTRY_SIZE;
+        self.buf.resize(len, 0);
+        let offset = (first * ENTRY_SIZE) as u64;
+        if self
+            .lookup
+            .storage
+            .read_at(self.partition, offset, &mut self.buf)
+            .is_err()
+        {
+            self.lookup.read_errors.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        self.chunk += 1;
+        self.pos = 0;
+        true
+    }
+}
+
+/// Read side of a finished disk index.
+pub struct DiskLookup {
+    /// Partition storage with sorted entries.
+    storage: Box<dyn IndexStorage>,
+    /// Weak hash bits selecting a partition.
+    partition_bits: u32,
+    /// Entries per partition.
+    counts: Vec<u64>,
+    /// Weak hash of every `FENCE_STRIDE`-th entry, per partition.
+    fences: Vec<Vec<u32>>,
+    /// Filter over all stored weak hashes.
+    filter: HashFilter,
+    /// Storage reads that failed during lookups.
+    read_errors: AtomicU64,
+}
+
+impl DiskLookup {
+    /// Iterates over the entries with a weak hash, in source order.
+    ///
+    /// Storage errors end the lookup early (the block is treated as a
+    /// miss, so patches stay correct) and are counted in `read_errors()`.
+    pub fn entries(&self, weak_hash: u32) -> DiskEntries<'_> {
+        let partition = partition_of(weak_hash, self.partition_bits);
+        let fences = &self.fences[partition];
+
+        // Entries with this hash can't start before the last smaller fence
+        let chunk = fences
+            .partition_point(|&fence| fence < weak_hash)
+            .saturating_sub(1);
+
+        DiskEntries {
+            lookup: self,
+            weak_hash,
+            partition,
+            count: self.counts[partition] as usize,
+            chunk,
+            buf: Vec::new(),
+            pos: 0,
+            done: !self.filter.may_contain(weak_hash),
+        }
+    }
+
+    /// Returns the total number of entries.
+    pub fn entry_count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the heap memory held by the filter and fence tables.
+    pub fn memory_usage(&self) -> usize {
+        self.filter.memory_usage()
+            + self.counts.capacity() * 8
+            + self
+                .fences
+                .iter()
+                .map(|fences| fences.capacity() * 4)
+                .sum::<usize>()
+    }
+
+    /// Returns the number of failed storage reads so far.
+    pub fn read_errors(&self) -> u64 {
+        self.read_errors.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::rolling_hash::RollingHash;
+    use crate::test_util::noise;
+
+    /// Builds an in-memory index over the same data for comparison.
+    fn memory_index(data: &[u8], block_size: usize) -> BlockIndex {
+        let mut index = BlockIndex::with_block_size(block_size);
+        index.add_chunk(data);
+        index.finalize();
+        index
+    }
+
+    #[test]
+    fn test_matches_memory_index() {
+        let mut data = noise(32 * 3000, 1);
+        data.copy_within(0..32 * 200, 32 * 2000);
+
+        let storage = Box::new(MemoryStorage::new());
+        let mut builder = DiskIndexBuilder::with_partition_bits(storage, 32, 4);
+        for chunk in data.chunks(1000) {
+            builder.add_chunk(chunk).unwrap();
+        }
+        let index = builder.finish().unwrap();
+        let expected = memory_index(&data, 32);
+        assert!(index.is_disk_backed());
+        assert_eq!(index.block_count(), 3000);
+
+        let mut hasher = RollingHash::new(32);
+        for block in data.chunks_exact(32) {
+            let weak = hasher.hash_chunk(block);
+            let offsets =
+                |index: &BlockIndex| -> Vec<u64> { index.lookup(weak).map(|e| e.offset).collect() };
+            assert_eq!(offsets(&index), offsets(&expected));
+            assert_eq!(
+                index.find_verified_match(weak, block),
+                expected.find_verified_match(weak, block)
+            );
+        }
+
+        // Misses are mostly rejected by the filter without reading storage
+        assert_eq!(index.lookup(0xDEAD_BEEF).count(), 0);
+        assert!(index.memory_usage() < expected.memory_usage() / 4);
+    }
+
+    #[test]
+    fn test_duplicates_span_fences() {
+        // 1000 identical blocks share one weak hash across many fences
+        let data = vec![5u8; 16 * 1000];
+        let mut builder = DiskIndexBuilder::new(Box::new(MemoryStorage::new()), 16);
+        builder.add_chunk(&data).unwrap();
+        let index = builder.finish().unwrap();
+
+        let weak = RollingHash::new(16).hash_chunk(&data[..16]);
+        let entries: Vec<_> = index.lookup(weak).collect();
+        assert_eq!(entries.len(), 1000);
+        assert_eq!(entries[999].offset, 999 * 16);
+        assert_eq!(
+            index.find_verified_match_in(weak, &data[..16], &(512..528)),
+            Some(512)
+        );
+    }
+
+    #[test]
+    fn test_merges_sorted_runs() {
+        let mut data = noise(32 * 3000, 6);
+        data.copy_within(0..32 * 300, 32 * 2500);
+
+        let mut builder =
+            DiskIndexBuilder::with_partition_bits(Box::new(MemoryStorage::new()), 32, 1);
+        builder.run_entries = 100;
+        builder.add_chunk(&data).unwrap();
+        let index = builder.finish().unwrap();
+        let expected = memory_index(&data, 32);
+
+        let mut hasher = RollingHash::new(32);
+        for block in data.chunks_exact(32) {
+            let weak = hasher.hash_chunk(block);
+            let offsets =
+                |index: &BlockIndex| -> Vec<u64> { index.lookup(weak).map(|e| e.offset).collect() };
+            assert_eq!(offsets(&index), offsets(&expected));
+        }
+        assert_eq!(index.storage_errors(), 0);
+    }
+
+    #[test]
+    fn test_diff_output_matches_memory_index() {
+        use crate::diff::streaming_diff::StreamingDiff;
+
+        let source = noise(200_000, 3);
+        let mut target = source.clone();
+        target[50_000..50_100].fill(0);
+        target.splice(120_000..120_000, noise(3000, 4));
+
+        let run = |index: BlockIndex| {
+            let mut diff = StreamingDiff::new(index);
+            let mut output = Vec::new();
+            diff.process_target_chunk(&target, &mut output).unwrap();
+            diff.finalize(&mut output).unwrap();
+            output
+        };
+
+        let mut builder = DiskIndexBuilder::new(Box::new(MemoryStorage::new()), 256);
+        builder.add_chunk(&source).unwrap();
+        assert_eq!(
+            run(builder.finish().unwrap()),
+            run(memory_index(&source, 256))
+        );
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_dir_storage_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("patchly-disk-index-{}", std::process::id()));
+        let data = noise(64 * 500, 2);
+
+        let mut builder = DiskIndexBuilder::new(Box::new(DirStorage::new(&dir).unwrap()), 64);
+        builder.add_chunk(&data).unwrap();
+        let index = builder.finish().unwrap();
+
+        let mut hasher = RollingHash::new(64);
+        for (n, block) in data.chunks_exact(64).enumerate() {
+            let weak = hasher.hash_chunk(block);
+            assert_eq!(index.find_verified_match(weak, block), Some(n as u64 * 64));
+        }
+        assert_eq!(index.storage_errors(), 0);
+
+        let mut writer = crate::format::state::StateWriter::new();
+        assert!(index.write_state(&mut writer).is_err());
+
+        // Dropping the index deletes the partition files
+        drop(index);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+//! Bit-array prefilter for weak hash lookups.
+//!
+//! One bit per slot, set for every weak hash in the index. A clear bit
+//! proves the hash is absent, so most misses are rejected with a single
+//! memory read instead of a table or disk lookup. Sized at about 8 bits per
+//! entry, which gives roughly a 12% false positive rate.
+
+use std::mem::size_of;
+
+/// Filter bits per expected entry.
+const BITS_PER_ENTRY: usize = 8;
+
+/// Multiplier spreading weak hashes over the slots (2^32 / golden ratio).
+const SPREAD: u32 = 0x9E37_79B9;
+
+/// Bit array keyed by weak hash.
+#[derive(Debug, Clone)]
+pub struct HashFilter {
+    /// Filter bits.
+    bits: Vec<u64>,
+    /// log2 of the number of bits.
+    bits_log2: u32,
+}
+
+impl HashFilter {
+    /// Creates an empty filter sized for `expected_entries` hashes.
+    pub fn with_capacity(expected_entries: usize) -> Self {
+        let wanted = expected_entries.saturating_mul(BITS_PER_ENTRY).max(64);
+        let bits_log2 = wanted.next_power_of_two().trailing_zeros().min(32);
+
+        Self {
+            bits: vec![0; (1usize << bits_log2) / 64],
+            bits_log2,
+        }
+    }
+
+    /// Returns the bit position for a weak hash.
+    fn slot(&self, weak_hash: u32) -> usize {
+        (weak_hash.wrapping_mul(SPREAD) as u64 >> (32 - self.bits_log2)) as usize
+    }
+
+    /// Records a weak hash.
+    pub fn insert(&mut self, weak_hash: u32) {
+        let slot = self.slot(weak_hash);
+        self.bits[slot / 64] |= 1 << (slot % 64);
+    }
+
+    /// Returns false if the weak hash was definitely never inserted.
+    pub fn may_contain(&self, weak_hash: u32) -> bool {
+        let slot = self.slot(weak_hash);
+        self.bits[slot / 64] & (1 << (slot % 64)) != 0
+    }
+
+    /// Returns the heap memory held by the filter in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.bits.capacity() * size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives() {
+        let hashes: Vec<u32> = (0..10_000u32).map(|i| i.wrapping_mul(2654435761)).collect();
+        let mut filter = HashFilter::with_capacity(hashes.len());
+        for &hash in &hashes {
+            filter.insert(hash);
+        }
+
+        assert!(hashes.iter().all(|&hash| filter.may_contain(hash)));
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let mut filter = HashFilter::with_capacity(10_000);
+        for i in 0..10_000u32 {
+            filter.insert(i.wrapping_mul(2654435761));
+        }
+
+        // Adler-style hashes differ mostly in low bits; probe similar values
+        let false_positives = (0..100_000u32)
+            .map(|i| 0x4000_0000 | i)
+            .filter(|&hash| filter.may_contain(hash))
+            .count();
+        assert!(false_positives < 20_000, "{}", false_positives);
+    }
+
+    #[test]
+    fn test_tiny_filter() {
+        let mut filter = HashFilter::with_capacity(0);
+        assert!(!filter.may_contain(42));
+        filter.insert(42);
+        assert!(filter.may_contain(42));
+        assert_eq!(filter.memory_usage(), 8);
+    }
+}
@@ -0,0 +1,542 @@
+//! Instruction serialization shared by the diff generators.
+//!
+//! Collects COPY and INSERT instructions and serializes them in patch
+//! format straight into the [`PatchSink`] passed to each call. A COPY that
+//! continues the previous COPY in the source is merged into it, so a run of
+//! matched blocks becomes a single instruction. The last COPY is held back
+//! until another instruction (or `finish()`) ends it, which keeps the
+//! output independent of how the calls are split.
+//!
+//! With `set_fine_index()`, each INSERT is scanned against a second index
+//! with smaller blocks before it is serialized. Fragments the main index is
+//! too coarse to find become COPYs, so partially edited structured files
+//! get small patches without shrinking the main block size. Only INSERT
+//! data is scanned, so matched regions cost nothing extra. With
+//! `set_source_access()` those matches are byte-verified like the main
+//! ones (see `diff::verify`).
+//!
+//! Pending INSERT data is serialized whenever it reaches the insert limit
+//! (`DEFAULT_INSERT_LIMIT` unless set), so memory stays bounded however
+//! dissimilar the files are, and no INSERT exceeds the u32 length field.
+
+use super::block_index::BlockIndex;
+use super::rolling_hash::RollingHasher;
+use super::verify::{self, SourceVerifier};
+use crate::apply::patch_applier::SourceAccess;
+use crate::format::patch_format::{TYPE_COPY, TYPE_INSERT};
+use crate::format::sink::PatchSink;
+use crate::format::state::{invalid, StateReader, StateWriter};
+use std::io;
+
+/// Default INSERT size at which pending data is serialized (1MB).
+pub const DEFAULT_INSERT_LIMIT: usize = 1024 * 1024;
+
+/// Largest INSERT the u32 length field can describe.
+const MAX_INSERT_LEN: usize = u32::MAX as usize;
+
+/// Serialized instruction stream with COPY coalescing.
+pub struct InstructionWriter {
+    /// Pending INSERT data.
+    insert_buffer: Vec<u8>,
+    /// COPY that may still be extended: source offset and length.
+    pending_copy: Option<(u64, u32)>,
+    /// Source offset just past the most recent COPY.
+    copy_end: Option<u64>,
+    /// Finalized fine-grained index for INSERT data, if enabled.
+    fine_index: Option<Box<BlockIndex>>,
+    /// Pending INSERT size that triggers serialization.
+    insert_limit: usize,
+    /// Byte verification of matches, if enabled.
+    verifier: Option<SourceVerifier>,
+}
+
+impl Default for InstructionWriter {
+    fn default() -> Self {
+        Self {
+            insert_buffer: Vec::new(),
+            pending_copy: None,
+            copy_end: None,
+            fine_index: None,
+            insert_limit: DEFAULT_INSERT_LIMIT,
+            verifier: None,
+        }
+    }
+}
+
+impl InstructionWriter {
+    /// Creates an empty writer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a COPY of `length` source bytes at `offset`.
+    ///
+    /// Merged with the previous COPY when it ends at `offset` and the
+    /// combined length fits in the u32 length field.
+    pub fn push_copy(
+        &mut self,
+        offset: u64,
+        length: u32,
+        out: &mut dyn PatchSink,
+    ) -> io::Result<()> {
+        self.flush_insert(out)?;
+        self.add_copy(offset, length, out)
+    }
+
+    /// Merges a COPY into the pending one; pending INSERT data must be
+    /// serialized already.
+    fn add_copy(&mut self, offset: u64, length: u32, out: &mut dyn PatchSink) -> io::Result<()> {
+        match &mut self.pending_copy {
+            Some((start, len))
+                if *start + *len as u64 == offset && len.checked_add(length).is_some() =>
+            {
+                *len += length;
+            }
+            _ => {
+                self.flush_copy(out)?;
+                self.pending_copy = Some((offset, length));
+            }
+        }
+        self.copy_end = Some(offset + length as u64);
+        Ok(())
+    }
+
+    /// Appends literal bytes to the pending INSERT.
+    pub fn push_insert(&mut self, mut data: &[u8], out: &mut dyn PatchSink) -> io::Result<()> {
+        while !data.is_empty() {
+            self.flush_copy(out)?;
+            if self.insert_buffer.len() >= self.insert_limit {
+                self.flush_insert(out)?;
+            }
+
+            let take = data.len().min(self.insert_limit - self.insert_buffer.len());
+            self.insert_buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            if self.insert_buffer.len() >= self.insert_limit {
+                self.flush_insert(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends one literal byte to the pending INSERT.
+    #[inline]
+    pub fn push_literal(&mut self, byte: u8, out: &mut dyn PatchSink) -> io::Result<()> {
+        self.flush_copy(out)?;
+        self.insert_buffer.push(byte);
+
+        if self.insert_buffer.len() >= self.insert_limit {
+            self.flush_insert(out)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the pending INSERT size at which it is serialized.
+    ///
+    /// Clamped to 1 byte..4GiB. Pending data beyond a lowered limit is
+    /// serialized by the next call that writes output.
+    pub fn set_insert_limit(&mut self, bytes: usize) {
+        self.insert_limit = bytes.clamp(1, MAX_INSERT_LEN);
+    }
+
+    /// Returns the pending INSERT size at which it is serialized.
+    pub fn insert_limit(&self) -> usize {
+        self.insert_limit
+    }
+
+    /// Scans INSERT data against a finalized fine-grained index, or `None`
+    /// to serialize INSERTs as they are.
+    ///
+    /// The index must use a smaller block size than the main index.
+    pub fn set_fine_index(&mut self, index: Option<BlockIndex>) {
+        self.fine_index = index.map(Box::new);
+    }
+
+    /// Returns the fine-grained index, if set.
+    pub fn fine_index(&self) -> Option<&BlockIndex> {
+        self.fine_index.as_deref()
+    }
+
+    /// Verifies matches against source bytes read from `source`, or `None`
+    /// to turn verification off. Not saved by `write_state()`.
+    ///
+    /// Used for fine index matches; diff engines verify their own matches
+    /// through `verifier()`.
+    pub fn set_source_access(&mut self, source: Option<Box<dyn SourceAccess>>) {
+        self.verifier = source.map(SourceVerifier::new);
+    }
+
+    /// Returns the match verifier, if verification is enabled.
+    pub fn verifier(&mut self) -> Option<&mut SourceVerifier> {
+        self.verifier.as_mut()
+    }
+
+    /// Returns the number of candidates rejected by byte verification.
+    pub fn rejected_matches(&self) -> u64 {
+        self.verifier.as_ref().map_or(0, SourceVerifier::rejected)
+    }
+
+    /// Returns the source offset just past the most recent COPY.
+    ///
+    /// A match there continues that COPY; see `BlockIndex::find_best_match`.
+    pub fn copy_end(&self) -> Option<u64> {
+        self.copy_end
+    }
+
+    /// Serializes all pending instructions.
+    pub fn finish(&mut self, out: &mut dyn PatchSink) -> io::Result<()> {
+        self.flush_insert(out)?;
+        self.flush_copy(out)
+    }
+
+    /// Serializes the pending COPY, if any.
+    fn flush_copy(&mut self, out: &mut dyn PatchSink) -> io::Result<()> {
+        if let Some((offset, length)) = self.pending_copy.take() {
+            // Serialize COPY: type(1) + offset(8) + length(4)
+            let mut copy = [TYPE_COPY; 13];
+            copy[1..9].copy_from_slice(&offset.to_le_bytes());
+            copy[9..].copy_from_slice(&length.to_le_bytes());
+            out.write_patch(&copy)?;
+        }
+        Ok(())
+    }
+
+    /// Serializes the pending INSERT, if any, refining it first when a fine
+    /// index is set.
+    fn flush_insert(&mut self, out: &mut dyn PatchSink) -> io::Result<()> {
+        if let Some(fine) = self.fine_index.take() {
+            let refined = self.refine_insert(&fine, out);
+            self.fine_index = Some(fine);
+            refined?;
+        }
+        self.write_insert(out)
+    }
+
+    /// Replaces matched fragments of the pending INSERT with COPYs from
+    /// `fine`, leaving the unmatched tail pending.
+    fn refine_insert(&mut self, fine: &BlockIndex, out: &mut dyn PatchSink) -> io::Result<()> {
+        let block_size = fine.block_size();
+        if self.insert_buffer.len() < block_size {
+            return Ok(());
+        }
+
+        let data = std::mem::take(&mut self.insert_buffer);
+        let mut hasher = fine.hasher();
+        let mut literal_start = 0;
+        let mut pos = 0;
+        let mut current_hash = None;
+
+        while pos + block_size <= data.len() {
+            let block = &data[pos..pos + block_size];
+            let hash = match current_hash {
+                Some(hash) => hash,
+                None => hasher.hash_chunk(block),
+            };
+
+            let found = verify::find_match(
+                fine,
+                self.verifier.as_mut(),
+                hash,
+                block,
+                None,
+                self.copy_end,
+            );
+            if let Some(offset) = found {
+                if literal_start < pos {
+                    self.flush_copy(out)?;
+                    self.insert_buffer
+                        .extend_from_slice(&data[literal_start..pos]);
+                    self.write_insert(out)?;
+                }
+                self.add_copy(offset, block_size as u32, out)?;
+                pos += block_size;
+                literal_start = pos;
+                current_hash = None;
+            } else {
+                pos += 1;
+                current_hash = (pos + block_size <= data.len())
+                    .then(|| hasher.roll(data[pos - 1], data[pos + block_size - 1]));
+            }
+        }
+
+        if literal_start < data.len() {
+            self.flush_copy(out)?;
+            self.insert_buffer.extend_from_slice(&data[literal_start..]);
+        }
+        Ok(())
+    }
+
+    /// Serializes the pending INSERT as it is.
+    fn write_insert(&mut self, out: &mut dyn PatchSink) -> io::Result<()> {
+        for part in self.insert_buffer.chunks(MAX_INSERT_LEN) {
+            // Serialize INSERT: type(1) + length(4) + data
+            let mut header = [TYPE_INSERT; 5];
+            header[1..].copy_from_slice(&(part.len() as u32).to_le_bytes());
+            out.write_patch(&header)?;
+            out.write_patch(part)?;
+        }
+        self.insert_buffer.clear();
+        Ok(())
+    }
+
+    /// Writes pending instructions and the fine index to suspended builder
+    /// state.
+    pub fn write_state(&self, writer: &mut StateWriter) -> io::Result<()> {
+        writer.put_bytes(&self.insert_buffer);
+        writer.put_u64(self.insert_limit as u64);
+
+        match self.pending_copy {
+            Some((offset, length)) => {
+                writer.put_bool(true);
+                writer.put_u64(offset);
+                writer.put_u32(length);
+            }
+            None => writer.put_bool(false),
+        }
+        match self.copy_end {
+            Some(end) => {
+                writer.put_bool(true);
+                writer.put_u64(end);
+            }
+            None => writer.put_bool(false),
+        }
+        match &self.fine_index {
+            Some(index) => {
+                writer.put_bool(true);
+                index.write_state(writer)?;
+            }
+            None => writer.put_bool(false),
+        }
+        Ok(())
+    }
+
+    /// Reads a writer written by `write_state()`.
+    pub fn read_state(reader: &mut StateReader) -> io::Result<Self> {
+        let mut writer = Self::new();
+        writer.insert_buffer = reader.bytes()?;
+        writer.insert_limit = match reader.u64()? {
+            0 => return Err(invalid("Invalid insert limit in builder state")),
+            limit => limit.min(MAX_INSERT_LEN as u64) as usize,
+        };
+
+        if reader.bool()? {
+            writer.pending_copy = Some((reader.u64()?, reader.u32()?));
+        }
+        if reader.bool()? {
+            writer.copy_end = Some(reader.u64()?);
+        }
+        if reader.bool()? {
+            writer.fine_index = Some(Box::new(BlockIndex::read_state(reader)?));
+        }
+        Ok(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serializes a COPY instruction.
+    fn copy(offset: u64, length: u32) -> Vec<u8> {
+        let mut out = vec![TYPE_COPY];
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&length.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn test_contiguous_copies_merge() {
+        let mut writer = InstructionWriter::new();
+        let mut out = Vec::new();
+        writer.push_copy(100, 4, &mut out).unwrap();
+        writer.push_copy(104, 4, &mut out).unwrap();
+        writer.push_copy(108, 4, &mut out).unwrap();
+        assert!(out.is_empty());
+
+        writer.push_copy(0, 4, &mut out).unwrap();
+        writer.finish(&mut out).unwrap();
+        assert_eq!(out, [copy(100, 12), copy(0, 4)].concat());
+        assert_eq!(writer.copy_end(), Some(4));
+    }
+
+    #[test]
+    fn test_insert_ends_copy() {
+        let mut writer = InstructionWriter::new();
+        let mut out = Vec::new();
+        writer.push_copy(0, 4, &mut out).unwrap();
+        writer.push_literal(b'x', &mut out).unwrap();
+        writer.push_insert(b"yz", &mut out).unwrap();
+        writer.push_copy(4, 4, &mut out).unwrap();
+        writer.finish(&mut out).unwrap();
+
+        let mut expected = copy(0, 4);
+        expected.extend_from_slice(&[TYPE_INSERT, 3, 0, 0, 0, b'x', b'y', b'z']);
+        expected.extend(copy(4, 4));
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_merge_splits_at_u32_limit() {
+        let mut writer = InstructionWriter::new();
+        let mut out = Vec::new();
+        writer.push_copy(0, u32::MAX - 1, &mut out).unwrap();
+        writer.push_copy(u32::MAX as u64 - 1, 1, &mut out).unwrap();
+        writer.push_copy(u32::MAX as u64, 4, &mut out).unwrap();
+        writer.finish(&mut out).unwrap();
+
+        assert_eq!(out, [copy(0, u32::MAX), copy(u32::MAX as u64, 4)].concat());
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        let mut writer = InstructionWriter::new();
+        let mut out = Vec::new();
+        writer.push_insert(b"ab", &mut out).unwrap();
+        writer.push_copy(8, 4, &mut out).unwrap();
+
+        let mut state = StateWriter::new();
+        writer.write_state(&mut state).unwrap();
+        let bytes = state.into_bytes();
+        let mut restored = InstructionWriter::read_state(&mut StateReader::new(&bytes)).unwrap();
+
+        let mut resumed = out.clone();
+        writer.push_copy(12, 4, &mut out).unwrap();
+        writer.finish(&mut out).unwrap();
+        restored.push_copy(12, 4, &mut resumed).unwrap();
+        restored.finish(&mut resumed).unwrap();
+        assert_eq!(out, resumed);
+    }
+
+    #[test]
+    fn test_state_insert_limit_is_clamped() {
+        let restore = |limit: u64| {
+            let mut state = StateWriter::new();
+            state.put_bytes(b"");
+            state.put_u64(limit);
+            state.put_bool(false);
+            state.put_bool(false);
+            state.put_bool(false);
+            let bytes = state.into_bytes();
+            InstructionWriter::read_state(&mut StateReader::new(&bytes)).map(|w| w.insert_limit)
+        };
+
+        assert!(restore(0).is_err());
+        assert_eq!(restore(7).unwrap(), 7);
+        assert_eq!(restore(1 << 32).unwrap(), MAX_INSERT_LEN);
+        assert_eq!(restore((1 << 32) + 1).unwrap(), MAX_INSERT_LEN);
+    }
+
+    #[test]
+    fn test_insert_limit_splits_inserts() {
+        let insert = |data: &[u8]| {
+            let mut out = vec![TYPE_INSERT];
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(data);
+            out
+        };
+
+        // Bytes pushed one at a time or as spans split at the same places
+        let mut bytewise = InstructionWriter::new();
+        let mut spans = InstructionWriter::new();
+        let mut bytewise_out = Vec::new();
+        let mut spans_out = Vec::new();
+        bytewise.set_insert_limit(4);
+        spans.set_insert_limit(4);
+        for &byte in b"abcdefghij" {
+            bytewise.push_literal(byte, &mut bytewise_out).unwrap();
+        }
+        spans.push_insert(b"abc", &mut spans_out).unwrap();
+        spans.push_insert(b"defghij", &mut spans_out).unwrap();
+        assert_eq!(bytewise_out.len(), 2 * 9);
+
+        for (writer, out) in [
+            (&mut bytewise, &mut bytewise_out),
+            (&mut spans, &mut spans_out),
+        ] {
+            writer.push_copy(0, 4, out).unwrap();
+            writer.finish(out).unwrap();
+        }
+        let expected = [insert(b"abcd"), insert(b"efgh"), insert(b"ij"), copy(0, 4)].concat();
+        assert_eq!(bytewise_out, expected);
+        assert_eq!(spans_out, expected);
+
+        // Pending data beyond a lowered limit goes out with the next INSERT
+        let mut writer = InstructionWriter::new();
+        let mut out = Vec::new();
+        writer.push_insert(b"abcdef", &mut out).unwrap();
+        writer.set_insert_limit(2);
+        assert!(out.is_empty());
+        writer.push_insert(b"gh", &mut out).unwrap();
+        assert_eq!(out, [insert(b"abcdef"), insert(b"gh")].concat());
+        assert_eq!(writer.insert_limit(), 2);
+    }
+
+    #[test]
+    fn test_fine_index_refines_insert() {
+        // 8-byte fragments of the source inside new data
+        let source = b"0123456789abcdefghijklmnopqrstuv";
+        let mut fine = BlockIndex::with_block_size(8);
+        fine.add_chunk(source);
+        fine.finalize();
+
+        let mut writer = InstructionWriter::new();
+        let mut out = Vec::new();
+        writer.set_fine_index(Some(fine));
+        writer.push_copy(100, 4, &mut out).unwrap();
+        writer.push_insert(b"XY89abcdefZ", &mut out).unwrap();
+        writer.push_insert(b"ghijklmnopqrstuvW", &mut out).unwrap();
+        writer.push_copy(200, 4, &mut out).unwrap();
+        writer.finish(&mut out).unwrap();
+
+        let mut expected = copy(100, 4);
+        expected.extend_from_slice(&[TYPE_INSERT, 2, 0, 0, 0, b'X', b'Y']);
+        expected.extend(copy(8, 8));
+        expected.extend_from_slice(&[TYPE_INSERT, 1, 0, 0, 0, b'Z']);
+        expected.extend(copy(16, 16));
+        expected.extend_from_slice(&[TYPE_INSERT, 1, 0, 0, 0, b'W']);
+        expected.extend(copy(200, 4));
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_fine_index_matches_are_verified() {
+        let source = b"0123456789abcdefghijklmnopqrstuv";
+        let mut fine = BlockIndex::with_block_size(8);
+        fine.add_chunk(source);
+        fine.finalize();
+
+        // The verifier reads a source that no longer has these blocks
+        let mut writer = InstructionWriter::new();
+        let mut out = Vec::new();
+        writer.set_fine_index(Some(fine));
+        writer.set_source_access(Some(Box::new(vec![0u8; source.len()])));
+        writer.push_insert(b"XY89abcdefZ", &mut out).unwrap();
+        writer.finish(&mut out).unwrap();
+
+        let mut plain = InstructionWriter::new();
+        let mut plain_out = Vec::new();
+        plain.push_insert(b"XY89abcdefZ", &mut plain_out).unwrap();
+        plain.finish(&mut plain_out).unwrap();
+        assert_eq!(out, plain_out);
+        assert_eq!(writer.rejected_matches(), 1);
+    }
+
+    #[test]
+    fn test_sink_errors_are_returned() {
+        struct Failing;
+        impl io::Write for Failing {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut writer = InstructionWriter::new();
+        writer.push_copy(0, 4, &mut Failing).unwrap();
+        let err = writer.push_literal(b'x', &mut Failing).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -1,3 +1,8 @@
 pub mod block_index;
+pub mod disk_index;
+pub mod hash_filter;
+pub mod instruction_writer;
+pub mod parallel_diff;
 pub mod rolling_hash;
 pub mod streaming_diff;
+pub mod verify;
@@ -0,0 +1,624 @@
+//! Multi-threaded diff generator for patch creation.
+//!
+//! Buffers target data into batches, splits each batch into fixed-size
+//! segments and scans the segments concurrently against one shared,
+//! immutable [`BlockIndex`]. Segment scans are then stitched in order:
+//!
+//! - A segment scan starts at the segment start, but the sequential scan
+//!   may arrive later because a COPY from the previous segment straddles
+//!   the boundary.
+//! - From the arrival position the stitcher rescans serially until it
+//!   reaches a position the segment scan also visited. From there both
+//!   scans visit the same positions and find the same blocks.
+//! - Which duplicate a block copies depends on where the previous COPY
+//!   ended (see `BlockIndex::find_best_match`), which a segment scan only
+//!   guesses. The stitcher re-selects the reused COPYs one by one until a
+//!   choice agrees with the segment scan; from there the rest are reused.
+//!
+//! The result is byte-identical to [`StreamingDiff`] for any thread count,
+//! segment size or chunking.
+//!
+//! With `set_source_access()`, stitched COPYs are byte-verified on the
+//! calling thread before they are serialized. A rejected COPY is replaced
+//! by the next verified candidate, or its block becomes INSERT data, so the
+//! output only differs from a verified `StreamingDiff` when a hash
+//! collision was actually rejected.
+//!
+//! On native builds segments run on a rayon thread pool of at most
+//! [`MAX_THREADS`] threads. The wasm build with the `threads` feature runs
+//! them on the global Web Worker pool started through `crate::threads`,
+//! whose size is set by the JS caller. Other wasm builds scan the segments
+//! one after another on the calling thread. On wasm the thread count only
+//! sets the batch size.
+//!
+//! [`StreamingDiff`]: super::streaming_diff::StreamingDiff
+
+use super::block_index::BlockIndex;
+use super::instruction_writer::InstructionWriter;
+use super::rolling_hash::RollingHasher;
+use crate::apply::patch_applier::SourceAccess;
+use crate::error::Result;
+use crate::format::sink::PatchSink;
+use crate::format::state::{invalid, StateReader, StateWriter};
+use std::io;
+
+/// Default target bytes per segment (1MB).
+pub const DEFAULT_SEGMENT_SIZE: usize = 1024 * 1024;
+
+/// Largest number of worker threads; larger counts are clamped.
+pub const MAX_THREADS: usize = 256;
+
+/// Largest number of target bytes per segment (64MB).
+pub const MAX_SEGMENT_SIZE: usize = 64 * 1024 * 1024;
+
+/// COPY found by a scan: target position and source offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Match {
+    /// Target position of the matched block.
+    target_pos: usize,
+    /// Source offset of the matched block.
+    source_offset: u64,
+}
+
+/// Result of scanning one segment.
+struct SegmentScan {
+    /// Position where the scan started.
+    start: usize,
+    /// COPYs in target order.
+    matches: Vec<Match>,
+    /// Position where the scan stopped.
+    end: usize,
+}
+
+impl SegmentScan {
+    /// Checks whether the scan visited a position, i.e. it is not inside a
+    /// COPY. Positions outside the scanned range are never visited.
+    fn visited(&self, pos: usize, block_size: usize) -> bool {
+        if pos < self.start || pos >= self.end {
+            return false;
+        }
+
+        let next = self.matches.partition_point(|m| m.target_pos < pos);
+        match next.checked_sub(1).map(|i| self.matches[i]) {
+            Some(prev) => pos >= prev.target_pos + block_size,
+            None => true,
+        }
+    }
+
+    /// Returns the COPYs at or after a position.
+    fn matches_from(&self, pos: usize) -> &[Match] {
+        let first = self.matches.partition_point(|m| m.target_pos < pos);
+        &self.matches[first..]
+    }
+}
+
+/// Greedily scans `data` from `start` until `stop` returns true or no full
+/// block remains, mirroring the `StreamingDiff` matching loop.
+///
+/// `near` is the source offset where the previous COPY ended, if any.
+fn scan<F>(
+    index: &BlockIndex,
+    data: &[u8],
+    start: usize,
+    mut near: Option<u64>,
+    mut stop: F,
+) -> (Vec<Match>, usize)
+where
+    F: FnMut(usize) -> bool,
+{
+    let block_size = index.block_size();
+    let mut hasher = index.hasher();
+    let mut matches = Vec::new();
+    let mut pos = start;
+    let mut current_hash = None;
+
+    while pos + block_size <= data.len() && !stop(pos) {
+        let block = &data[pos..pos + block_size];
+        let hash = match current_hash {
+            Some(hash) => hash,
+            None => hasher.hash_chunk(block),
+        };
+
+        if let Some(source_offset) = index.find_best_match(hash, block, None, near) {
+            matches.push(Match {
+                target_pos: pos,
+                source_offset,
+            });
+            near = Some(source_offset + block_size as u64);
+            pos += block_size;
+            current_hash = None;
+        } else {
+            pos += 1;
+            current_hash = (pos + block_size <= data.len())
+                .then(|| hasher.roll(data[pos - 1], data[pos + block_size - 1]));
+        }
+    }
+
+    (matches, pos)
+}
+
+/// Runs segment scans, in parallel where threads are available.
+#[cfg(not(target_arch = "wasm32"))]
+fn scan_segments(
+    pool: &Pool,
+    index: &BlockIndex,
+    data: &[u8],
+    starts: &[usize],
+) -> Vec<SegmentScan> {
+    use rayon::prelude::*;
+
+    pool.install(|| {
+        (0..starts.len())
+            .into_par_iter()
+            .map(|i| scan_segment(index, data, starts, i))
+            .collect()
+    })
+}
+
+/// Runs segment scans on the Web Worker pool once it is running, and on
+/// the calling thread before that.
+#[cfg(all(target_arch = "wasm32", feature = "threads"))]
+fn scan_segments(
+    _pool: &Pool,
+    index: &BlockIndex,
+    data: &[u8],
+    starts: &[usize],
+) -> Vec<SegmentScan> {
+    use rayon::prelude::*;
+
+    if !crate::threads::pool_ready() {
+        return (0..starts.len())
+            .map(|i| scan_segment(index, data, starts, i))
+            .collect();
+    }
+
+    (0..starts.len())
+        .into_par_iter()
+        .map(|i| scan_segment(index, data, starts, i))
+        .collect()
+}
+
+/// Runs segment scans on the calling thread.
+#[cfg(all(target_arch = "wasm32", not(feature = "threads")))]
+fn scan_segments(
+    _pool: &Pool,
+    index: &BlockIndex,
+    data: &[u8],
+    starts: &[usize],
+) -> Vec<SegmentScan> {
+    (0..starts.len())
+        .map(|i| scan_segment(index, data, starts, i))
+        .collect()
+}
+
+/// Scans segment `i`, stopping at the next segment start.
+///
+/// The end of the previous COPY is unknown, so the scan starts without one.
+fn scan_segment(index: &BlockIndex, data: &[u8], starts: &[usize], i: usize) -> SegmentScan {
+    let start = starts[i];
+    let next_start = starts.get(i + 1).copied().unwrap_or(usize::MAX);
+    let (matches, end) = scan(index, data, start, None, |p| p >= next_start);
+
+    SegmentScan {
+        start,
+        matches,
+        end,
+    }
+}
+
+/// Thread pool type for the current target.
+#[cfg(not(target_arch = "wasm32"))]
+type Pool = rayon::ThreadPool;
+
+/// Thread pool type for the current target.
+#[cfg(target_arch = "wasm32")]
+type Pool = ();
+
+/// Creates a pool with the given number of threads.
+#[cfg(not(target_arch = "wasm32"))]
+fn build_pool(threads: usize) -> Result<Pool> {
+    use crate::error::PatchlyError;
+
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|err| {
+            PatchlyError::Io(io::Error::other(format!(
+                "Failed to create diff thread pool: {}",
+                err
+            )))
+        })
+}
+
+/// Creates a pool with the given number of threads.
+#[cfg(target_arch = "wasm32")]
+fn build_pool(_threads: usize) -> Result<Pool> {
+    Ok(())
+}
+
+/// Parallel diff generator with the same output as `StreamingDiff`.
+pub struct ParallelDiff {
+    /// Block index built from source file, shared by all segment scans.
+    index: BlockIndex,
+    /// Worker threads.
+    pool: Pool,
+    /// Number of worker threads.
+    threads: usize,
+    /// Target bytes per segment.
+    segment_size: usize,
+    /// Buffered target data, starting at the scan position.
+    buffer: Vec<u8>,
+    /// Instruction serializer holding pending INSERT and COPY data.
+    writer: InstructionWriter,
+}
+
+impl ParallelDiff {
+    /// Creates a `ParallelDiff` with the default segment size.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Finalized source block index.
+    /// * `threads` - Number of worker threads, clamped to `1..=MAX_THREADS`.
+    ///
+    /// Fails if the thread pool can't be created.
+    pub fn new(index: BlockIndex, threads: usize) -> Result<Self> {
+        Self::with_segment_size(index, threads, DEFAULT_SEGMENT_SIZE)
+    }
+
+    /// Creates a `ParallelDiff` with a custom segment size.
+    ///
+    /// The segment size is clamped to at least one block and at most
+    /// `MAX_SEGMENT_SIZE`.
+    pub fn with_segment_size(
+        index: BlockIndex,
+        threads: usize,
+        segment_size: usize,
+    ) -> Result<Self> {
+        let threads = threads.clamp(1, MAX_THREADS);
+        let segment_size = segment_size.clamp(index.block_size(), MAX_SEGMENT_SIZE);
+
+        Ok(Self {
+            index,
+            pool: build_pool(threads)?,
+            threads,
+            segment_size,
+            buffer: Vec::new(),
+            writer: InstructionWriter::new(),
+        })
+    }
+
+    /// Target bytes buffered before a batch is scanned.
+    fn batch_size(&self) -> usize {
+        self.segment_size * self.threads * 2
+    }
+
+    /// Processes a chunk of target data, writing finished instructions to
+    /// `out`.
+    ///
+    /// Data is buffered until a full batch is available.
+    pub fn process_target_chunk(
+        &mut self,
+        chunk: &[u8],
+        out: &mut dyn PatchSink,
+    ) -> io::Result<()> {
+        self.buffer.extend_from_slice(chunk);
+
+        if self.buffer.len() >= self.batch_size() + self.index.block_size() {
+            self.process_batch(false, out)?;
+        }
+        Ok(())
+    }
+
+    /// Scans the buffered data and serializes the stitched result.
+    ///
+    /// Scans stop where no full block is left. Unless `last` is set, the
+    /// unscanned tail stays buffered for the next batch.
+    fn process_batch(&mut self, last: bool, out: &mut dyn PatchSink) -> io::Result<()> {
+        let block_size = self.index.block_size();
+        let scan_end = (self.buffer.len() + 1).saturating_sub(block_size);
+
+        let starts: Vec<usize> = (0..scan_end).step_by(self.segment_size).collect();
+        let scans = scan_segments(&self.pool, &self.index, &self.buffer, &starts);
+
+        // Stitch segments into the sequential scan
+        let mut hasher = self.index.hasher();
+        let mut near = self.writer.copy_end();
+        let mut matches = Vec::new();
+        let mut pos = 0;
+        for (i, segment) in scans.iter().enumerate() {
+            let next_start = starts.get(i + 1).copied().unwrap_or(scan_end);
+            if pos >= next_start {
+                continue;
+            }
+
+            let (fixup, stopped) = scan(&self.index, &self.buffer, pos, near, |p| {
+                p >= next_start || segment.visited(p, block_size)
+            });
+            if let Some(last) = fixup.last() {
+                near = Some(last.source_offset + block_size as u64);
+            }
+            matches.extend(fixup);
+            pos = stopped;
+
+            if segment.visited(pos, block_size) {
+                // Re-select with the true previous COPY until the segment agrees
+                let mut rest = segment.matches_from(pos);
+                while let Some((m, tail)) = rest.split_first() {
+                    let block = &self.buffer[m.target_pos..m.target_pos + block_size];
+                    let source_offset = self
+                        .index
+                        .find_best_match(hasher.hash_chunk(block), block, None, near)
+                        .unwrap_or(m.source_offset);
+                    matches.push(Match {
+                        target_pos: m.target_pos,
+                        source_offset,
+                    });
+                    near = Some(source_offset + block_size as u64);
+                    rest = tail;
+
+                    if source_offset == m.source_offset {
+                        break;
+                    }
+                }
+                if let Some(last) = rest.last() {
+                    near = Some(last.source_offset + block_size as u64);
+                }
+                matches.extend_from_slice(rest);
+                pos = segment.end;
+            }
+        }
+
+        // Serialize: everything between COPYs is INSERT data
+        let mut literal_start = 0;
+        for m in matches {
+            let block = &self.buffer[m.target_pos..m.target_pos + block_size];
+            let near = self.writer.copy_end();
+            let mut source_offset = m.source_offset;
+            if let Some(verifier) = self.writer.verifier() {
+                if !verifier.matches(source_offset, block) {
+                    let found = self.index.find_checked_match(
+                        hasher.hash_chunk(block),
+                        block,
+                        None,
+                        near,
+                        |offset| offset != m.source_offset && verifier.matches(offset, block),
+                    );
+                    // Without a replacement the block stays INSERT data
+                    match found {
+                        Some(offset) => source_offset = offset,
+                        None => continue,
+                    }
+                }
+            }
+
+            self.writer
+                .push_insert(&self.buffer[literal_start..m.target_pos], out)?;
+            self.writer
+                .push_copy(source_offset, block_size as u32, out)?;
+            literal_start = m.target_pos + block_size;
+        }
+
+        let consumed = if last { self.buffer.len() } else { pos };
+        self.writer
+            .push_insert(&self.buffer[literal_start..consumed], out)?;
+        self.buffer.drain(..consumed);
+        Ok(())
+    }
+
+    /// Refines INSERT data against a finalized fine-grained index, or
+    /// `None` to disable (see `InstructionWriter::set_fine_index()`).
+    pub fn set_fine_index(&mut self, index: Option<BlockIndex>) {
+        self.writer.set_fine_index(index);
+    }
+
+    /// Sets the pending INSERT size at which it is emitted
+    /// (see `InstructionWriter::set_insert_limit()`).
+    pub fn set_insert_limit(&mut self, bytes: usize) {
+        self.writer.set_insert_limit(bytes);
+    }
+
+    /// Verifies every COPY against source bytes read from `source`, or
+    /// `None` to turn verification off. Not saved by `write_state()`.
+    pub fn set_source_access(&mut self, source: Option<Box<dyn SourceAccess>>) {
+        self.writer.set_source_access(source);
+    }
+
+    /// Returns the number of candidates rejected by byte verification.
+    pub fn rejected_matches(&self) -> u64 {
+        self.writer.rejected_matches()
+    }
+
+    /// Returns the fine-grained index, if set.
+    pub fn fine_index(&self) -> Option<&BlockIndex> {
+        self.writer.fine_index()
+    }
+
+    /// Finalizes processing, writing the remaining instructions to `out`.
+    pub fn finalize(&mut self, out: &mut dyn PatchSink) -> io::Result<()> {
+        self.process_batch(true, out)?;
+        self.writer.finish(out)
+    }
+
+    /// Returns the source block index.
+    pub fn index(&self) -> &BlockIndex {
+        &self.index
+    }
+
+    /// Writes the index and buffered data to suspended builder state.
+    pub fn write_state(&self, writer: &mut StateWriter) -> io::Result<()> {
+        self.index.write_state(writer)?;
+        writer.put_u64(self.threads as u64);
+        writer.put_u64(self.segment_size as u64);
+        writer.put_bytes(&self.buffer);
+        self.writer.write_state(writer)?;
+        Ok(())
+    }
+
+    /// Reads a diff written by `write_state()`.
+    pub fn read_state(reader: &mut StateReader) -> io::Result<Self> {
+        let index = BlockIndex::read_state(reader)?;
+        let threads = reader.u64()?;
+        let segment_size = reader.u64()?;
+        if !(1..=MAX_THREADS as u64).contains(&threads)
+            || !(index.block_size() as u64..=MAX_SEGMENT_SIZE as u64).contains(&segment_size)
+        {
+            return Err(invalid("Invalid parallel diff settings in builder state"));
+        }
+
+        let mut diff = Self::with_segment_size(index, threads as usize, segment_size as usize)?;
+        diff.buffer = reader.bytes()?;
+        diff.writer = InstructionWriter::read_state(reader)?;
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::streaming_diff::StreamingDiff;
+    use crate::test_util::{apply, noise};
+
+    fn build_index(data: &[u8], block_size: usize) -> BlockIndex {
+        let mut index = BlockIndex::with_block_size(block_size);
+        index.add_chunk(data);
+        index.finalize();
+        index
+    }
+
+    fn sequential(source: &[u8], target: &[u8], block_size: usize) -> Vec<u8> {
+        let mut diff = StreamingDiff::new(build_index(source, block_size));
+        let mut output = Vec::new();
+        diff.process_target_chunk(target, &mut output).unwrap();
+        diff.finalize(&mut output).unwrap();
+        output
+    }
+
+    fn parallel(
+        source: &[u8],
+        target: &[u8],
+        block_size: usize,
+        threads: usize,
+        segment_size: usize,
+        chunk: usize,
+    ) -> Vec<u8> {
+        let index = build_index(source, block_size);
+        let mut diff = ParallelDiff::with_segment_size(index, threads, segment_size).unwrap();
+        let mut output = Vec::new();
+        for piece in target.chunks(chunk) {
+            diff.process_target_chunk(piece, &mut output).unwrap();
+        }
+        diff.finalize(&mut output).unwrap();
+        output
+    }
+
+    /// Target with edits, moved ranges and repeats of a source.
+    fn edited_target(source: &[u8]) -> Vec<u8> {
+        let mut target = Vec::new();
+        target.extend_from_slice(&source[5000..20_000]);
+        target.extend(noise(777, 42));
+        target.extend_from_slice(&source[..5000]);
+        target.extend_from_slice(&source[1..30_001]);
+        target.extend_from_slice(&source[..3000]);
+        target.extend(noise(333, 43));
+        target
+    }
+
+    #[test]
+    fn test_matches_sequential_output() {
+        let source = noise(40_000, 1);
+        let target = edited_target(&source);
+        let expected = sequential(&source, &target, 256);
+
+        for threads in [1, 2, 3, 8] {
+            for segment_size in [256, 1000, 4096, 100_000] {
+                for chunk in [97, 5000, target.len()] {
+                    let output = parallel(&source, &target, 256, threads, segment_size, chunk);
+                    assert_eq!(
+                        output, expected,
+                        "threads {} segment {} chunk {}",
+                        threads, segment_size, chunk
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_straddling_repetitive_matches() {
+        // Every offset matches, so segment scans are misaligned with the
+        // sequential scan and never resynchronize
+        let source = vec![0u8; 4096];
+        let mut target = vec![0u8; 50_000];
+        target[1234] = 1;
+        target[30_001] = 2;
+        let expected = sequential(&source, &target, 64);
+
+        for segment_size in [64, 100, 999] {
+            let output = parallel(&source, &target, 64, 4, segment_size, 7000);
+            assert_eq!(output, expected, "segment {}", segment_size);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_source_blocks() {
+        // Every block exists twice, so COPY offsets depend on the previous COPY
+        let half = noise(20_000, 5);
+        let source = [half.as_slice(), half.as_slice()].concat();
+        let target = edited_target(&source);
+        let expected = sequential(&source, &target, 256);
+
+        for segment_size in [256, 1000, 4096] {
+            let output = parallel(&source, &target, 256, 4, segment_size, 3000);
+            assert_eq!(output, expected, "segment {}", segment_size);
+        }
+    }
+
+    #[test]
+    fn test_verification_rejects_changed_source() {
+        // The index describes the original source, but the verifier reads a
+        // copy whose first half changed, as after a hash collision
+        let source = noise(40_000, 1);
+        let mut changed = source.clone();
+        for byte in &mut changed[..20_000] {
+            *byte ^= 0x55;
+        }
+        let target = edited_target(&source);
+
+        let mut diff = ParallelDiff::with_segment_size(build_index(&source, 256), 4, 1000).unwrap();
+        diff.set_source_access(Some(Box::new(changed.clone())));
+        let mut output = Vec::new();
+        diff.process_target_chunk(&target, &mut output).unwrap();
+        diff.finalize(&mut output).unwrap();
+
+        assert_eq!(apply(&changed, &output), target);
+        assert!(diff.rejected_matches() > 0);
+    }
+
+    #[test]
+    fn test_thread_counts_are_bounded() {
+        let source = noise(1000, 3);
+        let diff = ParallelDiff::with_segment_size(build_index(&source, 128), 0, 0).unwrap();
+        assert_eq!((diff.threads, diff.segment_size), (1, 128));
+
+        let index = build_index(&source, 128);
+        // Untrusted state can't ask for a huge pool or batch
+        for (threads, segment_size) in [(0, 1000), (u64::MAX, 1000), (4, u64::MAX)] {
+            let mut writer = StateWriter::new();
+            index.write_state(&mut writer).unwrap();
+            writer.put_u64(threads);
+            writer.put_u64(segment_size);
+            let state = writer.into_bytes();
+            assert!(ParallelDiff::read_state(&mut StateReader::new(&state)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_short_and_empty_targets() {
+        let source = noise(1000, 3);
+
+        for target in [Vec::new(), source[..10].to_vec(), source[..300].to_vec()] {
+            let expected = sequential(&source, &target, 128);
+            assert_eq!(parallel(&source, &target, 128, 4, 128, 50), expected);
+        }
+    }
+}
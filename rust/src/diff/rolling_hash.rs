@@ -1,15 +1,116 @@
-//! Rolling hash implementation for content-defined chunking.
+//! Rolling hash implementations for content-defined chunking.
 //!
-//! Uses an Adler-32 style algorithm with two sums:
-//! - `sum_a`: Sum of all bytes in the window
-//! - `sum_b`: Weighted sum (positional)
+//! Two weak hashes implement [`RollingHasher`], selected per patch by
+//! [`RollingHashKind`]:
 //!
-//! This allows O(1) "rolling" when sliding the window by one byte.
+//! - [`RollingHash`]: Adler-32 style algorithm with two sums:
+//!   - `sum_a`: Sum of all bytes in the window
+//!   - `sum_b`: Weighted sum (positional)
+//! - [`BuzHash`]: table-driven cyclic polynomial hash (Buzhash). Each byte
+//!   maps through a random 64-bit table and the window is combined with
+//!   rotations and XOR, so there is no modulus per byte and low-entropy
+//!   data (zero runs, sparse records) spreads over the whole 32-bit range.
+//!
+//! Both allow O(1) "rolling" when sliding the window by one byte.
+
+use crate::simd;
 
 /// Modulus for hash calculation to prevent overflow.
 /// Using a prime close to 2^16 for better distribution.
 const MODULUS: u32 = 65521;
 
+/// Random per-byte values for `BuzHash` (splitmix64 sequence).
+const BUZHASH_TABLE: [u64; 256] = buzhash_table();
+
+/// Builds `BUZHASH_TABLE` at compile time.
+const fn buzhash_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x5EED_u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Weak hash over a sliding window of fixed size.
+pub trait RollingHasher {
+    /// Hashes a complete window, resetting any previous state.
+    fn hash_chunk(&mut self, data: &[u8]) -> u32;
+
+    /// Slides the window forward by one byte and returns the new hash.
+    fn roll(&mut self, old_byte: u8, new_byte: u8) -> u32;
+}
+
+/// Weak hash algorithm recorded in a patch header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RollingHashKind {
+    /// Adler-32 style `RollingHash` (format default).
+    #[default]
+    Adler,
+    /// Table-driven `BuzHash`.
+    Buzhash,
+}
+
+impl RollingHashKind {
+    /// Returns the kind for a header ID, if known.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::Adler),
+            1 => Some(Self::Buzhash),
+            _ => None,
+        }
+    }
+
+    /// Returns the header ID of this kind.
+    pub fn id(self) -> u8 {
+        match self {
+            Self::Adler => 0,
+            Self::Buzhash => 1,
+        }
+    }
+
+    /// Creates a hasher of this kind for `window_size`-byte windows.
+    pub fn hasher(self, window_size: usize) -> WeakHasher {
+        match self {
+            Self::Adler => WeakHasher::Adler(RollingHash::new(window_size)),
+            Self::Buzhash => WeakHasher::Buzhash(BuzHash::new(window_size)),
+        }
+    }
+}
+
+/// Hasher selected at runtime by `RollingHashKind`.
+#[derive(Debug, Clone)]
+pub enum WeakHasher {
+    /// Adler-32 style hash.
+    Adler(RollingHash),
+    /// Buzhash.
+    Buzhash(BuzHash),
+}
+
+impl RollingHasher for WeakHasher {
+    #[inline]
+    fn hash_chunk(&mut self, data: &[u8]) -> u32 {
+        match self {
+            Self::Adler(hasher) => hasher.hash_chunk(data),
+            Self::Buzhash(hasher) => hasher.hash_chunk(data),
+        }
+    }
+
+    #[inline]
+    fn roll(&mut self, old_byte: u8, new_byte: u8) -> u32 {
+        match self {
+            Self::Adler(hasher) => hasher.roll(old_byte, new_byte),
+            Self::Buzhash(hasher) => hasher.roll(old_byte, new_byte),
+        }
+    }
+}
+
 /// Rolling hash calculator for efficient window-based hashing.
 ///
 /// Supports O(1) hash updates when sliding a window through data,
@@ -50,15 +151,8 @@ impl RollingHash {
     ///
     /// The computed 32-bit hash value.
     pub fn hash_chunk(&mut self, data: &[u8]) -> u32 {
-        self.sum_a = 0;
-        self.sum_b = 0;
-
-        for (i, &byte) in data.iter().enumerate() {
-            self.sum_a = (self.sum_a + byte as u32) % MODULUS;
-            // Weight: window_size - i (first byte has highest weight)
-            self.sum_b = (self.sum_b + (self.window_size - i) as u32 * byte as u32) % MODULUS;
-        }
-
+        // Vectorized where available; byte i has weight window_size - i
+        (self.sum_a, self.sum_b) = simd::adler_sums(data, self.window_size);
         self.digest()
     }
 
@@ -105,9 +199,69 @@ impl RollingHash {
     }
 }
 
+impl RollingHasher for RollingHash {
+    fn hash_chunk(&mut self, data: &[u8]) -> u32 {
+        RollingHash::hash_chunk(self, data)
+    }
+
+    fn roll(&mut self, old_byte: u8, new_byte: u8) -> u32 {
+        RollingHash::roll(self, old_byte, new_byte)
+    }
+}
+
+/// Buzhash (cyclic polynomial) rolling hash.
+///
+/// The state is the XOR of `BUZHASH_TABLE[byte_i]` rotated left by
+/// `n - 1 - i` bits over the window; the digest is its top 32 bits.
+/// Rolling rotates by one, XORs in the entering byte and XORs out the
+/// leaving byte rotated by `n`. Windows of one repeated byte cancel to 0
+/// when `n` is a multiple of 128, so such blocks share a weak hash and are
+/// told apart by the strong hash.
+#[derive(Debug, Clone)]
+pub struct BuzHash {
+    /// Rotate/XOR state.
+    hash: u64,
+    /// Rotation of the byte leaving the window (window size mod 64).
+    leave_rotation: u32,
+}
+
+impl BuzHash {
+    /// Creates a new `BuzHash` with the specified window size.
+    pub fn new(window_size: usize) -> Self {
+        Self {
+            hash: 0,
+            leave_rotation: (window_size % 64) as u32,
+        }
+    }
+
+    /// Returns the 32-bit hash of the current window.
+    #[inline]
+    pub fn digest(&self) -> u32 {
+        (self.hash >> 32) as u32
+    }
+}
+
+impl RollingHasher for BuzHash {
+    fn hash_chunk(&mut self, data: &[u8]) -> u32 {
+        self.hash = data.iter().fold(0u64, |hash, &byte| {
+            hash.rotate_left(1) ^ BUZHASH_TABLE[byte as usize]
+        });
+        self.digest()
+    }
+
+    #[inline]
+    fn roll(&mut self, old_byte: u8, new_byte: u8) -> u32 {
+        self.hash = self.hash.rotate_left(1)
+            ^ BUZHASH_TABLE[old_byte as usize].rotate_left(self.leave_rotation)
+            ^ BUZHASH_TABLE[new_byte as usize];
+        self.digest()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::noise;
 
     #[test]
     fn test_hash_chunk_basic() {
@@ -180,6 +334,108 @@ mod tests {
         assert_ne!(hash, 0);
     }
 
+    /// Hashes every block with a fresh hasher of `kind`.
+    fn block_hashes(kind: RollingHashKind, blocks: &[Vec<u8>]) -> Vec<u32> {
+        let mut hasher = kind.hasher(blocks[0].len());
+        blocks
+            .iter()
+            .map(|block| hasher.hash_chunk(block))
+            .collect()
+    }
+
+    /// Returns (distinct hashes, largest of 256 top-byte buckets).
+    fn distribution(hashes: &[u32]) -> (usize, usize) {
+        let mut buckets = [0usize; 256];
+        for &hash in hashes {
+            buckets[(hash >> 24) as usize] += 1;
+        }
+
+        let mut distinct = hashes.to_vec();
+        distinct.sort_unstable();
+        distinct.dedup();
+        (distinct.len(), buckets.into_iter().max().unwrap())
+    }
+
+    /// 8192 distinct 64-byte blocks, each zero except for one or two bytes.
+    fn sparse_blocks() -> Vec<Vec<u8>> {
+        (0..8192usize)
+            .map(|i| {
+                let mut block = vec![0u8; 64];
+                block[i % 64] = (i / 64) as u8 + 1;
+                if i >= 4096 {
+                    block[(i * 7) % 64] ^= 0x80;
+                }
+                block
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_buzhash_roll_equivalence() {
+        let data = noise(2000, 11);
+
+        // Window sizes below, at and above the 64-bit rotation width
+        for window in [1, 63, 64, 100, 128, 1000] {
+            for kind in [RollingHashKind::Adler, RollingHashKind::Buzhash] {
+                let mut rolling = kind.hasher(window);
+                let mut direct = kind.hasher(window);
+                rolling.hash_chunk(&data[..window]);
+
+                for pos in 1..=data.len() - window {
+                    let hash = rolling.roll(data[pos - 1], data[pos + window - 1]);
+                    assert_eq!(hash, direct.hash_chunk(&data[pos..pos + window]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_kind_ids() {
+        for kind in [RollingHashKind::Adler, RollingHashKind::Buzhash] {
+            assert_eq!(RollingHashKind::from_id(kind.id()), Some(kind));
+        }
+        assert_eq!(RollingHashKind::from_id(9), None);
+        assert_eq!(RollingHashKind::default(), RollingHashKind::Adler);
+    }
+
+    #[test]
+    fn test_random_data_distribution() {
+        let blocks: Vec<Vec<u8>> = noise(8192 * 64, 7).chunks(64).map(<[u8]>::to_vec).collect();
+
+        // Mean bucket size is 32
+        let (distinct, largest) = distribution(&block_hashes(RollingHashKind::Buzhash, &blocks));
+        assert!(distinct >= 8180, "{}", distinct);
+        assert!(largest < 64, "{}", largest);
+    }
+
+    #[test]
+    fn test_low_entropy_distribution() {
+        let blocks = sparse_blocks();
+
+        let (buzhash_distinct, buzhash_largest) =
+            distribution(&block_hashes(RollingHashKind::Buzhash, &blocks));
+        assert!(buzhash_distinct >= 8180, "{}", buzhash_distinct);
+        assert!(buzhash_largest < 64, "{}", buzhash_largest);
+
+        // Adler sums stay small on sparse data and crowd into few buckets
+        let (adler_distinct, adler_largest) =
+            distribution(&block_hashes(RollingHashKind::Adler, &blocks));
+        assert!(adler_distinct < buzhash_distinct);
+        assert!(adler_largest > 4 * buzhash_largest);
+    }
+
+    #[test]
+    fn test_constant_blocks() {
+        let constant = |len: usize| -> Vec<u32> {
+            let blocks: Vec<Vec<u8>> = (0..=255u8).map(|byte| vec![byte; len]).collect();
+            block_hashes(RollingHashKind::Buzhash, &blocks)
+        };
+
+        // Blocks of one repeated byte differ unless the rotations cancel
+        assert_eq!(distribution(&constant(1000)).0, 256);
+        assert!(constant(4096).iter().all(|&hash| hash == 0));
+    }
+
     #[test]
     fn test_binary_data() {
         // Test with non-ASCII binary data
@@ -2,12 +2,31 @@
 //!
 //! Processes target file chunks and generates serialized patch instructions
 //! by comparing against a pre-built source file index.
+//!
+//! Matches are normally accepted on weak hash plus 64-bit strong hash. With
+//! `set_source_access()`, each candidate's source bytes are read back and
+//! compared before a COPY is emitted (see `diff::verify`).
+//!
+//! When a block occurs several times in the source, the copy nearest the
+//! end of the previous COPY wins, so runs of matches merge into one COPY
+//! and the applier reads the source sequentially.
+//!
+//! Target chunks are scanned in place. Only the bytes that can't form a
+//! complete block yet are carried over to the next chunk, and the rolling
+//! hash continues across chunk boundaries instead of being recomputed.
 
 use super::block_index::BlockIndex;
-use super::rolling_hash::RollingHash;
-use crate::format::patch_format::{TYPE_COPY, TYPE_INSERT};
-
-/// Streaming diff generator that outputs serialized patch data directly.
+use super::instruction_writer::InstructionWriter;
+use super::rolling_hash::{RollingHasher, WeakHasher};
+use super::verify;
+use crate::apply::patch_applier::SourceAccess;
+use crate::format::sink::PatchSink;
+use crate::format::state::{StateReader, StateWriter};
+use std::io;
+use std::ops::Range;
+
+/// Streaming diff generator that serializes patch instructions straight
+/// into a [`PatchSink`].
 ///
 /// Compares target file data against a source file's block index to find
 /// matching blocks (COPY) and new data (INSERT).
@@ -16,139 +35,284 @@ pub struct StreamingDiff {
     index: BlockIndex,
     /// Block size for matching.
     block_size: usize,
-    /// Buffer for pending target data to process.
-    buffer: Vec<u8>,
-    /// Pending INSERT data.
-    insert_buffer: Vec<u8>,
-    /// Serialized output ready to be consumed.
-    output_buffer: Vec<u8>,
+    /// Target bytes carried over from earlier chunks, shorter than a block
+    /// (plus the byte before them while `hash_behind` is set).
+    carry: Vec<u8>,
+    /// Rolling hash over the target.
+    hasher: WeakHasher,
+    /// Whether `hasher` holds the window one byte before the scan position,
+    /// so the next window is a roll instead of a full hash.
+    hash_behind: bool,
+    /// Instruction serializer holding pending INSERT and COPY data.
+    writer: InstructionWriter,
+    /// Source range whose blocks are preferred when several entries match.
+    preferred_range: Option<Range<u64>>,
 }
 
 impl StreamingDiff {
     /// Creates a new `StreamingDiff` from a `BlockIndex`.
     pub fn new(index: BlockIndex) -> Self {
         let block_size = index.block_size();
+        let hasher = index.hasher();
 
         Self {
             index,
             block_size,
-            buffer: Vec::new(),
-            insert_buffer: Vec::new(),
-            output_buffer: Vec::new(),
+            carry: Vec::new(),
+            hasher,
+            hash_behind: false,
+            writer: InstructionWriter::new(),
+            preferred_range: None,
         }
     }
 
-    /// Processes a chunk of target data.
+    /// Processes a chunk of target data, writing finished instructions to
+    /// `out`.
     ///
-    /// This may generate serialized output in the output buffer.
-    pub fn process_target_chunk(&mut self, chunk: &[u8]) {
-        self.buffer.extend_from_slice(chunk);
-        self.process_buffer();
+    /// A `Vec<u8>` works as a buffered sink. After a sink error the output
+    /// is incomplete and the diff can't continue.
+    pub fn process_target_chunk(
+        &mut self,
+        chunk: &[u8],
+        out: &mut dyn PatchSink,
+    ) -> io::Result<()> {
+        let mut start = 0;
+
+        if !self.carry.is_empty() {
+            // Windows starting in the carry need at most one more block
+            let carried = self.carry.len();
+            let head = chunk.len().min(self.block_size);
+            let mut carry = std::mem::take(&mut self.carry);
+            carry.extend_from_slice(&chunk[..head]);
+
+            let pos = self.scan(&carry, self.hash_behind as usize, carried + 1, out)?;
+            if head == chunk.len() {
+                self.keep_tail(&carry, pos);
+                return Ok(());
+            }
+
+            // The scan went past the carry, so the rest continues in `chunk`
+            start = pos - carried;
+            carry.clear();
+            self.carry = carry;
+        }
+
+        let pos = self.scan(chunk, start, usize::MAX, out)?;
+        self.keep_tail(chunk, pos);
+        Ok(())
     }
 
-    /// Processes buffered data using rolling hash.
-    fn process_buffer(&mut self) {
-        if self.buffer.len() < self.block_size {
-            return;
+    /// Matches windows of `data` starting before `stop` until no complete
+    /// window is left, and returns the next scan position.
+    ///
+    /// While `hash_behind` is set, `data[pos - 1]` must be the byte before
+    /// the scan position.
+    fn scan(
+        &mut self,
+        data: &[u8],
+        mut pos: usize,
+        stop: usize,
+        out: &mut dyn PatchSink,
+    ) -> io::Result<usize> {
+        let block_size = self.block_size;
+
+        while pos < stop && pos + block_size <= data.len() {
+            let current_block = &data[pos..pos + block_size];
+            let current_hash = if self.hash_behind {
+                // Roll hash forward - O(1) operation
+                self.hasher.roll(data[pos - 1], data[pos + block_size - 1])
+            } else {
+                self.hasher.hash_chunk(current_block)
+            };
+
+            if let Some(source_offset) = self.find_match(current_hash, current_block) {
+                // Found a verified match - emit COPY (merged when contiguous)
+                self.writer
+                    .push_copy(source_offset, block_size as u32, out)?;
+
+                // Skip past the matched block; the next window is hashed anew
+                pos += block_size;
+                self.hash_behind = false;
+            } else {
+                // No match - add byte to INSERT buffer
+                self.writer.push_literal(data[pos], out)?;
+                pos += 1;
+                self.hash_behind = true;
+            }
         }
 
-        let mut hasher = RollingHash::new(self.block_size);
-        let mut pos = 0;
+        Ok(pos)
+    }
 
-        // Calculate initial hash
-        let mut current_hash = hasher.hash_chunk(&self.buffer[0..self.block_size]);
+    /// Carries the unscanned bytes of `data` from `pos` over to the next
+    /// chunk, with the byte before them when the hash is behind.
+    fn keep_tail(&mut self, data: &[u8], pos: usize) {
+        let from = pos - self.hash_behind as usize;
+        self.carry.clear();
+        self.carry.extend_from_slice(&data[from..]);
+    }
 
-        while pos + self.block_size <= self.buffer.len() {
-            // Get the current block for verification
-            let current_block = &self.buffer[pos..pos + self.block_size];
+    /// Returns carried bytes that have not been scanned yet.
+    fn unscanned(&self) -> &[u8] {
+        let skip = (self.hash_behind as usize).min(self.carry.len());
+        &self.carry[skip..]
+    }
 
-            // Look up current hash in index with strong hash verification
-            let matched_offset = self.index.find_verified_match(current_hash, current_block);
+    /// Looks up a target block in the index with strong hash verification,
+    /// preferring the candidate that continues the previous COPY.
+    fn find_match(&mut self, weak_hash: u32, block: &[u8]) -> Option<u64> {
+        let near = self.writer.copy_end();
+        verify::find_match(
+            &self.index,
+            self.writer.verifier(),
+            weak_hash,
+            block,
+            self.preferred_range.as_ref(),
+            near,
+        )
+    }
 
-            if let Some(source_offset) = matched_offset {
-                // Found a verified match - flush pending INSERT data first
-                self.flush_insert_buffer();
+    /// Verifies every match, including fine index matches, against source
+    /// bytes read from `source`.
+    ///
+    /// Candidates whose bytes differ from the target block (or can't be
+    /// read) are skipped and counted in `rejected_matches()`. `None` turns
+    /// verification off. Not saved by `write_state()`.
+    pub fn set_source_access(&mut self, source: Option<Box<dyn SourceAccess>>) {
+        self.writer.set_source_access(source);
+    }
 
-                // Emit COPY instruction
-                self.emit_copy(source_offset, self.block_size as u32);
+    /// Refines INSERT data against a finalized fine-grained index, or
+    /// `None` to disable (see `InstructionWriter::set_fine_index()`).
+    pub fn set_fine_index(&mut self, index: Option<BlockIndex>) {
+        self.writer.set_fine_index(index);
+    }
 
-                // Skip past the matched block
-                pos += self.block_size;
+    /// Sets the pending INSERT size at which it is emitted
+    /// (see `InstructionWriter::set_insert_limit()`).
+    pub fn set_insert_limit(&mut self, bytes: usize) {
+        self.writer.set_insert_limit(bytes);
+    }
 
-                // Recalculate hash for new position
-                if pos + self.block_size <= self.buffer.len() {
-                    current_hash = hasher.hash_chunk(&self.buffer[pos..pos + self.block_size]);
-                }
-            } else {
-                // No match - add byte to INSERT buffer
-                self.insert_buffer.push(self.buffer[pos]);
-                pos += 1;
+    /// Returns the fine-grained index, if set.
+    pub fn fine_index(&self) -> Option<&BlockIndex> {
+        self.writer.fine_index()
+    }
 
-                // Roll hash forward - O(1) operation
-                if pos + self.block_size <= self.buffer.len() {
-                    let old_byte = self.buffer[pos - 1];
-                    let new_byte = self.buffer[pos + self.block_size - 1];
-                    current_hash = hasher.roll(old_byte, new_byte);
-                }
-            }
-        }
+    /// Checks `bytes` against the source at `offset` when verification is
+    /// enabled, for COPYs callers find without the index.
+    pub fn verify(&mut self, offset: u64, bytes: &[u8]) -> bool {
+        self.writer
+            .verifier()
+            .is_none_or(|verifier| verifier.matches(offset, bytes))
+    }
 
-        // Keep remaining bytes that couldn't form a complete block
-        self.buffer = self.buffer[pos..].to_vec();
+    /// Returns the number of candidates rejected by byte verification.
+    pub fn rejected_matches(&self) -> u64 {
+        self.writer.rejected_matches()
     }
 
-    /// Flushes pending INSERT data to output buffer.
-    fn flush_insert_buffer(&mut self) {
-        if !self.insert_buffer.is_empty() {
-            // Serialize INSERT: type(1) + length(4) + data
-            self.output_buffer.push(TYPE_INSERT);
-            self.output_buffer
-                .extend_from_slice(&(self.insert_buffer.len() as u32).to_le_bytes());
-            self.output_buffer.extend_from_slice(&self.insert_buffer);
+    /// Sets the source range preferred for matches, or `None` for no preference.
+    pub fn set_preferred_range(&mut self, range: Option<Range<u64>>) {
+        self.preferred_range = range;
+    }
 
-            self.insert_buffer.clear();
-        }
+    /// Takes target bytes that have not formed a complete block yet.
+    ///
+    /// Lets callers end a segment (e.g. a container member) so no block
+    /// straddles the boundary; the bytes are returned instead of being
+    /// carried over into the next chunk.
+    pub fn take_pending(&mut self) -> Vec<u8> {
+        let pending = self.unscanned().to_vec();
+        self.end_scan();
+        pending
     }
 
-    /// Emits a COPY instruction to output buffer.
-    fn emit_copy(&mut self, offset: u64, length: u32) {
-        // Serialize COPY: type(1) + offset(8) + length(4)
-        self.output_buffer.push(TYPE_COPY);
-        self.output_buffer.extend_from_slice(&offset.to_le_bytes());
-        self.output_buffer.extend_from_slice(&length.to_le_bytes());
+    /// Drops the carry so the next chunk starts a fresh window.
+    fn end_scan(&mut self) {
+        self.carry.clear();
+        self.hash_behind = false;
     }
 
-    /// Finalizes processing and flushes remaining data.
-    pub fn finalize(&mut self) {
+    /// Emits a COPY of a known source range, flushing pending INSERT data first.
+    ///
+    /// Must only be called at a segment boundary (no pending target bytes).
+    pub fn push_copy(
+        &mut self,
+        offset: u64,
+        length: u32,
+        out: &mut dyn PatchSink,
+    ) -> io::Result<()> {
+        debug_assert!(self.unscanned().is_empty());
+        self.end_scan();
+        self.writer.push_copy(offset, length, out)
+    }
+
+    /// Appends literal bytes to the pending INSERT.
+    ///
+    /// Must only be called at a segment boundary (no pending target bytes).
+    pub fn push_insert(&mut self, data: &[u8], out: &mut dyn PatchSink) -> io::Result<()> {
+        debug_assert!(self.unscanned().is_empty());
+        self.end_scan();
+        self.writer.push_insert(data, out)
+    }
+
+    /// Finalizes processing, writing the remaining instructions to `out`.
+    pub fn finalize(&mut self, out: &mut dyn PatchSink) -> io::Result<()> {
         // Any remaining bytes in buffer go to INSERT
-        self.insert_buffer.extend_from_slice(&self.buffer);
-        self.buffer.clear();
+        let pending = self.take_pending();
+        self.writer.push_insert(&pending, out)?;
 
-        // Flush final INSERT if any
-        self.flush_insert_buffer();
+        // Flush final instructions
+        self.writer.finish(out)
     }
 
-    /// Takes the output buffer, transferring ownership.
-    ///
-    /// Returns serialized patch instructions ready to write.
-    pub fn take_output(&mut self) -> Vec<u8> {
-        std::mem::take(&mut self.output_buffer)
+    /// Returns the source block index.
+    pub fn index(&self) -> &BlockIndex {
+        &self.index
     }
 
-    /// Returns the current output buffer size.
-    pub fn output_len(&self) -> usize {
-        self.output_buffer.len()
+    /// Returns the block size used for matching.
+    pub fn block_size(&self) -> usize {
+        self.block_size
     }
 
-    /// Checks if there's pending output to consume.
-    pub fn has_output(&self) -> bool {
-        !self.output_buffer.is_empty()
+    /// Writes the index and buffered data to suspended builder state.
+    pub fn write_state(&self, writer: &mut StateWriter) -> io::Result<()> {
+        self.index.write_state(writer)?;
+        // The hash is recomputed from the unscanned bytes on resume
+        writer.put_bytes(self.unscanned());
+        self.writer.write_state(writer)?;
+
+        match &self.preferred_range {
+            Some(range) => {
+                writer.put_bool(true);
+                writer.put_u64(range.start);
+                writer.put_u64(range.end);
+            }
+            None => writer.put_bool(false),
+        }
+        Ok(())
+    }
+
+    /// Reads a diff written by `write_state()`.
+    pub fn read_state(reader: &mut StateReader) -> io::Result<Self> {
+        let mut diff = Self::new(BlockIndex::read_state(reader)?);
+        diff.carry = reader.bytes()?;
+        diff.writer = InstructionWriter::read_state(reader)?;
+
+        if reader.bool()? {
+            diff.preferred_range = Some(reader.u64()?..reader.u64()?);
+        }
+
+        Ok(diff)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::format::patch_format::{TYPE_COPY, TYPE_INSERT};
 
     fn build_index(data: &[u8], block_size: usize) -> BlockIndex {
         let mut index = BlockIndex::with_block_size(block_size);
@@ -164,20 +328,34 @@ mod tests {
         let index = build_index(source, 4);
 
         let mut diff = StreamingDiff::new(index);
-        diff.process_target_chunk(target);
-        diff.finalize();
 
-        let output = diff.take_output();
+        let mut output = Vec::new();
+        diff.process_target_chunk(target, &mut output).unwrap();
+        diff.finalize(&mut output).unwrap();
 
-        // Should have 4 COPY instructions (13 bytes each)
-        // Each COPY: type(1) + offset(8) + length(4) = 13 bytes
-        assert_eq!(output.len(), 4 * 13);
-
-        // First byte of each instruction should be TYPE_COPY
+        // Contiguous blocks merge into one COPY instruction
+        // COPY: type(1) + offset(8) + length(4) = 13 bytes
+        assert_eq!(output.len(), 13);
         assert_eq!(output[0], 0x01);
-        assert_eq!(output[13], 0x01);
-        assert_eq!(output[26], 0x01);
-        assert_eq!(output[39], 0x01);
+        assert_eq!(u64::from_le_bytes(output[1..9].try_into().unwrap()), 0);
+        assert_eq!(u32::from_le_bytes(output[9..13].try_into().unwrap()), 16);
+    }
+
+    #[test]
+    fn test_prefers_contiguous_duplicate() {
+        // "aaaa" at 0 and 12; the copy at 12 continues the "bbbb" COPY
+        let source = b"aaaaxxxxbbbbaaaacccc";
+        let target = b"bbbbaaaacccc";
+        let index = build_index(source, 4);
+
+        let mut diff = StreamingDiff::new(index);
+
+        let mut output = Vec::new();
+        diff.process_target_chunk(target, &mut output).unwrap();
+        diff.finalize(&mut output).unwrap();
+        assert_eq!(output.len(), 13);
+        assert_eq!(u64::from_le_bytes(output[1..9].try_into().unwrap()), 8);
+        assert_eq!(u32::from_le_bytes(output[9..13].try_into().unwrap()), 12);
     }
 
     #[test]
@@ -187,10 +365,10 @@ mod tests {
         let index = build_index(source, 4);
 
         let mut diff = StreamingDiff::new(index);
-        diff.process_target_chunk(target);
-        diff.finalize();
 
-        let output = diff.take_output();
+        let mut output = Vec::new();
+        diff.process_target_chunk(target, &mut output).unwrap();
+        diff.finalize(&mut output).unwrap();
 
         // Should have 1 INSERT instruction
         // INSERT: type(1) + length(4) + data(4) = 9 bytes
@@ -212,10 +390,10 @@ mod tests {
         let index = build_index(source, 4);
 
         let mut diff = StreamingDiff::new(index);
-        diff.process_target_chunk(target);
-        diff.finalize();
 
-        let output = diff.take_output();
+        let mut output = Vec::new();
+        diff.process_target_chunk(target, &mut output).unwrap();
+        diff.finalize(&mut output).unwrap();
 
         // Should have: INSERT(xxxx) + COPY(bbbb) + INSERT(yyyy) + COPY(cccc)
         // INSERT: 1 + 4 + 4 = 9 bytes
@@ -224,6 +402,130 @@ mod tests {
         assert_eq!(output.len(), 44);
     }
 
+    /// Source reader that corrupts one block, standing in for a block whose
+    /// hashes collide with different bytes.
+    struct CorruptBlock {
+        data: Vec<u8>,
+        corrupt: u64,
+    }
+
+    impl SourceAccess for CorruptBlock {
+        fn len(&self) -> u64 {
+            self.data.len() as u64
+        }
+
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            self.data.as_slice().read_at(offset, buf)?;
+            if offset == self.corrupt {
+                buf[0] ^= 1;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_source_verification_rejects_mismatch() {
+        let source = b"aaaabbbbaaaacccc";
+        let target = b"aaaacccc";
+        let run = |corrupt: u64| {
+            let mut diff = StreamingDiff::new(build_index(source, 4));
+            let mut output = Vec::new();
+            let reader = CorruptBlock {
+                data: source.to_vec(),
+                corrupt,
+            };
+            diff.set_source_access(Some(Box::new(reader)));
+            diff.process_target_chunk(target, &mut output).unwrap();
+            diff.finalize(&mut output).unwrap();
+            (output, diff.rejected_matches())
+        };
+
+        // First "aaaa" candidate rejected: the duplicate at 8 is used and
+        // "cccc" at 12 extends its COPY
+        let (output, rejected) = run(0);
+        assert_eq!(rejected, 1);
+        assert_eq!(output.len(), 13);
+        assert_eq!(u64::from_le_bytes(output[1..9].try_into().unwrap()), 8);
+        assert_eq!(u32::from_le_bytes(output[9..13].try_into().unwrap()), 8);
+
+        // Only "cccc" candidate rejected: its bytes become an INSERT
+        let (output, rejected) = run(12);
+        assert_eq!(rejected, 1);
+        assert_eq!(u64::from_le_bytes(output[1..9].try_into().unwrap()), 0);
+        assert_eq!(output[13], TYPE_INSERT);
+        assert_eq!(&output[18..], b"cccc");
+    }
+
+    #[test]
+    fn test_source_verification_keeps_output() {
+        let source: Vec<u8> = (0..20_000u32).map(|i| (i * 13 % 251) as u8).collect();
+        let mut target = source[5000..].to_vec();
+        target.extend_from_slice(&source[..7000]);
+
+        let run = |verify: bool| {
+            let mut diff = StreamingDiff::new(build_index(&source, 256));
+            let mut output = Vec::new();
+            if verify {
+                diff.set_source_access(Some(Box::new(source.clone())));
+            }
+            for chunk in target.chunks(1000) {
+                diff.process_target_chunk(chunk, &mut output).unwrap();
+            }
+            diff.finalize(&mut output).unwrap();
+            assert_eq!(diff.rejected_matches(), 0);
+            output
+        };
+
+        assert_eq!(run(true), run(false));
+    }
+
+    #[test]
+    fn test_segment_helpers() {
+        let source = b"aaaabbbb";
+        let index = build_index(source, 4);
+
+        let mut diff = StreamingDiff::new(index);
+
+        let mut output = Vec::new();
+        diff.process_target_chunk(b"bbbbxy", &mut output).unwrap();
+        assert_eq!(diff.take_pending(), b"xy");
+
+        diff.push_insert(b"zz", &mut output).unwrap();
+        diff.push_copy(0, 4, &mut output).unwrap();
+        diff.finalize(&mut output).unwrap();
+
+        // COPY(bbbb) + INSERT(zz) + COPY(aaaa)
+        assert_eq!(output.len(), 13 + 7 + 13);
+        assert_eq!(output[13], TYPE_INSERT);
+        assert_eq!(&output[18..20], b"zz");
+        assert_eq!(output[20], TYPE_COPY);
+    }
+
+    #[test]
+    fn test_chunking_does_not_change_output() {
+        let source: Vec<u8> = (0..5000u32).map(|i| (i * i % 251) as u8).collect();
+        let mut target = b"new".to_vec();
+        target.extend_from_slice(&source[100..2100]);
+        target.extend_from_slice(&[7; 150]);
+        target.extend_from_slice(&source[3001..4900]);
+
+        let run = |chunk_size: usize| {
+            let mut diff = StreamingDiff::new(build_index(&source, 64));
+            let mut output = Vec::new();
+            for chunk in target.chunks(chunk_size) {
+                diff.process_target_chunk(chunk, &mut output).unwrap();
+                assert!(diff.carry.len() <= 64);
+            }
+            diff.finalize(&mut output).unwrap();
+            output
+        };
+
+        let expected = run(target.len());
+        for chunk_size in [1, 2, 63, 64, 65, 127, 1000] {
+            assert_eq!(run(chunk_size), expected, "chunk size {}", chunk_size);
+        }
+    }
+
     #[test]
     fn test_incremental_output() {
         let source = b"aaaabbbbccccdddd";
@@ -231,21 +533,21 @@ mod tests {
 
         let mut diff = StreamingDiff::new(index);
 
+        let mut output = Vec::new();
+
         // Process in small chunks
-        diff.process_target_chunk(b"aaaa");
-        let out1 = diff.output_len();
+        diff.process_target_chunk(b"aaaa", &mut output).unwrap();
+        let out1 = output.len();
 
-        diff.process_target_chunk(b"bbbb");
-        let out2 = diff.output_len();
+        diff.process_target_chunk(b"bbbb", &mut output).unwrap();
+        let out2 = output.len();
 
         // Output should grow as we process
         assert!(out2 >= out1);
 
-        diff.process_target_chunk(b"cccc");
-        diff.process_target_chunk(b"dddd");
-        diff.finalize();
-
-        let output = diff.take_output();
-        assert_eq!(output.len(), 4 * 13); // 4 COPY instructions
+        diff.process_target_chunk(b"cccc", &mut output).unwrap();
+        diff.process_target_chunk(b"dddd", &mut output).unwrap();
+        diff.finalize(&mut output).unwrap();
+        assert_eq!(output.len(), 13); // One merged COPY instruction
     }
 }
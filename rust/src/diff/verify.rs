@@ -0,0 +1,79 @@
+//! Byte verification of matches against the source.
+//!
+//! Matches are normally accepted on weak hash plus 64-bit strong hash. A
+//! [`SourceVerifier`] reads each candidate's source bytes back and compares
+//! them with the target block, so a hash collision falls through to the
+//! next candidate (or an INSERT) instead of corrupting the target. Every
+//! diff engine and the fine-grained INSERT refinement look up matches
+//! through it when verification is enabled.
+
+use super::block_index::BlockIndex;
+use crate::apply::patch_applier::SourceAccess;
+use std::ops::Range;
+
+/// Source reader that checks candidate blocks byte for byte.
+pub struct SourceVerifier {
+    /// Source reader.
+    source: Box<dyn SourceAccess>,
+    /// Scratch buffer for source bytes.
+    block: Vec<u8>,
+    /// Candidates rejected so far.
+    rejected: u64,
+}
+
+impl SourceVerifier {
+    /// Creates a verifier reading from `source`.
+    pub fn new(source: Box<dyn SourceAccess>) -> Self {
+        Self {
+            source,
+            block: Vec::new(),
+            rejected: 0,
+        }
+    }
+
+    /// Checks whether the source bytes at `offset` equal `target_block`.
+    ///
+    /// Unreadable ranges count as mismatches.
+    pub fn matches(&mut self, offset: u64, target_block: &[u8]) -> bool {
+        self.block.resize(target_block.len(), 0);
+        let same =
+            self.source.read_at(offset, &mut self.block).is_ok() && self.block[..] == *target_block;
+        self.rejected += u64::from(!same);
+        same
+    }
+
+    /// Finds the match `BlockIndex::find_best_match()` would pick, falling
+    /// back to the next candidate whose bytes match.
+    pub fn find_match(
+        &mut self,
+        index: &BlockIndex,
+        weak_hash: u32,
+        target_block: &[u8],
+        preferred: Option<&Range<u64>>,
+        near: Option<u64>,
+    ) -> Option<u64> {
+        index.find_checked_match(weak_hash, target_block, preferred, near, |offset| {
+            self.matches(offset, target_block)
+        })
+    }
+
+    /// Returns the number of candidates rejected so far.
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+/// Looks up a match, verified when a verifier is given.
+pub fn find_match(
+    index: &BlockIndex,
+    verifier: Option<&mut SourceVerifier>,
+    weak_hash: u32,
+    target_block: &[u8],
+    preferred: Option<&Range<u64>>,
+    near: Option<u64>,
+) -> Option<u64> {
+    match verifier {
+        Some(verifier) => verifier.find_match(index, weak_hash, target_block, preferred, near),
+        None => index.find_best_match(weak_hash, target_block, preferred, near),
+    }
+}
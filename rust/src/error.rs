@@ -0,0 +1,258 @@
+//! Crate-wide error type.
+//!
+//! Every failure a caller can act on is a [`PatchlyError`] variant with
+//! structured fields and a stable [`code()`](PatchlyError::code) string.
+//! In wasm exports the error becomes a JS `Error` whose `code` property
+//! holds that string, so JS can branch on it without matching messages.
+//!
+//! Traits built on `std::io` (`SourceAccess`, `PatchSink`, disk indexes)
+//! keep returning `io::Error`. A `PatchlyError` passed through them is
+//! wrapped inside the `io::Error` and unwrapped again by the `From`
+//! conversion, so no detail is lost on the way.
+
+use std::fmt;
+use std::io;
+use wasm_bindgen::JsValue;
+
+/// Result type used throughout the crate.
+pub type Result<T> = std::result::Result<T, PatchlyError>;
+
+/// Errors produced while building, parsing or applying patches.
+#[derive(Debug)]
+pub enum PatchlyError {
+    /// Patch data does not start with the `PTCH` magic bytes.
+    BadMagic,
+    /// Patch format version this build can't read.
+    UnsupportedVersion { version: u8 },
+    /// Header feature flags this build doesn't understand.
+    UnsupportedFlags { flags: u32 },
+    /// Fewer header bytes than the header needs.
+    TruncatedHeader { len: usize, required: usize },
+    /// Header fields or extensions are malformed.
+    InvalidHeader(String),
+    /// Instruction with an unknown type byte.
+    ///
+    /// `offset` counts instruction bytes from the end of the header.
+    UnknownInstruction { offset: u64, kind: u8 },
+    /// Patch ends inside an instruction.
+    ///
+    /// `offset` is the number of instruction bytes consumed when the patch
+    /// ended, counted from the end of the header.
+    TruncatedInstruction { offset: u64 },
+    /// COPY reads past the end of the source.
+    CopyOutOfRange {
+        offset: u64,
+        length: u64,
+        source_size: u64,
+    },
+    /// Source size differs from the one recorded in the header.
+    SourceSizeMismatch { expected: u64, actual: u64 },
+    /// Source hash differs from the one recorded in the header.
+    SourceHashMismatch { expected: u64, actual: u64 },
+    /// Target size differs from the one recorded in the patch.
+    TargetSizeMismatch { expected: u64, actual: u64 },
+    /// Rebuilt target hash differs from the one in the patch trailer.
+    TargetHashMismatch { expected: u64, actual: u64 },
+    /// Patch announces a trailer but doesn't end with one, or has one
+    /// without announcing it.
+    MissingTrailer,
+    /// Written target doesn't match the checkpoint it resumes from.
+    CheckpointMismatch(String),
+    /// Saved builder state is malformed.
+    InvalidState(String),
+    /// Other malformed input (deflate recipes, checkpoints, ...).
+    InvalidData(String),
+    /// API called out of order or with an unsupported configuration.
+    Misuse(&'static str),
+    /// Error from an underlying reader or writer.
+    Io(io::Error),
+}
+
+impl PatchlyError {
+    /// Returns the stable error code exposed to JS as `error.code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PatchlyError::BadMagic => "BAD_MAGIC",
+            PatchlyError::UnsupportedVersion { .. } => "UNSUPPORTED_VERSION",
+            PatchlyError::UnsupportedFlags { .. } => "UNSUPPORTED_FLAGS",
+            PatchlyError::TruncatedHeader { .. } => "TRUNCATED_HEADER",
+            PatchlyError::InvalidHeader(_) => "INVALID_HEADER",
+            PatchlyError::UnknownInstruction { .. } => "UNKNOWN_INSTRUCTION",
+            PatchlyError::TruncatedInstruction { .. } => "TRUNCATED_INSTRUCTION",
+            PatchlyError::CopyOutOfRange { .. } => "COPY_OUT_OF_RANGE",
+            PatchlyError::SourceSizeMismatch { .. } => "SOURCE_SIZE_MISMATCH",
+            PatchlyError::SourceHashMismatch { .. } => "SOURCE_HASH_MISMATCH",
+            PatchlyError::TargetSizeMismatch { .. } => "TARGET_SIZE_MISMATCH",
+            PatchlyError::TargetHashMismatch { .. } => "TARGET_HASH_MISMATCH",
+            PatchlyError::MissingTrailer => "MISSING_TRAILER",
+            PatchlyError::CheckpointMismatch(_) => "CHECKPOINT_MISMATCH",
+            PatchlyError::InvalidState(_) => "INVALID_STATE",
+            PatchlyError::InvalidData(_) => "INVALID_DATA",
+            PatchlyError::Misuse(_) => "MISUSE",
+            PatchlyError::Io(_) => "IO",
+        }
+    }
+
+    /// Returns the `io::ErrorKind` used when wrapped in an `io::Error`.
+    fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            PatchlyError::TruncatedHeader { .. } | PatchlyError::TruncatedInstruction { .. } => {
+                io::ErrorKind::UnexpectedEof
+            }
+            PatchlyError::Misuse(_) => io::ErrorKind::InvalidInput,
+            PatchlyError::Io(err) => err.kind(),
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl fmt::Display for PatchlyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchlyError::BadMagic => write!(f, "Invalid patch file: bad magic bytes"),
+            PatchlyError::UnsupportedVersion { version } => {
+                write!(f, "Unsupported patch version: {}", version)
+            }
+            PatchlyError::UnsupportedFlags { flags } => {
+                write!(f, "Unsupported patch feature flags: {:#x}", flags)
+            }
+            PatchlyError::TruncatedHeader { len, required } => {
+                write!(f, "Header too small: {} bytes (need {})", len, required)
+            }
+            PatchlyError::InvalidHeader(message) => write!(f, "Invalid header: {}", message),
+            PatchlyError::UnknownInstruction { offset, kind } => {
+                write!(f, "Unknown instruction type {} at offset {}", kind, offset)
+            }
+            PatchlyError::TruncatedInstruction { offset } => {
+                write!(f, "Patch ends inside an instruction at offset {}", offset)
+            }
+            PatchlyError::CopyOutOfRange {
+                offset,
+                length,
+                source_size,
+            } => write!(
+                f,
+                "COPY out of range: offset {} length {} (source is {} bytes)",
+                offset, length, source_size
+            ),
+            PatchlyError::SourceSizeMismatch { expected, actual } => write!(
+                f,
+                "Source size mismatch: expected {} bytes, got {} bytes",
+                expected, actual
+            ),
+            PatchlyError::SourceHashMismatch { expected, actual } => write!(
+                f,
+                "Source hash mismatch: expected {:016x}, got {:016x}",
+                expected, actual
+            ),
+            PatchlyError::TargetSizeMismatch { expected, actual } => write!(
+                f,
+                "Target size mismatch: expected {} bytes, got {} bytes",
+                expected, actual
+            ),
+            PatchlyError::TargetHashMismatch { expected, actual } => write!(
+                f,
+                "Target hash mismatch: expected {:016x}, got {:016x}",
+                expected, actual
+            ),
+            PatchlyError::MissingTrailer => write!(f, "Patch trailer missing or unexpected"),
+            PatchlyError::CheckpointMismatch(message)
+            | PatchlyError::InvalidState(message)
+            | PatchlyError::InvalidData(message) => f.write_str(message),
+            PatchlyError::Misuse(message) => f.write_str(message),
+            PatchlyError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PatchlyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PatchlyError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PatchlyError {
+    fn from(err: io::Error) -> Self {
+        if err
+            .get_ref()
+            .is_some_and(|inner| inner.is::<PatchlyError>())
+        {
+            let inner = err.into_inner().expect("checked above");
+            return *inner.downcast::<PatchlyError>().expect("checked above");
+        }
+        PatchlyError::Io(err)
+    }
+}
+
+impl From<PatchlyError> for io::Error {
+    fn from(err: PatchlyError) -> Self {
+        match err {
+            PatchlyError::Io(err) => err,
+            err => io::Error::new(err.io_kind(), err),
+        }
+    }
+}
+
+impl From<PatchlyError> for JsValue {
+    fn from(err: PatchlyError) -> Self {
+        let error = js_sys::Error::new(&err.to_string());
+        error.set_name("PatchlyError");
+        js_sys::Reflect::set(&error, &"code".into(), &err.code().into())
+            .expect("a fresh Error object accepts new properties");
+        error.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_roundtrip_keeps_variant() {
+        let err = PatchlyError::CopyOutOfRange {
+            offset: 10,
+            length: 20,
+            source_size: 15,
+        };
+        let io_err = io::Error::from(err);
+        assert_eq!(io_err.kind(), io::ErrorKind::InvalidData);
+
+        match PatchlyError::from(io_err) {
+            PatchlyError::CopyOutOfRange {
+                offset,
+                length,
+                source_size,
+            } => assert_eq!((offset, length, source_size), (10, 20, 15)),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_plain_io_errors_stay_io() {
+        let err = PatchlyError::from(io::Error::new(io::ErrorKind::StorageFull, "disk full"));
+        assert_eq!(err.code(), "IO");
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::StorageFull);
+    }
+
+    #[test]
+    fn test_display_and_codes() {
+        let err = PatchlyError::UnsupportedVersion { version: 9 };
+        assert_eq!(err.code(), "UNSUPPORTED_VERSION");
+        assert!(err.to_string().contains('9'));
+
+        let err = PatchlyError::SourceHashMismatch {
+            expected: 0xABCD,
+            actual: 0x1234,
+        };
+        assert_eq!(err.code(), "SOURCE_HASH_MISMATCH");
+        assert!(err.to_string().contains("abcd"));
+        assert!(err.to_string().contains("1234"));
+
+        let err = PatchlyError::Misuse("finalize_source() was not called");
+        assert_eq!(err.code(), "MISUSE");
+        assert_eq!(err.to_string(), "finalize_source() was not called");
+    }
+}
@@ -0,0 +1,385 @@
+//! BCJ-style branch address normalization for executables.
+//!
+//! Relative call/branch displacements change whenever code moves, so a
+//! recompiled binary differs from its predecessor in thousands of scattered
+//! 4-byte fields. These filters rewrite the displacements as absolute
+//! targets, which stay the same when only unrelated code moves, restoring
+//! long runs of identical bytes for block matching.
+//!
+//! - x86/x86-64: `E8`/`E9` (CALL/JMP rel32) with a displacement that fits
+//!   in 25 signed bits
+//! - AArch64: `BL imm26` on 4-byte aligned words
+//!
+//! Both filters are exact bijections: decoding an encoded stream yields the
+//! original bytes for any input, not just valid machine code.
+
+use crate::format::state::{invalid, StateReader, StateWriter};
+use std::io;
+
+/// x86 CALL rel32 opcode.
+const X86_CALL: u8 = 0xE8;
+
+/// x86 JMP rel32 opcode.
+const X86_JMP: u8 = 0xE9;
+
+/// ELF `e_machine` value for x86.
+const EM_386: u16 = 3;
+
+/// ELF `e_machine` value for x86-64.
+const EM_X86_64: u16 = 0x3E;
+
+/// ELF `e_machine` value for AArch64.
+const EM_AARCH64: u16 = 0xB7;
+
+/// Executable filter applied before indexing and inverted on apply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterKind {
+    /// No filtering.
+    #[default]
+    None,
+    /// x86/x86-64 CALL/JMP displacement conversion.
+    X86,
+    /// AArch64 BL displacement conversion.
+    Arm64,
+}
+
+impl FilterKind {
+    /// Returns the filter ID stored in the patch header.
+    pub fn id(self) -> u8 {
+        match self {
+            FilterKind::None => 0,
+            FilterKind::X86 => 1,
+            FilterKind::Arm64 => 2,
+        }
+    }
+
+    /// Looks up a filter by its header ID.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(FilterKind::None),
+            1 => Some(FilterKind::X86),
+            2 => Some(FilterKind::Arm64),
+            _ => None,
+        }
+    }
+
+    /// Picks a filter from the start of an ELF file.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - At least the first 20 bytes of the file.
+    ///
+    /// # Returns
+    ///
+    /// The filter for the ELF machine type, or `None` for other files.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.len() < 20 || &data[0..4] != b"\x7fELF" {
+            return FilterKind::None;
+        }
+
+        // EI_DATA: 1 = little-endian, 2 = big-endian
+        let machine = match data[5] {
+            1 => u16::from_le_bytes([data[18], data[19]]),
+            2 => u16::from_be_bytes([data[18], data[19]]),
+            _ => return FilterKind::None,
+        };
+
+        match machine {
+            EM_386 | EM_X86_64 => FilterKind::X86,
+            EM_AARCH64 => FilterKind::Arm64,
+            _ => FilterKind::None,
+        }
+    }
+}
+
+/// Streaming BCJ encoder or decoder.
+///
+/// Carries at most 4 bytes between chunks, so output lags input slightly
+/// until `finish()` is called.
+#[derive(Debug, Clone)]
+pub struct BcjFilter {
+    /// Filter algorithm.
+    kind: FilterKind,
+    /// Whether this instance encodes (true) or decodes (false).
+    encode: bool,
+    /// Absolute stream position of the first carried byte.
+    pos: u64,
+    /// Bytes that can't be decided until more input arrives.
+    carry: Vec<u8>,
+}
+
+impl BcjFilter {
+    /// Creates a filter that normalizes addresses (diff side).
+    pub fn encoder(kind: FilterKind) -> Self {
+        Self::new(kind, true)
+    }
+
+    /// Creates a filter that restores original bytes (apply side).
+    pub fn decoder(kind: FilterKind) -> Self {
+        Self::new(kind, false)
+    }
+
+    /// Creates a filter for the given direction.
+    fn new(kind: FilterKind, encode: bool) -> Self {
+        Self {
+            kind,
+            encode,
+            pos: 0,
+            carry: Vec::with_capacity(8),
+        }
+    }
+
+    /// Returns the filter algorithm.
+    pub fn kind(&self) -> FilterKind {
+        self.kind
+    }
+
+    /// Filters a chunk of data.
+    ///
+    /// # Returns
+    ///
+    /// Filtered bytes whose transformation is final.
+    pub fn process(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let mut data = std::mem::take(&mut self.carry);
+        data.extend_from_slice(chunk);
+
+        let done = match self.kind {
+            FilterKind::None => data.len(),
+            FilterKind::X86 => x86_convert(&mut data, self.pos, self.encode),
+            FilterKind::Arm64 => arm64_convert(&mut data, self.pos, self.encode),
+        };
+
+        self.carry = data.split_off(done);
+        self.pos += done as u64;
+        data
+    }
+
+    /// Flushes carried bytes at end of stream.
+    ///
+    /// Trailing bytes too short to hold an instruction are never converted.
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.pos += self.carry.len() as u64;
+        std::mem::take(&mut self.carry)
+    }
+
+    /// Writes the filter position and carried bytes to builder state.
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        writer.put_u8(self.kind.id());
+        writer.put_bool(self.encode);
+        writer.put_u64(self.pos);
+        writer.put_bytes(&self.carry);
+    }
+
+    /// Reads a filter written by `write_state()`.
+    pub fn read_state(reader: &mut StateReader) -> io::Result<Self> {
+        let kind = FilterKind::from_id(reader.u8()?)
+            .ok_or_else(|| invalid("Invalid filter ID in builder state"))?;
+        let mut filter = Self::new(kind, reader.bool()?);
+        filter.pos = reader.u64()?;
+        filter.carry = reader.bytes()?;
+        Ok(filter)
+    }
+}
+
+/// Converts x86 CALL/JMP displacements in place.
+///
+/// Every `E8`/`E9` byte claims the following 4 bytes whether or not they
+/// are converted, so encoder and decoder visit the same positions. A
+/// displacement is converted only if its top byte is `00` or `FF`; the
+/// result is sign-extended from 25 bits, which keeps that property and
+/// makes the transform invertible.
+///
+/// # Returns
+///
+/// Number of leading bytes that are final.
+fn x86_convert(data: &mut [u8], base: u64, encode: bool) -> usize {
+    let mut i = 0;
+
+    while i + 5 <= data.len() {
+        if data[i] != X86_CALL && data[i] != X86_JMP {
+            i += 1;
+            continue;
+        }
+
+        if data[i + 4] == 0x00 || data[i + 4] == 0xFF {
+            let src = u32::from_le_bytes([data[i + 1], data[i + 2], data[i + 3], data[i + 4]]);
+            let next = (base + i as u64 + 5) as u32;
+            let dest = if encode {
+                src.wrapping_add(next)
+            } else {
+                src.wrapping_sub(next)
+            };
+
+            // Sign-extend from bit 24
+            let dest = (dest & 0x01FF_FFFF) | (0u32.wrapping_sub((dest >> 24) & 1) << 25);
+            data[i + 1..i + 5].copy_from_slice(&dest.to_le_bytes());
+        }
+
+        i += 5;
+    }
+
+    // Fewer than 5 bytes remain: they wait for more input
+    i
+}
+
+/// Converts AArch64 BL displacements in place.
+///
+/// # Returns
+///
+/// Number of leading bytes that are final (a multiple of 4).
+fn arm64_convert(data: &mut [u8], base: u64, encode: bool) -> usize {
+    let done = data.len() & !3;
+
+    for (n, word) in data[..done].chunks_exact_mut(4).enumerate() {
+        let insn = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        if insn >> 26 != 0x25 {
+            continue;
+        }
+
+        let pc = ((base + n as u64 * 4) >> 2) as u32;
+        let imm = if encode {
+            (insn & 0x03FF_FFFF).wrapping_add(pc)
+        } else {
+            (insn & 0x03FF_FFFF).wrapping_sub(pc)
+        };
+        let converted = (insn & 0xFC00_0000) | (imm & 0x03FF_FFFF);
+        word.copy_from_slice(&converted.to_le_bytes());
+    }
+
+    done
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::noise;
+
+    /// Runs a filter over `data` in chunks of `chunk` bytes.
+    fn run(mut filter: BcjFilter, data: &[u8], chunk: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for piece in data.chunks(chunk) {
+            out.extend(filter.process(piece));
+        }
+        out.extend(filter.finish());
+        out
+    }
+
+    /// Code-like bytes: many calls with small displacements.
+    fn code_like(len: usize, seed: u32) -> Vec<u8> {
+        let mut data = noise(len, seed);
+        for (n, i) in (0..len.saturating_sub(5)).step_by(11).enumerate() {
+            data[i] = if n % 3 == 0 { X86_JMP } else { X86_CALL };
+            let disp = (n as i32 * 37 - 500) as u32;
+            data[i + 1..i + 5].copy_from_slice(&disp.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_x86_roundtrip_any_input() {
+        for seed in 1..20 {
+            let mut data = noise(3000, seed);
+            // Dense opcode bytes exercise overlapping candidates
+            for b in data.iter_mut().step_by(3) {
+                *b = if *b & 1 == 0 { X86_CALL } else { 0xFF };
+            }
+
+            for chunk in [1, 3, 7, 64, 3000] {
+                let encoded = run(BcjFilter::encoder(FilterKind::X86), &data, chunk);
+                assert_eq!(encoded.len(), data.len());
+                let decoded = run(BcjFilter::decoder(FilterKind::X86), &encoded, chunk);
+                assert_eq!(decoded, data, "seed {} chunk {}", seed, chunk);
+            }
+        }
+    }
+
+    #[test]
+    fn test_x86_chunking_is_transparent() {
+        let data = code_like(5000, 3);
+        let whole = run(BcjFilter::encoder(FilterKind::X86), &data, data.len());
+
+        for chunk in [1, 2, 5, 13, 100] {
+            assert_eq!(
+                run(BcjFilter::encoder(FilterKind::X86), &data, chunk),
+                whole
+            );
+        }
+    }
+
+    #[test]
+    fn test_x86_moved_calls_become_identical() {
+        // Same call target from two places: relative displacements differ
+        let mut a = vec![0x90u8; 64];
+        a[10] = X86_CALL;
+        a[11..15].copy_from_slice(&(100i32 - 15).to_le_bytes());
+        let mut b = vec![0x90u8; 64];
+        b[20] = X86_CALL;
+        b[21..25].copy_from_slice(&(100i32 - 25).to_le_bytes());
+
+        let ea = run(BcjFilter::encoder(FilterKind::X86), &a, 64);
+        let eb = run(BcjFilter::encoder(FilterKind::X86), &b, 64);
+
+        assert_eq!(ea[11..15], eb[21..25]);
+        assert_eq!(ea[11..15], 100u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_filter_state_roundtrip() {
+        let data = code_like(4000, 9);
+        let whole = run(BcjFilter::encoder(FilterKind::X86), &data, data.len());
+
+        let mut filter = BcjFilter::encoder(FilterKind::X86);
+        let mut out = filter.process(&data[..1234]);
+
+        let mut writer = StateWriter::new();
+        filter.write_state(&mut writer);
+        let bytes = writer.into_bytes();
+        let restored = BcjFilter::read_state(&mut StateReader::new(&bytes)).unwrap();
+
+        out.extend(run(restored, &data[1234..], 100));
+        assert_eq!(out, whole);
+    }
+
+    #[test]
+    fn test_arm64_roundtrip() {
+        let mut data = noise(4096, 5);
+        for word in data.chunks_exact_mut(4).step_by(2) {
+            word[3] = 0x94 | (word[3] & 0x03); // BL opcode bits
+        }
+
+        for chunk in [1, 3, 4, 17, 4096] {
+            let encoded = run(BcjFilter::encoder(FilterKind::Arm64), &data, chunk);
+            assert_ne!(encoded, data);
+            let decoded = run(BcjFilter::decoder(FilterKind::Arm64), &encoded, chunk);
+            assert_eq!(decoded, data);
+        }
+    }
+
+    #[test]
+    fn test_detect_elf_machine() {
+        let mut elf = vec![0u8; 64];
+        elf[0..4].copy_from_slice(b"\x7fELF");
+        elf[5] = 1;
+
+        elf[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        assert_eq!(FilterKind::detect(&elf), FilterKind::X86);
+
+        elf[18..20].copy_from_slice(&EM_AARCH64.to_le_bytes());
+        assert_eq!(FilterKind::detect(&elf), FilterKind::Arm64);
+
+        elf[18..20].copy_from_slice(&0x28u16.to_le_bytes());
+        assert_eq!(FilterKind::detect(&elf), FilterKind::None);
+        assert_eq!(
+            FilterKind::detect(b"MZ not an elf file here"),
+            FilterKind::None
+        );
+    }
+
+    #[test]
+    fn test_filter_ids() {
+        for kind in [FilterKind::None, FilterKind::X86, FilterKind::Arm64] {
+            assert_eq!(FilterKind::from_id(kind.id()), Some(kind));
+        }
+        assert_eq!(FilterKind::from_id(9), None);
+    }
+}
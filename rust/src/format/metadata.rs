@@ -0,0 +1,247 @@
+//! Descriptive patch metadata.
+//!
+//! Metadata travels in version 2 header extensions, one TLV record per
+//! field. Applying never needs it, so readers skip tags they don't know;
+//! anything an applier must understand is a header flag instead.
+//!
+//! Record payloads:
+//!   - `EXT_SOURCE_NAME`, `EXT_TARGET_NAME`, `EXT_GENERATOR`: UTF-8 string
+//!   - `EXT_CREATED_AT`: u64 LE milliseconds since the Unix epoch
+//!   - `EXT_USER_KEY` (repeatable): key length u16 LE + UTF-8 key +
+//!     UTF-8 value
+
+use super::patch_format::{
+    HeaderExtension, EXT_CREATED_AT, EXT_GENERATOR, EXT_SOURCE_NAME, EXT_TARGET_NAME, EXT_USER_KEY,
+};
+use super::state::{StateReader, StateWriter};
+use crate::error::{PatchlyError, Result};
+use std::io;
+
+/// Generator string recorded in patches that carry metadata.
+pub const GENERATOR: &str = concat!("patchly ", env!("CARGO_PKG_VERSION"));
+
+/// Optional descriptive fields of a patch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchMetadata {
+    /// Original source file name.
+    pub source_name: Option<String>,
+    /// Original target file name.
+    pub target_name: Option<String>,
+    /// Creation time in milliseconds since the Unix epoch.
+    pub created_at: Option<u64>,
+    /// Name and version of the program that built the patch.
+    pub generator: Option<String>,
+    /// Custom key/value pairs, in insertion order with unique keys.
+    pub user: Vec<(String, String)>,
+}
+
+impl PatchMetadata {
+    /// Returns whether no field is set.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns the value of a custom key.
+    pub fn user_value(&self, key: &str) -> Option<&str> {
+        self.user
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Sets a custom key, replacing an earlier value.
+    pub fn set_user_value(&mut self, key: &str, value: &str) {
+        match self.user.iter_mut().find(|(k, _)| k == key) {
+            Some((_, old)) => *old = value.to_string(),
+            None => self.user.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Encodes the set fields as header extension records.
+    pub fn to_extensions(&self) -> Vec<HeaderExtension> {
+        let mut out = Vec::new();
+        let mut push = |tag, data| out.push(HeaderExtension { tag, data });
+
+        if let Some(name) = &self.source_name {
+            push(EXT_SOURCE_NAME, name.as_bytes().to_vec());
+        }
+        if let Some(name) = &self.target_name {
+            push(EXT_TARGET_NAME, name.as_bytes().to_vec());
+        }
+        if let Some(time) = self.created_at {
+            push(EXT_CREATED_AT, time.to_le_bytes().to_vec());
+        }
+        if let Some(generator) = &self.generator {
+            push(EXT_GENERATOR, generator.as_bytes().to_vec());
+        }
+        for (key, value) in &self.user {
+            let mut data = Vec::with_capacity(2 + key.len() + value.len());
+            data.extend_from_slice(&(key.len() as u16).to_le_bytes());
+            data.extend_from_slice(key.as_bytes());
+            data.extend_from_slice(value.as_bytes());
+            push(EXT_USER_KEY, data);
+        }
+
+        out
+    }
+
+    /// Decodes metadata records, ignoring other extensions.
+    pub fn from_extensions(extensions: &[HeaderExtension]) -> Result<Self> {
+        let mut metadata = Self::default();
+
+        for ext in extensions {
+            match ext.tag {
+                EXT_SOURCE_NAME => metadata.source_name = Some(utf8(&ext.data)?),
+                EXT_TARGET_NAME => metadata.target_name = Some(utf8(&ext.data)?),
+                EXT_GENERATOR => metadata.generator = Some(utf8(&ext.data)?),
+                EXT_CREATED_AT => {
+                    let bytes = ext.data.as_slice().try_into().map_err(|_| {
+                        PatchlyError::InvalidHeader("Invalid creation time".to_string())
+                    })?;
+                    metadata.created_at = Some(u64::from_le_bytes(bytes));
+                }
+                EXT_USER_KEY => {
+                    let (key, value) = split_user_record(&ext.data)?;
+                    metadata.set_user_value(&key, &value);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    /// Writes the metadata to suspended builder state.
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        let put_opt_str = |writer: &mut StateWriter, value: &Option<String>| {
+            writer.put_bool(value.is_some());
+            writer.put_str(value.as_deref().unwrap_or_default());
+        };
+
+        put_opt_str(writer, &self.source_name);
+        put_opt_str(writer, &self.target_name);
+        writer.put_bool(self.created_at.is_some());
+        writer.put_u64(self.created_at.unwrap_or(0));
+        put_opt_str(writer, &self.generator);
+        writer.put_u64(self.user.len() as u64);
+        for (key, value) in &self.user {
+            writer.put_str(key);
+            writer.put_str(value);
+        }
+    }
+
+    /// Reads metadata written by `write_state()`.
+    pub fn read_state(reader: &mut StateReader) -> io::Result<Self> {
+        let opt_str = |reader: &mut StateReader| -> io::Result<Option<String>> {
+            let present = reader.bool()?;
+            let value = reader.string()?;
+            Ok(present.then_some(value))
+        };
+
+        let source_name = opt_str(reader)?;
+        let target_name = opt_str(reader)?;
+        let has_created_at = reader.bool()?;
+        let created_at = reader.u64()?;
+        let generator = opt_str(reader)?;
+
+        let mut metadata = Self {
+            source_name,
+            target_name,
+            created_at: has_created_at.then_some(created_at),
+            generator,
+            user: Vec::new(),
+        };
+        for _ in 0..reader.u64()? {
+            let key = reader.string()?;
+            let value = reader.string()?;
+            metadata.set_user_value(&key, &value);
+        }
+        Ok(metadata)
+    }
+}
+
+/// Decodes a UTF-8 metadata string.
+fn utf8(data: &[u8]) -> Result<String> {
+    String::from_utf8(data.to_vec())
+        .map_err(|_| PatchlyError::InvalidHeader("Invalid UTF-8 in metadata".to_string()))
+}
+
+/// Splits a custom key record into key and value.
+fn split_user_record(data: &[u8]) -> Result<(String, String)> {
+    let invalid = || PatchlyError::InvalidHeader("Invalid custom metadata record".to_string());
+
+    let key_len = data
+        .get(..2)
+        .map(|len| u16::from_le_bytes([len[0], len[1]]) as usize)
+        .ok_or_else(invalid)?;
+    let key = data.get(2..2 + key_len).ok_or_else(invalid)?;
+    Ok((utf8(key)?, utf8(&data[2 + key_len..])?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Metadata with every field set.
+    fn sample() -> PatchMetadata {
+        let mut metadata = PatchMetadata {
+            source_name: Some("app-1.0.bin".to_string()),
+            target_name: Some("app-1.1.bin".to_string()),
+            created_at: Some(1_760_000_000_000),
+            generator: Some(GENERATOR.to_string()),
+            user: Vec::new(),
+        };
+        metadata.set_user_value("channel", "beta");
+        metadata.set_user_value("ticket", "");
+        metadata
+    }
+
+    #[test]
+    fn test_extensions_roundtrip() {
+        let metadata = sample();
+        let mut extensions = metadata.to_extensions();
+        assert_eq!(extensions.len(), 6);
+
+        // Unknown tags are skipped
+        extensions.push(HeaderExtension {
+            tag: 0x7F00,
+            data: vec![0xFF],
+        });
+        assert_eq!(
+            PatchMetadata::from_extensions(&extensions).unwrap(),
+            metadata
+        );
+        assert_eq!(metadata.user_value("channel"), Some("beta"));
+        assert_eq!(metadata.user_value("missing"), None);
+
+        assert!(PatchMetadata::default().is_empty());
+        assert!(PatchMetadata::default().to_extensions().is_empty());
+    }
+
+    #[test]
+    fn test_malformed_records() {
+        let bad = [
+            (EXT_SOURCE_NAME, vec![0xFF, 0xFE]),
+            (EXT_CREATED_AT, vec![1, 2, 3]),
+            (EXT_USER_KEY, vec![5, 0, b'a']),
+            (EXT_USER_KEY, vec![1]),
+        ];
+        for (tag, data) in bad {
+            let result = PatchMetadata::from_extensions(&[HeaderExtension { tag, data }]);
+            assert!(matches!(result, Err(PatchlyError::InvalidHeader(_))));
+        }
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        for metadata in [sample(), PatchMetadata::default()] {
+            let mut writer = StateWriter::new();
+            metadata.write_state(&mut writer);
+            let bytes = writer.into_bytes();
+
+            let mut reader = StateReader::new(&bytes);
+            assert_eq!(PatchMetadata::read_state(&mut reader).unwrap(), metadata);
+            reader.finish().unwrap();
+        }
+    }
+}
@@ -1 +1,4 @@
+pub mod metadata;
 pub mod patch_format;
+pub mod sink;
+pub mod state;
@@ -12,11 +12,37 @@
 //!   - Source hash: u64 LE (8 bytes)
 //!   - Target size: u64 LE (8 bytes)
 //!
+//! Version 2 appends (after the 33 bytes above, with version = 2):
+//!   - Flags: u32 LE (4 bytes)
+//!   - Extensions length: u32 LE (4 bytes)
+//!   - Extensions: repeated tag(u16 LE) + length(u32 LE) + data
+//!
+//! Version 1 is written whenever no extension is needed.
+//!
+//! Flags name features a reader must support to apply the patch, so
+//! unknown flags are rejected. Extensions are TLV records: readers skip
+//! tags they don't know. Extensions needed to rebuild the target (deflate
+//! recipe, filter ID) are announced by a flag, so a reader that doesn't
+//! know them rejects the patch instead of skipping them. Tags
+//! `0x0004..=0x0008` carry descriptive metadata (see `format::metadata`).
+//!
 //! Instructions (variable):
 //!   - COPY: 0x01 + offset(u64 LE) + length(u32 LE)
 //!   - INSERT: 0x02 + length(u32 LE) + data
+//!
+//! Trailer (17 bytes, only with `FLAG_TRAILER`):
+//!   - END: 0x00 (1 byte)
+//!   - Target size: u64 LE (8 bytes)
+//!   - Target hash: u64 LE (8 bytes)
+//!
+//! Patches built from a target of unknown length set `FLAG_TRAILER`, write
+//! a target size of 0 in the header and record the real size and hash in
+//! the trailer, which ends the patch.
 
+use super::metadata::PatchMetadata;
+use crate::error::{PatchlyError, Result};
 use std::io::{self, Read, Write};
+use wasm_bindgen::prelude::*;
 
 /// Magic bytes to identify patch files.
 pub const MAGIC: &[u8; 4] = b"PTCH";
@@ -24,51 +50,77 @@ pub const MAGIC: &[u8; 4] = b"PTCH";
 /// Current format version.
 pub const VERSION: u8 = 1;
 
+/// Format version with flags and header extensions.
+pub const VERSION_2: u8 = 2;
+
 /// Header size in bytes.
 pub const HEADER_SIZE: usize = 33;
 
+/// Fixed part of a version 2 header (before extension data) in bytes.
+pub const HEADER_V2_FIXED_SIZE: usize = HEADER_SIZE + 8;
+
+/// Extension tag: deflate stream recipe (see `container::deflate`).
+/// Required; announced by `FLAG_DEFLATE`.
+pub const EXT_DEFLATE: u16 = 0x0001;
+
+/// Extension tag: executable filter ID (see `filter::bcj`).
+/// Required; announced by `FLAG_FILTER`.
+pub const EXT_FILTER: u16 = 0x0002;
+
+/// Extension tag: weak rolling hash ID used when diffing, `1` for
+/// Buzhash (see `diff::rolling_hash`). Written only
+/// for non-default hashes. Informational; applying does not need it.
+pub const EXT_ROLLING_HASH: u16 = 0x0003;
+
+/// Extension tag: original source file name.
+pub const EXT_SOURCE_NAME: u16 = 0x0004;
+
+/// Extension tag: original target file name.
+pub const EXT_TARGET_NAME: u16 = 0x0005;
+
+/// Extension tag: creation time.
+pub const EXT_CREATED_AT: u16 = 0x0006;
+
+/// Extension tag: generator name and version.
+pub const EXT_GENERATOR: u16 = 0x0007;
+
+/// Extension tag: custom key/value pair (repeatable).
+pub const EXT_USER_KEY: u16 = 0x0008;
+
 /// Instruction type marker for COPY.
 pub const TYPE_COPY: u8 = 0x01;
 
 /// Instruction type marker for INSERT.
 pub const TYPE_INSERT: u8 = 0x02;
 
+/// Instruction type marker for the END record that starts the trailer.
+pub const TYPE_END: u8 = 0x00;
+
+/// Trailer size in bytes.
+pub const TRAILER_SIZE: usize = 17;
+
+/// Header flag: the target size and hash are in a trailer.
+pub const FLAG_TRAILER: u32 = 1 << 0;
+
+/// Header flag: the target is rebuilt with the `EXT_DEFLATE` recipe.
+pub const FLAG_DEFLATE: u32 = 1 << 1;
+
+/// Header flag: the target is rebuilt with the `EXT_FILTER` filter.
+pub const FLAG_FILTER: u32 = 1 << 2;
+
+/// All flags this version understands.
+pub const KNOWN_FLAGS: u32 = FLAG_TRAILER | FLAG_DEFLATE | FLAG_FILTER;
+
+/// Flags that announce a required extension, with the extension's tag.
+const REQUIRED_EXTENSIONS: [(u32, u16); 2] =
+    [(FLAG_DEFLATE, EXT_DEFLATE), (FLAG_FILTER, EXT_FILTER)];
+
 /// FNV-1a hash offset basis.
 const FNV_OFFSET: u64 = 0xcbf29ce484222325;
 
 /// FNV-1a hash prime.
 const FNV_PRIME: u64 = 0x100000001b3;
 
-/// Validation error when source file doesn't match patch requirements.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum ValidationError {
-    /// Source file size doesn't match expected size.
-    SizeMismatch { expected: u64, actual: u64 },
-    /// Source file hash doesn't match expected hash.
-    HashMismatch { expected: u64, actual: u64 },
-}
-
-impl std::fmt::Display for ValidationError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ValidationError::SizeMismatch { expected, actual } => {
-                write!(
-                    f,
-                    "Source size mismatch: expected {} bytes, got {} bytes",
-                    expected, actual
-                )
-            }
-            ValidationError::HashMismatch { expected, actual } => {
-                write!(
-                    f,
-                    "Source hash mismatch: expected {:016x}, got {:016x}",
-                    expected, actual
-                )
-            }
-        }
-    }
-}
-
 /// Calculates a 64-bit FNV-1a hash of data.
 pub fn calculate_hash(data: &[u8]) -> u64 {
     let mut hash = FNV_OFFSET;
@@ -94,6 +146,15 @@ impl HashBuilder {
         Self { hash: FNV_OFFSET }
     }
 
+    /// Continues hashing from a previously finalized value.
+    ///
+    /// FNV-1a has no state beyond the running hash, so
+    /// `from_state(a.finalize())` followed by `update(b)` equals hashing
+    /// `a` and `b` together.
+    pub fn from_state(hash: u64) -> Self {
+        Self { hash }
+    }
+
     /// Updates the hash with additional data.
     pub fn update(&mut self, data: &[u8]) {
         for &byte in data {
@@ -137,62 +198,252 @@ pub fn serialize_header(
     Ok(buffer)
 }
 
+/// Target size and hash recorded after the instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchTrailer {
+    /// Size of the target file after patching.
+    pub target_size: u64,
+    /// FNV-1a hash of the target file.
+    pub target_hash: u64,
+}
+
+impl PatchTrailer {
+    /// Serializes the trailer, including its END marker.
+    pub fn to_bytes(&self) -> [u8; TRAILER_SIZE] {
+        let mut out = [0u8; TRAILER_SIZE];
+        out[0] = TYPE_END;
+        out[1..9].copy_from_slice(&self.target_size.to_le_bytes());
+        out[9..17].copy_from_slice(&self.target_hash.to_le_bytes());
+        out
+    }
+
+    /// Parses a trailer.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The last `TRAILER_SIZE` bytes of a patch.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() != TRAILER_SIZE || data[0] != TYPE_END {
+            return Err(PatchlyError::MissingTrailer);
+        }
+
+        Ok(Self {
+            target_size: u64::from_le_bytes(data[1..9].try_into().unwrap()),
+            target_hash: u64::from_le_bytes(data[9..17].try_into().unwrap()),
+        })
+    }
+}
+
+/// Tagged extension record carried by a version 2 header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderExtension {
+    /// Extension tag (`EXT_*`).
+    pub tag: u16,
+    /// Extension payload.
+    pub data: Vec<u8>,
+}
+
 /// Parsed patch header information.
+#[wasm_bindgen]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PatchHeader {
     /// Chunk size used during diff generation.
+    #[wasm_bindgen(readonly)]
     pub chunk_size: u32,
     /// Size of the original source file.
+    #[wasm_bindgen(readonly)]
     pub source_size: u64,
     /// Hash of the original source file.
+    #[wasm_bindgen(readonly)]
     pub source_hash: u64,
     /// Size of the target file after patching.
+    ///
+    /// 0 in the header bytes of a trailer patch until `read_trailer()`.
+    #[wasm_bindgen(readonly)]
     pub target_size: u64,
+    /// Hash of the target file, known once `read_trailer()` was called.
+    ///
+    /// Not part of the header bytes.
+    #[wasm_bindgen(readonly)]
+    pub target_hash: Option<u64>,
+    /// Feature flags (version 2 only).
+    #[wasm_bindgen(readonly)]
+    pub flags: u32,
+    /// Extension records (version 2 only).
+    #[wasm_bindgen(skip)]
+    pub extensions: Vec<HeaderExtension>,
+    /// Lowest version this header serializes to: the version read by
+    /// `parse()`, raised to 2 once an extension is added.
+    min_version: u8,
 }
 
 impl PatchHeader {
-    /// Parses a header from bytes.
+    /// Creates a header without flags or extensions.
+    pub fn new(chunk_size: u32, source_size: u64, source_hash: u64, target_size: u64) -> Self {
+        Self {
+            chunk_size,
+            source_size,
+            source_hash,
+            target_size,
+            target_hash: None,
+            flags: 0,
+            extensions: Vec::new(),
+            min_version: VERSION,
+        }
+    }
+
+    /// Returns the payload of the first extension with `tag`.
+    pub fn extension(&self, tag: u16) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|ext| ext.tag == tag)
+            .map(|ext| ext.data.as_slice())
+    }
+
+    /// Adds or replaces the extension with `tag`.
+    pub fn set_extension(&mut self, tag: u16, data: Vec<u8>) {
+        match self.extensions.iter_mut().find(|ext| ext.tag == tag) {
+            Some(ext) => ext.data = data,
+            None => self.extensions.push(HeaderExtension { tag, data }),
+        }
+        self.min_version = VERSION_2;
+    }
+
+    /// Serializes the header, as version 1 when no v2 features are used.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.encoded_len());
+
+        buffer.extend_from_slice(MAGIC);
+        buffer.push(self.version());
+        buffer.extend_from_slice(&self.chunk_size.to_le_bytes());
+        buffer.extend_from_slice(&self.source_size.to_le_bytes());
+        buffer.extend_from_slice(&self.source_hash.to_le_bytes());
+        buffer.extend_from_slice(&self.target_size.to_le_bytes());
+
+        if self.version() == VERSION_2 {
+            buffer.extend_from_slice(&self.flags.to_le_bytes());
+            buffer.extend_from_slice(&(self.extensions_len() as u32).to_le_bytes());
+            for ext in &self.extensions {
+                buffer.extend_from_slice(&ext.tag.to_le_bytes());
+                buffer.extend_from_slice(&(ext.data.len() as u32).to_le_bytes());
+                buffer.extend_from_slice(&ext.data);
+            }
+        }
+
+        buffer
+    }
+
+    /// Decodes the metadata records.
+    pub fn metadata(&self) -> Result<PatchMetadata> {
+        PatchMetadata::from_extensions(&self.extensions)
+    }
+
+    /// Replaces the metadata records with `metadata`.
+    pub fn set_metadata(&mut self, metadata: &PatchMetadata) {
+        let metadata_tags = EXT_SOURCE_NAME..=EXT_USER_KEY;
+        self.extensions
+            .retain(|ext| !metadata_tags.contains(&ext.tag));
+        self.extensions.extend(metadata.to_extensions());
+        if !self.extensions.is_empty() {
+            self.min_version = VERSION_2;
+        }
+    }
+
+    /// Total size of the serialized extension records.
+    fn extensions_len(&self) -> usize {
+        self.extensions.iter().map(|ext| 6 + ext.data.len()).sum()
+    }
+
+    /// Validates that a source file matches this header's requirements.
+    pub fn validate_source(&self, source_size: u64, source_hash: u64) -> Result<()> {
+        if source_size != self.source_size {
+            return Err(PatchlyError::SourceSizeMismatch {
+                expected: self.source_size,
+                actual: source_size,
+            });
+        }
+        if source_hash != self.source_hash {
+            return Err(PatchlyError::SourceHashMismatch {
+                expected: self.source_hash,
+                actual: source_hash,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// JS view of the header. `PatchHeader.parse()` is the one parser used
+/// by both Rust and JS; fields are exposed as read-only properties.
+#[wasm_bindgen]
+impl PatchHeader {
+    /// Determines the full header size from its first bytes.
     ///
     /// # Arguments
     ///
-    /// * `data` - At least 33 bytes of header data.
-    pub fn parse(data: &[u8]) -> io::Result<Self> {
+    /// * `data` - At least 33 bytes (41 for version 2) of header data.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes `parse()` needs.
+    pub fn required_len(data: &[u8]) -> Result<usize> {
         if data.len() < HEADER_SIZE {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "Header too small: {} bytes (need {})",
-                    data.len(),
-                    HEADER_SIZE
-                ),
-            ));
+            return Err(PatchlyError::TruncatedHeader {
+                len: data.len(),
+                required: HEADER_SIZE,
+            });
         }
 
-        let mut cursor = io::Cursor::new(data);
+        if &data[0..4] != MAGIC {
+            return Err(PatchlyError::BadMagic);
+        }
 
-        // Validate magic bytes
-        let mut magic = [0u8; 4];
-        cursor.read_exact(&mut magic)?;
-        if &magic != MAGIC {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid patch file: bad magic bytes",
-            ));
+        match data[4] {
+            VERSION => Ok(HEADER_SIZE),
+            VERSION_2 => {
+                if data.len() < HEADER_V2_FIXED_SIZE {
+                    return Err(PatchlyError::TruncatedHeader {
+                        len: data.len(),
+                        required: HEADER_V2_FIXED_SIZE,
+                    });
+                }
+                let ext_len = u32::from_le_bytes([data[37], data[38], data[39], data[40]]);
+                usize::try_from(ext_len)
+                    .ok()
+                    .and_then(|len| HEADER_V2_FIXED_SIZE.checked_add(len))
+                    .ok_or_else(|| {
+                        PatchlyError::InvalidHeader(format!(
+                            "Header extensions too long: {} bytes",
+                            ext_len
+                        ))
+                    })
+            }
+            version => Err(PatchlyError::UnsupportedVersion { version }),
         }
+    }
 
-        // Validate version
-        let mut version = [0u8; 1];
-        cursor.read_exact(&mut version)?;
-        if version[0] != VERSION {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "Unsupported patch version: {} (expected {})",
-                    version[0], VERSION
-                ),
-            ));
+    /// Parses a header from bytes.
+    ///
+    /// Accepts version 1 and version 2 headers.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - At least `required_len()` bytes of header data.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let required = Self::required_len(data)?;
+        if data.len() < required {
+            return Err(PatchlyError::TruncatedHeader {
+                len: data.len(),
+                required,
+            });
         }
 
+        let mut cursor = io::Cursor::new(data);
+
+        // Magic and version were validated by required_len
+        let mut magic_version = [0u8; 5];
+        cursor.read_exact(&mut magic_version)?;
+        let version = magic_version[4];
+
         // Read chunk_size
         let mut chunk_size_bytes = [0u8; 4];
         cursor.read_exact(&mut chunk_size_bytes)?;
@@ -213,34 +464,158 @@ impl PatchHeader {
         cursor.read_exact(&mut target_size_bytes)?;
         let target_size = u64::from_le_bytes(target_size_bytes);
 
-        Ok(Self {
-            chunk_size,
-            source_size,
-            source_hash,
-            target_size,
-        })
-    }
+        let mut header = Self::new(chunk_size, source_size, source_hash, target_size);
+        header.min_version = version;
+        if version == VERSION {
+            return Ok(header);
+        }
 
-    /// Validates that a source file matches this header's requirements.
-    pub fn validate_source(
-        &self,
-        source_size: u64,
-        source_hash: u64,
-    ) -> Result<(), ValidationError> {
-        if source_size != self.source_size {
-            return Err(ValidationError::SizeMismatch {
-                expected: self.source_size,
-                actual: source_size,
+        // Read flags and extension records
+        let mut u32_bytes = [0u8; 4];
+        cursor.read_exact(&mut u32_bytes)?;
+        header.flags = u32::from_le_bytes(u32_bytes);
+        cursor.read_exact(&mut u32_bytes)?;
+
+        if header.flags & !KNOWN_FLAGS != 0 {
+            return Err(PatchlyError::UnsupportedFlags {
+                flags: header.flags & !KNOWN_FLAGS,
             });
         }
-        if source_hash != self.source_hash {
-            return Err(ValidationError::HashMismatch {
-                expected: self.source_hash,
-                actual: source_hash,
+
+        let mut pos = HEADER_V2_FIXED_SIZE;
+        while pos < required {
+            if required - pos < 6 {
+                return Err(PatchlyError::InvalidHeader(
+                    "Truncated header extension".to_string(),
+                ));
+            }
+            let tag = u16::from_le_bytes([data[pos], data[pos + 1]]);
+            let len =
+                u32::from_le_bytes([data[pos + 2], data[pos + 3], data[pos + 4], data[pos + 5]])
+                    as usize;
+            pos += 6;
+
+            if required - pos < len {
+                return Err(PatchlyError::InvalidHeader(
+                    "Truncated header extension".to_string(),
+                ));
+            }
+            header.extensions.push(HeaderExtension {
+                tag,
+                data: data[pos..pos + len].to_vec(),
             });
+            pos += len;
+        }
+
+        for (flag, tag) in REQUIRED_EXTENSIONS {
+            if (header.flags & flag != 0) != header.extension(tag).is_some() {
+                return Err(PatchlyError::InvalidHeader(format!(
+                    "Flag {:#x} and extension {:#06x} must be used together",
+                    flag, tag
+                )));
+            }
         }
+
+        // Reject malformed metadata here so the getters can't fail
+        PatchMetadata::from_extensions(&header.extensions)?;
+        Ok(header)
+    }
+
+    /// Returns the format version this header serializes to.
+    ///
+    /// Version 2 if it was parsed as version 2 or uses flags or
+    /// extensions, so a parsed header keeps its encoded length.
+    #[wasm_bindgen(getter)]
+    pub fn version(&self) -> u8 {
+        if self.flags == 0 && self.extensions.is_empty() {
+            self.min_version
+        } else {
+            VERSION_2
+        }
+    }
+
+    /// Returns whether the target size and hash are in a trailer.
+    #[wasm_bindgen(getter)]
+    pub fn has_trailer(&self) -> bool {
+        self.flags & FLAG_TRAILER != 0
+    }
+
+    /// Returns the serialized header size in bytes.
+    #[wasm_bindgen(getter = header_size)]
+    pub fn encoded_len(&self) -> usize {
+        if self.version() == VERSION {
+            return HEADER_SIZE;
+        }
+        HEADER_V2_FIXED_SIZE + self.extensions_len()
+    }
+
+    /// Takes the target size and hash from the patch trailer.
+    ///
+    /// # Arguments
+    ///
+    /// * `tail` - The last `TRAILER_SIZE` bytes of the patch.
+    pub fn read_trailer(&mut self, tail: &[u8]) -> Result<()> {
+        if !self.has_trailer() {
+            return Err(PatchlyError::InvalidHeader(
+                "Patch has no trailer".to_string(),
+            ));
+        }
+
+        let trailer = PatchTrailer::parse(tail)?;
+        self.target_size = trailer.target_size;
+        self.target_hash = Some(trailer.target_hash);
         Ok(())
     }
+
+    /// Returns a copy of the payload of the first extension with `tag`.
+    #[wasm_bindgen(js_name = extension)]
+    pub fn extension_data(&self, tag: u16) -> Option<Vec<u8>> {
+        self.extension(tag).map(<[u8]>::to_vec)
+    }
+
+    /// Returns the executable filter ID, if a filter was used.
+    #[wasm_bindgen(getter)]
+    pub fn filter_id(&self) -> Option<u8> {
+        self.extension(EXT_FILTER)
+            .and_then(|data| data.first().copied())
+    }
+
+    /// Returns the weak rolling hash ID, if not the default.
+    #[wasm_bindgen(getter)]
+    pub fn rolling_hash_id(&self) -> Option<u8> {
+        self.extension(EXT_ROLLING_HASH)
+            .and_then(|data| data.first().copied())
+    }
+
+    /// Returns the original source file name.
+    #[wasm_bindgen(getter)]
+    pub fn source_name(&self) -> Option<String> {
+        self.metadata().ok()?.source_name
+    }
+
+    /// Returns the original target file name.
+    #[wasm_bindgen(getter)]
+    pub fn target_name(&self) -> Option<String> {
+        self.metadata().ok()?.target_name
+    }
+
+    /// Returns the creation time in milliseconds since the Unix epoch.
+    #[wasm_bindgen(getter)]
+    pub fn created_at(&self) -> Option<u64> {
+        self.metadata().ok()?.created_at
+    }
+
+    /// Returns the name and version of the program that built the patch.
+    #[wasm_bindgen(getter)]
+    pub fn generator(&self) -> Option<String> {
+        self.metadata().ok()?.generator
+    }
+
+    /// Returns the value of a custom metadata key.
+    #[wasm_bindgen(js_name = metadata_value)]
+    pub fn user_value(&self, key: &str) -> Option<String> {
+        self.metadata().ok()?.user_value(key).map(str::to_string)
+    }
 }
 
 #[cfg(test)]
@@ -274,6 +649,11 @@ mod tests {
         let incremental_hash = builder.finalize();
 
         assert_eq!(full_hash, incremental_hash);
+
+        // Resumed hash
+        let mut resumed = HashBuilder::from_state(calculate_hash(b"hello "));
+        resumed.update(b"world");
+        assert_eq!(full_hash, resumed.finalize());
     }
 
     #[test]
@@ -307,8 +687,7 @@ mod tests {
         let bad_data = b"BADM\x01\x00\x10\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
         let result = PatchHeader::parse(bad_data);
 
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("magic"));
+        assert!(matches!(result, Err(PatchlyError::BadMagic)));
     }
 
     #[test]
@@ -316,40 +695,223 @@ mod tests {
         let bad_data = b"PTCH\x99\x00\x10\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
         let result = PatchHeader::parse(bad_data);
 
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("version"));
+        assert!(matches!(
+            result,
+            Err(PatchlyError::UnsupportedVersion { version: 0x99 })
+        ));
+    }
+
+    #[test]
+    fn test_header_v1_when_no_extensions() {
+        let header = PatchHeader::new(4096, 12345, 0xDEADBEEF, 67890);
+        let bytes = header.to_bytes();
+
+        assert_eq!(header.version(), VERSION);
+        assert_eq!(
+            bytes,
+            serialize_header(4096, 12345, 0xDEADBEEF, 67890).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_header_v2_roundtrip() {
+        let mut header = PatchHeader::new(4096, 1, 2, 3);
+        header.flags = FLAG_TRAILER | FLAG_DEFLATE;
+        header.set_extension(EXT_DEFLATE, vec![1, 2, 3]);
+        header.set_extension(0x7F00, Vec::new());
+
+        let bytes = header.to_bytes();
+        assert_eq!(bytes[4], VERSION_2);
+        assert_eq!(bytes.len(), header.encoded_len());
+        assert_eq!(PatchHeader::required_len(&bytes).unwrap(), bytes.len());
+
+        let parsed = PatchHeader::parse(&bytes).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(parsed.extension(EXT_DEFLATE), Some(&[1u8, 2, 3][..]));
+        assert_eq!(parsed.extension(0x1234), None);
+        assert_eq!(parsed.extension_data(EXT_DEFLATE), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_required_len_of_huge_extensions() {
+        let mut bytes = PatchHeader::new(4096, 1, 2, 3);
+        bytes.flags = FLAG_TRAILER;
+        let mut bytes = bytes.to_bytes();
+        bytes[37..41].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        // Too long to address on 32-bit targets; never a wrapped length
+        match PatchHeader::required_len(&bytes) {
+            Ok(len) => assert_eq!(len as u64, HEADER_V2_FIXED_SIZE as u64 + u32::MAX as u64),
+            Err(err) => assert!(matches!(err, PatchlyError::InvalidHeader(_))),
+        }
+        assert!(PatchHeader::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_header_v2_without_extensions() {
+        use crate::apply::patch_applier::apply_patch;
+
+        let source = [0u8];
+        let mut patch = serialize_header(4096, 1, calculate_hash(&source), 3).unwrap();
+        patch[4] = VERSION_2;
+        patch.extend_from_slice(&[0; 8]);
+
+        let header = PatchHeader::parse(&patch).unwrap();
+        assert_eq!(header.version(), VERSION_2);
+        assert_eq!(header.encoded_len(), HEADER_V2_FIXED_SIZE);
+        assert_eq!(header.to_bytes(), patch);
+
+        // Instructions start after the full version 2 header
+        patch.extend_from_slice(&[TYPE_INSERT, 3, 0, 0, 0, 7, 8, 9]);
+        assert_eq!(apply_patch(&source, &patch).unwrap(), vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn test_header_v2_truncated() {
+        let mut header = PatchHeader::new(4096, 1, 2, 3);
+        header.set_extension(EXT_DEFLATE, vec![0; 16]);
+        let bytes = header.to_bytes();
+
+        assert!(PatchHeader::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(PatchHeader::parse(&bytes[..HEADER_SIZE + 2]).is_err());
+    }
+
+    #[test]
+    fn test_header_v2_rejects_unknown_flags() {
+        let mut header = PatchHeader::new(4096, 1, 2, 3);
+        header.flags = FLAG_TRAILER | 0x8000_0000;
+
+        assert!(matches!(
+            PatchHeader::parse(&header.to_bytes()),
+            Err(PatchlyError::UnsupportedFlags { flags: 0x8000_0000 })
+        ));
+    }
+
+    #[test]
+    fn test_header_v2_requires_flag_with_extension() {
+        let mut header = PatchHeader::new(4096, 1, 2, 3);
+        header.set_extension(EXT_DEFLATE, vec![1]);
+        assert!(matches!(
+            PatchHeader::parse(&header.to_bytes()),
+            Err(PatchlyError::InvalidHeader(_))
+        ));
+
+        let mut header = PatchHeader::new(4096, 1, 2, 3);
+        header.flags = FLAG_FILTER;
+        assert!(matches!(
+            PatchHeader::parse(&header.to_bytes()),
+            Err(PatchlyError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_header_metadata_roundtrip() {
+        let mut metadata = PatchMetadata {
+            source_name: Some("old.iso".to_string()),
+            target_name: Some("new.iso".to_string()),
+            created_at: Some(1_700_000_000_123),
+            generator: Some("test 1.0".to_string()),
+            user: Vec::new(),
+        };
+        metadata.set_user_value("build", "42");
+
+        let mut header = PatchHeader::new(4096, 1, 2, 3);
+        header.flags = FLAG_FILTER;
+        header.set_extension(EXT_FILTER, vec![1]);
+        header.set_metadata(&metadata);
+        let bytes = header.to_bytes();
+        assert_eq!(bytes[4], VERSION_2);
+
+        let parsed = PatchHeader::parse(&bytes).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(parsed.metadata().unwrap(), metadata);
+        assert_eq!(parsed.source_name().as_deref(), Some("old.iso"));
+        assert_eq!(parsed.target_name().as_deref(), Some("new.iso"));
+        assert_eq!(parsed.created_at(), Some(1_700_000_000_123));
+        assert_eq!(parsed.generator().as_deref(), Some("test 1.0"));
+        assert_eq!(parsed.user_value("build").as_deref(), Some("42"));
+        assert_eq!(parsed.filter_id(), Some(1));
+        assert_eq!(parsed.rolling_hash_id(), None);
+
+        // Replacing metadata keeps other extensions
+        header.set_metadata(&PatchMetadata::default());
+        assert_eq!(header.extensions.len(), 1);
+        assert_eq!(header.extension(EXT_FILTER), Some(&[1u8][..]));
+
+        // Version 1 headers have no metadata
+        let v1 = PatchHeader::parse(&serialize_header(4096, 1, 2, 3).unwrap()).unwrap();
+        assert!(v1.metadata().unwrap().is_empty());
+        assert_eq!(v1.source_name(), None);
+    }
+
+    #[test]
+    fn test_header_rejects_malformed_metadata() {
+        let mut header = PatchHeader::new(4096, 1, 2, 3);
+        header.set_extension(EXT_CREATED_AT, vec![1, 2]);
+
+        assert!(matches!(
+            PatchHeader::parse(&header.to_bytes()),
+            Err(PatchlyError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_trailer_roundtrip() {
+        let mut header = PatchHeader::new(4096, 1, 2, 0);
+        header.flags = FLAG_TRAILER;
+        let trailer = PatchTrailer {
+            target_size: 1234,
+            target_hash: 0xFEED,
+        };
+
+        let mut patch = header.to_bytes();
+        patch.extend_from_slice(&[TYPE_INSERT, 0, 0, 0, 0]);
+        patch.extend_from_slice(&trailer.to_bytes());
+
+        let mut parsed = PatchHeader::parse(&patch).unwrap();
+        assert!(parsed.has_trailer());
+        assert_eq!(parsed.target_size, 0);
+        assert_eq!(parsed.target_hash, None);
+
+        let tail = &patch[patch.len() - TRAILER_SIZE..];
+        parsed.read_trailer(tail).unwrap();
+        assert_eq!(PatchTrailer::parse(tail).unwrap(), trailer);
+        assert_eq!(parsed.target_size, 1234);
+        assert_eq!(parsed.target_hash, Some(0xFEED));
+
+        assert!(matches!(
+            parsed.read_trailer(&tail[1..]),
+            Err(PatchlyError::MissingTrailer)
+        ));
+        assert!(PatchHeader::new(4096, 1, 2, 3).read_trailer(tail).is_err());
     }
 
     #[test]
     fn test_header_too_small() {
         let result = PatchHeader::parse(&[0u8; 10]);
-        assert!(result.is_err());
+        assert!(matches!(
+            result,
+            Err(PatchlyError::TruncatedHeader {
+                len: 10,
+                required: HEADER_SIZE
+            })
+        ));
     }
 
     #[test]
     fn test_validate_source_success() {
-        let header = PatchHeader {
-            chunk_size: 4096,
-            source_size: 100,
-            source_hash: 0xABCD,
-            target_size: 200,
-        };
+        let header = PatchHeader::new(4096, 100, 0xABCD, 200);
         assert!(header.validate_source(100, 0xABCD).is_ok());
     }
 
     #[test]
     fn test_validate_source_size_mismatch() {
-        let header = PatchHeader {
-            chunk_size: 4096,
-            source_size: 100,
-            source_hash: 0xABCD,
-            target_size: 200,
-        };
+        let header = PatchHeader::new(4096, 100, 0xABCD, 200);
         let result = header.validate_source(50, 0xABCD);
 
         assert!(result.is_err());
         match result.unwrap_err() {
-            ValidationError::SizeMismatch { expected, actual } => {
+            PatchlyError::SourceSizeMismatch { expected, actual } => {
                 assert_eq!(expected, 100);
                 assert_eq!(actual, 50);
             }
@@ -359,17 +921,12 @@ mod tests {
 
     #[test]
     fn test_validate_source_hash_mismatch() {
-        let header = PatchHeader {
-            chunk_size: 4096,
-            source_size: 100,
-            source_hash: 0xABCD,
-            target_size: 200,
-        };
+        let header = PatchHeader::new(4096, 100, 0xABCD, 200);
         let result = header.validate_source(100, 0x1234);
 
         assert!(result.is_err());
         match result.unwrap_err() {
-            ValidationError::HashMismatch { expected, actual } => {
+            PatchlyError::SourceHashMismatch { expected, actual } => {
                 assert_eq!(expected, 0xABCD);
                 assert_eq!(actual, 0x1234);
             }
@@ -377,23 +934,6 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_validation_error_display() {
-        let size_err = ValidationError::SizeMismatch {
-            expected: 100,
-            actual: 50,
-        };
-        assert!(size_err.to_string().contains("100"));
-        assert!(size_err.to_string().contains("50"));
-
-        let hash_err = ValidationError::HashMismatch {
-            expected: 0xABCD,
-            actual: 0x1234,
-        };
-        assert!(hash_err.to_string().contains("abcd"));
-        assert!(hash_err.to_string().contains("1234"));
-    }
-
     #[test]
     fn test_constants() {
         assert_eq!(MAGIC, b"PTCH");
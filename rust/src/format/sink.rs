@@ -0,0 +1,21 @@
+//! Destinations for serialized patch bytes.
+//!
+//! Diff engines serialize each instruction straight into the
+//! [`PatchSink`] passed to them. Every `io::Write` is a sink, so native
+//! callers can stream a patch straight into a file, socket or hasher. The
+//! wasm API uses a `VecDeque<u8>` queue as its sink and hands the bytes to
+//! JS on `flush_output()`.
+
+use std::io;
+
+/// Receives serialized patch bytes in order.
+pub trait PatchSink {
+    /// Writes all of `data`.
+    fn write_patch(&mut self, data: &[u8]) -> io::Result<()>;
+}
+
+impl<W: io::Write + ?Sized> PatchSink for W {
+    fn write_patch(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_all(data)
+    }
+}
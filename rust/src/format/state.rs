@@ -0,0 +1,209 @@
+//! Binary encoding for suspended builder state.
+//!
+//! `PatchBuilder` state is written as a flat little-endian stream: fixed
+//! width integers, and byte strings prefixed with a u64 length. Each
+//! component writes its own fields with [`StateWriter`] and reads them back
+//! in the same order with [`StateReader`].
+
+use crate::error::PatchlyError;
+use std::io;
+
+/// Magic bytes at the start of a saved builder state.
+pub const STATE_MAGIC: &[u8; 4] = b"PTST";
+
+/// Current builder state encoding version.
+pub const STATE_VERSION: u8 = 1;
+
+/// Appends state fields to a buffer.
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    /// Encoded state.
+    buffer: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Creates an empty writer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes the state magic bytes and version.
+    pub fn put_header(&mut self) {
+        self.buffer.extend_from_slice(STATE_MAGIC);
+        self.buffer.push(STATE_VERSION);
+    }
+
+    /// Writes a single byte.
+    pub fn put_u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    /// Writes a boolean as one byte.
+    pub fn put_bool(&mut self, value: bool) {
+        self.buffer.push(value as u8);
+    }
+
+    /// Writes a u32.
+    pub fn put_u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a u64.
+    pub fn put_u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a length-prefixed byte string.
+    pub fn put_bytes(&mut self, data: &[u8]) {
+        self.put_u64(data.len() as u64);
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Writes a length-prefixed UTF-8 string.
+    pub fn put_str(&mut self, value: &str) {
+        self.put_bytes(value.as_bytes());
+    }
+
+    /// Returns the encoded state.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+/// Reads state fields written by [`StateWriter`].
+pub struct StateReader<'a> {
+    /// Encoded state.
+    data: &'a [u8],
+    /// Read position.
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /// Creates a reader over encoded state.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Takes the next `len` bytes.
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| invalid("Truncated builder state"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Reads and validates the state magic bytes and version.
+    pub fn header(&mut self) -> io::Result<()> {
+        if self.take(STATE_MAGIC.len())? != STATE_MAGIC {
+            return Err(invalid("Invalid builder state: bad magic bytes"));
+        }
+
+        let version = self.u8()?;
+        if version != STATE_VERSION {
+            return Err(invalid(&format!(
+                "Unsupported builder state version: {} (expected {})",
+                version, STATE_VERSION
+            )));
+        }
+        Ok(())
+    }
+
+    /// Reads a single byte.
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a boolean.
+    pub fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(invalid(&format!(
+                "Invalid boolean in builder state: {}",
+                other
+            ))),
+        }
+    }
+
+    /// Reads a u32.
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Reads a u64.
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a length-prefixed byte string.
+    pub fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = usize::try_from(self.u64()?).map_err(|_| invalid("Truncated builder state"))?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /// Reads a length-prefixed UTF-8 string.
+    pub fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| invalid("Invalid string in builder state"))
+    }
+
+    /// Checks that all state was consumed.
+    pub fn finish(&self) -> io::Result<()> {
+        if self.pos != self.data.len() {
+            return Err(invalid("Trailing bytes in builder state"));
+        }
+        Ok(())
+    }
+}
+
+/// Creates an error for malformed state.
+///
+/// The error wraps [`PatchlyError::InvalidState`], which `PatchBuilder`
+/// unwraps again when restoring.
+pub fn invalid(message: &str) -> io::Error {
+    PatchlyError::InvalidState(message.to_string()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let mut writer = StateWriter::new();
+        writer.put_header();
+        writer.put_u8(7);
+        writer.put_bool(true);
+        writer.put_u32(0xDEAD_BEEF);
+        writer.put_u64(u64::MAX - 1);
+        writer.put_bytes(b"payload");
+        writer.put_str("dir/file.txt");
+        let bytes = writer.into_bytes();
+
+        let mut reader = StateReader::new(&bytes);
+        reader.header().unwrap();
+        assert_eq!(reader.u8().unwrap(), 7);
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.u32().unwrap(), 0xDEAD_BEEF);
+        assert_eq!(reader.u64().unwrap(), u64::MAX - 1);
+        assert_eq!(reader.bytes().unwrap(), b"payload");
+        assert_eq!(reader.string().unwrap(), "dir/file.txt");
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_state_truncated() {
+        let mut writer = StateWriter::new();
+        writer.put_bytes(b"payload");
+        let bytes = writer.into_bytes();
+
+        let mut reader = StateReader::new(&bytes[..10]);
+        assert!(reader.bytes().is_err());
+
+        let mut reader = StateReader::new(&bytes);
+        assert!(reader.u32().is_ok());
+        assert!(reader.finish().is_err());
+    }
+}
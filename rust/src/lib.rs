@@ -2,26 +2,285 @@
 //!
 //! Provides streaming APIs for memory-efficient processing of large files.
 
+pub mod apply;
+pub mod container;
 pub mod diff;
+pub mod error;
+pub mod filter;
 pub mod format;
+pub mod simd;
+#[cfg(test)]
+mod test_util;
+#[cfg(all(target_arch = "wasm32", feature = "threads"))]
+pub mod threads;
 
+use std::collections::VecDeque;
+use std::io;
 use wasm_bindgen::prelude::*;
 
+use crate::apply::checkpoint::ApplyCheckpoint;
+use crate::apply::patch_applier::{JsSyncAccessSource, PatchApplier, SourceAccess};
+use crate::container::deflate::{self, DeflateRecipe};
+use crate::container::tar::{TarDiff, TarSourceIndex};
 use crate::diff::block_index::BlockIndex;
+use crate::diff::instruction_writer::DEFAULT_INSERT_LIMIT;
+use crate::diff::parallel_diff::{ParallelDiff, MAX_THREADS};
+use crate::diff::rolling_hash::RollingHashKind;
 use crate::diff::streaming_diff::StreamingDiff;
-use crate::format::patch_format::{calculate_hash, HashBuilder};
+use crate::error::PatchlyError;
+use crate::filter::bcj::{BcjFilter, FilterKind};
+use crate::format::metadata::{PatchMetadata, GENERATOR};
+use crate::format::patch_format::{
+    calculate_hash, HashBuilder, PatchHeader, PatchTrailer, EXT_DEFLATE, EXT_FILTER,
+    EXT_ROLLING_HASH, FLAG_DEFLATE, FLAG_FILTER, FLAG_TRAILER,
+};
+use crate::format::sink::PatchSink;
+use crate::format::state::{invalid, StateReader, StateWriter};
 
 /// Default chunk size for diff matching (4KB)
 const DEFAULT_CHUNK_SIZE: usize = 4096;
 
+/// Smallest block size accepted for the fine-grained index.
+const MIN_FINE_BLOCK_SIZE: usize = 16;
+
+/// Source-side indexer selected by the builder mode.
+enum SourceIndexer {
+    /// Fixed-offset block index.
+    Plain(Box<BlockIndex>),
+    /// Member-aligned index for tar archives.
+    Tar(Box<TarSourceIndex>),
+}
+
+impl SourceIndexer {
+    /// Creates an empty indexer for the given mode.
+    fn new(tar_mode: bool, chunk_size: usize) -> Self {
+        if tar_mode {
+            Self::Tar(Box::new(TarSourceIndex::with_block_size(chunk_size)))
+        } else {
+            Self::Plain(Box::new(BlockIndex::with_block_size(chunk_size)))
+        }
+    }
+
+    /// Adds a chunk of source data.
+    fn add_chunk(&mut self, chunk: &[u8]) {
+        match self {
+            Self::Plain(index) => index.add_chunk(chunk),
+            Self::Tar(index) => index.add_chunk(chunk),
+        }
+    }
+
+    /// Caps the memory of the index being built.
+    fn set_memory_budget(&mut self, bytes: usize) {
+        match self {
+            Self::Plain(index) => index.set_memory_budget(bytes),
+            Self::Tar(index) => index.set_memory_budget(bytes),
+        }
+    }
+
+    /// Selects the weak hash algorithm of the index being built.
+    fn set_rolling_hash(&mut self, kind: RollingHashKind) {
+        match self {
+            Self::Plain(index) => index.set_rolling_hash(kind),
+            Self::Tar(index) => index.set_rolling_hash(kind),
+        }
+    }
+
+    /// Returns the block index being built.
+    fn index(&self) -> &BlockIndex {
+        match self {
+            Self::Plain(index) => index,
+            Self::Tar(index) => index.index(),
+        }
+    }
+
+    /// Finalizes the index and creates the matching target-side engine.
+    ///
+    /// Plain indexes get a parallel engine when `threads` is non-zero.
+    fn into_engine(self, threads: usize) -> error::Result<DiffEngine> {
+        Ok(match self {
+            Self::Plain(mut index) if threads > 0 => {
+                index.finalize();
+                DiffEngine::Parallel(Box::new(ParallelDiff::new(*index, threads)?))
+            }
+            Self::Plain(mut index) => {
+                index.finalize();
+                DiffEngine::Plain(Box::new(StreamingDiff::new(*index)))
+            }
+            Self::Tar(mut index) => {
+                index.finalize();
+                DiffEngine::Tar(Box::new(TarDiff::new(*index)))
+            }
+        })
+    }
+}
+
+/// Target-side diff engine selected by the builder mode.
+enum DiffEngine {
+    /// Plain rolling-hash diff.
+    Plain(Box<StreamingDiff>),
+    /// Plain diff scanned on several threads.
+    Parallel(Box<ParallelDiff>),
+    /// Member-by-member tar diff.
+    Tar(Box<TarDiff>),
+}
+
+impl DiffEngine {
+    /// Processes a chunk of target data, writing instructions to `out`.
+    fn process_target_chunk(&mut self, chunk: &[u8], out: &mut dyn PatchSink) -> io::Result<()> {
+        match self {
+            Self::Plain(diff) => diff.process_target_chunk(chunk, out),
+            Self::Parallel(diff) => diff.process_target_chunk(chunk, out),
+            Self::Tar(diff) => diff.process_target_chunk(chunk, out),
+        }
+    }
+
+    /// Finalizes processing, writing the remaining instructions to `out`.
+    fn finalize(&mut self, out: &mut dyn PatchSink) -> io::Result<()> {
+        match self {
+            Self::Plain(diff) => diff.finalize(out),
+            Self::Parallel(diff) => diff.finalize(out),
+            Self::Tar(diff) => diff.finalize(out),
+        }
+    }
+
+    /// Returns the source block index.
+    fn index(&self) -> &BlockIndex {
+        match self {
+            Self::Plain(diff) => diff.index(),
+            Self::Parallel(diff) => diff.index(),
+            Self::Tar(diff) => diff.index(),
+        }
+    }
+
+    /// Sets the fine-grained index used to refine INSERT data.
+    fn set_fine_index(&mut self, index: Option<BlockIndex>) {
+        match self {
+            Self::Plain(diff) => diff.set_fine_index(index),
+            Self::Parallel(diff) => diff.set_fine_index(index),
+            Self::Tar(diff) => diff.set_fine_index(index),
+        }
+    }
+
+    /// Sets the pending INSERT size at which it is emitted.
+    fn set_insert_limit(&mut self, bytes: usize) {
+        match self {
+            Self::Plain(diff) => diff.set_insert_limit(bytes),
+            Self::Parallel(diff) => diff.set_insert_limit(bytes),
+            Self::Tar(diff) => diff.set_insert_limit(bytes),
+        }
+    }
+
+    /// Sets the source reader used to verify matches.
+    fn set_source_access(&mut self, source: Option<Box<dyn SourceAccess>>) {
+        match self {
+            Self::Plain(diff) => diff.set_source_access(source),
+            Self::Parallel(diff) => diff.set_source_access(source),
+            Self::Tar(diff) => diff.set_source_access(source),
+        }
+    }
+
+    /// Returns the number of candidates rejected by verification.
+    fn rejected_matches(&self) -> u64 {
+        match self {
+            Self::Plain(diff) => diff.rejected_matches(),
+            Self::Parallel(diff) => diff.rejected_matches(),
+            Self::Tar(diff) => diff.rejected_matches(),
+        }
+    }
+
+    /// Returns the fine-grained index, if set.
+    fn fine_index(&self) -> Option<&BlockIndex> {
+        match self {
+            Self::Plain(diff) => diff.fine_index(),
+            Self::Parallel(diff) => diff.fine_index(),
+            Self::Tar(diff) => diff.fine_index(),
+        }
+    }
+
+    /// Writes the engine to suspended builder state.
+    fn write_state(&self, writer: &mut StateWriter) -> io::Result<()> {
+        match self {
+            Self::Plain(diff) => {
+                writer.put_u8(0);
+                diff.write_state(writer)
+            }
+            Self::Tar(diff) => {
+                writer.put_u8(1);
+                diff.write_state(writer)
+            }
+            Self::Parallel(diff) => {
+                writer.put_u8(2);
+                diff.write_state(writer)
+            }
+        }
+    }
+
+    /// Reads an engine written by `write_state()`.
+    fn read_state(reader: &mut StateReader) -> io::Result<Self> {
+        match reader.u8()? {
+            0 => Ok(Self::Plain(Box::new(StreamingDiff::read_state(reader)?))),
+            1 => Ok(Self::Tar(Box::new(TarDiff::read_state(reader)?))),
+            2 => Ok(Self::Parallel(Box::new(ParallelDiff::read_state(reader)?))),
+            _ => Err(invalid("Invalid diff engine in builder state")),
+        }
+    }
+}
+
+/// Where the builder sends diff output.
+enum Output<'a> {
+    /// The builder's queue, read by `flush_output()` and `flush_into()`.
+    Queue,
+    /// A caller-supplied sink; the header and queued bytes are written first.
+    Sink(&'a mut dyn PatchSink),
+}
+
+/// Result of `PatchBuilder::add_target_chunk()`.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetStatus {
+    /// More target data can be added.
+    Ready = 0,
+    /// Pending output reached the high-water mark: drain it with
+    /// `flush_output()` or `flush_into()` before adding more target data.
+    Drain = 1,
+}
+
+/// Lifecycle phase of a `PatchBuilder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Accepting source chunks.
+    Source,
+    /// Source indexed; accepting target chunks.
+    Target,
+    /// All target data diffed; only output is left.
+    Finished,
+}
+
+/// Target size announced before the target data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TargetLength {
+    /// Not announced yet.
+    Unset,
+    /// Known up front and recorded in the header.
+    Known(u64),
+    /// Unknown up front; recorded in a trailer after the instructions.
+    Unknown,
+}
+
 /// Streaming binary patch builder.
 ///
 /// Processes source and target files in chunks to generate a binary patch.
 /// Designed for memory-efficient handling of large files (multi-GB).
+///
+/// Calls must follow the lifecycle: configure, `add_source_chunk()`,
+/// `finalize_source()`, `set_target_size()` (or
+/// `set_target_size_unknown()`), `add_target_chunk()`, `finalize_target()`.
+/// Out-of-order calls fail with a `MISUSE` error instead of producing a
+/// broken patch.
 #[wasm_bindgen]
 pub struct PatchBuilder {
     /// Block index for source file.
-    source_index: BlockIndex,
+    source_index: SourceIndexer,
     /// Hash builder for source verification.
     source_hasher: HashBuilder,
     /// Hash builder for target (identical file detection).
@@ -30,20 +289,51 @@ pub struct PatchBuilder {
     source_size: u64,
     /// Total target bytes received.
     target_size: u64,
-    /// Expected total target size (for header).
-    target_total_size: u64,
+    /// Expected total target size (for header or trailer).
+    target_length: TargetLength,
+    /// Descriptive metadata recorded in the header.
+    metadata: PatchMetadata,
     /// Streaming diff processor.
-    diff: Option<StreamingDiff>,
-    /// Whether source has been finalized.
-    source_finalized: bool,
+    diff: Option<DiffEngine>,
+    /// Current lifecycle phase.
+    phase: Phase,
     /// Chunk size for matching.
     chunk_size: usize,
-    /// Serialized patch data ready to output.
-    output_buffer: Vec<u8>,
+    /// Serialized patch data ready to output, consumed from the front.
+    output_buffer: VecDeque<u8>,
     /// Whether header has been written.
     header_written: bool,
-    /// Whether all target data has been processed.
-    target_finalized: bool,
+    /// Whether source and target are diffed as tar archives.
+    tar_mode: bool,
+    /// Whether deflate streams are expanded before diffing.
+    deflate_mode: bool,
+    /// Raw source buffered for deflate expansion.
+    deflate_source: Vec<u8>,
+    /// Raw target buffered for deflate expansion.
+    deflate_target: Vec<u8>,
+    /// Streams expanded in source and target, recorded in the header.
+    deflate_recipe: DeflateRecipe,
+    /// Executable filter applied to source data before indexing.
+    source_filter: BcjFilter,
+    /// Executable filter applied to target data before diffing.
+    target_filter: BcjFilter,
+    /// Worker threads for target diffing (0 = single-threaded).
+    parallel_threads: usize,
+    /// Source index memory cap in bytes (0 = unlimited).
+    index_budget: usize,
+    /// Weak hash algorithm used for block matching.
+    rolling_hash: RollingHashKind,
+    /// Block size of the fine-grained source index (0 = disabled).
+    fine_block_size: usize,
+    /// Fine-grained source index being built.
+    fine_index: Option<Box<BlockIndex>>,
+    /// Unmatched target bytes buffered before an INSERT is emitted.
+    max_insert_size: usize,
+    /// Pending output size that asks the caller to drain (0 = unlimited).
+    output_high_water_mark: usize,
+    /// Source reader for match verification, handed to the diff engine by
+    /// `finalize_source()`.
+    source_access: Option<Box<dyn SourceAccess>>,
 }
 
 #[wasm_bindgen]
@@ -52,102 +342,398 @@ impl PatchBuilder {
     #[wasm_bindgen(constructor)]
     pub fn new() -> Self {
         Self {
-            source_index: BlockIndex::with_block_size(DEFAULT_CHUNK_SIZE),
+            source_index: SourceIndexer::new(false, DEFAULT_CHUNK_SIZE),
             source_hasher: HashBuilder::new(),
             target_hasher: HashBuilder::new(),
             source_size: 0,
             target_size: 0,
-            target_total_size: 0,
+            target_length: TargetLength::Unset,
+            metadata: PatchMetadata::default(),
             diff: None,
-            source_finalized: false,
+            phase: Phase::Source,
             chunk_size: DEFAULT_CHUNK_SIZE,
-            output_buffer: Vec::new(),
+            output_buffer: VecDeque::new(),
             header_written: false,
-            target_finalized: false,
+            tar_mode: false,
+            deflate_mode: false,
+            deflate_source: Vec::new(),
+            deflate_target: Vec::new(),
+            deflate_recipe: DeflateRecipe::default(),
+            source_filter: BcjFilter::encoder(FilterKind::None),
+            target_filter: BcjFilter::encoder(FilterKind::None),
+            parallel_threads: 0,
+            index_budget: 0,
+            rolling_hash: RollingHashKind::default(),
+            fine_block_size: 0,
+            fine_index: None,
+            max_insert_size: DEFAULT_INSERT_LIMIT,
+            output_high_water_mark: 0,
+            source_access: None,
         }
     }
 
-    /// Adds a chunk of source (old file) data.
+    /// Enables or disables tar-aware diffing.
+    ///
+    /// In tar mode both files are parsed as tar archives; member contents are
+    /// matched against the source member with the same path, aligned on
+    /// member boundaries. The patch format is unchanged.
+    ///
+    /// Fails with `MISUSE` once `add_source_chunk()` was called.
+    #[wasm_bindgen]
+    pub fn set_tar_mode(&mut self, enabled: bool) -> Result<(), PatchlyError> {
+        self.check_configurable()?;
+
+        self.tar_mode = enabled;
+        self.rebuild_indexes();
+        Ok(())
+    }
+
+    /// Enables or disables deflate-aware diffing for gzip and zip files.
+    ///
+    /// Deflate streams are decompressed before diffing and the target streams
+    /// are recompressed on apply, which requires a version 2 patch. Both files
+    /// are buffered in memory, and output is only available after
+    /// `finalize_target()`. Files larger than 256MB are rejected with
+    /// `INVALID_DATA`, and streams that would expand a file past 256MB stay
+    /// compressed.
+    ///
+    /// Fails with `MISUSE` once `add_source_chunk()` was called.
+    #[wasm_bindgen]
+    pub fn set_deflate_mode(&mut self, enabled: bool) -> Result<(), PatchlyError> {
+        self.check_configurable()?;
+
+        self.deflate_mode = enabled;
+        Ok(())
+    }
+
+    /// Selects an executable preprocessing filter by ID.
+    ///
+    /// `0` = none, `1` = x86/x86-64, `2` = AArch64 (see `detect_filter()`).
+    /// Branch displacements are normalized in both files before matching and
+    /// restored on apply; the filter ID is recorded in a version 2 header.
+    /// Unknown IDs fail with `INVALID_DATA`.
+    ///
+    /// Fails with `MISUSE` once `add_source_chunk()` was called.
+    #[wasm_bindgen]
+    pub fn set_filter(&mut self, filter_id: u8) -> Result<(), PatchlyError> {
+        self.check_configurable()?;
+
+        let kind = filter_kind(filter_id)?;
+        self.source_filter = BcjFilter::encoder(kind);
+        self.target_filter = BcjFilter::encoder(kind);
+        Ok(())
+    }
+
+    /// Diffs the target on several threads sharing the source index.
+    ///
+    /// The target is buffered in batches and split into segments that are
+    /// matched concurrently; the patch is identical to a single-threaded
+    /// run. `0` disables parallel diffing. Counts above 256 are clamped.
+    /// Ignored in tar mode. On wasm the segments run on the Web Worker pool
+    /// of a `threads` build once `init_thread_pool()` and its workers have
+    /// started, and on the calling thread otherwise; the count then sets the
+    /// batch size, and the pool size the parallelism.
+    ///
+    /// Fails with `MISUSE` once `add_source_chunk()` was called.
+    #[wasm_bindgen]
+    pub fn set_parallel_threads(&mut self, threads: u32) -> Result<(), PatchlyError> {
+        self.check_configurable()?;
+
+        self.parallel_threads = (threads as usize).min(MAX_THREADS);
+        Ok(())
+    }
+
+    /// Caps the source index memory in bytes (0 = unlimited).
+    ///
+    /// When a large source would exceed the budget, only a content-defined
+    /// sample of source blocks is indexed: the patch gets larger, but memory
+    /// stays bounded. See `index_coverage()`.
+    ///
+    /// Fails with `MISUSE` once `add_source_chunk()` was called.
+    #[wasm_bindgen]
+    pub fn set_index_memory_budget(&mut self, bytes: usize) -> Result<(), PatchlyError> {
+        self.check_configurable()?;
+
+        self.index_budget = bytes;
+        self.rebuild_indexes();
+        Ok(())
+    }
+
+    /// Selects the weak rolling hash by ID.
+    ///
+    /// `0` = Adler-32 style (default), `1` = table-driven Buzhash, which is
+    /// faster and spreads low-entropy data (zero runs, sparse records)
+    /// better. A non-default hash is recorded in a version 2 header. Unknown
+    /// IDs fail with `INVALID_DATA`.
+    ///
+    /// Fails with `MISUSE` once `add_source_chunk()` was called.
+    #[wasm_bindgen]
+    pub fn set_rolling_hash(&mut self, hash_id: u8) -> Result<(), PatchlyError> {
+        self.check_configurable()?;
+
+        self.rolling_hash = RollingHashKind::from_id(hash_id).ok_or_else(|| {
+            PatchlyError::InvalidData(format!("Unknown rolling hash ID: {}", hash_id))
+        })?;
+        self.rebuild_indexes();
+        Ok(())
+    }
+
+    /// Adds a secondary source index with smaller blocks (0 = disabled).
+    ///
+    /// Target data the main index can't match (INSERT regions) is scanned
+    /// again against this index, so fragments shared between edited parts
+    /// of structured files still become COPYs. 64 is a good choice; sizes
+    /// below 16 or not below the main block size fail with `INVALID_DATA`.
+    /// The index covers the whole source and is subject to the memory budget
+    /// on its own, so it needs several times the main index memory.
+    ///
+    /// Fails with `MISUSE` once `add_source_chunk()` was called.
+    #[wasm_bindgen]
+    pub fn set_fine_block_size(&mut self, size: usize) -> Result<(), PatchlyError> {
+        self.check_configurable()?;
+
+        if size != 0 && !(MIN_FINE_BLOCK_SIZE..self.chunk_size).contains(&size) {
+            return Err(PatchlyError::InvalidData(format!(
+                "Fine block size must be 0 or in {}..{}, got {}",
+                MIN_FINE_BLOCK_SIZE, self.chunk_size, size
+            )));
+        }
+
+        self.fine_block_size = size;
+        self.rebuild_indexes();
+        Ok(())
+    }
+
+    /// Verifies every match against the source file before it becomes a
+    /// COPY, so a hash collision can't corrupt the target.
+    ///
+    /// `handle` is an OPFS `FileSystemSyncAccessHandle` of the source file
+    /// (any object with its `read(buffer, { at })` and `getSize()` methods).
+    /// Costs one source read per match. Not saved by `save_state()`; set it
+    /// again after `restore_state()`. Fails in deflate and filter modes,
+    /// which diff transformed bytes, and after `finalize_target()`.
+    #[wasm_bindgen]
+    pub fn set_source_handle(&mut self, handle: JsValue) -> Result<(), PatchlyError> {
+        let source = JsSyncAccessSource::new(handle)?;
+        self.set_source_access(Some(Box::new(source)))
+    }
+
+    /// Returns the number of match candidates rejected by verification.
+    #[wasm_bindgen]
+    pub fn rejected_matches(&self) -> u64 {
+        self.diff.as_ref().map_or(0, DiffEngine::rejected_matches)
+    }
+
+    /// Sets the largest INSERT emitted in one piece, in bytes.
+    ///
+    /// Unmatched target data is buffered until it reaches this size and is
+    /// then emitted as an INSERT, so memory stays bounded for dissimilar
+    /// files. Defaults to 1MB; `0` is ignored. Takes effect immediately.
     #[wasm_bindgen]
-    pub fn add_source_chunk(&mut self, chunk: &[u8]) {
-        if self.source_finalized {
+    pub fn set_max_insert_size(&mut self, bytes: u32) {
+        if bytes == 0 {
             return;
         }
 
+        self.max_insert_size = bytes as usize;
+        if let Some(diff) = &mut self.diff {
+            diff.set_insert_limit(self.max_insert_size);
+        }
+    }
+
+    /// Adds a chunk of source (old file) data.
+    ///
+    /// Fails once `finalize_source()` was called.
+    #[wasm_bindgen]
+    pub fn add_source_chunk(&mut self, chunk: &[u8]) -> Result<(), PatchlyError> {
+        if self.phase != Phase::Source {
+            return Err(PatchlyError::Misuse(
+                "add_source_chunk() called after finalize_source()",
+            ));
+        }
+
+        if self.deflate_mode {
+            check_deflate_size(self.deflate_source.len(), chunk)?;
+        }
+
         self.source_hasher.update(chunk);
-        self.source_index.add_chunk(chunk);
+        if self.deflate_mode {
+            self.deflate_source.extend_from_slice(chunk);
+        } else {
+            self.index_source(chunk);
+        }
         self.source_size += chunk.len() as u64;
+        Ok(())
     }
 
     /// Finalizes source processing.
+    ///
+    /// Fails if called more than once.
     #[wasm_bindgen]
-    pub fn finalize_source(&mut self) {
-        if self.source_finalized {
-            return;
+    pub fn finalize_source(&mut self) -> Result<(), PatchlyError> {
+        if self.phase != Phase::Source {
+            return Err(PatchlyError::Misuse("finalize_source() called twice"));
+        }
+        if self.source_access.is_some()
+            && (self.deflate_mode || self.source_filter.kind() != FilterKind::None)
+        {
+            return Err(PatchlyError::Misuse(
+                "Match verification is not available in deflate or filter mode",
+            ));
         }
 
-        self.source_index.finalize();
+        if self.deflate_mode {
+            // Source streams only need to decompress, not to be reproducible
+            let streams = deflate::find_streams(&self.deflate_source, false);
+            let expanded = deflate::expand(&self.deflate_source, &streams)?;
+            self.deflate_source = Vec::new();
+            self.index_source(&expanded);
+            self.deflate_recipe.source = streams;
+        }
 
-        // Create StreamingDiff with the built index
-        let index = std::mem::replace(
-            &mut self.source_index,
-            BlockIndex::with_block_size(self.chunk_size),
-        );
-        self.diff = Some(StreamingDiff::new(index));
-        self.source_finalized = true;
+        let tail = self.source_filter.finish();
+        self.add_filtered_source(&tail);
+
+        // Create the diff engine with the built index
+        let fresh = self.new_indexer();
+        let index = std::mem::replace(&mut self.source_index, fresh);
+        let mut diff = match index.into_engine(self.parallel_threads) {
+            Ok(diff) => diff,
+            Err(err) => {
+                // The source index is gone; only reset() can recover
+                self.phase = Phase::Finished;
+                return Err(err);
+            }
+        };
+        diff.set_insert_limit(self.max_insert_size);
+
+        let fresh = self.new_fine_index();
+        let fine = std::mem::replace(&mut self.fine_index, fresh);
+        diff.set_fine_index(fine.map(|mut fine| {
+            fine.finalize();
+            *fine
+        }));
+        diff.set_source_access(self.source_access.take());
+
+        self.diff = Some(diff);
+        self.phase = Phase::Target;
+        Ok(())
     }
 
     /// Sets the expected total target size.
     ///
-    /// Must be called before `add_target_chunk()` for proper header generation.
+    /// Required before `add_target_chunk()` and `finalize_target()`, since
+    /// the header that starts the output records it. Fails once output was
+    /// flushed or the target was finalized.
     #[wasm_bindgen]
-    pub fn set_target_size(&mut self, size: u64) {
-        self.target_total_size = size;
+    pub fn set_target_size(&mut self, size: u64) -> Result<(), PatchlyError> {
+        self.set_target_length(TargetLength::Known(size))
     }
 
-    /// Adds a chunk of target (new file) data.
+    /// Builds the patch without knowing the target size up front.
     ///
-    /// Generates patch output immediately; call `flush_output()` to retrieve it.
+    /// For unbounded streams such as `fetch()` bodies. The header gets the
+    /// trailer flag and a target size of 0; `finalize_target()` appends a
+    /// trailer with the real target size and hash, which makes the patch
+    /// version 2. Replaces `set_target_size()` and fails in the same cases.
     #[wasm_bindgen]
-    pub fn add_target_chunk(&mut self, chunk: &[u8]) {
-        if !self.source_finalized {
-            self.finalize_source();
-        }
+    pub fn set_target_size_unknown(&mut self) -> Result<(), PatchlyError> {
+        self.set_target_length(TargetLength::Unknown)
+    }
 
-        self.target_hasher.update(chunk);
-        self.target_size += chunk.len() as u64;
+    /// Records the original source file name in the header.
+    ///
+    /// Metadata makes the patch version 2 and is informational only.
+    /// Like the target size, it fails once the header was written.
+    #[wasm_bindgen]
+    pub fn set_source_name(&mut self, name: &str) -> Result<(), PatchlyError> {
+        self.check_metadata_open()?;
+        self.metadata.source_name = Some(name.to_string());
+        Ok(())
+    }
 
-        if let Some(diff) = &mut self.diff {
-            diff.process_target_chunk(chunk);
+    /// Records the original target file name in the header.
+    #[wasm_bindgen]
+    pub fn set_target_name(&mut self, name: &str) -> Result<(), PatchlyError> {
+        self.check_metadata_open()?;
+        self.metadata.target_name = Some(name.to_string());
+        Ok(())
+    }
 
-            // Pull output from diff into our buffer
-            if diff.has_output() {
-                let output = diff.take_output();
-                self.output_buffer.extend_from_slice(&output);
-            }
-        }
+    /// Records the creation time, in milliseconds since the Unix epoch.
+    #[wasm_bindgen]
+    pub fn set_created_at(&mut self, millis: u64) -> Result<(), PatchlyError> {
+        self.check_metadata_open()?;
+        self.metadata.created_at = Some(millis);
+        Ok(())
     }
 
-    /// Finalizes target processing.
+    /// Records a custom key/value pair, replacing an earlier value.
     ///
-    /// Call this after all target chunks have been added.
+    /// Keys are limited to 65535 bytes; longer ones fail with
+    /// `INVALID_DATA`.
     #[wasm_bindgen]
-    pub fn finalize_target(&mut self) {
-        if self.target_finalized {
-            return;
+    pub fn set_metadata(&mut self, key: &str, value: &str) -> Result<(), PatchlyError> {
+        self.check_metadata_open()?;
+        if key.len() > u16::MAX as usize {
+            return Err(PatchlyError::InvalidData(format!(
+                "Metadata key too long: {} bytes (max 65535)",
+                key.len()
+            )));
         }
+        self.metadata.set_user_value(key, value);
+        Ok(())
+    }
 
-        if let Some(diff) = &mut self.diff {
-            diff.finalize();
+    /// Sets the pending output size at which target ingestion asks the
+    /// caller to drain, in bytes (0 = unlimited, the default).
+    ///
+    /// Pending output then stays below the mark plus the output of one
+    /// target chunk (at most the chunk size plus the max INSERT size and
+    /// instruction overhead). With `set_parallel_threads()`, a chunk that
+    /// completes a batch releases the whole batch, so the bound grows by the
+    /// batch size (2 MB per thread). Takes effect immediately.
+    #[wasm_bindgen]
+    pub fn set_output_high_water_mark(&mut self, bytes: usize) {
+        self.output_high_water_mark = bytes;
+    }
 
-            // Pull final output
-            if diff.has_output() {
-                let output = diff.take_output();
-                self.output_buffer.extend_from_slice(&output);
-            }
+    /// Adds a chunk of target (new file) data.
+    ///
+    /// Generates patch output immediately; call `flush_output()` to retrieve
+    /// it. The chunk is always consumed; `TargetStatus::Drain` means the
+    /// pending output reached the high-water mark and must be drained
+    /// before the next chunk. Deflate mode buffers the target and always
+    /// returns `Ready`.
+    ///
+    /// Fails before `finalize_source()` and `set_target_size()`, after
+    /// `finalize_target()`, and when the chunk would exceed the target size.
+    #[wasm_bindgen]
+    pub fn add_target_chunk(&mut self, chunk: &[u8]) -> Result<TargetStatus, PatchlyError> {
+        self.ingest_target(chunk, &mut Output::Queue)?;
+        Ok(self.target_status())
+    }
+
+    /// Returns whether pending output must be drained before more target
+    /// data is added.
+    #[wasm_bindgen]
+    pub fn target_status(&self) -> TargetStatus {
+        let limit = self.output_high_water_mark;
+        if limit > 0 && self.output_buffer.len() >= limit {
+            TargetStatus::Drain
+        } else {
+            TargetStatus::Ready
         }
+    }
 
-        self.target_finalized = true;
+    /// Finalizes target processing.
+    ///
+    /// Call this after all target chunks have been added. Fails if the
+    /// target is shorter than `set_target_size()` announced, and on the
+    /// same out-of-order calls as `add_target_chunk()`.
+    #[wasm_bindgen]
+    pub fn finalize_target(&mut self) -> Result<(), PatchlyError> {
+        self.complete_target(&mut Output::Queue)
     }
 
     /// Returns the current source size in bytes.
@@ -172,48 +758,92 @@ impl PatchBuilder {
         same_size && same_hash
     }
 
-    /// Checks if there's patch output available to read.
+    /// Returns the heap memory held by the source indexes in bytes.
     #[wasm_bindgen]
-    pub fn has_output(&self) -> bool {
-        // Has output if: header not written yet, OR there's data in buffer
-        !self.header_written || !self.output_buffer.is_empty()
+    pub fn index_memory_usage(&self) -> usize {
+        let fine = match &self.diff {
+            Some(diff) => diff.fine_index(),
+            None => self.fine_index.as_deref(),
+        };
+        self.block_index().memory_usage() + fine.map_or(0, BlockIndex::memory_usage)
     }
 
-    /// Returns the next chunk of patch output.
+    /// Returns the measured source index memory per block in bytes.
     ///
-    /// Returns serialized patch data ready to write to file.
+    /// Final after `finalize_source()`. Multiply by `source_size / 4096`
+    /// to estimate the index memory needed for a larger source.
     #[wasm_bindgen]
-    pub fn flush_output(&mut self, max_size: usize) -> Vec<u8> {
-        let mut result = Vec::with_capacity(max_size);
+    pub fn index_bytes_per_block(&self) -> f64 {
+        self.block_index().bytes_per_block()
+    }
 
-        // Write header first if not written
-        if !self.header_written {
-            let source_hash = self.source_hasher.finalize();
+    /// Returns the fraction of source blocks in the index (0.0 to 1.0).
+    ///
+    /// Below 1.0 when `set_index_memory_budget()` forced sampling.
+    #[wasm_bindgen]
+    pub fn index_coverage(&self) -> f64 {
+        self.block_index().coverage()
+    }
 
-            // Magic(4) + version(1) + chunk_size(4) + source_size(8) + source_hash(8) + target_size(8) = 33 bytes
-            result.extend_from_slice(b"PTCH");
-            result.push(1); // VERSION
-            result.extend_from_slice(&(self.chunk_size as u32).to_le_bytes());
-            result.extend_from_slice(&self.source_size.to_le_bytes());
-            result.extend_from_slice(&source_hash.to_le_bytes());
-            result.extend_from_slice(&self.target_total_size.to_le_bytes());
+    /// Checks if there's patch output available to read.
+    #[wasm_bindgen]
+    pub fn has_output(&self) -> bool {
+        // The header needs the final source hash and the target size
+        if self.phase == Phase::Source || self.target_length == TargetLength::Unset {
+            return false;
+        }
 
-            self.header_written = true;
+        // Deflate mode only knows the header once the target is expanded
+        if self.deflate_mode && self.phase != Phase::Finished {
+            return false;
         }
 
-        // Fill remaining space with output buffer data
-        let space_remaining = max_size - result.len();
-        let bytes_to_take = space_remaining.min(self.output_buffer.len());
+        // Has output if: header not written yet, OR there's data in buffer
+        !self.header_written || !self.output_buffer.is_empty()
+    }
+
+    /// Returns the next chunk of patch output, at most `max_size` bytes.
+    ///
+    /// Returns serialized patch data ready to write to file. Allocates a
+    /// new array per call; `flush_into()` avoids that.
+    ///
+    /// Fails before `finalize_source()` and `set_target_size()`, as the
+    /// header isn't known yet.
+    #[wasm_bindgen]
+    pub fn flush_output(&mut self, max_size: usize) -> Result<Vec<u8>, PatchlyError> {
+        self.check_header_known()?;
+        if !self.has_output() {
+            return Ok(Vec::new());
+        }
 
-        if bytes_to_take > 0 {
-            // Take from front of output_buffer
-            result.extend_from_slice(&self.output_buffer[..bytes_to_take]);
+        self.queue_header();
+        let mut result = vec![0; max_size.min(self.output_buffer.len())];
+        self.flush_into(&mut result)?;
+        Ok(result)
+    }
 
-            // Remove taken bytes from buffer
-            self.output_buffer = self.output_buffer[bytes_to_take..].to_vec();
+    /// Writes the next patch output into `out` and returns the bytes written.
+    ///
+    /// Fills as much of `out` as there is output for; `0` means nothing is
+    /// available yet (see `has_output()`). From JS, pass a reused
+    /// `Uint8Array` to stream a large patch without per-call allocation.
+    /// Fails like `flush_output()`.
+    #[wasm_bindgen]
+    pub fn flush_into(&mut self, out: &mut [u8]) -> Result<usize, PatchlyError> {
+        self.check_header_known()?;
+        if !self.has_output() {
+            return Ok(0);
         }
 
-        result
+        self.queue_header();
+        let len = out.len().min(self.output_buffer.len());
+        let (front, back) = self.output_buffer.as_slices();
+        let from_front = len.min(front.len());
+        out[..from_front].copy_from_slice(&front[..from_front]);
+        out[from_front..len].copy_from_slice(&back[..len - from_front]);
+
+        self.output_buffer.drain(..len);
+        Ok(len)
     }
 
     /// Returns the approximate pending output size.
@@ -222,124 +852,536 @@ impl PatchBuilder {
         self.output_buffer.len()
     }
 
+    /// Saves the builder so diffing can be resumed later.
+    ///
+    /// Only available after `finalize_source()`. The state holds the source
+    /// index, hashers and all buffered target and output data; output
+    /// already returned by `flush_output()` is not included. Resume with
+    /// `PatchBuilder.restore_state()` and continue feeding the target from
+    /// `target_size()` to get the same patch as an uninterrupted run.
+    #[wasm_bindgen]
+    pub fn save_state(&self) -> Result<Vec<u8>, PatchlyError> {
+        self.encode_state()
+    }
+
+    /// Recreates a builder from `save_state()` output.
+    #[wasm_bindgen]
+    pub fn restore_state(state: &[u8]) -> Result<PatchBuilder, PatchlyError> {
+        Self::decode_state(state)
+    }
+
     /// Resets the builder for reuse.
     #[wasm_bindgen]
     pub fn reset(&mut self) {
-        self.source_index = BlockIndex::with_block_size(self.chunk_size);
+        self.source_index = self.new_indexer();
+        self.fine_index = self.new_fine_index();
         self.source_hasher = HashBuilder::new();
         self.target_hasher = HashBuilder::new();
         self.source_size = 0;
         self.target_size = 0;
-        self.target_total_size = 0;
+        self.target_length = TargetLength::Unset;
+        self.metadata = PatchMetadata::default();
         self.diff = None;
-        self.source_finalized = false;
+        self.phase = Phase::Source;
         self.output_buffer.clear();
         self.header_written = false;
-        self.target_finalized = false;
+        self.deflate_source = Vec::new();
+        self.deflate_target = Vec::new();
+        self.deflate_recipe = DeflateRecipe::default();
+        self.source_filter = BcjFilter::encoder(self.source_filter.kind());
+        self.target_filter = BcjFilter::encoder(self.target_filter.kind());
     }
 }
 
-impl Default for PatchBuilder {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+impl PatchBuilder {
+    /// Verifies matches against source bytes read from `source`, or `None`
+    /// to turn verification off (see `set_source_handle()`).
+    pub fn set_source_access(
+        &mut self,
+        source: Option<Box<dyn SourceAccess>>,
+    ) -> error::Result<()> {
+        if self.phase == Phase::Finished {
+            return Err(PatchlyError::Misuse("finalize_target() was already called"));
+        }
+        if source.is_some() && (self.deflate_mode || self.source_filter.kind() != FilterKind::None)
+        {
+            return Err(PatchlyError::Misuse(
+                "Match verification is not available in deflate or filter mode",
+            ));
+        }
 
-/// Parses only the patch header (33 bytes) without parsing instructions.
-///
-/// Returns JSON with sourceSize, sourceHash, targetSize, chunkSize, and headerSize.
-/// TypeScript will parse instructions directly from OPFS to avoid loading entire patch.
-#[wasm_bindgen]
-pub fn parse_patch_header_only(header_data: &[u8]) -> Result<String, JsError> {
-    if header_data.len() < 33 {
-        return Err(JsError::new(
-            "Header data too small (need at least 33 bytes)",
-        ));
+        match &mut self.diff {
+            Some(diff) => diff.set_source_access(source),
+            None => self.source_access = source,
+        }
+        Ok(())
     }
 
-    // Validate magic
-    if &header_data[0..4] != b"PTCH" {
-        return Err(JsError::new("Invalid patch file: bad magic bytes"));
+    /// Creates an empty source indexer for the configured mode and options.
+    fn new_indexer(&self) -> SourceIndexer {
+        let mut indexer = SourceIndexer::new(self.tar_mode, self.chunk_size);
+        indexer.set_memory_budget(self.index_budget);
+        indexer.set_rolling_hash(self.rolling_hash);
+        indexer
     }
 
-    // Validate version
-    if header_data[4] != 1 {
-        return Err(JsError::new(&format!(
-            "Unsupported patch version: {} (expected 1)",
-            header_data[4]
-        )));
+    /// Adds a chunk of target data, writing patch output directly to `sink`.
+    ///
+    /// Native alternative to `add_target_chunk()` that skips the output
+    /// queue. The header (set the target size first) and anything still
+    /// queued are written before the chunk's output.
+    ///
+    /// Instructions are serialized straight into `sink`. If a write fails
+    /// the patch is incomplete and the builder needs `reset()`.
+    pub fn add_target_chunk_into(
+        &mut self,
+        chunk: &[u8],
+        sink: &mut dyn PatchSink,
+    ) -> error::Result<()> {
+        self.ingest_target(chunk, &mut Output::Sink(sink))
     }
 
-    // Parse fields
-    let chunk_size = u32::from_le_bytes([
-        header_data[5],
-        header_data[6],
-        header_data[7],
-        header_data[8],
-    ]);
-    let source_size = u64::from_le_bytes([
-        header_data[9],
-        header_data[10],
-        header_data[11],
-        header_data[12],
-        header_data[13],
-        header_data[14],
-        header_data[15],
-        header_data[16],
-    ]);
-    let source_hash = u64::from_le_bytes([
-        header_data[17],
-        header_data[18],
-        header_data[19],
-        header_data[20],
-        header_data[21],
-        header_data[22],
-        header_data[23],
-        header_data[24],
-    ]);
-    let target_size = u64::from_le_bytes([
-        header_data[25],
-        header_data[26],
-        header_data[27],
-        header_data[28],
-        header_data[29],
-        header_data[30],
-        header_data[31],
-        header_data[32],
-    ]);
-
-    Ok(format!(
-        "{{\"sourceSize\":{},\"sourceHash\":\"{:016x}\",\"targetSize\":{},\"chunkSize\":{},\"headerSize\":33}}",
-        source_size, source_hash, target_size, chunk_size
-    ))
-}
+    /// Finalizes target processing, writing the rest of the patch to `sink`.
+    ///
+    /// After this the whole patch has been written; nothing is left queued.
+    pub fn finalize_target_into(&mut self, sink: &mut dyn PatchSink) -> error::Result<()> {
+        let mut out = Output::Sink(sink);
+        self.complete_target(&mut out)?;
+        if let Output::Sink(sink) = out {
+            self.drain_queue(sink)?;
+        }
+        Ok(())
+    }
 
-/// Returns the library version.
-#[wasm_bindgen]
-pub fn version() -> String {
-    env!("CARGO_PKG_VERSION").to_string()
-}
+    /// Hashes, buffers or diffs a target chunk.
+    fn ingest_target(&mut self, chunk: &[u8], out: &mut Output) -> error::Result<()> {
+        let expected = self.check_target_phase()?;
+        let actual = self.target_size + chunk.len() as u64;
+        if let Some(expected) = expected.filter(|&expected| actual > expected) {
+            return Err(PatchlyError::TargetSizeMismatch { expected, actual });
+        }
+        if self.deflate_mode {
+            check_deflate_size(self.deflate_target.len(), chunk)?;
+        }
 
-/// WASM-bindable streaming hash builder.
-///
-/// Use this to calculate hash incrementally from JavaScript without BigInt allocations.
-#[wasm_bindgen]
-pub struct StreamingHasher {
-    /// Inner hash builder.
-    inner: HashBuilder,
-}
+        self.target_hasher.update(chunk);
+        self.target_size += chunk.len() as u64;
 
-#[wasm_bindgen]
-impl StreamingHasher {
-    /// Creates a new hash builder.
-    #[wasm_bindgen(constructor)]
-    pub fn new() -> Self {
-        Self {
-            inner: HashBuilder::new(),
+        if self.deflate_mode {
+            self.deflate_target.extend_from_slice(chunk);
+            return Ok(());
         }
+
+        self.diff_target(chunk, out)?;
+        Ok(())
     }
 
-    /// Updates the hash with a chunk of data.
+    /// Diffs the remaining target data and flushes the diff engine.
+    fn complete_target(&mut self, out: &mut Output) -> error::Result<()> {
+        let expected = self.check_target_phase()?;
+        if let Some(expected) = expected.filter(|&expected| self.target_size != expected) {
+            return Err(PatchlyError::TargetSizeMismatch {
+                expected,
+                actual: self.target_size,
+            });
+        }
+
+        if self.deflate_mode {
+            let streams = deflate::find_streams(&self.deflate_target, true);
+            let expanded = deflate::expand(&self.deflate_target, &streams)?;
+            self.deflate_target = Vec::new();
+            // The header records the recipe, so it is set before any output
+            self.deflate_recipe.target = streams;
+            self.diff_target(&expanded, out)?;
+        }
+
+        let tail = self.target_filter.finish();
+        self.feed_diff(&tail, out)?;
+        self.run_diff(out, |diff, sink| diff.finalize(sink))?;
+        if self.target_length == TargetLength::Unknown {
+            self.write_trailer(out)?;
+        }
+
+        self.phase = Phase::Finished;
+        Ok(())
+    }
+
+    /// Appends the trailer with the final target size and hash to `out`.
+    fn write_trailer(&mut self, out: &mut Output) -> error::Result<()> {
+        let trailer = PatchTrailer {
+            target_size: self.target_size,
+            target_hash: self.target_hasher.finalize(),
+        };
+
+        match out {
+            Output::Queue => self.output_buffer.extend(trailer.to_bytes()),
+            Output::Sink(sink) => {
+                self.drain_queue(*sink)?;
+                sink.write_patch(&trailer.to_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Announces the target size, before any output was written.
+    fn set_target_length(&mut self, length: TargetLength) -> error::Result<()> {
+        if self.header_written || self.phase == Phase::Finished {
+            return Err(PatchlyError::Misuse(
+                "Target size set after the header was written",
+            ));
+        }
+
+        self.target_length = length;
+        Ok(())
+    }
+
+    /// Checks that metadata can still go into the header.
+    fn check_metadata_open(&self) -> error::Result<()> {
+        if self.header_written || self.phase == Phase::Finished {
+            return Err(PatchlyError::Misuse(
+                "Metadata set after the header was written",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that target data is accepted and returns the target size,
+    /// `None` if it's unknown up front.
+    fn check_target_phase(&self) -> error::Result<Option<u64>> {
+        match (self.phase, self.target_length) {
+            (Phase::Source, _) => Err(PatchlyError::Misuse(
+                "finalize_source() must be called before target data",
+            )),
+            (Phase::Finished, _) => {
+                Err(PatchlyError::Misuse("finalize_target() was already called"))
+            }
+            (Phase::Target, TargetLength::Unset) => Err(PatchlyError::Misuse(
+                "set_target_size() must be called before target data",
+            )),
+            (Phase::Target, TargetLength::Known(size)) => Ok(Some(size)),
+            (Phase::Target, TargetLength::Unknown) => Ok(None),
+        }
+    }
+
+    /// Checks that the header fields are final before output is flushed.
+    fn check_header_known(&self) -> error::Result<()> {
+        if self.phase == Phase::Source {
+            return Err(PatchlyError::Misuse(
+                "Output flushed before finalize_source()",
+            ));
+        }
+        if self.target_length == TargetLength::Unset {
+            return Err(PatchlyError::Misuse(
+                "Output flushed before set_target_size()",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that diff settings can still change.
+    fn check_configurable(&self) -> error::Result<()> {
+        if self.phase != Phase::Source || self.source_size != 0 {
+            return Err(PatchlyError::Misuse(
+                "Diff settings changed after add_source_chunk()",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Runs `step` on the diff engine with `out` as its sink.
+    ///
+    /// In sink mode the header and anything still queued are written first.
+    /// A failed write leaves the patch incomplete, so the builder is finished
+    /// and needs `reset()`.
+    fn run_diff(
+        &mut self,
+        out: &mut Output,
+        step: impl FnOnce(&mut DiffEngine, &mut dyn PatchSink) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if self.diff.is_none() {
+            return Ok(());
+        }
+
+        let result = match out {
+            Output::Queue => {
+                let diff = self.diff.as_mut().expect("checked above");
+                step(diff, &mut self.output_buffer)
+            }
+            Output::Sink(sink) => self.drain_queue(*sink).and_then(|()| {
+                let diff = self.diff.as_mut().expect("checked above");
+                step(diff, *sink)
+            }),
+        };
+        if result.is_err() {
+            self.phase = Phase::Finished;
+        }
+        result
+    }
+
+    /// Writes the header, if not written yet, and the queued output to `sink`.
+    ///
+    /// A failed write may already have passed part of the queue to `sink`,
+    /// so the queue is dropped either way and a failure finishes the builder;
+    /// it then needs `reset()`.
+    fn drain_queue(&mut self, sink: &mut dyn PatchSink) -> io::Result<()> {
+        self.queue_header();
+        let result = sink.write_patch(self.output_buffer.make_contiguous());
+        self.output_buffer.clear();
+        if result.is_err() {
+            self.phase = Phase::Finished;
+        }
+        result
+    }
+
+    /// Queues the patch header in front of the output if not done yet.
+    fn queue_header(&mut self) {
+        if self.header_written {
+            return;
+        }
+
+        let mut header = PatchHeader::new(
+            self.chunk_size as u32,
+            self.source_size,
+            self.source_hasher.finalize(),
+            0,
+        );
+        match self.target_length {
+            TargetLength::Known(size) => header.target_size = size,
+            TargetLength::Unknown => header.flags |= FLAG_TRAILER,
+            TargetLength::Unset => unreachable!("the header is queued once the target size is set"),
+        }
+        if self.deflate_mode {
+            header.flags |= FLAG_DEFLATE;
+            header.set_extension(EXT_DEFLATE, self.deflate_recipe.to_bytes());
+        }
+        if self.source_filter.kind() != FilterKind::None {
+            header.flags |= FLAG_FILTER;
+            header.set_extension(EXT_FILTER, vec![self.source_filter.kind().id()]);
+        }
+        if self.rolling_hash != RollingHashKind::Adler {
+            header.set_extension(EXT_ROLLING_HASH, vec![self.rolling_hash.id()]);
+        }
+        if !self.metadata.is_empty() {
+            let mut metadata = self.metadata.clone();
+            metadata.generator = Some(GENERATOR.to_string());
+            header.set_metadata(&metadata);
+        }
+
+        for &byte in header.to_bytes().iter().rev() {
+            self.output_buffer.push_front(byte);
+        }
+        self.header_written = true;
+    }
+
+    /// Recreates the empty source indexes from the current settings, so
+    /// setters take effect in any order.
+    fn rebuild_indexes(&mut self) {
+        self.source_index = self.new_indexer();
+        self.fine_index = self.new_fine_index();
+    }
+
+    /// Creates an empty fine-grained index if one is configured.
+    fn new_fine_index(&self) -> Option<Box<BlockIndex>> {
+        if self.fine_block_size == 0 {
+            return None;
+        }
+
+        let mut index = BlockIndex::with_block_size(self.fine_block_size);
+        index.set_memory_budget(self.index_budget);
+        index.set_rolling_hash(self.rolling_hash);
+        Some(Box::new(index))
+    }
+
+    /// Returns the source block index, before or after `finalize_source()`.
+    fn block_index(&self) -> &BlockIndex {
+        match &self.diff {
+            Some(diff) => diff.index(),
+            None => self.source_index.index(),
+        }
+    }
+
+    /// Serializes the builder state (see `save_state()`).
+    pub fn encode_state(&self) -> error::Result<Vec<u8>> {
+        let diff = match &self.diff {
+            Some(diff) if self.phase != Phase::Source => diff,
+            _ => {
+                return Err(PatchlyError::Misuse(
+                    "Builder state can only be saved after finalize_source()",
+                ))
+            }
+        };
+
+        let mut writer = StateWriter::new();
+        writer.put_header();
+
+        writer.put_u64(self.chunk_size as u64);
+        writer.put_bool(self.tar_mode);
+        writer.put_u64(self.parallel_threads as u64);
+        writer.put_u64(self.index_budget as u64);
+        writer.put_u8(self.rolling_hash.id());
+        writer.put_u64(self.fine_block_size as u64);
+        writer.put_u64(self.max_insert_size as u64);
+        writer.put_u64(self.output_high_water_mark as u64);
+        writer.put_bool(self.deflate_mode);
+        writer.put_u64(self.source_size);
+        writer.put_u64(self.target_size);
+        match self.target_length {
+            TargetLength::Unset => writer.put_u8(0),
+            TargetLength::Known(size) => {
+                writer.put_u8(1);
+                writer.put_u64(size);
+            }
+            TargetLength::Unknown => writer.put_u8(2),
+        }
+        self.metadata.write_state(&mut writer);
+        writer.put_u64(self.source_hasher.finalize());
+        writer.put_u64(self.target_hasher.finalize());
+        writer.put_bool(self.header_written);
+        writer.put_bool(self.phase == Phase::Finished);
+        let (front, back) = self.output_buffer.as_slices();
+        writer.put_bytes(&[front, back].concat());
+        writer.put_bytes(&self.deflate_target);
+        writer.put_bytes(&self.deflate_recipe.to_bytes());
+        writer.put_u8(self.source_filter.kind().id());
+        self.target_filter.write_state(&mut writer);
+        diff.write_state(&mut writer)?;
+
+        Ok(writer.into_bytes())
+    }
+
+    /// Deserializes builder state written by `encode_state()`.
+    pub fn decode_state(state: &[u8]) -> error::Result<Self> {
+        let mut reader = StateReader::new(state);
+        reader.header()?;
+
+        let chunk_size = reader.u64()? as usize;
+        let tar_mode = reader.bool()?;
+
+        let mut builder = Self::new();
+        builder.chunk_size = chunk_size;
+        builder.tar_mode = tar_mode;
+        builder.parallel_threads = reader.u64()? as usize;
+        if builder.parallel_threads > MAX_THREADS {
+            return Err(invalid("Invalid thread count in builder state").into());
+        }
+        builder.index_budget = reader.u64()? as usize;
+        builder.rolling_hash = RollingHashKind::from_id(reader.u8()?)
+            .ok_or_else(|| invalid("Invalid rolling hash in builder state"))?;
+        builder.fine_block_size = reader.u64()? as usize;
+        builder.max_insert_size = reader.u64()? as usize;
+        builder.output_high_water_mark = reader.u64()? as usize;
+        builder.source_index = builder.new_indexer();
+        builder.fine_index = builder.new_fine_index();
+        builder.deflate_mode = reader.bool()?;
+        builder.source_size = reader.u64()?;
+        builder.target_size = reader.u64()?;
+        builder.target_length = match reader.u8()? {
+            0 => TargetLength::Unset,
+            1 => TargetLength::Known(reader.u64()?),
+            2 => TargetLength::Unknown,
+            _ => return Err(invalid("Invalid target size in builder state").into()),
+        };
+        builder.metadata = PatchMetadata::read_state(&mut reader)?;
+        builder.source_hasher = HashBuilder::from_state(reader.u64()?);
+        builder.target_hasher = HashBuilder::from_state(reader.u64()?);
+        builder.header_written = reader.bool()?;
+        builder.phase = if reader.bool()? {
+            Phase::Finished
+        } else {
+            Phase::Target
+        };
+        builder.output_buffer = reader.bytes()?.into();
+        builder.deflate_target = reader.bytes()?;
+        builder.deflate_recipe = DeflateRecipe::from_bytes(&reader.bytes()?)?;
+
+        let source_filter = FilterKind::from_id(reader.u8()?)
+            .ok_or_else(|| invalid("Invalid filter ID in builder state"))?;
+        builder.source_filter = BcjFilter::encoder(source_filter);
+        builder.target_filter = BcjFilter::read_state(&mut reader)?;
+        builder.diff = Some(DiffEngine::read_state(&mut reader)?);
+
+        reader.finish()?;
+        Ok(builder)
+    }
+
+    /// Filters source data and adds it to the index.
+    fn index_source(&mut self, data: &[u8]) {
+        let filtered = self.source_filter.process(data);
+        self.add_filtered_source(&filtered);
+    }
+
+    /// Adds already-filtered source data to the indexes.
+    fn add_filtered_source(&mut self, data: &[u8]) {
+        self.source_index.add_chunk(data);
+        if let Some(fine) = &mut self.fine_index {
+            fine.add_chunk(data);
+        }
+    }
+
+    /// Filters target data and diffs it.
+    fn diff_target(&mut self, data: &[u8], out: &mut Output) -> io::Result<()> {
+        let filtered = self.target_filter.process(data);
+        self.feed_diff(&filtered, out)
+    }
+
+    /// Diffs already-filtered target data, writing the output to `out`.
+    fn feed_diff(&mut self, data: &[u8], out: &mut Output) -> io::Result<()> {
+        self.run_diff(out, |diff, sink| diff.process_target_chunk(data, sink))
+    }
+}
+
+impl Default for PatchBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fails if buffering `chunk` after `buffered` bytes would exceed the
+/// deflate mode size limit.
+fn check_deflate_size(buffered: usize, chunk: &[u8]) -> error::Result<()> {
+    if buffered.saturating_add(chunk.len()) > deflate::MAX_DEFLATE_SIZE {
+        return Err(PatchlyError::InvalidData(format!(
+            "Deflate mode supports files up to {} bytes",
+            deflate::MAX_DEFLATE_SIZE
+        )));
+    }
+    Ok(())
+}
+
+/// Detects the executable filter ID for a file from its first bytes.
+///
+/// Returns `1` for x86/x86-64 ELF, `2` for AArch64 ELF and `0` otherwise.
+#[wasm_bindgen]
+pub fn detect_filter(header_data: &[u8]) -> u8 {
+    FilterKind::detect(header_data).id()
+}
+
+/// Returns the library version.
+#[wasm_bindgen]
+pub fn version() -> String {
+    env!("CARGO_PKG_VERSION").to_string()
+}
+
+/// WASM-bindable streaming hash builder.
+///
+/// Use this to calculate hash incrementally from JavaScript without BigInt allocations.
+#[wasm_bindgen]
+pub struct StreamingHasher {
+    /// Inner hash builder.
+    inner: HashBuilder,
+}
+
+#[wasm_bindgen]
+impl StreamingHasher {
+    /// Creates a new hash builder.
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            inner: HashBuilder::new(),
+        }
+    }
+
+    /// Updates the hash with a chunk of data.
     pub fn update(&mut self, data: &[u8]) {
         self.inner.update(data);
     }
@@ -355,6 +1397,146 @@ impl StreamingHasher {
     }
 }
 
+impl Default for StreamingHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// WASM-bindable streaming executable filter.
+///
+/// Lets a JS applier undo a patch's filter (see `PatchHeader.filter_id`):
+/// run the source through an encoder before applying, and the rebuilt
+/// target through a decoder.
+#[wasm_bindgen]
+pub struct ExecutableFilter {
+    /// Inner BCJ filter.
+    inner: BcjFilter,
+}
+
+#[wasm_bindgen]
+impl ExecutableFilter {
+    /// Creates a filter that normalizes addresses, as done to the source
+    /// before diffing. Unknown IDs fail with `INVALID_DATA`.
+    pub fn encoder(filter_id: u8) -> Result<ExecutableFilter, PatchlyError> {
+        Ok(Self {
+            inner: BcjFilter::encoder(filter_kind(filter_id)?),
+        })
+    }
+
+    /// Creates a filter that restores the original bytes of a filtered
+    /// target. Unknown IDs fail with `INVALID_DATA`.
+    pub fn decoder(filter_id: u8) -> Result<ExecutableFilter, PatchlyError> {
+        Ok(Self {
+            inner: BcjFilter::decoder(filter_kind(filter_id)?),
+        })
+    }
+
+    /// Filters a chunk; up to 4 bytes are held back for the next one.
+    pub fn process(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.inner.process(chunk)
+    }
+
+    /// Returns the bytes held back at the end of the stream.
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.inner.finish()
+    }
+}
+
+/// Looks up a filter by its header ID, failing with `INVALID_DATA`.
+fn filter_kind(filter_id: u8) -> error::Result<FilterKind> {
+    FilterKind::from_id(filter_id)
+        .ok_or_else(|| PatchlyError::InvalidData(format!("Unknown filter ID: {}", filter_id)))
+}
+
+/// WASM-bindable streaming patch applier with resumable checkpoints.
+///
+/// Reads COPY data through an OPFS `FileSystemSyncAccessHandle` of the
+/// source and rebuilds the target from the instruction bytes fed to
+/// `apply_chunk()`. Checkpoints describe the instruction stream and its
+/// direct output, so for patches with deflate or filter extensions they
+/// cover the target before those are undone.
+#[wasm_bindgen]
+pub struct StreamingApplier {
+    /// Inner applier.
+    inner: PatchApplier<JsSyncAccessSource>,
+}
+
+#[wasm_bindgen]
+impl StreamingApplier {
+    /// Creates an applier reading COPY data from `source_handle`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(source_handle: JsValue) -> Result<StreamingApplier, PatchlyError> {
+        Ok(Self {
+            inner: PatchApplier::new(JsSyncAccessSource::new(source_handle)?),
+        })
+    }
+
+    /// Resumes from a checkpoint returned by `take_checkpoint()`.
+    ///
+    /// Verifies the target already written through `written_handle`, then
+    /// continues at `patch_offset()` (counted after the header). Truncate
+    /// the target to `target_written()` bytes before appending output.
+    /// Fails with `CHECKPOINT_MISMATCH` if the written target differs.
+    pub fn resume(
+        source_handle: JsValue,
+        checkpoint: &[u8],
+        written_handle: JsValue,
+    ) -> Result<StreamingApplier, PatchlyError> {
+        let checkpoint = ApplyCheckpoint::from_bytes(checkpoint)?;
+        let mut written = JsSyncAccessSource::new(written_handle)?;
+        let source = JsSyncAccessSource::new(source_handle)?;
+        Ok(Self {
+            inner: PatchApplier::resume(source, &checkpoint, &mut written)?,
+        })
+    }
+
+    /// Records a checkpoint every `interval` target bytes (0 disables).
+    pub fn set_checkpoint_interval(&mut self, interval: u64) {
+        self.inner.set_checkpoint_interval(interval);
+    }
+
+    /// Applies a chunk of instruction bytes.
+    ///
+    /// Returns `true` if it paused inside a large COPY with about 1 MiB of
+    /// output waiting. The rest of `chunk` is kept: take the output, then
+    /// call again with an empty chunk until it returns `false`.
+    pub fn apply_chunk(&mut self, chunk: &[u8]) -> Result<bool, PatchlyError> {
+        self.inner.apply_chunk(chunk)
+    }
+
+    /// Takes the target bytes rebuilt so far.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.inner.take_output()
+    }
+
+    /// Takes the latest serialized checkpoint, if one was recorded.
+    ///
+    /// The checkpoint can cover output still waiting in the applier, so
+    /// call `take_output()` and persist its bytes before taking it, in that
+    /// order.
+    pub fn take_checkpoint(&mut self) -> Option<Vec<u8>> {
+        self.inner
+            .take_checkpoint()
+            .map(|checkpoint| checkpoint.to_bytes())
+    }
+
+    /// Returns the number of instruction bytes consumed.
+    pub fn patch_offset(&self) -> u64 {
+        self.inner.patch_offset()
+    }
+
+    /// Returns the total number of target bytes produced.
+    pub fn target_written(&self) -> u64 {
+        self.inner.target_written()
+    }
+
+    /// Checks that the patch didn't end inside an instruction.
+    pub fn finish(&self) -> Result<(), PatchlyError> {
+        self.inner.finish()
+    }
+}
+
 /// Calculates hash of data and returns it as a hex string.
 #[wasm_bindgen]
 pub fn hash_data(data: &[u8]) -> String {
@@ -370,9 +1552,10 @@ mod tests {
         let data = b"identical content here";
 
         let mut builder = PatchBuilder::new();
-        builder.add_source_chunk(data);
-        builder.finalize_source();
-        builder.add_target_chunk(data);
+        builder.add_source_chunk(data).unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(data.len() as u64).unwrap();
+        builder.add_target_chunk(data).unwrap();
 
         assert!(builder.are_files_identical());
     }
@@ -383,19 +1566,601 @@ mod tests {
         let target = b"modified content";
 
         let mut builder = PatchBuilder::new();
-        builder.add_source_chunk(source);
-        builder.finalize_source();
-        builder.add_target_chunk(target);
+        builder.add_source_chunk(source).unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(target.len() as u64).unwrap();
+        builder.add_target_chunk(target).unwrap();
 
         assert!(!builder.are_files_identical());
     }
 
+    /// Builds a patch, optionally suspending and restoring after `split`
+    /// target bytes.
+    fn build_with_suspend(
+        source: &[u8],
+        target: &[u8],
+        configure: fn(&mut PatchBuilder),
+        split: Option<usize>,
+    ) -> Vec<u8> {
+        let mut builder = PatchBuilder::new();
+        configure(&mut builder);
+        builder.add_source_chunk(source).unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(target.len() as u64).unwrap();
+
+        let mut patch = Vec::new();
+        let split = split.unwrap_or(target.len());
+        for chunk in target[..split].chunks(777) {
+            builder.add_target_chunk(chunk).unwrap();
+        }
+        patch.extend(builder.flush_output(1000).unwrap());
+
+        if split < target.len() {
+            let state = builder.save_state().unwrap();
+            builder = PatchBuilder::restore_state(&state).unwrap();
+            assert_eq!(builder.target_size(), split);
+        }
+
+        for chunk in target[split..].chunks(777) {
+            builder.add_target_chunk(chunk).unwrap();
+        }
+        builder.finalize_target().unwrap();
+        while builder.has_output() {
+            patch.extend(builder.flush_output(64 * 1024).unwrap());
+        }
+        patch
+    }
+
+    #[test]
+    fn test_suspend_resume_is_byte_identical() {
+        let source: Vec<u8> = (0..60_000u32).map(|i| (i * 31 % 253) as u8).collect();
+        let mut target = source.clone();
+        target.splice(10_000..10_000, (0..3000u32).map(|i| (i % 7) as u8));
+        target[40_000] ^= 0xFF;
+
+        let configs: [fn(&mut PatchBuilder); 7] = [
+            |_| {},
+            |builder| builder.set_filter(1).unwrap(),
+            |builder| builder.set_tar_mode(true).unwrap(),
+            |builder| builder.set_parallel_threads(4).unwrap(),
+            |builder| builder.set_rolling_hash(1).unwrap(),
+            |builder| builder.set_fine_block_size(64).unwrap(),
+            |builder| {
+                builder.set_fine_block_size(64).unwrap();
+                builder.set_parallel_threads(4).unwrap();
+            },
+        ];
+
+        for configure in configs {
+            let expected = build_with_suspend(&source, &target, configure, None);
+            for split in [0, 5000, 10_500, 41_000, target.len() - 1] {
+                let resumed = build_with_suspend(&source, &target, configure, Some(split));
+                assert_eq!(resumed, expected, "split {}", split);
+            }
+        }
+    }
+
+    #[test]
+    fn test_small_flushes_match_large_flush() {
+        let source: Vec<u8> = (0..30_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut target = source.clone();
+        target.splice(5000..5000, [9u8; 700]);
+        let expected = build_with_suspend(&source, &target, |_| {}, None);
+
+        let run = |flush: &mut dyn FnMut(&mut PatchBuilder, &mut Vec<u8>)| {
+            let mut builder = PatchBuilder::new();
+            builder.add_source_chunk(&source).unwrap();
+            builder.finalize_source().unwrap();
+            builder.set_target_size(target.len() as u64).unwrap();
+
+            let mut patch = Vec::new();
+            for chunk in target.chunks(4000) {
+                builder.add_target_chunk(chunk).unwrap();
+                flush(&mut builder, &mut patch);
+            }
+            builder.finalize_target().unwrap();
+            while builder.has_output() {
+                flush(&mut builder, &mut patch);
+            }
+            patch
+        };
+
+        // Flushes smaller than the header
+        let patch = run(&mut |builder, patch| patch.extend(builder.flush_output(5).unwrap()));
+        assert_eq!(patch, expected);
+
+        let mut buf = [0u8; 7];
+        let patch = run(&mut |builder, patch| {
+            let written = builder.flush_into(&mut buf).unwrap();
+            patch.extend_from_slice(&buf[..written]);
+        });
+        assert_eq!(patch, expected);
+    }
+
+    #[test]
+    fn test_high_water_mark_bounds_pending_output() {
+        use crate::apply::patch_applier::apply_patch;
+
+        let source = vec![0u8; 8192];
+        let target: Vec<u8> = (0..200_000u32).map(|i| (i % 249 + 1) as u8).collect();
+
+        let mut builder = PatchBuilder::new();
+        builder.set_output_high_water_mark(10_000);
+        builder.set_max_insert_size(4096);
+        builder.add_source_chunk(&source).unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(target.len() as u64).unwrap();
+
+        let mut patch = Vec::new();
+        let mut drains = 0;
+        for chunk in target.chunks(1000) {
+            if builder.add_target_chunk(chunk).unwrap() == TargetStatus::Drain {
+                drains += 1;
+                assert!(builder.pending_output_size() < 10_000 + 1000 + 4096 + 5);
+                while builder.target_status() == TargetStatus::Drain {
+                    patch.extend(builder.flush_output(3000).unwrap());
+                }
+            }
+        }
+        builder.finalize_target().unwrap();
+        while builder.has_output() {
+            patch.extend(builder.flush_output(64 * 1024).unwrap());
+        }
+
+        assert!(drains > 10, "{}", drains);
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn test_sink_output_matches_queue() {
+        use crate::format::patch_format::HEADER_SIZE;
+
+        let source: Vec<u8> = (0..40_000u32).map(|i| (i * 13 % 241) as u8).collect();
+        let mut target = source[..30_000].to_vec();
+        target.splice(12_000..12_000, [5u8; 900]);
+
+        let configs: [fn(&mut PatchBuilder); 3] = [
+            |_| {},
+            |builder| builder.set_parallel_threads(2).unwrap(),
+            |builder| builder.set_filter(1).unwrap(),
+        ];
+        for configure in configs {
+            let expected = build_with_suspend(&source, &target, configure, None);
+
+            let mut builder = PatchBuilder::new();
+            configure(&mut builder);
+            builder.add_source_chunk(&source).unwrap();
+            builder.finalize_source().unwrap();
+            builder.set_target_size(target.len() as u64).unwrap();
+
+            let mut patch = Vec::new();
+            for chunk in target.chunks(5000) {
+                builder.add_target_chunk_into(chunk, &mut patch).unwrap();
+            }
+            builder.finalize_target_into(&mut patch).unwrap();
+
+            assert_eq!(patch, expected);
+            assert!(!builder.has_output());
+        }
+
+        // Empty target: the header is still written
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(&source).unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(0).unwrap();
+        let mut patch = Vec::new();
+        builder.finalize_target_into(&mut patch).unwrap();
+        assert_eq!(patch.len(), HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_sink_errors_are_returned() {
+        struct Full;
+
+        impl io::Write for Full {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut builder = PatchBuilder::new();
+        builder.set_max_insert_size(1000);
+        builder.add_source_chunk(&[1u8; 10_000]).unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(10_000).unwrap();
+        let err = builder
+            .add_target_chunk_into(&[2u8; 10_000], &mut Full)
+            .unwrap_err();
+        assert!(matches!(err, PatchlyError::Io(err) if err.kind() == io::ErrorKind::StorageFull));
+
+        // The patch is incomplete, so the builder can't continue
+        assert!(is_misuse(
+            builder.add_target_chunk_into(&[2u8; 10], &mut Vec::new())
+        ));
+    }
+
+    #[test]
+    fn test_failed_queue_write_finishes_builder() {
+        /// Accepts a few bytes, then fails.
+        struct Short {
+            data: Vec<u8>,
+        }
+
+        impl io::Write for Short {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if !self.data.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"));
+                }
+                let len = buf.len().min(5);
+                self.data.extend_from_slice(&buf[..len]);
+                Ok(len)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(&[1u8; 10_000]).unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(20_000).unwrap();
+        builder.add_target_chunk(&[2u8; 10_000]).unwrap();
+        assert!(builder.has_output());
+
+        let mut sink = Short { data: Vec::new() };
+        assert!(builder
+            .add_target_chunk_into(&[2u8; 10_000], &mut sink)
+            .is_err());
+        assert_eq!(sink.data.len(), 5);
+
+        // The written prefix can't be taken back, so nothing is offered twice
+        assert!(!builder.has_output());
+        assert!(is_misuse(
+            builder.add_target_chunk_into(&[2u8; 10], &mut sink)
+        ));
+        assert!(is_misuse(builder.finalize_target_into(&mut sink)));
+        assert_eq!(sink.data.len(), 5);
+    }
+
+    #[test]
+    fn test_unknown_target_size_writes_trailer() {
+        use crate::apply::patch_applier::apply_patch;
+        use crate::format::patch_format::{HEADER_SIZE, TRAILER_SIZE};
+
+        let source: Vec<u8> = (0..30_000u32).map(|i| (i * 11 % 239) as u8).collect();
+        let mut target = source.clone();
+        target.splice(9000..9000, [3u8; 600]);
+        let known = build_with_suspend(&source, &target, |_| {}, None);
+
+        let start = |builder: &mut PatchBuilder| {
+            builder.add_source_chunk(&source).unwrap();
+            builder.finalize_source().unwrap();
+            builder.set_target_size_unknown().unwrap();
+        };
+
+        // Queued output, suspended halfway
+        let mut builder = PatchBuilder::new();
+        start(&mut builder);
+        let mut patch = Vec::new();
+        for chunk in target[..15_000].chunks(4000) {
+            builder.add_target_chunk(chunk).unwrap();
+        }
+        patch.extend(builder.flush_output(1000).unwrap());
+        let mut builder = PatchBuilder::restore_state(&builder.save_state().unwrap()).unwrap();
+        for chunk in target[15_000..].chunks(4000) {
+            builder.add_target_chunk(chunk).unwrap();
+        }
+        builder.finalize_target().unwrap();
+        while builder.has_output() {
+            patch.extend(builder.flush_output(64 * 1024).unwrap());
+        }
+
+        let mut header = PatchHeader::parse(&patch).unwrap();
+        assert!(header.has_trailer());
+        assert_eq!(header.target_size, 0);
+        header
+            .read_trailer(&patch[patch.len() - TRAILER_SIZE..])
+            .unwrap();
+        assert_eq!(header.target_size, target.len() as u64);
+        assert_eq!(header.target_hash, Some(calculate_hash(&target)));
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+
+        // Same instructions as with a known size
+        let body = &patch[header.encoded_len()..patch.len() - TRAILER_SIZE];
+        assert_eq!(body, &known[HEADER_SIZE..]);
+
+        // Sink output matches
+        let mut builder = PatchBuilder::new();
+        start(&mut builder);
+        let mut sunk = Vec::new();
+        for chunk in target.chunks(4000) {
+            builder.add_target_chunk_into(chunk, &mut sunk).unwrap();
+        }
+        builder.finalize_target_into(&mut sunk).unwrap();
+        assert_eq!(sunk, patch);
+
+        // Deflate mode only knows the header at the end
+        let mut builder = PatchBuilder::new();
+        builder.set_deflate_mode(true).unwrap();
+        start(&mut builder);
+        builder.add_target_chunk(&target).unwrap();
+        builder.finalize_target().unwrap();
+        let patch = builder.flush_output(usize::MAX).unwrap();
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn test_metadata_in_header() {
+        use crate::apply::patch_applier::apply_patch;
+        use crate::format::patch_format::{HEADER_SIZE, VERSION, VERSION_2};
+
+        let source: Vec<u8> = (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut target = source.clone();
+        target[5000] ^= 0xFF;
+        let plain = build_with_suspend(&source, &target, |_| {}, None);
+        assert_eq!(plain[4], VERSION);
+
+        let mut builder = PatchBuilder::new();
+        builder.set_source_name("app-1.0.bin").unwrap();
+        builder.add_source_chunk(&source).unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_name("app-1.1.bin").unwrap();
+        builder.set_created_at(1_760_000_000_000).unwrap();
+        builder.set_metadata("channel", "beta").unwrap();
+        assert!(matches!(
+            builder.set_metadata(&"k".repeat(70_000), "x"),
+            Err(PatchlyError::InvalidData(_))
+        ));
+        builder.set_target_size(target.len() as u64).unwrap();
+
+        // Metadata survives a suspend
+        let mut builder = PatchBuilder::restore_state(&builder.save_state().unwrap()).unwrap();
+        builder.add_target_chunk(&target).unwrap();
+        let mut patch = builder.flush_output(usize::MAX).unwrap();
+        assert!(is_misuse(builder.set_metadata("late", "x")));
+        builder.finalize_target().unwrap();
+        patch.extend(builder.flush_output(usize::MAX).unwrap());
+
+        let header = PatchHeader::parse(&patch).unwrap();
+        assert_eq!(header.version(), VERSION_2);
+        let metadata = header.metadata().unwrap();
+        assert_eq!(metadata.source_name.as_deref(), Some("app-1.0.bin"));
+        assert_eq!(metadata.target_name.as_deref(), Some("app-1.1.bin"));
+        assert_eq!(metadata.created_at, Some(1_760_000_000_000));
+        assert_eq!(metadata.generator.as_deref(), Some(GENERATOR));
+        assert_eq!(metadata.user_value("channel"), Some("beta"));
+
+        // Metadata only changes the header
+        assert_eq!(&patch[header.encoded_len()..], &plain[HEADER_SIZE..]);
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn test_parallel_matches_single_threaded() {
+        let source: Vec<u8> = (0..50_000u32).map(|i| (i * 17 % 241) as u8).collect();
+        let mut target = source[20_000..].to_vec();
+        target.extend_from_slice(&source[..25_000]);
+        target[1000] ^= 1;
+
+        let single = build_with_suspend(&source, &target, |_| {}, None);
+        let parallel = build_with_suspend(
+            &source,
+            &target,
+            |builder| builder.set_parallel_threads(3).unwrap(),
+            None,
+        );
+        assert_eq!(parallel, single);
+    }
+
+    #[test]
+    fn test_parallel_threads_are_clamped() {
+        let mut builder = PatchBuilder::new();
+        builder.set_parallel_threads(u32::MAX).unwrap();
+        assert_eq!(builder.parallel_threads, MAX_THREADS);
+    }
+
+    #[test]
+    fn test_verified_matches_keep_patch() {
+        let source: Vec<u8> = (0..50_000u32).map(|i| (i * 17 % 241) as u8).collect();
+        let mut target = source[20_000..].to_vec();
+        target.extend_from_slice(&source[..25_000]);
+        target[1000] ^= 1;
+
+        let configs: [fn(&mut PatchBuilder); 4] = [
+            |_| {},
+            |builder| builder.set_parallel_threads(3).unwrap(),
+            |builder| builder.set_tar_mode(true).unwrap(),
+            |builder| builder.set_fine_block_size(64).unwrap(),
+        ];
+        for configure in configs {
+            let expected = build_with_suspend(&source, &target, configure, None);
+
+            let mut builder = PatchBuilder::new();
+            configure(&mut builder);
+            builder
+                .set_source_access(Some(Box::new(source.clone())))
+                .unwrap();
+            builder.add_source_chunk(&source).unwrap();
+            builder.finalize_source().unwrap();
+            builder.set_target_size(target.len() as u64).unwrap();
+            builder.add_target_chunk(&target).unwrap();
+            builder.finalize_target().unwrap();
+
+            let mut patch = Vec::new();
+            while builder.has_output() {
+                patch.extend(builder.flush_output(64 * 1024).unwrap());
+            }
+            assert_eq!(patch, expected);
+            assert_eq!(builder.rejected_matches(), 0);
+        }
+    }
+
+    #[test]
+    fn test_verification_rejects_transformed_modes() {
+        let source = vec![7u8; 100];
+
+        let mut builder = PatchBuilder::new();
+        builder.set_deflate_mode(true).unwrap();
+        assert!(matches!(
+            builder.set_source_access(Some(Box::new(source.clone()))),
+            Err(PatchlyError::Misuse(_))
+        ));
+
+        // A filter chosen after the verifier fails before indexing ends
+        let mut builder = PatchBuilder::new();
+        builder
+            .set_source_access(Some(Box::new(source.clone())))
+            .unwrap();
+        builder.set_filter(1).unwrap();
+        builder.add_source_chunk(&source).unwrap();
+        assert!(matches!(
+            builder.finalize_source(),
+            Err(PatchlyError::Misuse(_))
+        ));
+    }
+
+    #[test]
+    fn test_save_state_requires_finalized_source() {
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(b"source").unwrap();
+        assert!(builder.encode_state().is_err());
+
+        builder.finalize_source().unwrap();
+        let mut state = builder.encode_state().unwrap();
+        assert!(PatchBuilder::decode_state(&state).is_ok());
+
+        state.push(0);
+        assert!(PatchBuilder::decode_state(&state).is_err());
+        assert!(PatchBuilder::decode_state(&state[..20]).is_err());
+        assert!(PatchBuilder::decode_state(b"garbage").is_err());
+    }
+
+    /// Returns whether `result` failed with a misuse error.
+    fn is_misuse<T>(result: error::Result<T>) -> bool {
+        matches!(result, Err(PatchlyError::Misuse(_)))
+    }
+
+    #[test]
+    fn test_source_calls_after_finalize_source_fail() {
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(b"source").unwrap();
+        builder.finalize_source().unwrap();
+
+        assert!(is_misuse(builder.add_source_chunk(b"more")));
+        assert!(is_misuse(builder.finalize_source()));
+        assert_eq!(builder.source_size(), 6);
+    }
+
+    #[test]
+    fn test_target_calls_before_finalize_source_fail() {
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(b"source").unwrap();
+        builder.set_target_size(4).unwrap();
+
+        assert!(is_misuse(builder.add_target_chunk(b"data")));
+        assert!(is_misuse(builder.finalize_target()));
+        assert!(is_misuse(
+            builder.add_target_chunk_into(b"data", &mut Vec::new())
+        ));
+        assert_eq!(builder.target_size(), 0);
+    }
+
+    #[test]
+    fn test_flush_before_header_is_known_fails() {
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(b"source").unwrap();
+        assert!(!builder.has_output());
+        assert!(is_misuse(builder.flush_output(1024)));
+        assert!(is_misuse(builder.flush_into(&mut [0u8; 64])));
+
+        // The source hash is final, but the target size isn't
+        builder.finalize_source().unwrap();
+        assert!(!builder.has_output());
+        assert!(is_misuse(builder.flush_output(1024)));
+
+        builder.set_target_size(0).unwrap();
+        assert!(builder.has_output());
+        let header = PatchHeader::parse(&builder.flush_output(1024).unwrap()).unwrap();
+        assert_eq!(header.source_hash, calculate_hash(b"source"));
+    }
+
+    #[test]
+    fn test_target_requires_target_size() {
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(b"source").unwrap();
+        builder.finalize_source().unwrap();
+
+        assert!(is_misuse(builder.add_target_chunk(b"data")));
+        assert!(is_misuse(builder.finalize_target()));
+    }
+
+    #[test]
+    fn test_target_size_mismatch_fails() {
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(b"source").unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(6).unwrap();
+        builder.add_target_chunk(b"data").unwrap();
+
+        assert!(matches!(
+            builder.add_target_chunk(b"more"),
+            Err(PatchlyError::TargetSizeMismatch {
+                expected: 6,
+                actual: 8
+            })
+        ));
+        assert!(matches!(
+            builder.finalize_target(),
+            Err(PatchlyError::TargetSizeMismatch {
+                expected: 6,
+                actual: 4
+            })
+        ));
+
+        builder.add_target_chunk(b"!!").unwrap();
+        builder.finalize_target().unwrap();
+    }
+
+    #[test]
+    fn test_calls_after_finalize_target_fail() {
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(b"source").unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(4).unwrap();
+        builder.add_target_chunk(b"data").unwrap();
+        builder.finalize_target().unwrap();
+
+        assert!(is_misuse(builder.add_target_chunk(b"")));
+        assert!(is_misuse(builder.finalize_target()));
+        assert!(is_misuse(builder.set_target_size(4)));
+        assert!(builder.has_output());
+    }
+
+    #[test]
+    fn test_set_target_size_after_header_fails() {
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(b"source").unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(4).unwrap();
+        builder.set_target_size(8).unwrap();
+        builder.add_target_chunk(b"data").unwrap();
+        builder.flush_output(1024).unwrap();
+
+        assert!(is_misuse(builder.set_target_size(4)));
+    }
+
     #[test]
     fn test_reset() {
         let mut builder = PatchBuilder::new();
-        builder.add_source_chunk(b"test");
-        builder.finalize_source();
-        builder.add_target_chunk(b"data");
+        builder.add_source_chunk(b"test").unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(4).unwrap();
+        builder.add_target_chunk(b"data").unwrap();
 
         assert!(builder.source_size() > 0);
 
@@ -405,6 +2170,336 @@ mod tests {
         assert_eq!(builder.target_size(), 0);
     }
 
+    #[test]
+    fn test_settings_fail_after_source() {
+        let setters: [fn(&mut PatchBuilder) -> error::Result<()>; 7] = [
+            |builder| builder.set_tar_mode(true),
+            |builder| builder.set_deflate_mode(true),
+            |builder| builder.set_filter(1),
+            |builder| builder.set_parallel_threads(2),
+            |builder| builder.set_index_memory_budget(1024),
+            |builder| builder.set_rolling_hash(1),
+            |builder| builder.set_fine_block_size(64),
+        ];
+
+        for setter in setters {
+            let mut builder = PatchBuilder::new();
+            builder.add_source_chunk(b"test").unwrap();
+            assert!(matches!(setter(&mut builder), Err(PatchlyError::Misuse(_))));
+
+            builder.finalize_source().unwrap();
+            assert!(matches!(setter(&mut builder), Err(PatchlyError::Misuse(_))));
+        }
+
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(b"test").unwrap();
+        assert!(builder.set_tar_mode(true).is_err());
+        assert!(!builder.tar_mode);
+    }
+
+    #[test]
+    fn test_invalid_setting_values() {
+        let mut builder = PatchBuilder::new();
+        let results = [
+            builder.set_filter(3),
+            builder.set_rolling_hash(2),
+            builder.set_fine_block_size(8),
+            builder.set_fine_block_size(DEFAULT_CHUNK_SIZE),
+        ];
+        for result in results {
+            assert!(matches!(result, Err(PatchlyError::InvalidData(_))));
+        }
+
+        // Nothing changed
+        assert_eq!(builder.source_filter.kind(), FilterKind::None);
+        assert_eq!(builder.rolling_hash, RollingHashKind::Adler);
+        assert_eq!(builder.fine_block_size, 0);
+    }
+
+    #[test]
+    fn test_index_memory_reporting() {
+        let source: Vec<u8> = (0..400_000u32).map(|i| (i % 253) as u8).collect();
+
+        let mut builder = PatchBuilder::new();
+        builder.add_source_chunk(&source).unwrap();
+        builder.finalize_source().unwrap();
+
+        assert!(builder.index_memory_usage() > 0);
+        let per_block = builder.index_bytes_per_block();
+        assert!(per_block > 0.0 && per_block <= 24.0, "{}", per_block);
+    }
+
+    #[test]
+    fn test_index_budget_degrades_patch_size() {
+        use crate::apply::patch_applier::apply_patch;
+
+        let source = crate::test_util::noise(400_000, 7);
+        let mut target = source.clone();
+        target[200_000] ^= 1;
+
+        let full = build_with_suspend(&source, &target, |_| {}, None);
+        let capped = build_with_suspend(
+            &source,
+            &target,
+            |builder| builder.set_index_memory_budget(1000).unwrap(),
+            None,
+        );
+
+        assert_eq!(apply_patch(&source, &capped).unwrap(), target);
+        assert!(capped.len() > full.len());
+        assert!(capped.len() < target.len());
+
+        let mut builder = PatchBuilder::new();
+        builder.set_index_memory_budget(1000).unwrap();
+        builder.add_source_chunk(&source).unwrap();
+        builder.finalize_source().unwrap();
+        assert!(builder.index_coverage() < 0.5);
+        assert!(builder.index_memory_usage() <= 1000);
+    }
+
+    #[test]
+    fn test_tar_mode_builds_patch() {
+        let mut builder = PatchBuilder::new();
+        builder.set_tar_mode(true).unwrap();
+        builder.add_source_chunk(&[0u8; 1024]).unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(1024).unwrap();
+        builder.add_target_chunk(&[0u8; 1024]).unwrap();
+        builder.finalize_target().unwrap();
+
+        let output = builder.flush_output(4096).unwrap();
+        assert!(output.len() > 33);
+        assert_eq!(&output[0..4], b"PTCH");
+    }
+
+    #[test]
+    fn test_deflate_mode_roundtrip() {
+        use crate::apply::patch_applier::apply_patch;
+        use miniz_oxide::deflate::compress_to_vec;
+
+        let gzip = |contents: &[u8]| {
+            let mut out = vec![0x1f, 0x8b, 0x08, 0, 0, 0, 0, 0, 0, 0xff];
+            out.extend_from_slice(&compress_to_vec(contents, 6));
+            out.extend_from_slice(&[0; 8]);
+            out
+        };
+        let text: Vec<u8> = (0..20_000u32)
+            .flat_map(|i| (i.wrapping_mul(2654435761) >> 7).to_le_bytes())
+            .collect();
+        let mut edited = text.clone();
+        edited[40_000..40_010].copy_from_slice(b"0123456789");
+
+        let source = gzip(&text);
+        let target = gzip(&edited);
+
+        let mut builder = PatchBuilder::new();
+        builder.set_deflate_mode(true).unwrap();
+        builder.add_source_chunk(&source).unwrap();
+        builder.finalize_source().unwrap();
+        builder.set_target_size(target.len() as u64).unwrap();
+        builder.add_target_chunk(&target).unwrap();
+        assert!(!builder.has_output());
+        builder.finalize_target().unwrap();
+
+        let mut patch = Vec::new();
+        while builder.has_output() {
+            patch.extend(builder.flush_output(64 * 1024).unwrap());
+        }
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+        assert!(patch.len() < target.len() / 4);
+    }
+
+    #[test]
+    fn test_deflate_mode_size_limit() {
+        let limit = deflate::MAX_DEFLATE_SIZE;
+        assert!(check_deflate_size(limit - 2, &[0; 2]).is_ok());
+        assert!(matches!(
+            check_deflate_size(limit - 1, &[0; 2]),
+            Err(PatchlyError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn test_buzhash_roundtrip() {
+        use crate::apply::patch_applier::apply_patch;
+
+        // Sparse records: mostly zeros, shifted by an inserted prefix
+        let source: Vec<u8> = (0..80_000u32)
+            .map(|i| if i % 97 == 0 { (i / 97) as u8 } else { 0 })
+            .collect();
+        let mut target = vec![1u8; 300];
+        target.extend_from_slice(&source);
+
+        let configs: [fn(&mut PatchBuilder); 2] =
+            [|_| {}, |builder| builder.set_rolling_hash(1).unwrap()];
+        let [adler, buzhash] =
+            configs.map(|configure| build_with_suspend(&source, &target, configure, None));
+
+        let header = PatchHeader::parse(&buzhash).unwrap();
+        assert_eq!(header.extension(EXT_ROLLING_HASH), Some(&[1u8][..]));
+        assert_eq!(PatchHeader::parse(&adler).unwrap().version(), 1);
+
+        assert_eq!(apply_patch(&source, &buzhash).unwrap(), target);
+        assert!(buzhash.len() < 3000, "{}", buzhash.len());
+    }
+
+    #[test]
+    fn test_fine_index_matches_small_fragments() {
+        use crate::apply::patch_applier::apply_patch;
+
+        // 200-byte records in reverse order: no 4KB block survives
+        let mut x = 1u32;
+        let source: Vec<u8> = (0..80_000)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (x >> 16) as u8
+            })
+            .collect();
+        let target: Vec<u8> = source.chunks(200).rev().flatten().copied().collect();
+
+        let configs: [fn(&mut PatchBuilder); 3] = [
+            |_| {},
+            |builder| builder.set_fine_block_size(64).unwrap(),
+            |builder| {
+                builder.set_fine_block_size(64).unwrap();
+                builder.set_parallel_threads(3).unwrap();
+            },
+        ];
+        let [coarse, fine, parallel] =
+            configs.map(|configure| build_with_suspend(&source, &target, configure, None));
+
+        assert_eq!(apply_patch(&source, &fine).unwrap(), target);
+        assert_eq!(parallel, fine);
+        assert!(coarse.len() > target.len());
+        assert!(fine.len() < target.len() / 2, "{}", fine.len());
+
+        // Settings reach the fine index in any call order
+        let mut budget_first = PatchBuilder::new();
+        budget_first.set_index_memory_budget(8 * 1024).unwrap();
+        budget_first.set_rolling_hash(1).unwrap();
+        budget_first.set_fine_block_size(64).unwrap();
+        let mut fine_first = PatchBuilder::new();
+        fine_first.set_fine_block_size(64).unwrap();
+        fine_first.set_index_memory_budget(8 * 1024).unwrap();
+        fine_first.set_rolling_hash(1).unwrap();
+        for builder in [&mut budget_first, &mut fine_first] {
+            builder.add_source_chunk(&source).unwrap();
+            builder.finalize_source().unwrap();
+            let fine = builder.diff.as_ref().unwrap().fine_index().unwrap();
+            assert_eq!(fine.rolling_hash(), RollingHashKind::Buzhash);
+            assert!(fine.coverage() < 1.0);
+        }
+        assert_eq!(
+            budget_first.index_memory_usage(),
+            fine_first.index_memory_usage()
+        );
+    }
+
+    #[test]
+    fn test_max_insert_size_bounds_inserts() {
+        use crate::apply::patch_applier::apply_patch;
+        use crate::format::patch_format::{HEADER_SIZE, TYPE_INSERT};
+
+        let source = vec![0u8; 10_000];
+        let target: Vec<u8> = (0..50_000u32).map(|i| (i % 251 + 1) as u8).collect();
+
+        let configs: [fn(&mut PatchBuilder); 2] = [
+            |builder| builder.set_max_insert_size(4000),
+            |builder| {
+                builder.set_max_insert_size(4000);
+                builder.set_parallel_threads(2).unwrap();
+            },
+        ];
+        for configure in configs {
+            let patch = build_with_suspend(&source, &target, configure, Some(21_000));
+            assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+
+            // 12 full INSERTs and a 2000-byte tail, each with a 5-byte prefix
+            let body = &patch[HEADER_SIZE..];
+            assert_eq!(body.len(), target.len() + 13 * 5);
+            for i in 0..13 {
+                let at = i * 4005;
+                assert_eq!(body[at], TYPE_INSERT);
+                let len = u32::from_le_bytes(body[at + 1..at + 5].try_into().unwrap());
+                assert_eq!(len, if i < 12 { 4000 } else { 2000 });
+            }
+        }
+    }
+
+    #[test]
+    fn test_filter_roundtrip() {
+        use crate::apply::patch_applier::apply_patch;
+
+        // Code-like data where every call target shifts by an inserted prefix
+        let code = |shift: usize| {
+            let mut out = vec![0x90u8; shift];
+            for i in 0..4000u32 {
+                let pos = out.len() as i32;
+                out.push(0xE8);
+                out.extend_from_slice(&(50_000 - pos - 5).to_le_bytes());
+                out.extend_from_slice(&[(i % 0xE0) as u8, (i / 0xE0) as u8]);
+            }
+            out
+        };
+        let source = code(0);
+        let target = code(7);
+
+        let build = |filter: u8| {
+            let mut builder = PatchBuilder::new();
+            builder.set_filter(filter).unwrap();
+            builder.add_source_chunk(&source).unwrap();
+            builder.finalize_source().unwrap();
+            builder.set_target_size(target.len() as u64).unwrap();
+            for chunk in target.chunks(1000) {
+                builder.add_target_chunk(chunk).unwrap();
+            }
+            builder.finalize_target().unwrap();
+
+            let mut patch = Vec::new();
+            while builder.has_output() {
+                patch.extend(builder.flush_output(64 * 1024).unwrap());
+            }
+            patch
+        };
+
+        let plain = build(0);
+        let filtered = build(1);
+
+        assert_eq!(apply_patch(&source, &plain).unwrap(), target);
+        assert_eq!(apply_patch(&source, &filtered).unwrap(), target);
+        assert!(filtered.len() < plain.len() / 4);
+
+        // Streaming apply as done in the browser: filtered source in,
+        // decoded target out
+        let header = PatchHeader::parse(&filtered).unwrap();
+        let filter_id = header.filter_id().unwrap();
+        let mut encoder = ExecutableFilter::encoder(filter_id).unwrap();
+        let mut prepared = Vec::new();
+        for chunk in source.chunks(999) {
+            prepared.extend(encoder.process(chunk));
+        }
+        prepared.extend(encoder.finish());
+
+        let mut applier = PatchApplier::new(prepared);
+        let mut decoder = ExecutableFilter::decoder(filter_id).unwrap();
+        let mut rebuilt = Vec::new();
+        for mut chunk in filtered[header.encoded_len()..].chunks(1001) {
+            while applier.apply_chunk(chunk).unwrap() {
+                rebuilt.extend(decoder.process(&applier.take_output()));
+                chunk = &[];
+            }
+            rebuilt.extend(decoder.process(&applier.take_output()));
+        }
+        applier.finish().unwrap();
+        rebuilt.extend(decoder.finish());
+        assert_eq!(rebuilt, target);
+
+        assert!(matches!(
+            ExecutableFilter::decoder(9),
+            Err(PatchlyError::InvalidData(_))
+        ));
+    }
+
     #[test]
     fn test_version() {
         let v = version();
@@ -0,0 +1,234 @@
+//! SIMD kernels for block hashing.
+//!
+//! Two hot loops are vectorized:
+//!
+//! - Adler sums of a whole window (`RollingHash::hash_chunk`), computed
+//!   16 or 32 bytes at a time with exact integer arithmetic and reduced
+//!   modulo 65521 at the end.
+//! - FNV-1a strong hashes of consecutive index blocks. FNV-1a is a serial
+//!   chain within one block, so blocks are hashed side by side, one per
+//!   vector lane.
+//!
+//! Native x86-64 uses AVX2 when the CPU supports it and SSE2 otherwise.
+//! On wasm32 the `simd` cargo feature enables `simd128` kernels; the module
+//! must then run on an engine with SIMD support. Every path returns exactly
+//! the scalar result.
+
+#[cfg(all(target_arch = "wasm32", feature = "simd"))]
+mod wasm;
+#[cfg(target_arch = "x86_64")]
+mod x86;
+
+use crate::format::patch_format::calculate_hash;
+
+/// Modulus of the Adler sums (see `diff::rolling_hash`).
+const ADLER_MODULUS: u64 = 65521;
+
+/// Vector chunks processed between reductions, keeping lane sums in range.
+#[cfg(any(target_arch = "x86_64", all(target_arch = "wasm32", feature = "simd")))]
+const RUN_CHUNKS: usize = 256;
+
+/// Exact sums over a run of whole vector chunks.
+///
+/// For chunks `c` of `CHUNK` bytes `b[c][j]`:
+/// - `sum`: total of all bytes
+/// - `prefix`: sum over chunks of the bytes in all earlier chunks
+/// - `weighted`: sum of `(CHUNK - j) * b[c][j]`
+#[cfg(any(target_arch = "x86_64", all(target_arch = "wasm32", feature = "simd")))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct AdlerRun {
+    sum: u64,
+    prefix: u64,
+    weighted: u64,
+}
+
+/// Computes the Adler sums `(sum_a, sum_b)` of a window.
+///
+/// `sum_a` is the byte sum and `sum_b` weighs byte `i` by
+/// `window_size - i`, both modulo 65521. `data` must not be longer than
+/// `window_size`.
+pub fn adler_sums(data: &[u8], window_size: usize) -> (u32, u32) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if std::is_x86_feature_detected!("avx2") {
+            return adler_sums_with(data, window_size, 32, |run| {
+                // SAFETY: AVX2 support was just detected.
+                unsafe { x86::adler_run_avx2(run) }
+            });
+        }
+        adler_sums_with(data, window_size, 16, |run| {
+            // SAFETY: SSE2 is part of the x86-64 baseline.
+            unsafe { x86::adler_run_sse2(run) }
+        })
+    }
+
+    #[cfg(all(target_arch = "wasm32", feature = "simd"))]
+    {
+        adler_sums_with(data, window_size, 16, |run| wasm::adler_run(run))
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", all(target_arch = "wasm32", feature = "simd"))))]
+    {
+        adler_sums_scalar(data, window_size)
+    }
+}
+
+/// Combines vector runs of `chunk` bytes with a scalar tail.
+#[cfg(any(target_arch = "x86_64", all(target_arch = "wasm32", feature = "simd")))]
+fn adler_sums_with<F>(data: &[u8], window_size: usize, chunk: usize, kernel: F) -> (u32, u32)
+where
+    F: Fn(&[u8]) -> AdlerRun,
+{
+    let chunks = data.len() / chunk;
+    let vector_len = chunks * chunk;
+
+    // Combine runs into totals over all vector chunks
+    let mut total = AdlerRun::default();
+    for run in data[..vector_len].chunks(RUN_CHUNKS * chunk) {
+        let sums = kernel(run);
+        total.prefix += sums.prefix + (run.len() / chunk) as u64 * total.sum;
+        total.sum += sums.sum;
+        total.weighted += sums.weighted;
+    }
+
+    // Weight of chunk c byte j: (window - chunk * chunks) + chunk * (chunks - 1 - c) + (chunk - j)
+    let base = (window_size - vector_len) as u64;
+    let mut sum_a = total.sum % ADLER_MODULUS;
+    let mut sum_b = (base % ADLER_MODULUS * sum_a
+        + chunk as u64 * (total.prefix % ADLER_MODULUS)
+        + total.weighted)
+        % ADLER_MODULUS;
+
+    for (i, &byte) in data.iter().enumerate().skip(vector_len) {
+        sum_a = (sum_a + byte as u64) % ADLER_MODULUS;
+        sum_b = (sum_b + (window_size - i) as u64 * byte as u64) % ADLER_MODULUS;
+    }
+
+    (sum_a as u32, sum_b as u32)
+}
+
+/// Scalar reference for `adler_sums()`.
+pub fn adler_sums_scalar(data: &[u8], window_size: usize) -> (u32, u32) {
+    let mut sum_a = 0u32;
+    let mut sum_b = 0u32;
+    let modulus = ADLER_MODULUS as u32;
+
+    for (i, &byte) in data.iter().enumerate() {
+        sum_a = (sum_a + byte as u32) % modulus;
+        // Weight: window_size - i (first byte has highest weight)
+        sum_b = (sum_b + (window_size - i) as u32 * byte as u32) % modulus;
+    }
+
+    (sum_a, sum_b)
+}
+
+/// Appends the FNV-1a hash of each complete `block_size` block of `data`
+/// to `out`, equal to `calculate_hash()` of every block.
+pub fn block_hashes(data: &[u8], block_size: usize, out: &mut Vec<u64>) {
+    if block_size == 0 {
+        return;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    let lanes = if std::is_x86_feature_detected!("avx2") {
+        // SAFETY: AVX2 support was just detected.
+        unsafe { x86::block_hashes_avx2(data, block_size, out) }
+    } else {
+        // SAFETY: SSE2 is part of the x86-64 baseline.
+        unsafe { x86::block_hashes_sse2(data, block_size, out) }
+    };
+
+    #[cfg(all(target_arch = "wasm32", feature = "simd"))]
+    let lanes = wasm::block_hashes(data, block_size, out);
+
+    #[cfg(not(any(target_arch = "x86_64", all(target_arch = "wasm32", feature = "simd"))))]
+    let lanes = 0;
+
+    // Blocks left over after the last full group of lanes
+    let done = lanes * block_size;
+    block_hashes_scalar(&data[done..], block_size, out);
+}
+
+/// Scalar reference for `block_hashes()`.
+pub fn block_hashes_scalar(data: &[u8], block_size: usize, out: &mut Vec<u64>) {
+    out.extend(data.chunks_exact(block_size).map(calculate_hash));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::noise;
+
+    #[test]
+    fn test_adler_sums_match_scalar() {
+        let data = noise(70_000, 3);
+        let saturated = vec![0xFFu8; 70_000];
+
+        for window in [
+            1, 15, 16, 17, 31, 32, 33, 100, 4096, 8191, 8192, 8193, 70_000,
+        ] {
+            for offset in [0, 1, 7] {
+                for source in [&data, &saturated] {
+                    let window_data = &source[offset..offset + window.min(source.len() - offset)];
+                    assert_eq!(
+                        adler_sums(window_data, window),
+                        adler_sums_scalar(window_data, window),
+                        "window {} offset {}",
+                        window,
+                        offset
+                    );
+                }
+            }
+        }
+
+        // Data shorter than the window
+        assert_eq!(
+            adler_sums(&data[..50], 64),
+            adler_sums_scalar(&data[..50], 64)
+        );
+        assert_eq!(adler_sums(&[], 64), (0, 0));
+    }
+
+    #[test]
+    fn test_block_hashes_match_scalar() {
+        let data = noise(50_003, 4);
+
+        for block_size in [1, 3, 8, 64, 1000, 4096, 50_003] {
+            for offset in [0, 5] {
+                let data = &data[offset..];
+                let mut simd = vec![7];
+                let mut scalar = vec![7];
+                block_hashes(data, block_size, &mut simd);
+                block_hashes_scalar(data, block_size, &mut scalar);
+                assert_eq!(simd, scalar, "block size {}", block_size);
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_every_x86_kernel_matches_scalar() {
+        let data = noise(20_000, 5);
+        let window = &data[3..4099];
+
+        let sse2 = adler_sums_with(window, 4096, 16, |run| unsafe { x86::adler_run_sse2(run) });
+        assert_eq!(sse2, adler_sums_scalar(window, 4096));
+
+        let mut hashes = Vec::new();
+        let lanes = unsafe { x86::block_hashes_sse2(&data, 100, &mut hashes) };
+        block_hashes_scalar(&data[lanes * 100..], 100, &mut hashes);
+        let mut expected = Vec::new();
+        block_hashes_scalar(&data, 100, &mut expected);
+        assert_eq!(hashes, expected);
+
+        if std::is_x86_feature_detected!("avx2") {
+            let avx2 = adler_sums_with(window, 4096, 32, |run| unsafe { x86::adler_run_avx2(run) });
+            assert_eq!(avx2, adler_sums_scalar(window, 4096));
+
+            hashes.clear();
+            let lanes = unsafe { x86::block_hashes_avx2(&data, 100, &mut hashes) };
+            block_hashes_scalar(&data[lanes * 100..], 100, &mut hashes);
+            assert_eq!(hashes, expected);
+        }
+    }
+}
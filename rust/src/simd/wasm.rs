@@ -0,0 +1,86 @@
+//! wasm32 `simd128` kernels.
+
+use super::AdlerRun;
+use std::arch::wasm32::*;
+
+/// FNV-1a offset basis (see `format::patch_format`).
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a prime.
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Sums a run of 16-byte chunks.
+#[target_feature(enable = "simd128")]
+pub fn adler_run(data: &[u8]) -> AdlerRun {
+    let weights_low = i16x8(16, 15, 14, 13, 12, 11, 10, 9);
+    let weights_high = i16x8(8, 7, 6, 5, 4, 3, 2, 1);
+
+    // Lane sums stay below 2^32 for `RUN_CHUNKS` chunks
+    let mut sum = u32x4_splat(0);
+    let mut prefix = u32x4_splat(0);
+    let mut weighted = u32x4_splat(0);
+
+    for chunk in data.chunks_exact(16) {
+        // SAFETY: `chunk` is exactly 16 readable bytes.
+        let bytes = unsafe { v128_load(chunk.as_ptr() as *const v128) };
+        prefix = i32x4_add(prefix, sum);
+        let pairs = u16x8_extadd_pairwise_u8x16(bytes);
+        sum = i32x4_add(sum, u32x4_extadd_pairwise_u16x8(pairs));
+
+        let low = i32x4_dot_i16x8(u16x8_extend_low_u8x16(bytes), weights_low);
+        let high = i32x4_dot_i16x8(u16x8_extend_high_u8x16(bytes), weights_high);
+        weighted = i32x4_add(weighted, i32x4_add(low, high));
+    }
+
+    let lanes = |v: v128| {
+        u32x4_extract_lane::<0>(v) as u64
+            + u32x4_extract_lane::<1>(v) as u64
+            + u32x4_extract_lane::<2>(v) as u64
+            + u32x4_extract_lane::<3>(v) as u64
+    };
+
+    AdlerRun {
+        sum: lanes(sum),
+        prefix: lanes(prefix),
+        weighted: lanes(weighted),
+    }
+}
+
+/// Hashes groups of 2 blocks, one per lane; returns the blocks hashed.
+#[target_feature(enable = "simd128")]
+pub fn block_hashes(data: &[u8], block_size: usize, out: &mut Vec<u64>) -> usize {
+    let groups = data.len() / block_size / 2;
+    let prime = u64x2_splat(FNV_PRIME);
+    let mask = u64x2_splat(0xFF);
+
+    for group in data.chunks_exact(block_size * 2).take(groups) {
+        let (first, second) = group.split_at(block_size);
+        let mut hash = u64x2_splat(FNV_OFFSET);
+
+        let mut pos = 0;
+        while pos + 8 <= block_size {
+            let mut bytes = u64x2(load_u64(first, pos), load_u64(second, pos));
+            for _ in 0..8 {
+                hash = i64x2_mul(v128_xor(hash, v128_and(bytes, mask)), prime);
+                bytes = u64x2_shr(bytes, 8);
+            }
+            pos += 8;
+        }
+        while pos < block_size {
+            let bytes = u64x2(first[pos] as u64, second[pos] as u64);
+            hash = i64x2_mul(v128_xor(hash, bytes), prime);
+            pos += 1;
+        }
+
+        out.push(u64x2_extract_lane::<0>(hash));
+        out.push(u64x2_extract_lane::<1>(hash));
+    }
+
+    groups * 2
+}
+
+/// Reads 8 little-endian bytes as a lane value.
+#[inline(always)]
+fn load_u64(block: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(block[pos..pos + 8].try_into().unwrap())
+}
@@ -0,0 +1,217 @@
+//! SSE2 and AVX2 kernels.
+
+use super::AdlerRun;
+use std::arch::x86_64::*;
+
+/// FNV-1a offset basis (see `format::patch_format`).
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Low part of the FNV-1a prime `2^40 + 0x1b3`.
+const FNV_PRIME_LOW: i32 = 0x1b3;
+
+/// Sums a run of 16-byte chunks.
+///
+/// # Safety
+///
+/// The CPU must support SSE2 (always true on x86-64).
+#[target_feature(enable = "sse2")]
+pub unsafe fn adler_run_sse2(data: &[u8]) -> AdlerRun {
+    let zero = _mm_setzero_si128();
+    let weights_low = _mm_setr_epi16(16, 15, 14, 13, 12, 11, 10, 9);
+    let weights_high = _mm_setr_epi16(8, 7, 6, 5, 4, 3, 2, 1);
+
+    let mut sum = zero;
+    let mut prefix = zero;
+    let mut weighted = zero;
+
+    for chunk in data.chunks_exact(16) {
+        let bytes = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+        prefix = _mm_add_epi64(prefix, sum);
+        sum = _mm_add_epi64(sum, _mm_sad_epu8(bytes, zero));
+
+        let low = _mm_madd_epi16(_mm_unpacklo_epi8(bytes, zero), weights_low);
+        let high = _mm_madd_epi16(_mm_unpackhi_epi8(bytes, zero), weights_high);
+        weighted = _mm_add_epi32(weighted, _mm_add_epi32(low, high));
+    }
+
+    let mut sums = [0u64; 2];
+    let mut prefixes = [0u64; 2];
+    let mut weights = [0u32; 4];
+    _mm_storeu_si128(sums.as_mut_ptr() as *mut __m128i, sum);
+    _mm_storeu_si128(prefixes.as_mut_ptr() as *mut __m128i, prefix);
+    _mm_storeu_si128(weights.as_mut_ptr() as *mut __m128i, weighted);
+
+    AdlerRun {
+        sum: sums.iter().sum(),
+        prefix: prefixes.iter().sum(),
+        weighted: weights.iter().map(|&w| w as u64).sum(),
+    }
+}
+
+/// Sums a run of 32-byte chunks.
+///
+/// # Safety
+///
+/// The CPU must support AVX2.
+#[target_feature(enable = "avx2")]
+pub unsafe fn adler_run_avx2(data: &[u8]) -> AdlerRun {
+    let zero = _mm256_setzero_si256();
+    let weights = _mm256_setr_epi8(
+        32, 31, 30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 20, 19, 18, 17, 16, 15, 14, 13, 12, 11, 10,
+        9, 8, 7, 6, 5, 4, 3, 2, 1,
+    );
+    let ones = _mm256_set1_epi16(1);
+
+    let mut sum = zero;
+    let mut prefix = zero;
+    let mut weighted = zero;
+
+    for chunk in data.chunks_exact(32) {
+        let bytes = _mm256_loadu_si256(chunk.as_ptr() as *const __m256i);
+        prefix = _mm256_add_epi64(prefix, sum);
+        sum = _mm256_add_epi64(sum, _mm256_sad_epu8(bytes, zero));
+
+        // Pairs of byte * weight fit i16 (at most 255 * 63)
+        let pairs = _mm256_maddubs_epi16(bytes, weights);
+        weighted = _mm256_add_epi32(weighted, _mm256_madd_epi16(pairs, ones));
+    }
+
+    let mut sums = [0u64; 4];
+    let mut prefixes = [0u64; 4];
+    let mut weights = [0u32; 8];
+    _mm256_storeu_si256(sums.as_mut_ptr() as *mut __m256i, sum);
+    _mm256_storeu_si256(prefixes.as_mut_ptr() as *mut __m256i, prefix);
+    _mm256_storeu_si256(weights.as_mut_ptr() as *mut __m256i, weighted);
+
+    AdlerRun {
+        sum: sums.iter().sum(),
+        prefix: prefixes.iter().sum(),
+        weighted: weights.iter().map(|&w| w as u64).sum(),
+    }
+}
+
+/// Hashes groups of 2 blocks, one per lane; returns the blocks hashed.
+///
+/// # Safety
+///
+/// The CPU must support SSE2 (always true on x86-64).
+#[target_feature(enable = "sse2")]
+pub unsafe fn block_hashes_sse2(data: &[u8], block_size: usize, out: &mut Vec<u64>) -> usize {
+    let groups = data.len() / block_size / 2;
+    let prime_low = _mm_set1_epi64x(FNV_PRIME_LOW as i64);
+    let mask = _mm_set1_epi64x(0xFF);
+
+    for group in data.chunks_exact(block_size * 2).take(groups) {
+        let (first, second) = group.split_at(block_size);
+        let mut hash = _mm_set1_epi64x(FNV_OFFSET as i64);
+
+        let mut pos = 0;
+        while pos + 8 <= block_size {
+            let mut bytes = _mm_set_epi64x(load_u64(second, pos), load_u64(first, pos));
+            for _ in 0..8 {
+                hash = _mm_xor_si128(hash, _mm_and_si128(bytes, mask));
+                hash = fnv_multiply_sse2(hash, prime_low);
+                bytes = _mm_srli_epi64(bytes, 8);
+            }
+            pos += 8;
+        }
+        while pos < block_size {
+            let bytes = _mm_set_epi64x(second[pos] as i64, first[pos] as i64);
+            hash = fnv_multiply_sse2(_mm_xor_si128(hash, bytes), prime_low);
+            pos += 1;
+        }
+
+        let mut lanes = [0u64; 2];
+        _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, hash);
+        out.extend_from_slice(&lanes);
+    }
+
+    groups * 2
+}
+
+/// Hashes groups of 4 blocks, one per lane; returns the blocks hashed.
+///
+/// # Safety
+///
+/// The CPU must support AVX2.
+#[target_feature(enable = "avx2")]
+pub unsafe fn block_hashes_avx2(data: &[u8], block_size: usize, out: &mut Vec<u64>) -> usize {
+    let groups = data.len() / block_size / 4;
+    let prime_low = _mm256_set1_epi64x(FNV_PRIME_LOW as i64);
+    let mask = _mm256_set1_epi64x(0xFF);
+
+    for group in data.chunks_exact(block_size * 4).take(groups) {
+        let blocks = [
+            &group[..block_size],
+            &group[block_size..block_size * 2],
+            &group[block_size * 2..block_size * 3],
+            &group[block_size * 3..],
+        ];
+        let mut hash = _mm256_set1_epi64x(FNV_OFFSET as i64);
+
+        let mut pos = 0;
+        while pos + 8 <= block_size {
+            let mut bytes = _mm256_setr_epi64x(
+                load_u64(blocks[0], pos),
+                load_u64(blocks[1], pos),
+                load_u64(blocks[2], pos),
+                load_u64(blocks[3], pos),
+            );
+            for _ in 0..8 {
+                hash = _mm256_xor_si256(hash, _mm256_and_si256(bytes, mask));
+                hash = fnv_multiply_avx2(hash, prime_low);
+                bytes = _mm256_srli_epi64(bytes, 8);
+            }
+            pos += 8;
+        }
+        while pos < block_size {
+            let bytes = _mm256_setr_epi64x(
+                blocks[0][pos] as i64,
+                blocks[1][pos] as i64,
+                blocks[2][pos] as i64,
+                blocks[3][pos] as i64,
+            );
+            hash = fnv_multiply_avx2(_mm256_xor_si256(hash, bytes), prime_low);
+            pos += 1;
+        }
+
+        let mut lanes = [0u64; 4];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, hash);
+        out.extend_from_slice(&lanes);
+    }
+
+    groups * 4
+}
+
+/// Reads 8 little-endian bytes as a lane value.
+#[inline(always)]
+fn load_u64(block: &[u8], pos: usize) -> i64 {
+    i64::from_le_bytes(block[pos..pos + 8].try_into().unwrap())
+}
+
+/// Multiplies each lane by the FNV-1a prime, wrapping at 2^64.
+///
+/// `h * (2^40 + p) = h_low * p + (h_high * p << 32) + (h << 40)`, with
+/// 32x32-bit products only.
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn fnv_multiply_sse2(hash: __m128i, prime_low: __m128i) -> __m128i {
+    let low = _mm_mul_epu32(hash, prime_low);
+    let high = _mm_mul_epu32(_mm_srli_epi64(hash, 32), prime_low);
+    _mm_add_epi64(
+        _mm_add_epi64(low, _mm_slli_epi64(high, 32)),
+        _mm_slli_epi64(hash, 40),
+    )
+}
+
+/// AVX2 version of `fnv_multiply_sse2()`.
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn fnv_multiply_avx2(hash: __m256i, prime_low: __m256i) -> __m256i {
+    let low = _mm256_mul_epu32(hash, prime_low);
+    let high = _mm256_mul_epu32(_mm256_srli_epi64(hash, 32), prime_low);
+    _mm256_add_epi64(
+        _mm256_add_epi64(low, _mm256_slli_epi64(high, 32)),
+        _mm256_slli_epi64(hash, 40),
+    )
+}
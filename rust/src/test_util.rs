@@ -0,0 +1,40 @@
+//! Helpers shared by the unit tests.
+
+use crate::format::patch_format::{TYPE_COPY, TYPE_INSERT};
+
+/// Deterministic pseudo-random bytes (xorshift32).
+pub fn noise(len: usize, seed: u32) -> Vec<u8> {
+    let mut x = seed.wrapping_mul(2654435761).max(1);
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect()
+}
+
+/// Applies serialized instructions (no header or trailer) to a source
+/// buffer.
+pub fn apply(source: &[u8], patch: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < patch.len() {
+        match patch[pos] {
+            TYPE_COPY => {
+                let offset = u64::from_le_bytes(patch[pos + 1..pos + 9].try_into().unwrap());
+                let len = u32::from_le_bytes(patch[pos + 9..pos + 13].try_into().unwrap());
+                out.extend_from_slice(&source[offset as usize..offset as usize + len as usize]);
+                pos += 13;
+            }
+            TYPE_INSERT => {
+                let len = u32::from_le_bytes(patch[pos + 1..pos + 5].try_into().unwrap()) as usize;
+                out.extend_from_slice(&patch[pos + 5..pos + 5 + len]);
+                pos += 5 + len;
+            }
+            other => panic!("unknown instruction {}", other),
+        }
+    }
+    out
+}
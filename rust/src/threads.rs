@@ -0,0 +1,83 @@
+//! Web Worker thread pool for the shared-memory wasm build.
+//!
+//! Built with the `threads` feature and atomics (see README), the module
+//! memory is shared, so Web Workers that instantiate the same module with
+//! that memory can run rayon threads:
+//!
+//! 1. `init_thread_pool(n)` creates the global rayon pool and parks one
+//!    thread builder per thread.
+//! 2. The caller starts `n` workers. Each passes `thread_pool_module()` and
+//!    `thread_pool_memory()` of the creating worker to `initSync()` and
+//!    calls `run_pool_thread()`, which runs until the worker is terminated.
+//!
+//! `ParallelDiff` scans its segments on this pool once every thread has
+//! started, and on the calling thread before that. Waiting for the pool
+//! blocks with `Atomics.wait`, so diffing must run in a worker.
+
+use crate::diff::parallel_diff::MAX_THREADS;
+use crate::error::PatchlyError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use wasm_bindgen::prelude::*;
+
+/// Thread builders waiting for a worker to run them.
+static PENDING: Mutex<Vec<rayon::ThreadBuilder>> = Mutex::new(Vec::new());
+
+/// Set once every pool thread has been started by a worker.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the pool is running and can take work.
+pub(crate) fn pool_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Creates the global pool with `threads` threads (clamped to `1..=256`).
+///
+/// Start that many workers calling `run_pool_thread()` afterwards. Fails
+/// with `MISUSE` if the pool was already created.
+#[wasm_bindgen]
+pub fn init_thread_pool(threads: usize) -> Result<(), PatchlyError> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.clamp(1, MAX_THREADS))
+        .spawn_handler(|thread| {
+            PENDING
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .push(thread);
+            Ok(())
+        })
+        .build_global()
+        .map_err(|_| PatchlyError::Misuse("Thread pool already initialized"))
+}
+
+/// Returns the compiled module, for `initSync()` in a pool worker.
+#[wasm_bindgen]
+pub fn thread_pool_module() -> JsValue {
+    wasm_bindgen::module()
+}
+
+/// Returns the shared memory, for `initSync()` in a pool worker.
+#[wasm_bindgen]
+pub fn thread_pool_memory() -> JsValue {
+    wasm_bindgen::memory()
+}
+
+/// Runs one pool thread on the calling worker; never returns on success.
+///
+/// Fails with `MISUSE` if every thread of the pool is already running.
+#[wasm_bindgen]
+pub fn run_pool_thread() -> Result<(), PatchlyError> {
+    let thread = {
+        let mut pending = PENDING.lock().unwrap_or_else(|err| err.into_inner());
+        let thread = pending
+            .pop()
+            .ok_or(PatchlyError::Misuse("No pool thread left to run"))?;
+        if pending.is_empty() {
+            READY.store(true, Ordering::Release);
+        }
+        thread
+    };
+
+    thread.run();
+    Ok(())
+}
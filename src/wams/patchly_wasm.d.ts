@@ -1,25 +1,71 @@
 /* tslint:disable */
 /* eslint-disable */
 
+/**
+ * WASM-bindable streaming executable filter.
+ *
+ * Lets a JS applier undo a patch's filter (see `PatchHeader.filter_id`):
+ * run the source through an encoder before applying, and the rebuilt
+ * target through a decoder.
+ */
+export class ExecutableFilter {
+    private constructor();
+    free(): void;
+    [Symbol.dispose](): void;
+    /**
+     * Creates a filter that restores the original bytes of a filtered
+     * target. Unknown IDs fail with `INVALID_DATA`.
+     */
+    static decoder(filter_id: number): ExecutableFilter;
+    /**
+     * Creates a filter that normalizes addresses, as done to the source
+     * before diffing. Unknown IDs fail with `INVALID_DATA`.
+     */
+    static encoder(filter_id: number): ExecutableFilter;
+    /**
+     * Returns the bytes held back at the end of the stream.
+     */
+    finish(): Uint8Array;
+    /**
+     * Filters a chunk; up to 4 bytes are held back for the next one.
+     */
+    process(chunk: Uint8Array): Uint8Array;
+}
+
 /**
  * Streaming binary patch builder.
  *
  * Processes source and target files in chunks to generate a binary patch.
  * Designed for memory-efficient handling of large files (multi-GB).
+ *
+ * Calls must follow the lifecycle: configure, `add_source_chunk()`,
+ * `finalize_source()`, `set_target_size()` (or
+ * `set_target_size_unknown()`), `add_target_chunk()`, `finalize_target()`.
+ * Out-of-order calls fail with a `MISUSE` error instead of producing a
+ * broken patch.
  */
 export class PatchBuilder {
     free(): void;
     [Symbol.dispose](): void;
     /**
      * Adds a chunk of source (old file) data.
+     *
+     * Fails once `finalize_source()` was called.
      */
     add_source_chunk(chunk: Uint8Array): void;
     /**
      * Adds a chunk of target (new file) data.
      *
-     * Generates patch output immediately; call `flush_output()` to retrieve it.
+     * Generates patch output immediately; call `flush_output()` to retrieve
+     * it. The chunk is always consumed; `TargetStatus::Drain` means the
+     * pending output reached the high-water mark and must be drained
+     * before the next chunk. Deflate mode buffers the target and always
+     * returns `Ready`.
+     *
+     * Fails before `finalize_source()` and `set_target_size()`, after
+     * `finalize_target()`, and when the chunk would exceed the target size.
      */
-    add_target_chunk(chunk: Uint8Array): void;
+    add_target_chunk(chunk: Uint8Array): TargetStatus;
     /**
      * Checks if source and target files are identical.
      *
@@ -28,24 +74,58 @@ export class PatchBuilder {
     are_files_identical(): boolean;
     /**
      * Finalizes source processing.
+     *
+     * Fails if called more than once.
      */
     finalize_source(): void;
     /**
      * Finalizes target processing.
      *
-     * Call this after all target chunks have been added.
+     * Call this after all target chunks have been added. Fails if the
+     * target is shorter than `set_target_size()` announced, and on the
+     * same out-of-order calls as `add_target_chunk()`.
      */
     finalize_target(): void;
     /**
-     * Returns the next chunk of patch output.
+     * Writes the next patch output into `out` and returns the bytes written.
      *
-     * Returns serialized patch data ready to write to file.
+     * Fills as much of `out` as there is output for; `0` means nothing is
+     * available yet (see `has_output()`). From JS, pass a reused
+     * `Uint8Array` to stream a large patch without per-call allocation.
+     * Fails like `flush_output()`.
+     */
+    flush_into(out: Uint8Array): number;
+    /**
+     * Returns the next chunk of patch output, at most `max_size` bytes.
+     *
+     * Returns serialized patch data ready to write to file. Allocates a
+     * new array per call; `flush_into()` avoids that.
+     *
+     * Fails before `finalize_source()` and `set_target_size()`, as the
+     * header isn't known yet.
      */
     flush_output(max_size: number): Uint8Array;
     /**
      * Checks if there's patch output available to read.
      */
     has_output(): boolean;
+    /**
+     * Returns the measured source index memory per block in bytes.
+     *
+     * Final after `finalize_source()`. Multiply by `source_size / 4096`
+     * to estimate the index memory needed for a larger source.
+     */
+    index_bytes_per_block(): number;
+    /**
+     * Returns the fraction of source blocks in the index (0.0 to 1.0).
+     *
+     * Below 1.0 when `set_index_memory_budget()` forced sampling.
+     */
+    index_coverage(): number;
+    /**
+     * Returns the heap memory held by the source indexes in bytes.
+     */
+    index_memory_usage(): number;
     /**
      * Creates a new `PatchBuilder` with default chunk size.
      */
@@ -54,16 +134,179 @@ export class PatchBuilder {
      * Returns the approximate pending output size.
      */
     pending_output_size(): number;
+    /**
+     * Returns the number of match candidates rejected by verification.
+     */
+    rejected_matches(): bigint;
     /**
      * Resets the builder for reuse.
      */
     reset(): void;
+    /**
+     * Recreates a builder from `save_state()` output.
+     */
+    static restore_state(state: Uint8Array): PatchBuilder;
+    /**
+     * Saves the builder so diffing can be resumed later.
+     *
+     * Only available after `finalize_source()`. The state holds the source
+     * index, hashers and all buffered target and output data; output
+     * already returned by `flush_output()` is not included. Resume with
+     * `PatchBuilder.restore_state()` and continue feeding the target from
+     * `target_size()` to get the same patch as an uninterrupted run.
+     */
+    save_state(): Uint8Array;
+    /**
+     * Records the creation time, in milliseconds since the Unix epoch.
+     */
+    set_created_at(millis: bigint): void;
+    /**
+     * Enables or disables deflate-aware diffing for gzip and zip files.
+     *
+     * Deflate streams are decompressed before diffing and the target streams
+     * are recompressed on apply, which requires a version 2 patch. Both files
+     * are buffered in memory, and output is only available after
+     * `finalize_target()`. Files larger than 256MB are rejected with
+     * `INVALID_DATA`, and streams that would expand a file past 256MB stay
+     * compressed.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     */
+    set_deflate_mode(enabled: boolean): void;
+    /**
+     * Selects an executable preprocessing filter by ID.
+     *
+     * `0` = none, `1` = x86/x86-64, `2` = AArch64 (see `detect_filter()`).
+     * Branch displacements are normalized in both files before matching and
+     * restored on apply; the filter ID is recorded in a version 2 header.
+     * Unknown IDs fail with `INVALID_DATA`.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     */
+    set_filter(filter_id: number): void;
+    /**
+     * Adds a secondary source index with smaller blocks (0 = disabled).
+     *
+     * Target data the main index can't match (INSERT regions) is scanned
+     * again against this index, so fragments shared between edited parts
+     * of structured files still become COPYs. 64 is a good choice; sizes
+     * below 16 or not below the main block size fail with `INVALID_DATA`.
+     * The index covers the whole source and is subject to the memory budget
+     * on its own, so it needs several times the main index memory.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     */
+    set_fine_block_size(size: number): void;
+    /**
+     * Caps the source index memory in bytes (0 = unlimited).
+     *
+     * When a large source would exceed the budget, only a content-defined
+     * sample of source blocks is indexed: the patch gets larger, but memory
+     * stays bounded. See `index_coverage()`.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     */
+    set_index_memory_budget(bytes: number): void;
+    /**
+     * Sets the largest INSERT emitted in one piece, in bytes.
+     *
+     * Unmatched target data is buffered until it reaches this size and is
+     * then emitted as an INSERT, so memory stays bounded for dissimilar
+     * files. Defaults to 1MB; `0` is ignored. Takes effect immediately.
+     */
+    set_max_insert_size(bytes: number): void;
+    /**
+     * Records a custom key/value pair, replacing an earlier value.
+     *
+     * Keys are limited to 65535 bytes; longer ones fail with
+     * `INVALID_DATA`.
+     */
+    set_metadata(key: string, value: string): void;
+    /**
+     * Sets the pending output size at which target ingestion asks the
+     * caller to drain, in bytes (0 = unlimited, the default).
+     *
+     * Pending output then stays below the mark plus the output of one
+     * target chunk (at most the chunk size plus the max INSERT size and
+     * instruction overhead). With `set_parallel_threads()`, a chunk that
+     * completes a batch releases the whole batch, so the bound grows by the
+     * batch size (2 MB per thread). Takes effect immediately.
+     */
+    set_output_high_water_mark(bytes: number): void;
+    /**
+     * Diffs the target on several threads sharing the source index.
+     *
+     * The target is buffered in batches and split into segments that are
+     * matched concurrently; the patch is identical to a single-threaded
+     * run. `0` disables parallel diffing. Counts above 256 are clamped.
+     * Ignored in tar mode. On wasm the segments run on the Web Worker pool
+     * of a `threads` build once `init_thread_pool()` and its workers have
+     * started, and on the calling thread otherwise; the count then sets the
+     * batch size, and the pool size the parallelism.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     */
+    set_parallel_threads(threads: number): void;
+    /**
+     * Selects the weak rolling hash by ID.
+     *
+     * `0` = Adler-32 style (default), `1` = table-driven Buzhash, which is
+     * faster and spreads low-entropy data (zero runs, sparse records)
+     * better. A non-default hash is recorded in a version 2 header. Unknown
+     * IDs fail with `INVALID_DATA`.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     */
+    set_rolling_hash(hash_id: number): void;
+    /**
+     * Verifies every match against the source file before it becomes a
+     * COPY, so a hash collision can't corrupt the target.
+     *
+     * `handle` is an OPFS `FileSystemSyncAccessHandle` of the source file
+     * (any object with its `read(buffer, { at })` and `getSize()` methods).
+     * Costs one source read per match. Not saved by `save_state()`; set it
+     * again after `restore_state()`. Fails in deflate and filter modes,
+     * which diff transformed bytes, and after `finalize_target()`.
+     */
+    set_source_handle(handle: any): void;
+    /**
+     * Records the original source file name in the header.
+     *
+     * Metadata makes the patch version 2 and is informational only.
+     * Like the target size, it fails once the header was written.
+     */
+    set_source_name(name: string): void;
+    /**
+     * Enables or disables tar-aware diffing.
+     *
+     * In tar mode both files are parsed as tar archives; member contents are
+     * matched against the source member with the same path, aligned on
+     * member boundaries. The patch format is unchanged.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     */
+    set_tar_mode(enabled: boolean): void;
+    /**
+     * Records the original target file name in the header.
+     */
+    set_target_name(name: string): void;
     /**
      * Sets the expected total target size.
      *
-     * Must be called before `add_target_chunk()` for proper header generation.
+     * Required before `add_target_chunk()` and `finalize_target()`, since
+     * the header that starts the output records it. Fails once output was
+     * flushed or the target was finalized.
      */
     set_target_size(size: bigint): void;
+    /**
+     * Builds the patch without knowing the target size up front.
+     *
+     * For unbounded streams such as `fetch()` bodies. The header gets the
+     * trailer flag and a target size of 0; `finalize_target()` appends a
+     * trailer with the real target size and hash, which makes the patch
+     * version 2. Replaces `set_target_size()` and fails in the same cases.
+     */
+    set_target_size_unknown(): void;
     /**
      * Returns the current source size in bytes.
      */
@@ -72,6 +315,188 @@ export class PatchBuilder {
      * Returns the current target size in bytes.
      */
     target_size(): number;
+    /**
+     * Returns whether pending output must be drained before more target
+     * data is added.
+     */
+    target_status(): TargetStatus;
+}
+
+/**
+ * Parsed patch header information.
+ */
+export class PatchHeader {
+    private constructor();
+    free(): void;
+    [Symbol.dispose](): void;
+    /**
+     * Returns a copy of the payload of the first extension with `tag`.
+     */
+    extension(tag: number): Uint8Array | undefined;
+    /**
+     * Returns the value of a custom metadata key.
+     */
+    metadata_value(key: string): string | undefined;
+    /**
+     * Parses a header from bytes.
+     *
+     * Accepts version 1 and version 2 headers.
+     *
+     * # Arguments
+     *
+     * * `data` - At least `required_len()` bytes of header data.
+     */
+    static parse(data: Uint8Array): PatchHeader;
+    /**
+     * Takes the target size and hash from the patch trailer.
+     *
+     * # Arguments
+     *
+     * * `tail` - The last `TRAILER_SIZE` bytes of the patch.
+     */
+    read_trailer(tail: Uint8Array): void;
+    /**
+     * Determines the full header size from its first bytes.
+     *
+     * # Arguments
+     *
+     * * `data` - At least 33 bytes (41 for version 2) of header data.
+     *
+     * # Returns
+     *
+     * The number of bytes `parse()` needs.
+     */
+    static required_len(data: Uint8Array): number;
+    /**
+     * Chunk size used during diff generation.
+     */
+    readonly chunk_size: number;
+    /**
+     * Feature flags (version 2 only).
+     */
+    readonly flags: number;
+    /**
+     * Hash of the original source file.
+     */
+    readonly source_hash: bigint;
+    /**
+     * Size of the original source file.
+     */
+    readonly source_size: bigint;
+    /**
+     * Hash of the target file, known once `read_trailer()` was called.
+     *
+     * Not part of the header bytes.
+     */
+    readonly target_hash: bigint | undefined;
+    /**
+     * Size of the target file after patching.
+     *
+     * 0 in the header bytes of a trailer patch until `read_trailer()`.
+     */
+    readonly target_size: bigint;
+    /**
+     * Returns the creation time in milliseconds since the Unix epoch.
+     */
+    readonly created_at: bigint | undefined;
+    /**
+     * Returns the serialized header size in bytes.
+     */
+    readonly header_size: number;
+    /**
+     * Returns the executable filter ID, if a filter was used.
+     */
+    readonly filter_id: number | undefined;
+    /**
+     * Returns the name and version of the program that built the patch.
+     */
+    readonly generator: string | undefined;
+    /**
+     * Returns whether the target size and hash are in a trailer.
+     */
+    readonly has_trailer: boolean;
+    /**
+     * Returns the weak rolling hash ID, if not the default.
+     */
+    readonly rolling_hash_id: number | undefined;
+    /**
+     * Returns the original source file name.
+     */
+    readonly source_name: string | undefined;
+    /**
+     * Returns the original target file name.
+     */
+    readonly target_name: string | undefined;
+    /**
+     * Returns the format version this header serializes to.
+     *
+     * Version 2 if it was parsed as version 2 or uses flags or
+     * extensions, so a parsed header keeps its encoded length.
+     */
+    readonly version: number;
+}
+
+/**
+ * WASM-bindable streaming patch applier with resumable checkpoints.
+ *
+ * Reads COPY data through an OPFS `FileSystemSyncAccessHandle` of the
+ * source and rebuilds the target from the instruction bytes fed to
+ * `apply_chunk()`. Checkpoints describe the instruction stream and its
+ * direct output, so for patches with deflate or filter extensions they
+ * cover the target before those are undone.
+ */
+export class StreamingApplier {
+    free(): void;
+    [Symbol.dispose](): void;
+    /**
+     * Applies a chunk of instruction bytes.
+     *
+     * Returns `true` if it paused inside a large COPY with about 1 MiB of
+     * output waiting. The rest of `chunk` is kept: take the output, then
+     * call again with an empty chunk until it returns `false`.
+     */
+    apply_chunk(chunk: Uint8Array): boolean;
+    /**
+     * Checks that the patch didn't end inside an instruction.
+     */
+    finish(): void;
+    /**
+     * Creates an applier reading COPY data from `source_handle`.
+     */
+    constructor(source_handle: any);
+    /**
+     * Returns the number of instruction bytes consumed.
+     */
+    patch_offset(): bigint;
+    /**
+     * Resumes from a checkpoint returned by `take_checkpoint()`.
+     *
+     * Verifies the target already written through `written_handle`, then
+     * continues at `patch_offset()` (counted after the header). Truncate
+     * the target to `target_written()` bytes before appending output.
+     * Fails with `CHECKPOINT_MISMATCH` if the written target differs.
+     */
+    static resume(source_handle: any, checkpoint: Uint8Array, written_handle: any): StreamingApplier;
+    /**
+     * Records a checkpoint every `interval` target bytes (0 disables).
+     */
+    set_checkpoint_interval(interval: bigint): void;
+    /**
+     * Takes the latest serialized checkpoint, if one was recorded.
+     *
+     * The checkpoint can cover output still waiting in the applier, so
+     * call `take_output()` and persist its bytes before taking it, in that
+     * order.
+     */
+    take_checkpoint(): Uint8Array | undefined;
+    /**
+     * Takes the target bytes rebuilt so far.
+     */
+    take_output(): Uint8Array;
+    /**
+     * Returns the total number of target bytes produced.
+     */
+    target_written(): bigint;
 }
 
 /**
@@ -101,17 +526,31 @@ export class StreamingHasher {
 }
 
 /**
- * Calculates hash of data and returns it as a hex string.
+ * Result of `PatchBuilder::add_target_chunk()`.
  */
-export function hash_data(data: Uint8Array): string;
+export enum TargetStatus {
+    /**
+     * More target data can be added.
+     */
+    Ready = 0,
+    /**
+     * Pending output reached the high-water mark: drain it with
+     * `flush_output()` or `flush_into()` before adding more target data.
+     */
+    Drain = 1,
+}
 
 /**
- * Parses only the patch header (33 bytes) without parsing instructions.
+ * Detects the executable filter ID for a file from its first bytes.
  *
- * Returns JSON with sourceSize, sourceHash, targetSize, chunkSize, and headerSize.
- * TypeScript will parse instructions directly from OPFS to avoid loading entire patch.
+ * Returns `1` for x86/x86-64 ELF, `2` for AArch64 ELF and `0` otherwise.
  */
-export function parse_patch_header_only(header_data: Uint8Array): string;
+export function detect_filter(header_data: Uint8Array): number;
+
+/**
+ * Calculates hash of data and returns it as a hex string.
+ */
+export function hash_data(data: Uint8Array): string;
 
 /**
  * Returns the library version.
@@ -122,32 +561,94 @@ export type InitInput = RequestInfo | URL | Response | BufferSource | WebAssembl
 
 export interface InitOutput {
     readonly memory: WebAssembly.Memory;
+    readonly __wbg_executablefilter_free: (a: number, b: number) => void;
     readonly __wbg_patchbuilder_free: (a: number, b: number) => void;
-    readonly patchbuilder_new: () => number;
-    readonly patchbuilder_add_source_chunk: (a: number, b: number, c: number) => void;
-    readonly patchbuilder_finalize_source: (a: number) => void;
-    readonly patchbuilder_set_target_size: (a: number, b: bigint) => void;
-    readonly patchbuilder_add_target_chunk: (a: number, b: number, c: number) => void;
-    readonly patchbuilder_finalize_target: (a: number) => void;
-    readonly patchbuilder_source_size: (a: number) => number;
-    readonly patchbuilder_target_size: (a: number) => number;
+    readonly __wbg_streamingapplier_free: (a: number, b: number) => void;
+    readonly __wbg_streaminghasher_free: (a: number, b: number) => void;
+    readonly detect_filter: (a: number, b: number) => number;
+    readonly executablefilter_decoder: (a: number) => [number, number, number];
+    readonly executablefilter_encoder: (a: number) => [number, number, number];
+    readonly executablefilter_finish: (a: number) => [number, number];
+    readonly executablefilter_process: (a: number, b: number, c: number) => [number, number];
+    readonly hash_data: (a: number, b: number) => [number, number];
+    readonly patchbuilder_add_source_chunk: (a: number, b: number, c: number) => [number, number];
+    readonly patchbuilder_add_target_chunk: (a: number, b: number, c: number) => [number, number, number];
     readonly patchbuilder_are_files_identical: (a: number) => number;
+    readonly patchbuilder_finalize_source: (a: number) => [number, number];
+    readonly patchbuilder_finalize_target: (a: number) => [number, number];
+    readonly patchbuilder_flush_into: (a: number, b: number, c: number, d: any) => [number, number, number];
+    readonly patchbuilder_flush_output: (a: number, b: number) => [number, number, number, number];
     readonly patchbuilder_has_output: (a: number) => number;
-    readonly patchbuilder_flush_output: (a: number, b: number) => [number, number];
+    readonly patchbuilder_index_bytes_per_block: (a: number) => number;
+    readonly patchbuilder_index_coverage: (a: number) => number;
+    readonly patchbuilder_index_memory_usage: (a: number) => number;
+    readonly patchbuilder_new: () => number;
     readonly patchbuilder_pending_output_size: (a: number) => number;
+    readonly patchbuilder_rejected_matches: (a: number) => bigint;
     readonly patchbuilder_reset: (a: number) => void;
-    readonly parse_patch_header_only: (a: number, b: number) => [number, number, number, number];
-    readonly version: () => [number, number];
-    readonly __wbg_streaminghasher_free: (a: number, b: number) => void;
-    readonly streaminghasher_new: () => number;
-    readonly streaminghasher_update: (a: number, b: number, c: number) => void;
+    readonly patchbuilder_restore_state: (a: number, b: number) => [number, number, number];
+    readonly patchbuilder_save_state: (a: number) => [number, number, number, number];
+    readonly patchbuilder_set_created_at: (a: number, b: bigint) => [number, number];
+    readonly patchbuilder_set_deflate_mode: (a: number, b: number) => [number, number];
+    readonly patchbuilder_set_filter: (a: number, b: number) => [number, number];
+    readonly patchbuilder_set_fine_block_size: (a: number, b: number) => [number, number];
+    readonly patchbuilder_set_index_memory_budget: (a: number, b: number) => [number, number];
+    readonly patchbuilder_set_max_insert_size: (a: number, b: number) => void;
+    readonly patchbuilder_set_metadata: (a: number, b: number, c: number, d: number, e: number) => [number, number];
+    readonly patchbuilder_set_output_high_water_mark: (a: number, b: number) => void;
+    readonly patchbuilder_set_parallel_threads: (a: number, b: number) => [number, number];
+    readonly patchbuilder_set_rolling_hash: (a: number, b: number) => [number, number];
+    readonly patchbuilder_set_source_handle: (a: number, b: any) => [number, number];
+    readonly patchbuilder_set_source_name: (a: number, b: number, c: number) => [number, number];
+    readonly patchbuilder_set_tar_mode: (a: number, b: number) => [number, number];
+    readonly patchbuilder_set_target_name: (a: number, b: number, c: number) => [number, number];
+    readonly patchbuilder_set_target_size: (a: number, b: bigint) => [number, number];
+    readonly patchbuilder_set_target_size_unknown: (a: number) => [number, number];
+    readonly patchbuilder_source_size: (a: number) => number;
+    readonly patchbuilder_target_size: (a: number) => number;
+    readonly patchbuilder_target_status: (a: number) => number;
+    readonly streamingapplier_apply_chunk: (a: number, b: number, c: number) => [number, number, number];
+    readonly streamingapplier_finish: (a: number) => [number, number];
+    readonly streamingapplier_new: (a: any) => [number, number, number];
+    readonly streamingapplier_patch_offset: (a: number) => bigint;
+    readonly streamingapplier_resume: (a: any, b: number, c: number, d: any) => [number, number, number];
+    readonly streamingapplier_set_checkpoint_interval: (a: number, b: bigint) => void;
+    readonly streamingapplier_take_checkpoint: (a: number) => [number, number];
+    readonly streamingapplier_take_output: (a: number) => [number, number];
+    readonly streamingapplier_target_written: (a: number) => bigint;
     readonly streaminghasher_finalize: (a: number) => [number, number];
     readonly streaminghasher_finalize_u64: (a: number) => bigint;
-    readonly hash_data: (a: number, b: number) => [number, number];
-    readonly __wbindgen_externrefs: WebAssembly.Table;
+    readonly streaminghasher_new: () => number;
+    readonly streaminghasher_update: (a: number, b: number, c: number) => void;
+    readonly version: () => [number, number];
+    readonly __wbg_get_patchheader_chunk_size: (a: number) => number;
+    readonly __wbg_get_patchheader_flags: (a: number) => number;
+    readonly __wbg_get_patchheader_source_hash: (a: number) => bigint;
+    readonly __wbg_get_patchheader_source_size: (a: number) => bigint;
+    readonly __wbg_get_patchheader_target_hash: (a: number) => [number, bigint];
+    readonly __wbg_get_patchheader_target_size: (a: number) => bigint;
+    readonly __wbg_patchheader_free: (a: number, b: number) => void;
+    readonly patchheader_created_at: (a: number) => [number, bigint];
+    readonly patchheader_encoded_len: (a: number) => number;
+    readonly patchheader_extension: (a: number, b: number) => [number, number];
+    readonly patchheader_filter_id: (a: number) => number;
+    readonly patchheader_generator: (a: number) => [number, number];
+    readonly patchheader_has_trailer: (a: number) => number;
+    readonly patchheader_metadata_value: (a: number, b: number, c: number) => [number, number];
+    readonly patchheader_parse: (a: number, b: number) => [number, number, number];
+    readonly patchheader_read_trailer: (a: number, b: number, c: number) => [number, number];
+    readonly patchheader_required_len: (a: number, b: number) => [number, number, number];
+    readonly patchheader_rolling_hash_id: (a: number) => number;
+    readonly patchheader_source_name: (a: number) => [number, number];
+    readonly patchheader_target_name: (a: number) => [number, number];
+    readonly patchheader_version: (a: number) => number;
     readonly __wbindgen_malloc: (a: number, b: number) => number;
-    readonly __wbindgen_free: (a: number, b: number, c: number) => void;
+    readonly __wbindgen_realloc: (a: number, b: number, c: number, d: number) => number;
+    readonly __wbindgen_exn_store: (a: number) => void;
+    readonly __externref_table_alloc: () => number;
+    readonly __wbindgen_externrefs: WebAssembly.Table;
     readonly __externref_table_dealloc: (a: number) => void;
+    readonly __wbindgen_free: (a: number, b: number, c: number) => void;
     readonly __wbindgen_start: () => void;
 }
 
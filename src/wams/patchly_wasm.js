@@ -1,12 +1,102 @@
 /* @ts-self-types="./patchly_wasm.d.ts" */
 
+/**
+ * WASM-bindable streaming executable filter.
+ *
+ * Lets a JS applier undo a patch's filter (see `PatchHeader.filter_id`):
+ * run the source through an encoder before applying, and the rebuilt
+ * target through a decoder.
+ */
+export class ExecutableFilter {
+    static __wrap(ptr) {
+        ptr = ptr >>> 0;
+        const obj = Object.create(ExecutableFilter.prototype);
+        obj.__wbg_ptr = ptr;
+        ExecutableFilterFinalization.register(obj, obj.__wbg_ptr, obj);
+        return obj;
+    }
+    __destroy_into_raw() {
+        const ptr = this.__wbg_ptr;
+        this.__wbg_ptr = 0;
+        ExecutableFilterFinalization.unregister(this);
+        return ptr;
+    }
+    free() {
+        const ptr = this.__destroy_into_raw();
+        wasm.__wbg_executablefilter_free(ptr, 0);
+    }
+    /**
+     * Creates a filter that restores the original bytes of a filtered
+     * target. Unknown IDs fail with `INVALID_DATA`.
+     * @param {number} filter_id
+     * @returns {ExecutableFilter}
+     */
+    static decoder(filter_id) {
+        const ret = wasm.executablefilter_decoder(filter_id);
+        if (ret[2]) {
+            throw takeFromExternrefTable0(ret[1]);
+        }
+        return ExecutableFilter.__wrap(ret[0]);
+    }
+    /**
+     * Creates a filter that normalizes addresses, as done to the source
+     * before diffing. Unknown IDs fail with `INVALID_DATA`.
+     * @param {number} filter_id
+     * @returns {ExecutableFilter}
+     */
+    static encoder(filter_id) {
+        const ret = wasm.executablefilter_encoder(filter_id);
+        if (ret[2]) {
+            throw takeFromExternrefTable0(ret[1]);
+        }
+        return ExecutableFilter.__wrap(ret[0]);
+    }
+    /**
+     * Returns the bytes held back at the end of the stream.
+     * @returns {Uint8Array}
+     */
+    finish() {
+        const ret = wasm.executablefilter_finish(this.__wbg_ptr);
+        var v1 = getArrayU8FromWasm0(ret[0], ret[1]).slice();
+        wasm.__wbindgen_free(ret[0], ret[1] * 1, 1);
+        return v1;
+    }
+    /**
+     * Filters a chunk; up to 4 bytes are held back for the next one.
+     * @param {Uint8Array} chunk
+     * @returns {Uint8Array}
+     */
+    process(chunk) {
+        const ptr0 = passArray8ToWasm0(chunk, wasm.__wbindgen_malloc);
+        const len0 = WASM_VECTOR_LEN;
+        const ret = wasm.executablefilter_process(this.__wbg_ptr, ptr0, len0);
+        var v2 = getArrayU8FromWasm0(ret[0], ret[1]).slice();
+        wasm.__wbindgen_free(ret[0], ret[1] * 1, 1);
+        return v2;
+    }
+}
+if (Symbol.dispose) ExecutableFilter.prototype[Symbol.dispose] = ExecutableFilter.prototype.free;
+
 /**
  * Streaming binary patch builder.
  *
  * Processes source and target files in chunks to generate a binary patch.
  * Designed for memory-efficient handling of large files (multi-GB).
+ *
+ * Calls must follow the lifecycle: configure, `add_source_chunk()`,
+ * `finalize_source()`, `set_target_size()` (or
+ * `set_target_size_unknown()`), `add_target_chunk()`, `finalize_target()`.
+ * Out-of-order calls fail with a `MISUSE` error instead of producing a
+ * broken patch.
  */
 export class PatchBuilder {
+    static __wrap(ptr) {
+        ptr = ptr >>> 0;
+        const obj = Object.create(PatchBuilder.prototype);
+        obj.__wbg_ptr = ptr;
+        PatchBuilderFinalization.register(obj, obj.__wbg_ptr, obj);
+        return obj;
+    }
     __destroy_into_raw() {
         const ptr = this.__wbg_ptr;
         this.__wbg_ptr = 0;
@@ -19,23 +109,40 @@ export class PatchBuilder {
     }
     /**
      * Adds a chunk of source (old file) data.
+     *
+     * Fails once `finalize_source()` was called.
      * @param {Uint8Array} chunk
      */
     add_source_chunk(chunk) {
         const ptr0 = passArray8ToWasm0(chunk, wasm.__wbindgen_malloc);
         const len0 = WASM_VECTOR_LEN;
-        wasm.patchbuilder_add_source_chunk(this.__wbg_ptr, ptr0, len0);
+        const ret = wasm.patchbuilder_add_source_chunk(this.__wbg_ptr, ptr0, len0);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
     }
     /**
      * Adds a chunk of target (new file) data.
      *
-     * Generates patch output immediately; call `flush_output()` to retrieve it.
+     * Generates patch output immediately; call `flush_output()` to retrieve
+     * it. The chunk is always consumed; `TargetStatus::Drain` means the
+     * pending output reached the high-water mark and must be drained
+     * before the next chunk. Deflate mode buffers the target and always
+     * returns `Ready`.
+     *
+     * Fails before `finalize_source()` and `set_target_size()`, after
+     * `finalize_target()`, and when the chunk would exceed the target size.
      * @param {Uint8Array} chunk
+     * @returns {TargetStatus}
      */
     add_target_chunk(chunk) {
         const ptr0 = passArray8ToWasm0(chunk, wasm.__wbindgen_malloc);
         const len0 = WASM_VECTOR_LEN;
-        wasm.patchbuilder_add_target_chunk(this.__wbg_ptr, ptr0, len0);
+        const ret = wasm.patchbuilder_add_target_chunk(this.__wbg_ptr, ptr0, len0);
+        if (ret[2]) {
+            throw takeFromExternrefTable0(ret[1]);
+        }
+        return ret[0];
     }
     /**
      * Checks if source and target files are identical.
@@ -49,27 +156,63 @@ export class PatchBuilder {
     }
     /**
      * Finalizes source processing.
+     *
+     * Fails if called more than once.
      */
     finalize_source() {
-        wasm.patchbuilder_finalize_source(this.__wbg_ptr);
+        const ret = wasm.patchbuilder_finalize_source(this.__wbg_ptr);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
     }
     /**
      * Finalizes target processing.
      *
-     * Call this after all target chunks have been added.
+     * Call this after all target chunks have been added. Fails if the
+     * target is shorter than `set_target_size()` announced, and on the
+     * same out-of-order calls as `add_target_chunk()`.
      */
     finalize_target() {
-        wasm.patchbuilder_finalize_target(this.__wbg_ptr);
+        const ret = wasm.patchbuilder_finalize_target(this.__wbg_ptr);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Writes the next patch output into `out` and returns the bytes written.
+     *
+     * Fills as much of `out` as there is output for; `0` means nothing is
+     * available yet (see `has_output()`). From JS, pass a reused
+     * `Uint8Array` to stream a large patch without per-call allocation.
+     * Fails like `flush_output()`.
+     * @param {Uint8Array} out
+     * @returns {number}
+     */
+    flush_into(out) {
+        var ptr0 = passArray8ToWasm0(out, wasm.__wbindgen_malloc);
+        var len0 = WASM_VECTOR_LEN;
+        const ret = wasm.patchbuilder_flush_into(this.__wbg_ptr, ptr0, len0, out);
+        if (ret[2]) {
+            throw takeFromExternrefTable0(ret[1]);
+        }
+        return ret[0] >>> 0;
     }
     /**
-     * Returns the next chunk of patch output.
+     * Returns the next chunk of patch output, at most `max_size` bytes.
+     *
+     * Returns serialized patch data ready to write to file. Allocates a
+     * new array per call; `flush_into()` avoids that.
      *
-     * Returns serialized patch data ready to write to file.
+     * Fails before `finalize_source()` and `set_target_size()`, as the
+     * header isn't known yet.
      * @param {number} max_size
      * @returns {Uint8Array}
      */
     flush_output(max_size) {
         const ret = wasm.patchbuilder_flush_output(this.__wbg_ptr, max_size);
+        if (ret[3]) {
+            throw takeFromExternrefTable0(ret[2]);
+        }
         var v1 = getArrayU8FromWasm0(ret[0], ret[1]).slice();
         wasm.__wbindgen_free(ret[0], ret[1] * 1, 1);
         return v1;
@@ -82,6 +225,35 @@ export class PatchBuilder {
         const ret = wasm.patchbuilder_has_output(this.__wbg_ptr);
         return ret !== 0;
     }
+    /**
+     * Returns the measured source index memory per block in bytes.
+     *
+     * Final after `finalize_source()`. Multiply by `source_size / 4096`
+     * to estimate the index memory needed for a larger source.
+     * @returns {number}
+     */
+    index_bytes_per_block() {
+        const ret = wasm.patchbuilder_index_bytes_per_block(this.__wbg_ptr);
+        return ret;
+    }
+    /**
+     * Returns the fraction of source blocks in the index (0.0 to 1.0).
+     *
+     * Below 1.0 when `set_index_memory_budget()` forced sampling.
+     * @returns {number}
+     */
+    index_coverage() {
+        const ret = wasm.patchbuilder_index_coverage(this.__wbg_ptr);
+        return ret;
+    }
+    /**
+     * Returns the heap memory held by the source indexes in bytes.
+     * @returns {number}
+     */
+    index_memory_usage() {
+        const ret = wasm.patchbuilder_index_memory_usage(this.__wbg_ptr);
+        return ret >>> 0;
+    }
     /**
      * Creates a new `PatchBuilder` with default chunk size.
      */
@@ -99,20 +271,301 @@ export class PatchBuilder {
         const ret = wasm.patchbuilder_pending_output_size(this.__wbg_ptr);
         return ret >>> 0;
     }
+    /**
+     * Returns the number of match candidates rejected by verification.
+     * @returns {bigint}
+     */
+    rejected_matches() {
+        const ret = wasm.patchbuilder_rejected_matches(this.__wbg_ptr);
+        return BigInt.asUintN(64, ret);
+    }
     /**
      * Resets the builder for reuse.
      */
     reset() {
         wasm.patchbuilder_reset(this.__wbg_ptr);
     }
+    /**
+     * Recreates a builder from `save_state()` output.
+     * @param {Uint8Array} state
+     * @returns {PatchBuilder}
+     */
+    static restore_state(state) {
+        const ptr0 = passArray8ToWasm0(state, wasm.__wbindgen_malloc);
+        const len0 = WASM_VECTOR_LEN;
+        const ret = wasm.patchbuilder_restore_state(ptr0, len0);
+        if (ret[2]) {
+            throw takeFromExternrefTable0(ret[1]);
+        }
+        return PatchBuilder.__wrap(ret[0]);
+    }
+    /**
+     * Saves the builder so diffing can be resumed later.
+     *
+     * Only available after `finalize_source()`. The state holds the source
+     * index, hashers and all buffered target and output data; output
+     * already returned by `flush_output()` is not included. Resume with
+     * `PatchBuilder.restore_state()` and continue feeding the target from
+     * `target_size()` to get the same patch as an uninterrupted run.
+     * @returns {Uint8Array}
+     */
+    save_state() {
+        const ret = wasm.patchbuilder_save_state(this.__wbg_ptr);
+        if (ret[3]) {
+            throw takeFromExternrefTable0(ret[2]);
+        }
+        var v1 = getArrayU8FromWasm0(ret[0], ret[1]).slice();
+        wasm.__wbindgen_free(ret[0], ret[1] * 1, 1);
+        return v1;
+    }
+    /**
+     * Records the creation time, in milliseconds since the Unix epoch.
+     * @param {bigint} millis
+     */
+    set_created_at(millis) {
+        const ret = wasm.patchbuilder_set_created_at(this.__wbg_ptr, millis);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Enables or disables deflate-aware diffing for gzip and zip files.
+     *
+     * Deflate streams are decompressed before diffing and the target streams
+     * are recompressed on apply, which requires a version 2 patch. Both files
+     * are buffered in memory, and output is only available after
+     * `finalize_target()`. Files larger than 256MB are rejected with
+     * `INVALID_DATA`, and streams that would expand a file past 256MB stay
+     * compressed.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     * @param {boolean} enabled
+     */
+    set_deflate_mode(enabled) {
+        const ret = wasm.patchbuilder_set_deflate_mode(this.__wbg_ptr, enabled);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Selects an executable preprocessing filter by ID.
+     *
+     * `0` = none, `1` = x86/x86-64, `2` = AArch64 (see `detect_filter()`).
+     * Branch displacements are normalized in both files before matching and
+     * restored on apply; the filter ID is recorded in a version 2 header.
+     * Unknown IDs fail with `INVALID_DATA`.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     * @param {number} filter_id
+     */
+    set_filter(filter_id) {
+        const ret = wasm.patchbuilder_set_filter(this.__wbg_ptr, filter_id);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Adds a secondary source index with smaller blocks (0 = disabled).
+     *
+     * Target data the main index can't match (INSERT regions) is scanned
+     * again against this index, so fragments shared between edited parts
+     * of structured files still become COPYs. 64 is a good choice; sizes
+     * below 16 or not below the main block size fail with `INVALID_DATA`.
+     * The index covers the whole source and is subject to the memory budget
+     * on its own, so it needs several times the main index memory.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     * @param {number} size
+     */
+    set_fine_block_size(size) {
+        const ret = wasm.patchbuilder_set_fine_block_size(this.__wbg_ptr, size);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Caps the source index memory in bytes (0 = unlimited).
+     *
+     * When a large source would exceed the budget, only a content-defined
+     * sample of source blocks is indexed: the patch gets larger, but memory
+     * stays bounded. See `index_coverage()`.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     * @param {number} bytes
+     */
+    set_index_memory_budget(bytes) {
+        const ret = wasm.patchbuilder_set_index_memory_budget(this.__wbg_ptr, bytes);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Sets the largest INSERT emitted in one piece, in bytes.
+     *
+     * Unmatched target data is buffered until it reaches this size and is
+     * then emitted as an INSERT, so memory stays bounded for dissimilar
+     * files. Defaults to 1MB; `0` is ignored. Takes effect immediately.
+     * @param {number} bytes
+     */
+    set_max_insert_size(bytes) {
+        wasm.patchbuilder_set_max_insert_size(this.__wbg_ptr, bytes);
+    }
+    /**
+     * Records a custom key/value pair, replacing an earlier value.
+     *
+     * Keys are limited to 65535 bytes; longer ones fail with
+     * `INVALID_DATA`.
+     * @param {string} key
+     * @param {string} value
+     */
+    set_metadata(key, value) {
+        const ptr0 = passStringToWasm0(key, wasm.__wbindgen_malloc, wasm.__wbindgen_realloc);
+        const len0 = WASM_VECTOR_LEN;
+        const ptr1 = passStringToWasm0(value, wasm.__wbindgen_malloc, wasm.__wbindgen_realloc);
+        const len1 = WASM_VECTOR_LEN;
+        const ret = wasm.patchbuilder_set_metadata(this.__wbg_ptr, ptr0, len0, ptr1, len1);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Sets the pending output size at which target ingestion asks the
+     * caller to drain, in bytes (0 = unlimited, the default).
+     *
+     * Pending output then stays below the mark plus the output of one
+     * target chunk (at most the chunk size plus the max INSERT size and
+     * instruction overhead). With `set_parallel_threads()`, a chunk that
+     * completes a batch releases the whole batch, so the bound grows by the
+     * batch size (2 MB per thread). Takes effect immediately.
+     * @param {number} bytes
+     */
+    set_output_high_water_mark(bytes) {
+        wasm.patchbuilder_set_output_high_water_mark(this.__wbg_ptr, bytes);
+    }
+    /**
+     * Diffs the target on several threads sharing the source index.
+     *
+     * The target is buffered in batches and split into segments that are
+     * matched concurrently; the patch is identical to a single-threaded
+     * run. `0` disables parallel diffing. Counts above 256 are clamped.
+     * Ignored in tar mode. On wasm the segments run on the Web Worker pool
+     * of a `threads` build once `init_thread_pool()` and its workers have
+     * started, and on the calling thread otherwise; the count then sets the
+     * batch size, and the pool size the parallelism.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     * @param {number} threads
+     */
+    set_parallel_threads(threads) {
+        const ret = wasm.patchbuilder_set_parallel_threads(this.__wbg_ptr, threads);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Selects the weak rolling hash by ID.
+     *
+     * `0` = Adler-32 style (default), `1` = table-driven Buzhash, which is
+     * faster and spreads low-entropy data (zero runs, sparse records)
+     * better. A non-default hash is recorded in a version 2 header. Unknown
+     * IDs fail with `INVALID_DATA`.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     * @param {number} hash_id
+     */
+    set_rolling_hash(hash_id) {
+        const ret = wasm.patchbuilder_set_rolling_hash(this.__wbg_ptr, hash_id);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Verifies every match against the source file before it becomes a
+     * COPY, so a hash collision can't corrupt the target.
+     *
+     * `handle` is an OPFS `FileSystemSyncAccessHandle` of the source file
+     * (any object with its `read(buffer, { at })` and `getSize()` methods).
+     * Costs one source read per match. Not saved by `save_state()`; set it
+     * again after `restore_state()`. Fails in deflate and filter modes,
+     * which diff transformed bytes, and after `finalize_target()`.
+     * @param {any} handle
+     */
+    set_source_handle(handle) {
+        const ret = wasm.patchbuilder_set_source_handle(this.__wbg_ptr, handle);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Records the original source file name in the header.
+     *
+     * Metadata makes the patch version 2 and is informational only.
+     * Like the target size, it fails once the header was written.
+     * @param {string} name
+     */
+    set_source_name(name) {
+        const ptr0 = passStringToWasm0(name, wasm.__wbindgen_malloc, wasm.__wbindgen_realloc);
+        const len0 = WASM_VECTOR_LEN;
+        const ret = wasm.patchbuilder_set_source_name(this.__wbg_ptr, ptr0, len0);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Enables or disables tar-aware diffing.
+     *
+     * In tar mode both files are parsed as tar archives; member contents are
+     * matched against the source member with the same path, aligned on
+     * member boundaries. The patch format is unchanged.
+     *
+     * Fails with `MISUSE` once `add_source_chunk()` was called.
+     * @param {boolean} enabled
+     */
+    set_tar_mode(enabled) {
+        const ret = wasm.patchbuilder_set_tar_mode(this.__wbg_ptr, enabled);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Records the original target file name in the header.
+     * @param {string} name
+     */
+    set_target_name(name) {
+        const ptr0 = passStringToWasm0(name, wasm.__wbindgen_malloc, wasm.__wbindgen_realloc);
+        const len0 = WASM_VECTOR_LEN;
+        const ret = wasm.patchbuilder_set_target_name(this.__wbg_ptr, ptr0, len0);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
     /**
      * Sets the expected total target size.
      *
-     * Must be called before `add_target_chunk()` for proper header generation.
+     * Required before `add_target_chunk()` and `finalize_target()`, since
+     * the header that starts the output records it. Fails once output was
+     * flushed or the target was finalized.
      * @param {bigint} size
      */
     set_target_size(size) {
-        wasm.patchbuilder_set_target_size(this.__wbg_ptr, size);
+        const ret = wasm.patchbuilder_set_target_size(this.__wbg_ptr, size);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Builds the patch without knowing the target size up front.
+     *
+     * For unbounded streams such as `fetch()` bodies. The header gets the
+     * trailer flag and a target size of 0; `finalize_target()` appends a
+     * trailer with the real target size and hash, which makes the patch
+     * version 2. Replaces `set_target_size()` and fails in the same cases.
+     */
+    set_target_size_unknown() {
+        const ret = wasm.patchbuilder_set_target_size_unknown(this.__wbg_ptr);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
     }
     /**
      * Returns the current source size in bytes.
@@ -130,9 +583,413 @@ export class PatchBuilder {
         const ret = wasm.patchbuilder_target_size(this.__wbg_ptr);
         return ret >>> 0;
     }
+    /**
+     * Returns whether pending output must be drained before more target
+     * data is added.
+     * @returns {TargetStatus}
+     */
+    target_status() {
+        const ret = wasm.patchbuilder_target_status(this.__wbg_ptr);
+        return ret;
+    }
 }
 if (Symbol.dispose) PatchBuilder.prototype[Symbol.dispose] = PatchBuilder.prototype.free;
 
+/**
+ * Parsed patch header information.
+ */
+export class PatchHeader {
+    static __wrap(ptr) {
+        ptr = ptr >>> 0;
+        const obj = Object.create(PatchHeader.prototype);
+        obj.__wbg_ptr = ptr;
+        PatchHeaderFinalization.register(obj, obj.__wbg_ptr, obj);
+        return obj;
+    }
+    __destroy_into_raw() {
+        const ptr = this.__wbg_ptr;
+        this.__wbg_ptr = 0;
+        PatchHeaderFinalization.unregister(this);
+        return ptr;
+    }
+    free() {
+        const ptr = this.__destroy_into_raw();
+        wasm.__wbg_patchheader_free(ptr, 0);
+    }
+    /**
+     * Chunk size used during diff generation.
+     * @returns {number}
+     */
+    get chunk_size() {
+        const ret = wasm.__wbg_get_patchheader_chunk_size(this.__wbg_ptr);
+        return ret >>> 0;
+    }
+    /**
+     * Feature flags (version 2 only).
+     * @returns {number}
+     */
+    get flags() {
+        const ret = wasm.__wbg_get_patchheader_flags(this.__wbg_ptr);
+        return ret >>> 0;
+    }
+    /**
+     * Hash of the original source file.
+     * @returns {bigint}
+     */
+    get source_hash() {
+        const ret = wasm.__wbg_get_patchheader_source_hash(this.__wbg_ptr);
+        return BigInt.asUintN(64, ret);
+    }
+    /**
+     * Size of the original source file.
+     * @returns {bigint}
+     */
+    get source_size() {
+        const ret = wasm.__wbg_get_patchheader_source_size(this.__wbg_ptr);
+        return BigInt.asUintN(64, ret);
+    }
+    /**
+     * Hash of the target file, known once `read_trailer()` was called.
+     *
+     * Not part of the header bytes.
+     * @returns {bigint | undefined}
+     */
+    get target_hash() {
+        const ret = wasm.__wbg_get_patchheader_target_hash(this.__wbg_ptr);
+        return ret[0] === 0 ? undefined : BigInt.asUintN(64, ret[1]);
+    }
+    /**
+     * Size of the target file after patching.
+     *
+     * 0 in the header bytes of a trailer patch until `read_trailer()`.
+     * @returns {bigint}
+     */
+    get target_size() {
+        const ret = wasm.__wbg_get_patchheader_target_size(this.__wbg_ptr);
+        return BigInt.asUintN(64, ret);
+    }
+    /**
+     * Returns the creation time in milliseconds since the Unix epoch.
+     * @returns {bigint | undefined}
+     */
+    get created_at() {
+        const ret = wasm.patchheader_created_at(this.__wbg_ptr);
+        return ret[0] === 0 ? undefined : BigInt.asUintN(64, ret[1]);
+    }
+    /**
+     * Returns the serialized header size in bytes.
+     * @returns {number}
+     */
+    get header_size() {
+        const ret = wasm.patchheader_encoded_len(this.__wbg_ptr);
+        return ret >>> 0;
+    }
+    /**
+     * Returns a copy of the payload of the first extension with `tag`.
+     * @param {number} tag
+     * @returns {Uint8Array | undefined}
+     */
+    extension(tag) {
+        const ret = wasm.patchheader_extension(this.__wbg_ptr, tag);
+        let v1;
+        if (ret[0] !== 0) {
+            v1 = getArrayU8FromWasm0(ret[0], ret[1]).slice();
+            wasm.__wbindgen_free(ret[0], ret[1] * 1, 1);
+        }
+        return v1;
+    }
+    /**
+     * Returns the executable filter ID, if a filter was used.
+     * @returns {number | undefined}
+     */
+    get filter_id() {
+        const ret = wasm.patchheader_filter_id(this.__wbg_ptr);
+        return ret === 0xFFFFFF ? undefined : ret;
+    }
+    /**
+     * Returns the name and version of the program that built the patch.
+     * @returns {string | undefined}
+     */
+    get generator() {
+        const ret = wasm.patchheader_generator(this.__wbg_ptr);
+        let v1;
+        if (ret[0] !== 0) {
+            v1 = getStringFromWasm0(ret[0], ret[1]).slice();
+            wasm.__wbindgen_free(ret[0], ret[1] * 1, 1);
+        }
+        return v1;
+    }
+    /**
+     * Returns whether the target size and hash are in a trailer.
+     * @returns {boolean}
+     */
+    get has_trailer() {
+        const ret = wasm.patchheader_has_trailer(this.__wbg_ptr);
+        return ret !== 0;
+    }
+    /**
+     * Returns the value of a custom metadata key.
+     * @param {string} key
+     * @returns {string | undefined}
+     */
+    metadata_value(key) {
+        const ptr0 = passStringToWasm0(key, wasm.__wbindgen_malloc, wasm.__wbindgen_realloc);
+        const len0 = WASM_VECTOR_LEN;
+        const ret = wasm.patchheader_metadata_value(this.__wbg_ptr, ptr0, len0);
+        let v2;
+        if (ret[0] !== 0) {
+            v2 = getStringFromWasm0(ret[0], ret[1]).slice();
+            wasm.__wbindgen_free(ret[0], ret[1] * 1, 1);
+        }
+        return v2;
+    }
+    /**
+     * Parses a header from bytes.
+     *
+     * Accepts version 1 and version 2 headers.
+     *
+     * # Arguments
+     *
+     * * `data` - At least `required_len()` bytes of header data.
+     * @param {Uint8Array} data
+     * @returns {PatchHeader}
+     */
+    static parse(data) {
+        const ptr0 = passArray8ToWasm0(data, wasm.__wbindgen_malloc);
+        const len0 = WASM_VECTOR_LEN;
+        const ret = wasm.patchheader_parse(ptr0, len0);
+        if (ret[2]) {
+            throw takeFromExternrefTable0(ret[1]);
+        }
+        return PatchHeader.__wrap(ret[0]);
+    }
+    /**
+     * Takes the target size and hash from the patch trailer.
+     *
+     * # Arguments
+     *
+     * * `tail` - The last `TRAILER_SIZE` bytes of the patch.
+     * @param {Uint8Array} tail
+     */
+    read_trailer(tail) {
+        const ptr0 = passArray8ToWasm0(tail, wasm.__wbindgen_malloc);
+        const len0 = WASM_VECTOR_LEN;
+        const ret = wasm.patchheader_read_trailer(this.__wbg_ptr, ptr0, len0);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Determines the full header size from its first bytes.
+     *
+     * # Arguments
+     *
+     * * `data` - At least 33 bytes (41 for version 2) of header data.
+     *
+     * # Returns
+     *
+     * The number of bytes `parse()` needs.
+     * @param {Uint8Array} data
+     * @returns {number}
+     */
+    static required_len(data) {
+        const ptr0 = passArray8ToWasm0(data, wasm.__wbindgen_malloc);
+        const len0 = WASM_VECTOR_LEN;
+        const ret = wasm.patchheader_required_len(ptr0, len0);
+        if (ret[2]) {
+            throw takeFromExternrefTable0(ret[1]);
+        }
+        return ret[0] >>> 0;
+    }
+    /**
+     * Returns the weak rolling hash ID, if not the default.
+     * @returns {number | undefined}
+     */
+    get rolling_hash_id() {
+        const ret = wasm.patchheader_rolling_hash_id(this.__wbg_ptr);
+        return ret === 0xFFFFFF ? undefined : ret;
+    }
+    /**
+     * Returns the original source file name.
+     * @returns {string | undefined}
+     */
+    get source_name() {
+        const ret = wasm.patchheader_source_name(this.__wbg_ptr);
+        let v1;
+        if (ret[0] !== 0) {
+            v1 = getStringFromWasm0(ret[0], ret[1]).slice();
+            wasm.__wbindgen_free(ret[0], ret[1] * 1, 1);
+        }
+        return v1;
+    }
+    /**
+     * Returns the original target file name.
+     * @returns {string | undefined}
+     */
+    get target_name() {
+        const ret = wasm.patchheader_target_name(this.__wbg_ptr);
+        let v1;
+        if (ret[0] !== 0) {
+            v1 = getStringFromWasm0(ret[0], ret[1]).slice();
+            wasm.__wbindgen_free(ret[0], ret[1] * 1, 1);
+        }
+        return v1;
+    }
+    /**
+     * Returns the format version this header serializes to.
+     *
+     * Version 2 if it was parsed as version 2 or uses flags or
+     * extensions, so a parsed header keeps its encoded length.
+     * @returns {number}
+     */
+    get version() {
+        const ret = wasm.patchheader_version(this.__wbg_ptr);
+        return ret;
+    }
+}
+if (Symbol.dispose) PatchHeader.prototype[Symbol.dispose] = PatchHeader.prototype.free;
+
+/**
+ * WASM-bindable streaming patch applier with resumable checkpoints.
+ *
+ * Reads COPY data through an OPFS `FileSystemSyncAccessHandle` of the
+ * source and rebuilds the target from the instruction bytes fed to
+ * `apply_chunk()`. Checkpoints describe the instruction stream and its
+ * direct output, so for patches with deflate or filter extensions they
+ * cover the target before those are undone.
+ */
+export class StreamingApplier {
+    static __wrap(ptr) {
+        ptr = ptr >>> 0;
+        const obj = Object.create(StreamingApplier.prototype);
+        obj.__wbg_ptr = ptr;
+        StreamingApplierFinalization.register(obj, obj.__wbg_ptr, obj);
+        return obj;
+    }
+    __destroy_into_raw() {
+        const ptr = this.__wbg_ptr;
+        this.__wbg_ptr = 0;
+        StreamingApplierFinalization.unregister(this);
+        return ptr;
+    }
+    free() {
+        const ptr = this.__destroy_into_raw();
+        wasm.__wbg_streamingapplier_free(ptr, 0);
+    }
+    /**
+     * Applies a chunk of instruction bytes.
+     *
+     * Returns `true` if it paused inside a large COPY with about 1 MiB of
+     * output waiting. The rest of `chunk` is kept: take the output, then
+     * call again with an empty chunk until it returns `false`.
+     * @param {Uint8Array} chunk
+     * @returns {boolean}
+     */
+    apply_chunk(chunk) {
+        const ptr0 = passArray8ToWasm0(chunk, wasm.__wbindgen_malloc);
+        const len0 = WASM_VECTOR_LEN;
+        const ret = wasm.streamingapplier_apply_chunk(this.__wbg_ptr, ptr0, len0);
+        if (ret[2]) {
+            throw takeFromExternrefTable0(ret[1]);
+        }
+        return ret[0] !== 0;
+    }
+    /**
+     * Checks that the patch didn't end inside an instruction.
+     */
+    finish() {
+        const ret = wasm.streamingapplier_finish(this.__wbg_ptr);
+        if (ret[1]) {
+            throw takeFromExternrefTable0(ret[0]);
+        }
+    }
+    /**
+     * Creates an applier reading COPY data from `source_handle`.
+     * @param {any} source_handle
+     */
+    constructor(source_handle) {
+        const ret = wasm.streamingapplier_new(source_handle);
+        if (ret[2]) {
+            throw takeFromExternrefTable0(ret[1]);
+        }
+        this.__wbg_ptr = ret[0] >>> 0;
+        StreamingApplierFinalization.register(this, this.__wbg_ptr, this);
+        return this;
+    }
+    /**
+     * Returns the number of instruction bytes consumed.
+     * @returns {bigint}
+     */
+    patch_offset() {
+        const ret = wasm.streamingapplier_patch_offset(this.__wbg_ptr);
+        return BigInt.asUintN(64, ret);
+    }
+    /**
+     * Resumes from a checkpoint returned by `take_checkpoint()`.
+     *
+     * Verifies the target already written through `written_handle`, then
+     * continues at `patch_offset()` (counted after the header). Truncate
+     * the target to `target_written()` bytes before appending output.
+     * Fails with `CHECKPOINT_MISMATCH` if the written target differs.
+     * @param {any} source_handle
+     * @param {Uint8Array} checkpoint
+     * @param {any} written_handle
+     * @returns {StreamingApplier}
+     */
+    static resume(source_handle, checkpoint, written_handle) {
+        const ptr0 = passArray8ToWasm0(checkpoint, wasm.__wbindgen_malloc);
+        const len0 = WASM_VECTOR_LEN;
+        const ret = wasm.streamingapplier_resume(source_handle, ptr0, len0, written_handle);
+        if (ret[2]) {
+            throw takeFromExternrefTable0(ret[1]);
+        }
+        return StreamingApplier.__wrap(ret[0]);
+    }
+    /**
+     * Records a checkpoint every `interval` target bytes (0 disables).
+     * @param {bigint} interval
+     */
+    set_checkpoint_interval(interval) {
+        wasm.streamingapplier_set_checkpoint_interval(this.__wbg_ptr, interval);
+    }
+    /**
+     * Takes the latest serialized checkpoint, if one was recorded.
+     *
+     * The checkpoint can cover output still waiting in the applier, so
+     * call `take_output()` and persist its bytes before taking it, in that
+     * order.
+     * @returns {Uint8Array | undefined}
+     */
+    take_checkpoint() {
+        const ret = wasm.streamingapplier_take_checkpoint(this.__wbg_ptr);
+        let v1;
+        if (ret[0] !== 0) {
+            v1 = getArrayU8FromWasm0(ret[0], ret[1]).slice();
+            wasm.__wbindgen_free(ret[0], ret[1] * 1, 1);
+        }
+        return v1;
+    }
+    /**
+     * Takes the target bytes rebuilt so far.
+     * @returns {Uint8Array}
+     */
+    take_output() {
+        const ret = wasm.streamingapplier_take_output(this.__wbg_ptr);
+        var v1 = getArrayU8FromWasm0(ret[0], ret[1]).slice();
+        wasm.__wbindgen_free(ret[0], ret[1] * 1, 1);
+        return v1;
+    }
+    /**
+     * Returns the total number of target bytes produced.
+     * @returns {bigint}
+     */
+    target_written() {
+        const ret = wasm.streamingapplier_target_written(this.__wbg_ptr);
+        return BigInt.asUintN(64, ret);
+    }
+}
+if (Symbol.dispose) StreamingApplier.prototype[Symbol.dispose] = StreamingApplier.prototype.free;
+
 /**
  * WASM-bindable streaming hash builder.
  *
@@ -194,6 +1051,36 @@ export class StreamingHasher {
 }
 if (Symbol.dispose) StreamingHasher.prototype[Symbol.dispose] = StreamingHasher.prototype.free;
 
+/**
+ * Result of `PatchBuilder::add_target_chunk()`.
+ * @enum {0 | 1}
+ */
+export const TargetStatus = Object.freeze({
+    /**
+     * More target data can be added.
+     */
+    Ready: 0, "0": "Ready",
+    /**
+     * Pending output reached the high-water mark: drain it with
+     * `flush_output()` or `flush_into()` before adding more target data.
+     */
+    Drain: 1, "1": "Drain",
+});
+
+/**
+ * Detects the executable filter ID for a file from its first bytes.
+ *
+ * Returns `1` for x86/x86-64 ELF, `2` for AArch64 ELF and `0` otherwise.
+ * @param {Uint8Array} header_data
+ * @returns {number}
+ */
+export function detect_filter(header_data) {
+    const ptr0 = passArray8ToWasm0(header_data, wasm.__wbindgen_malloc);
+    const len0 = WASM_VECTOR_LEN;
+    const ret = wasm.detect_filter(ptr0, len0);
+    return ret;
+}
+
 /**
  * Calculates hash of data and returns it as a hex string.
  * @param {Uint8Array} data
@@ -214,35 +1101,6 @@ export function hash_data(data) {
     }
 }
 
-/**
- * Parses only the patch header (33 bytes) without parsing instructions.
- *
- * Returns JSON with sourceSize, sourceHash, targetSize, chunkSize, and headerSize.
- * TypeScript will parse instructions directly from OPFS to avoid loading entire patch.
- * @param {Uint8Array} header_data
- * @returns {string}
- */
-export function parse_patch_header_only(header_data) {
-    let deferred3_0;
-    let deferred3_1;
-    try {
-        const ptr0 = passArray8ToWasm0(header_data, wasm.__wbindgen_malloc);
-        const len0 = WASM_VECTOR_LEN;
-        const ret = wasm.parse_patch_header_only(ptr0, len0);
-        var ptr2 = ret[0];
-        var len2 = ret[1];
-        if (ret[3]) {
-            ptr2 = 0; len2 = 0;
-            throw takeFromExternrefTable0(ret[2]);
-        }
-        deferred3_0 = ptr2;
-        deferred3_1 = len2;
-        return getStringFromWasm0(ptr2, len2);
-    } finally {
-        wasm.__wbindgen_free(deferred3_0, deferred3_1, 1);
-    }
-}
-
 /**
  * Returns the library version.
  * @returns {string}
@@ -263,13 +1121,77 @@ export function version() {
 function __wbg_get_imports() {
     const import0 = {
         __proto__: null,
-        __wbg_Error_8c4e43fe74559d73: function(arg0, arg1) {
-            const ret = Error(getStringFromWasm0(arg0, arg1));
+        __wbg___wbindgen_copy_to_typed_array_fc0809a4dec43528: function(arg0, arg1, arg2) {
+            new Uint8Array(arg2.buffer, arg2.byteOffset, arg2.byteLength).set(getArrayU8FromWasm0(arg0, arg1));
+        },
+        __wbg___wbindgen_debug_string_0bc8482c6e3508ae: function(arg0, arg1) {
+            const ret = debugString(arg1);
+            const ptr1 = passStringToWasm0(ret, wasm.__wbindgen_malloc, wasm.__wbindgen_realloc);
+            const len1 = WASM_VECTOR_LEN;
+            getDataViewMemory0().setInt32(arg0 + 4 * 1, len1, true);
+            getDataViewMemory0().setInt32(arg0 + 4 * 0, ptr1, true);
+        },
+        __wbg___wbindgen_is_function_0095a73b8b156f76: function(arg0) {
+            const ret = typeof(arg0) === 'function';
             return ret;
         },
+        __wbg___wbindgen_number_get_8ff4255516ccad3e: function(arg0, arg1) {
+            const obj = arg1;
+            const ret = typeof(obj) === 'number' ? obj : undefined;
+            getDataViewMemory0().setFloat64(arg0 + 8 * 1, isLikeNone(ret) ? 0 : ret, true);
+            getDataViewMemory0().setInt32(arg0 + 4 * 0, !isLikeNone(ret), true);
+        },
         __wbg___wbindgen_throw_be289d5034ed271b: function(arg0, arg1) {
             throw new Error(getStringFromWasm0(arg0, arg1));
         },
+        __wbg_call_389efe28435a9388: function() { return handleError(function (arg0, arg1) {
+            const ret = arg0.call(arg1);
+            return ret;
+        }, arguments); },
+        __wbg_call_812d25f1510c13c8: function() { return handleError(function (arg0, arg1, arg2, arg3) {
+            const ret = arg0.call(arg1, arg2, arg3);
+            return ret;
+        }, arguments); },
+        __wbg_get_b3ed3ad4be2bc8ac: function() { return handleError(function (arg0, arg1) {
+            const ret = Reflect.get(arg0, arg1);
+            return ret;
+        }, arguments); },
+        __wbg_length_32ed9a279acd054c: function(arg0) {
+            const ret = arg0.length;
+            return ret;
+        },
+        __wbg_new_361308b2356cecd0: function() {
+            const ret = new Object();
+            return ret;
+        },
+        __wbg_new_72b49615380db768: function(arg0, arg1) {
+            const ret = new Error(getStringFromWasm0(arg0, arg1));
+            return ret;
+        },
+        __wbg_new_with_length_a2c39cbe88fd8ff1: function(arg0) {
+            const ret = new Uint8Array(arg0 >>> 0);
+            return ret;
+        },
+        __wbg_prototypesetcall_bdcdcc5842e4d77d: function(arg0, arg1, arg2) {
+            Uint8Array.prototype.set.call(getArrayU8FromWasm0(arg0, arg1), arg2);
+        },
+        __wbg_set_6cb8631f80447a67: function() { return handleError(function (arg0, arg1, arg2) {
+            const ret = Reflect.set(arg0, arg1, arg2);
+            return ret;
+        }, arguments); },
+        __wbg_set_name_2aca19de8d382675: function(arg0, arg1, arg2) {
+            arg0.name = getStringFromWasm0(arg1, arg2);
+        },
+        __wbindgen_cast_0000000000000001: function(arg0) {
+            // Cast intrinsic for `F64 -> Externref`.
+            const ret = arg0;
+            return ret;
+        },
+        __wbindgen_cast_0000000000000002: function(arg0, arg1) {
+            // Cast intrinsic for `Ref(String) -> Externref`.
+            const ret = getStringFromWasm0(arg0, arg1);
+            return ret;
+        },
         __wbindgen_init_externref_table: function() {
             const table = wasm.__wbindgen_externrefs;
             const offset = table.grow(4);
@@ -286,18 +1208,106 @@ function __wbg_get_imports() {
     };
 }
 
+const ExecutableFilterFinalization = (typeof FinalizationRegistry === 'undefined')
+    ? { register: () => {}, unregister: () => {} }
+    : new FinalizationRegistry(ptr => wasm.__wbg_executablefilter_free(ptr >>> 0, 1));
 const PatchBuilderFinalization = (typeof FinalizationRegistry === 'undefined')
     ? { register: () => {}, unregister: () => {} }
     : new FinalizationRegistry(ptr => wasm.__wbg_patchbuilder_free(ptr >>> 0, 1));
+const PatchHeaderFinalization = (typeof FinalizationRegistry === 'undefined')
+    ? { register: () => {}, unregister: () => {} }
+    : new FinalizationRegistry(ptr => wasm.__wbg_patchheader_free(ptr >>> 0, 1));
+const StreamingApplierFinalization = (typeof FinalizationRegistry === 'undefined')
+    ? { register: () => {}, unregister: () => {} }
+    : new FinalizationRegistry(ptr => wasm.__wbg_streamingapplier_free(ptr >>> 0, 1));
 const StreamingHasherFinalization = (typeof FinalizationRegistry === 'undefined')
     ? { register: () => {}, unregister: () => {} }
     : new FinalizationRegistry(ptr => wasm.__wbg_streaminghasher_free(ptr >>> 0, 1));
 
+function addToExternrefTable0(obj) {
+    const idx = wasm.__externref_table_alloc();
+    wasm.__wbindgen_externrefs.set(idx, obj);
+    return idx;
+}
+
+function debugString(val) {
+    // primitive types
+    const type = typeof val;
+    if (type == 'number' || type == 'boolean' || val == null) {
+        return  `${val}`;
+    }
+    if (type == 'string') {
+        return `"${val}"`;
+    }
+    if (type == 'symbol') {
+        const description = val.description;
+        if (description == null) {
+            return 'Symbol';
+        } else {
+            return `Symbol(${description})`;
+        }
+    }
+    if (type == 'function') {
+        const name = val.name;
+        if (typeof name == 'string' && name.length > 0) {
+            return `Function(${name})`;
+        } else {
+            return 'Function';
+        }
+    }
+    // objects
+    if (Array.isArray(val)) {
+        const length = val.length;
+        let debug = '[';
+        if (length > 0) {
+            debug += debugString(val[0]);
+        }
+        for(let i = 1; i < length; i++) {
+            debug += ', ' + debugString(val[i]);
+        }
+        debug += ']';
+        return debug;
+    }
+    // Test for built-in
+    const builtInMatches = /\[object ([^\]]+)\]/.exec(toString.call(val));
+    let className;
+    if (builtInMatches && builtInMatches.length > 1) {
+        className = builtInMatches[1];
+    } else {
+        // Failed to match the standard '[object ClassName]'
+        return toString.call(val);
+    }
+    if (className == 'Object') {
+        // we're a user defined class or Object
+        // JSON.stringify avoids problems with cycles, and is generally much
+        // easier than looping through ownProperties of `val`.
+        try {
+            return 'Object(' + JSON.stringify(val) + ')';
+        } catch (_) {
+            return 'Object';
+        }
+    }
+    // errors
+    if (val instanceof Error) {
+        return `${val.name}: ${val.message}\n${val.stack}`;
+    }
+    // TODO we could test for more things here, like `Set`s and `Map`s.
+    return className;
+}
+
 function getArrayU8FromWasm0(ptr, len) {
     ptr = ptr >>> 0;
     return getUint8ArrayMemory0().subarray(ptr / 1, ptr / 1 + len);
 }
 
+let cachedDataViewMemory0 = null;
+function getDataViewMemory0() {
+    if (cachedDataViewMemory0 === null || cachedDataViewMemory0.buffer.detached === true || (cachedDataViewMemory0.buffer.detached === undefined && cachedDataViewMemory0.buffer !== wasm.memory.buffer)) {
+        cachedDataViewMemory0 = new DataView(wasm.memory.buffer);
+    }
+    return cachedDataViewMemory0;
+}
+
 function getStringFromWasm0(ptr, len) {
     ptr = ptr >>> 0;
     return decodeText(ptr, len);
@@ -311,6 +1321,19 @@ function getUint8ArrayMemory0() {
     return cachedUint8ArrayMemory0;
 }
 
+function handleError(f, args) {
+    try {
+        return f.apply(this, args);
+    } catch (e) {
+        const idx = addToExternrefTable0(e);
+        wasm.__wbindgen_exn_store(idx);
+    }
+}
+
+function isLikeNone(x) {
+    return x === undefined || x === null;
+}
+
 function passArray8ToWasm0(arg, malloc) {
     const ptr = malloc(arg.length * 1, 1) >>> 0;
     getUint8ArrayMemory0().set(arg, ptr / 1);
@@ -318,6 +1341,43 @@ function passArray8ToWasm0(arg, malloc) {
     return ptr;
 }
 
+function passStringToWasm0(arg, malloc, realloc) {
+    if (realloc === undefined) {
+        const buf = cachedTextEncoder.encode(arg);
+        const ptr = malloc(buf.length, 1) >>> 0;
+        getUint8ArrayMemory0().subarray(ptr, ptr + buf.length).set(buf);
+        WASM_VECTOR_LEN = buf.length;
+        return ptr;
+    }
+
+    let len = arg.length;
+    let ptr = malloc(len, 1) >>> 0;
+
+    const mem = getUint8ArrayMemory0();
+
+    let offset = 0;
+
+    for (; offset < len; offset++) {
+        const code = arg.charCodeAt(offset);
+        if (code > 0x7F) break;
+        mem[ptr + offset] = code;
+    }
+    if (offset !== len) {
+        if (offset !== 0) {
+            arg = arg.slice(offset);
+        }
+        ptr = realloc(ptr, len, len = offset + arg.length * 3, 1) >>> 0;
+        const view = getUint8ArrayMemory0().subarray(ptr + offset, ptr + len);
+        const ret = cachedTextEncoder.encodeInto(arg, view);
+
+        offset += ret.written;
+        ptr = realloc(ptr, len, offset, 1) >>> 0;
+    }
+
+    WASM_VECTOR_LEN = offset;
+    return ptr;
+}
+
 function takeFromExternrefTable0(idx) {
     const value = wasm.__wbindgen_externrefs.get(idx);
     wasm.__externref_table_dealloc(idx);
@@ -338,12 +1398,26 @@ function decodeText(ptr, len) {
     return cachedTextDecoder.decode(getUint8ArrayMemory0().subarray(ptr, ptr + len));
 }
 
+const cachedTextEncoder = new TextEncoder();
+
+if (!('encodeInto' in cachedTextEncoder)) {
+    cachedTextEncoder.encodeInto = function (arg, view) {
+        const buf = cachedTextEncoder.encode(arg);
+        view.set(buf);
+        return {
+            read: arg.length,
+            written: buf.length
+        };
+    };
+}
+
 let WASM_VECTOR_LEN = 0;
 
 let wasmModule, wasm;
 function __wbg_finalize_init(instance, module) {
     wasm = instance.exports;
     wasmModule = module;
+    cachedDataViewMemory0 = null;
     cachedUint8ArrayMemory0 = null;
     wasm.__wbindgen_start();
     return wasm;
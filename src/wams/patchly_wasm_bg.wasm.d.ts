@@ -1,30 +1,92 @@
 /* tslint:disable */
 /* eslint-disable */
 export const memory: WebAssembly.Memory;
+export const __wbg_executablefilter_free: (a: number, b: number) => void;
 export const __wbg_patchbuilder_free: (a: number, b: number) => void;
-export const patchbuilder_new: () => number;
-export const patchbuilder_add_source_chunk: (a: number, b: number, c: number) => void;
-export const patchbuilder_finalize_source: (a: number) => void;
-export const patchbuilder_set_target_size: (a: number, b: bigint) => void;
-export const patchbuilder_add_target_chunk: (a: number, b: number, c: number) => void;
-export const patchbuilder_finalize_target: (a: number) => void;
-export const patchbuilder_source_size: (a: number) => number;
-export const patchbuilder_target_size: (a: number) => number;
+export const __wbg_streamingapplier_free: (a: number, b: number) => void;
+export const __wbg_streaminghasher_free: (a: number, b: number) => void;
+export const detect_filter: (a: number, b: number) => number;
+export const executablefilter_decoder: (a: number) => [number, number, number];
+export const executablefilter_encoder: (a: number) => [number, number, number];
+export const executablefilter_finish: (a: number) => [number, number];
+export const executablefilter_process: (a: number, b: number, c: number) => [number, number];
+export const hash_data: (a: number, b: number) => [number, number];
+export const patchbuilder_add_source_chunk: (a: number, b: number, c: number) => [number, number];
+export const patchbuilder_add_target_chunk: (a: number, b: number, c: number) => [number, number, number];
 export const patchbuilder_are_files_identical: (a: number) => number;
+export const patchbuilder_finalize_source: (a: number) => [number, number];
+export const patchbuilder_finalize_target: (a: number) => [number, number];
+export const patchbuilder_flush_into: (a: number, b: number, c: number, d: any) => [number, number, number];
+export const patchbuilder_flush_output: (a: number, b: number) => [number, number, number, number];
 export const patchbuilder_has_output: (a: number) => number;
-export const patchbuilder_flush_output: (a: number, b: number) => [number, number];
+export const patchbuilder_index_bytes_per_block: (a: number) => number;
+export const patchbuilder_index_coverage: (a: number) => number;
+export const patchbuilder_index_memory_usage: (a: number) => number;
+export const patchbuilder_new: () => number;
 export const patchbuilder_pending_output_size: (a: number) => number;
+export const patchbuilder_rejected_matches: (a: number) => bigint;
 export const patchbuilder_reset: (a: number) => void;
-export const parse_patch_header_only: (a: number, b: number) => [number, number, number, number];
-export const version: () => [number, number];
-export const __wbg_streaminghasher_free: (a: number, b: number) => void;
-export const streaminghasher_new: () => number;
-export const streaminghasher_update: (a: number, b: number, c: number) => void;
+export const patchbuilder_restore_state: (a: number, b: number) => [number, number, number];
+export const patchbuilder_save_state: (a: number) => [number, number, number, number];
+export const patchbuilder_set_created_at: (a: number, b: bigint) => [number, number];
+export const patchbuilder_set_deflate_mode: (a: number, b: number) => [number, number];
+export const patchbuilder_set_filter: (a: number, b: number) => [number, number];
+export const patchbuilder_set_fine_block_size: (a: number, b: number) => [number, number];
+export const patchbuilder_set_index_memory_budget: (a: number, b: number) => [number, number];
+export const patchbuilder_set_max_insert_size: (a: number, b: number) => void;
+export const patchbuilder_set_metadata: (a: number, b: number, c: number, d: number, e: number) => [number, number];
+export const patchbuilder_set_output_high_water_mark: (a: number, b: number) => void;
+export const patchbuilder_set_parallel_threads: (a: number, b: number) => [number, number];
+export const patchbuilder_set_rolling_hash: (a: number, b: number) => [number, number];
+export const patchbuilder_set_source_handle: (a: number, b: any) => [number, number];
+export const patchbuilder_set_source_name: (a: number, b: number, c: number) => [number, number];
+export const patchbuilder_set_tar_mode: (a: number, b: number) => [number, number];
+export const patchbuilder_set_target_name: (a: number, b: number, c: number) => [number, number];
+export const patchbuilder_set_target_size: (a: number, b: bigint) => [number, number];
+export const patchbuilder_set_target_size_unknown: (a: number) => [number, number];
+export const patchbuilder_source_size: (a: number) => number;
+export const patchbuilder_target_size: (a: number) => number;
+export const patchbuilder_target_status: (a: number) => number;
+export const streamingapplier_apply_chunk: (a: number, b: number, c: number) => [number, number, number];
+export const streamingapplier_finish: (a: number) => [number, number];
+export const streamingapplier_new: (a: any) => [number, number, number];
+export const streamingapplier_patch_offset: (a: number) => bigint;
+export const streamingapplier_resume: (a: any, b: number, c: number, d: any) => [number, number, number];
+export const streamingapplier_set_checkpoint_interval: (a: number, b: bigint) => void;
+export const streamingapplier_take_checkpoint: (a: number) => [number, number];
+export const streamingapplier_take_output: (a: number) => [number, number];
+export const streamingapplier_target_written: (a: number) => bigint;
 export const streaminghasher_finalize: (a: number) => [number, number];
 export const streaminghasher_finalize_u64: (a: number) => bigint;
-export const hash_data: (a: number, b: number) => [number, number];
-export const __wbindgen_externrefs: WebAssembly.Table;
+export const streaminghasher_new: () => number;
+export const streaminghasher_update: (a: number, b: number, c: number) => void;
+export const version: () => [number, number];
+export const __wbg_get_patchheader_chunk_size: (a: number) => number;
+export const __wbg_get_patchheader_flags: (a: number) => number;
+export const __wbg_get_patchheader_source_hash: (a: number) => bigint;
+export const __wbg_get_patchheader_source_size: (a: number) => bigint;
+export const __wbg_get_patchheader_target_hash: (a: number) => [number, bigint];
+export const __wbg_get_patchheader_target_size: (a: number) => bigint;
+export const __wbg_patchheader_free: (a: number, b: number) => void;
+export const patchheader_created_at: (a: number) => [number, bigint];
+export const patchheader_encoded_len: (a: number) => number;
+export const patchheader_extension: (a: number, b: number) => [number, number];
+export const patchheader_filter_id: (a: number) => number;
+export const patchheader_generator: (a: number) => [number, number];
+export const patchheader_has_trailer: (a: number) => number;
+export const patchheader_metadata_value: (a: number, b: number, c: number) => [number, number];
+export const patchheader_parse: (a: number, b: number) => [number, number, number];
+export const patchheader_read_trailer: (a: number, b: number, c: number) => [number, number];
+export const patchheader_required_len: (a: number, b: number) => [number, number, number];
+export const patchheader_rolling_hash_id: (a: number) => number;
+export const patchheader_source_name: (a: number) => [number, number];
+export const patchheader_target_name: (a: number) => [number, number];
+export const patchheader_version: (a: number) => number;
 export const __wbindgen_malloc: (a: number, b: number) => number;
-export const __wbindgen_free: (a: number, b: number, c: number) => void;
+export const __wbindgen_realloc: (a: number, b: number, c: number, d: number) => number;
+export const __wbindgen_exn_store: (a: number) => void;
+export const __externref_table_alloc: () => number;
+export const __wbindgen_externrefs: WebAssembly.Table;
 export const __externref_table_dealloc: (a: number) => void;
+export const __wbindgen_free: (a: number, b: number, c: number) => void;
 export const __wbindgen_start: () => void;
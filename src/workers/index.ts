@@ -1,5 +1,6 @@
 import type {
   CompleteCallback,
+  CreatePatchOptions,
   ProgressCallback,
   ErrorCallback,
 } from "./types";
@@ -76,12 +77,18 @@ export class PatchlyWorker {
     this.onIdentical = onIdentical;
   }
 
-  createPatch(sourceFile: File, targetFile: File, outputName: string) {
+  createPatch(
+    sourceFile: File,
+    targetFile: File,
+    outputName: string,
+    options?: CreatePatchOptions,
+  ) {
     this.worker.postMessage({
       type: "createPatch",
       sourceFile,
       targetFile,
       outputName,
+      options,
     });
   }
 
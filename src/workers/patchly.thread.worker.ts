@@ -0,0 +1,29 @@
+/**
+ * Patchly diff thread worker
+ *
+ * Runs one thread of the WASM Web Worker pool used by parallel diffing.
+ * Only started for the shared-memory (`threads`) build: it instantiates
+ * the module with the memory of the patch worker and then runs until it
+ * is terminated.
+ */
+
+import { initSync } from "../wams/patchly_wasm.js";
+import * as patchly from "../wams/patchly_wasm.js";
+
+/** Pool exports present in the shared-memory build only. */
+interface PoolThreadExports {
+  run_pool_thread(): void;
+}
+
+/** Module and shared memory of the patch worker. */
+interface PoolThreadMessage {
+  module: WebAssembly.Module;
+  memory: WebAssembly.Memory;
+}
+
+self.onmessage = (event: MessageEvent<PoolThreadMessage>): void => {
+  // Only the threads build declares the memory option
+  const input: PoolThreadMessage = event.data;
+  initSync(input);
+  (patchly as unknown as PoolThreadExports).run_pool_thread();
+};
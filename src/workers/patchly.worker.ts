@@ -8,37 +8,53 @@
 import { formatSize } from "../utils/bytes";
 import {
   createOpfsFile,
-  streamFileToOpfs,
   getSyncAccessHandle,
   safeDeleteOpfsFile,
 } from "../utils/opfs";
 import init, {
+  ExecutableFilter,
   PatchBuilder,
+  PatchHeader,
   version,
   hash_data,
-  parse_patch_header_only,
+  StreamingApplier,
   StreamingHasher,
+  TargetStatus,
 } from "../wams/patchly_wasm.js";
-import type { WorkerMessage, WorkerResponse } from "./types";
+import * as patchly from "../wams/patchly_wasm.js";
+import type {
+  CreatePatchOptions,
+  WorkerMessage,
+  WorkerResponse,
+} from "./types";
 
 // ============================================================================
 // Constants
 // ============================================================================
 
+/** Most diff threads started for the shared-memory build. */
+const MAX_DIFF_THREADS = 8;
+
 /** Chunk size for batched OPFS writes (1MB). */
 const WRITE_CHUNK_SIZE = 1024 * 1024;
 
-/** Size of reusable read buffer for small reads (64KB). */
-const SMALL_READ_SIZE = 64 * 1024;
+/** Pending patch output at which target ingestion pauses to drain (4MB). */
+const OUTPUT_HIGH_WATER_MARK = 4 * WRITE_CHUNK_SIZE;
+
+/** Bytes read to determine the full header size (fixed part of a v2 header). */
+const HEADER_PREFIX_SIZE = 41;
+
+/** Patch trailer size in bytes (END marker + target size + target hash). */
+const TRAILER_SIZE = 17;
 
-/** Patch header size in bytes. */
-const HEADER_SIZE = 33;
+/** Header flags for features the browser applier lacks. */
+const UNSUPPORTED_FLAGS = [{ flag: 1 << 1, name: "deflate streams" }];
 
-/** COPY instruction type marker. */
-const TYPE_COPY = 0x01;
+/** Target bytes between apply checkpoints (64MB). */
+const CHECKPOINT_INTERVAL = 64 * 1024 * 1024;
 
-/** INSERT instruction type marker. */
-const TYPE_INSERT = 0x02;
+/** Size of a serialized apply checkpoint. */
+const CHECKPOINT_SIZE = 44;
 
 /** Progress update interval in milliseconds. */
 const PROGRESS_INTERVAL_MS = 100;
@@ -47,6 +63,8 @@ const PROGRESS_INTERVAL_MS = 100;
 const TEMP_FILES = {
   SOURCE: "_source.tmp",
   PATCH: "_patch.tmp",
+  /** Patch hash (8 bytes) followed by the latest apply checkpoint. */
+  CHECKPOINT: "_apply.checkpoint",
 } as const;
 
 // ============================================================================
@@ -55,6 +73,9 @@ const TEMP_FILES = {
 
 let wasmInitialized = false;
 
+/** Diff threads in the Web Worker pool (0 = no pool). */
+let diffThreads = 0;
+
 // ============================================================================
 // Utilities
 // ============================================================================
@@ -64,11 +85,49 @@ function send(msg: WorkerResponse): void {
   self.postMessage(msg);
 }
 
+/** Pool exports present in the shared-memory (`threads`) build only. */
+interface ThreadPoolExports {
+  init_thread_pool(threads: number): void;
+  thread_pool_module(): WebAssembly.Module;
+  thread_pool_memory(): WebAssembly.Memory;
+}
+
+/**
+ * Starts the Web Worker pool for parallel diffing.
+ *
+ * Needs the shared-memory build and a cross-origin isolated page; other
+ * builds diff on this worker alone.
+ */
+function startThreadPool(): void {
+  if (!("init_thread_pool" in patchly) || !self.crossOriginIsolated) return;
+
+  const pool = patchly as unknown as ThreadPoolExports;
+  const threads = Math.min(
+    MAX_DIFF_THREADS,
+    Math.max(1, navigator.hardwareConcurrency - 1),
+  );
+  pool.init_thread_pool(threads);
+
+  const message = {
+    module: pool.thread_pool_module(),
+    memory: pool.thread_pool_memory(),
+  };
+  for (let i = 0; i < threads; i++) {
+    const worker = new Worker(
+      new URL("./patchly.thread.worker.ts", import.meta.url),
+      { type: "module" },
+    );
+    worker.postMessage(message);
+  }
+  diffThreads = threads;
+}
+
 /** Initializes the WASM module. */
 async function initWasm(): Promise<void> {
   if (wasmInitialized) return;
 
   await init();
+  startThreadPool();
   wasmInitialized = true;
   send({ type: "ready" });
 }
@@ -109,14 +168,22 @@ async function readFileChunked(
  * @param sourceFile - Original file.
  * @param targetFile - Modified file.
  * @param outputName - Output filename in OPFS.
+ * @param options - Patch creation options.
  */
 async function createPatch(
   sourceFile: File,
   targetFile: File,
   outputName: string,
+  options: CreatePatchOptions = {},
 ): Promise<void> {
   try {
     const builder = new PatchBuilder();
+    if (diffThreads > 0) builder.set_parallel_threads(diffThreads);
+    if (options.includeMetadata) {
+      builder.set_source_name(sourceFile.name);
+      builder.set_target_name(targetFile.name);
+      builder.set_created_at(BigInt(Date.now()));
+    }
 
     // Phase 1: Index source file (0-40%)
     send({ type: "progress", stage: "Indexing source", percent: 0 });
@@ -136,28 +203,29 @@ async function createPatch(
 
     // Set target size for header
     builder.set_target_size(BigInt(targetFile.size));
+    builder.set_output_high_water_mark(OUTPUT_HIGH_WATER_MARK);
 
     // Phase 2: Process target file (40-90%)
     const writable = await createOpfsFile(outputName);
     let totalWritten = 0;
+    // Reused for every flush; each write is awaited before it is refilled
+    const outBuffer = new Uint8Array(WRITE_CHUNK_SIZE);
     const reader = targetFile.stream().getReader();
 
     while (true) {
       const { done, value } = await reader.read();
       if (done) break;
 
-      builder.add_target_chunk(value);
+      let status = builder.add_target_chunk(value);
 
-      // Flush output when buffer is large enough
-      while (
-        builder.has_output() &&
-        builder.pending_output_size() >= WRITE_CHUNK_SIZE
-      ) {
-        const patchChunk = builder.flush_output(WRITE_CHUNK_SIZE);
-        if (patchChunk.length === 0) break;
+      // Drain pending output before the next chunk once it reaches the mark
+      while (status === TargetStatus.Drain) {
+        const written = builder.flush_into(outBuffer);
+        if (written === 0) break;
 
-        await writable.write(new Blob([patchChunk.slice()]));
-        totalWritten += patchChunk.length;
+        await writable.write(outBuffer.subarray(0, written));
+        totalWritten += written;
+        status = builder.target_status();
       }
 
       send({
@@ -182,11 +250,11 @@ async function createPatch(
 
     // Flush remaining output
     while (builder.has_output()) {
-      const patchChunk = builder.flush_output(WRITE_CHUNK_SIZE);
-      if (patchChunk.length === 0) break;
+      const written = builder.flush_into(outBuffer);
+      if (written === 0) break;
 
-      await writable.write(new Blob([patchChunk.slice()]));
-      totalWritten += patchChunk.length;
+      await writable.write(outBuffer.subarray(0, written));
+      totalWritten += written;
     }
 
     await writable.close();
@@ -203,12 +271,124 @@ async function createPatch(
 // Patch Application
 // ============================================================================
 
-/** Parsed patch header information. */
-interface PatchHeader {
+/** Patch layout and validation data read from the header (and trailer). */
+interface PatchInfo {
   sourceSize: number;
-  sourceHash: string;
+  sourceHash: bigint;
   targetSize: number;
   headerSize: number;
+  /** Offset where the instructions end (start of the trailer, if any). */
+  instructionsEnd: number;
+  /** Executable filter the patch was built with, if any. */
+  filterId?: number;
+}
+
+/**
+ * Reads the patch header, and the trailer if the header announces one.
+ *
+ * Parsing is done by the WASM `PatchHeader` class, which throws an error
+ * with a stable `code` for malformed headers.
+ *
+ * @param patchHandle - Sync access handle of the patch file.
+ */
+function readPatchInfo(patchHandle: FileSystemSyncAccessHandle): PatchInfo {
+  const patchFileSize = patchHandle.getSize();
+  const prefix = new Uint8Array(Math.min(HEADER_PREFIX_SIZE, patchFileSize));
+  patchHandle.read(prefix, { at: 0 });
+
+  const headerBuffer = new Uint8Array(PatchHeader.required_len(prefix));
+  patchHandle.read(headerBuffer, { at: 0 });
+
+  const header = PatchHeader.parse(headerBuffer);
+  try {
+    for (const { flag, name } of UNSUPPORTED_FLAGS) {
+      if (header.flags & flag) {
+        throw new Error(`Patch uses ${name}, which are not supported here`);
+      }
+    }
+
+    let instructionsEnd = patchFileSize;
+    if (header.has_trailer) {
+      instructionsEnd -= TRAILER_SIZE;
+      const tail = new Uint8Array(TRAILER_SIZE);
+      patchHandle.read(tail, { at: instructionsEnd });
+      header.read_trailer(tail);
+    }
+
+    return {
+      sourceSize: Number(header.source_size),
+      sourceHash: header.source_hash,
+      targetSize: Number(header.target_size),
+      headerSize: header.header_size,
+      instructionsEnd,
+      filterId: header.filter_id,
+    };
+  } finally {
+    header.free();
+  }
+}
+
+/**
+ * Resumes from the checkpoint of an interrupted run of the same patch.
+ *
+ * Returns `undefined` if there is no checkpoint for this patch or the
+ * output written so far no longer matches it.
+ *
+ * @param sourceHandle - Sync access handle of the staged source.
+ * @param outputHandle - Sync access handle of the output file.
+ * @param checkpointHandle - Sync access handle of the checkpoint file.
+ * @param patchHash - Hash of the patch file being applied.
+ */
+function resumeApplier(
+  sourceHandle: FileSystemSyncAccessHandle,
+  outputHandle: FileSystemSyncAccessHandle,
+  checkpointHandle: FileSystemSyncAccessHandle,
+  patchHash: bigint,
+): StreamingApplier | undefined {
+  if (checkpointHandle.getSize() !== 8 + CHECKPOINT_SIZE) return undefined;
+
+  const saved = new Uint8Array(8 + CHECKPOINT_SIZE);
+  checkpointHandle.read(saved, { at: 0 });
+  if (new DataView(saved.buffer).getBigUint64(0, true) !== patchHash) {
+    return undefined;
+  }
+
+  try {
+    return StreamingApplier.resume(
+      sourceHandle,
+      saved.subarray(8),
+      outputHandle,
+    );
+  } catch (err) {
+    if ((err as { code?: string }).code === "CHECKPOINT_MISMATCH") {
+      return undefined;
+    }
+    throw err;
+  }
+}
+
+/**
+ * Persists an apply checkpoint, keyed by the patch hash.
+ *
+ * @param checkpointHandle - Sync access handle of the checkpoint file.
+ * @param patchHash - Hash of the patch file being applied.
+ * @param checkpoint - Serialized checkpoint from the applier.
+ */
+function saveCheckpoint(
+  checkpointHandle: FileSystemSyncAccessHandle,
+  patchHash: bigint,
+  checkpoint: Uint8Array,
+): void {
+  const saved = new Uint8Array(8 + CHECKPOINT_SIZE);
+  new DataView(saved.buffer).setBigUint64(0, patchHash, true);
+  saved.set(checkpoint, 8);
+  checkpointHandle.write(saved, { at: 0 });
+  checkpointHandle.flush();
+}
+
+/** Formats a 64-bit hash as 16 hex digits. */
+function formatHash(hash: bigint): string {
+  return hash.toString(16).padStart(16, "0");
 }
 
 /**
@@ -227,28 +407,48 @@ async function applyPatch(
   outputName: string,
 ): Promise<void> {
   try {
-    // Phase 1: Stream patch file to OPFS (0-10%)
+    // Phase 1: Stream patch file to OPFS (0-10%), hashing it to match
+    // checkpoints of an interrupted earlier run
     send({ type: "progress", stage: "Reading patch", percent: 0 });
 
-    await streamFileToOpfs(patchFile, TEMP_FILES.PATCH, (bytes, total) => {
-      send({
-        type: "progress",
-        stage: "Reading patch",
-        percent: (bytes / total) * 10,
-        detail: formatSize(bytes),
-      });
-    });
+    const patchHasher = new StreamingHasher();
+    const patchWritable = await createOpfsFile(TEMP_FILES.PATCH);
+    const patchReader = patchFile.stream().getReader();
+    let patchBytesWritten = 0;
+
+    try {
+      while (true) {
+        const { done, value } = await patchReader.read();
+        if (done) break;
+
+        patchHasher.update(value);
+        await patchWritable.write(value);
+        patchBytesWritten += value.length;
+
+        send({
+          type: "progress",
+          stage: "Reading patch",
+          percent: (patchBytesWritten / patchFile.size) * 10,
+          detail: formatSize(patchBytesWritten),
+        });
+      }
+    } finally {
+      await patchWritable.close();
+    }
+    const patchHash = patchHasher.finalize_u64();
+    patchHasher.free();
 
     // Phase 2: Parse patch header (10%)
     send({ type: "progress", stage: "Parsing header", percent: 10 });
 
     const patchHandle = await getSyncAccessHandle(TEMP_FILES.PATCH);
-    const headerBuffer = new Uint8Array(HEADER_SIZE);
-    patchHandle.read(headerBuffer, { at: 0 });
-
-    const headerInfo: PatchHeader = JSON.parse(
-      parse_patch_header_only(headerBuffer),
-    );
+    let headerInfo: PatchInfo;
+    try {
+      headerInfo = readPatchInfo(patchHandle);
+    } catch (err) {
+      patchHandle.close();
+      throw err;
+    }
 
     // Validate source file size
     if (sourceFile.size !== headerInfo.sourceSize) {
@@ -260,21 +460,26 @@ async function applyPatch(
       return;
     }
 
-    // Phase 3: Stream source to OPFS with hash validation (12-40%)
+    // Phase 3: Stream source to OPFS with hash validation (12-40%). Patches
+    // built with an executable filter were diffed against the filtered
+    // source, so that is what gets staged.
     send({ type: "progress", stage: "Reading source", percent: 12 });
 
     const hashBuilder = new StreamingHasher();
     const sourceWritable = await createOpfsFile(TEMP_FILES.SOURCE);
     const sourceReader = sourceFile.stream().getReader();
+    const filterId = headerInfo.filterId;
+    let encoder: ExecutableFilter | undefined;
     let sourceBytesWritten = 0;
 
     try {
+      if (filterId !== undefined) encoder = ExecutableFilter.encoder(filterId);
       while (true) {
         const { done, value } = await sourceReader.read();
         if (done) break;
 
         hashBuilder.update(value);
-        await sourceWritable.write(value);
+        await sourceWritable.write(encoder ? encoder.process(value) : value);
         sourceBytesWritten += value.length;
 
         send({
@@ -284,20 +489,22 @@ async function applyPatch(
           detail: `${formatSize(sourceBytesWritten)} / ${formatSize(sourceFile.size)}`,
         });
       }
+      if (encoder) await sourceWritable.write(encoder.finish());
     } finally {
+      encoder?.free();
       await sourceWritable.close();
     }
 
     // Validate source hash
     send({ type: "progress", stage: "Validating source", percent: 40 });
 
-    const computedHash = hashBuilder.finalize();
+    const computedHash = hashBuilder.finalize_u64();
     if (computedHash !== headerInfo.sourceHash) {
       patchHandle.close();
       hashBuilder.free();
       send({
         type: "error",
-        message: `Source hash mismatch. Expected ${headerInfo.sourceHash}, got ${computedHash}`,
+        message: `Source hash mismatch. Expected ${formatHash(headerInfo.sourceHash)}, got ${formatHash(computedHash)}`,
       });
       return;
     }
@@ -307,138 +514,108 @@ async function applyPatch(
     send({ type: "progress", stage: "Applying patch", percent: 45 });
 
     const sourceHandle = await getSyncAccessHandle(TEMP_FILES.SOURCE);
-    const outputWritable = await createOpfsFile(outputName);
-
-    let bytesWritten = 0;
-    let patchOffset = HEADER_SIZE;
-    const patchFileSize = patchHandle.getSize();
-
-    // Reusable buffers to reduce allocations
-    const instrHeaderBuffer = new Uint8Array(13);
-    const outputBuffer = new Uint8Array(WRITE_CHUNK_SIZE);
-    let outputBufferPos = 0;
-    const smallReadBuffer = new Uint8Array(SMALL_READ_SIZE);
-
-    const flushOutputBuffer = async (): Promise<void> => {
-      if (outputBufferPos > 0) {
-        await outputWritable.write(outputBuffer.slice(0, outputBufferPos));
-        bytesWritten += outputBufferPos;
-        outputBufferPos = 0;
-      }
-    };
-
-    const writeToOutput = async (data: Uint8Array): Promise<void> => {
-      let dataOffset = 0;
-      while (dataOffset < data.length) {
-        const spaceInBuffer = WRITE_CHUNK_SIZE - outputBufferPos;
-        const bytesToCopy = Math.min(spaceInBuffer, data.length - dataOffset);
-
-        outputBuffer.set(
-          data.subarray(dataOffset, dataOffset + bytesToCopy),
-          outputBufferPos,
-        );
-        outputBufferPos += bytesToCopy;
-        dataOffset += bytesToCopy;
+    const outputHandle = await getSyncAccessHandle(outputName);
+    const checkpointHandle = await getSyncAccessHandle(TEMP_FILES.CHECKPOINT);
+    let decoder: ExecutableFilter | undefined;
+    let applier: StreamingApplier | undefined;
 
-        if (outputBufferPos >= WRITE_CHUNK_SIZE) {
-          await flushOutputBuffer();
-        }
+    try {
+      // Checkpoints cover the applier's filtered output, not the decoded
+      // bytes on disk, so filtered patches always start over
+      if (filterId !== undefined) decoder = ExecutableFilter.decoder(filterId);
+      applier = decoder
+        ? undefined
+        : resumeApplier(sourceHandle, outputHandle, checkpointHandle, patchHash);
+      if (applier) {
+        send({
+          type: "progress",
+          stage: "Resuming",
+          percent: 45,
+          detail: formatSize(Number(applier.target_written())),
+        });
+      } else {
+        applier = new StreamingApplier(sourceHandle);
+      }
+      if (!decoder) {
+        applier.set_checkpoint_interval(BigInt(CHECKPOINT_INTERVAL));
       }
-    };
 
-    let lastProgressUpdate = Date.now();
+      let outputOffset = Number(applier.target_written());
+      outputHandle.truncate(outputOffset);
 
-    try {
-      while (patchOffset < patchFileSize) {
-        // Read instruction type
-        patchHandle.read(instrHeaderBuffer.subarray(0, 1), { at: patchOffset });
-        const instrType = instrHeaderBuffer[0];
-        patchOffset += 1;
-
-        if (instrType === TYPE_COPY) {
-          // COPY: offset(8) + length(4)
-          patchHandle.read(instrHeaderBuffer.subarray(0, 12), {
-            at: patchOffset,
-          });
-          patchOffset += 12;
-
-          const copyOffset = Number(
-            new DataView(instrHeaderBuffer.buffer).getBigUint64(0, true),
-          );
-          const copyLength = new DataView(instrHeaderBuffer.buffer).getUint32(
-            8,
-            true,
-          );
-
-          let remaining = copyLength;
-          let srcOffset = copyOffset;
-          while (remaining > 0) {
-            const chunkSize = Math.min(remaining, SMALL_READ_SIZE);
-            // Read into reusable buffer
-            sourceHandle.read(smallReadBuffer.subarray(0, chunkSize), {
-              at: srcOffset,
-            });
-            // IMPORTANT: Use slice() to copy data, not subarray() which is just a view
-            // Otherwise the buffer may be overwritten before writeToOutput finishes
-            await writeToOutput(smallReadBuffer.slice(0, chunkSize));
-            remaining -= chunkSize;
-            srcOffset += chunkSize;
-          }
-        } else if (instrType === TYPE_INSERT) {
-          // INSERT: length(4) + data
-          patchHandle.read(instrHeaderBuffer.subarray(0, 4), {
-            at: patchOffset,
-          });
-          patchOffset += 4;
-
-          const insertLength = new DataView(instrHeaderBuffer.buffer).getUint32(
-            0,
-            true,
-          );
-
-          let remaining = insertLength;
-          let insertOffset = patchOffset;
-          while (remaining > 0) {
-            const chunkSize = Math.min(remaining, SMALL_READ_SIZE);
-            // Read into reusable buffer
-            patchHandle.read(smallReadBuffer.subarray(0, chunkSize), {
-              at: insertOffset,
-            });
-            // IMPORTANT: Use slice() to copy data, not subarray() which is just a view
-            await writeToOutput(smallReadBuffer.slice(0, chunkSize));
-            remaining -= chunkSize;
-            insertOffset += chunkSize;
+      let patchOffset = headerInfo.headerSize + Number(applier.patch_offset());
+      const instructionsEnd = headerInfo.instructionsEnd;
+      const readBuffer = new Uint8Array(WRITE_CHUNK_SIZE);
+      let lastProgressUpdate = Date.now();
+
+      while (patchOffset < instructionsEnd) {
+        const chunk = readBuffer.subarray(
+          0,
+          Math.min(WRITE_CHUNK_SIZE, instructionsEnd - patchOffset),
+        );
+        patchHandle.read(chunk, { at: patchOffset });
+        patchOffset += chunk.length;
+
+        // Large COPYs pause the applier every MB or so; resume with an
+        // empty chunk once the output is written
+        let input = chunk;
+        let paused: boolean;
+        do {
+          paused = applier.apply_chunk(input);
+          input = readBuffer.subarray(0, 0);
+          const output = decoder
+            ? decoder.process(applier.take_output())
+            : applier.take_output();
+          outputHandle.write(output, { at: outputOffset });
+          outputOffset += output.length;
+
+          // The checkpoint must not get ahead of the output on disk
+          const checkpoint = applier.take_checkpoint();
+          if (checkpoint) {
+            outputHandle.flush();
+            saveCheckpoint(checkpointHandle, patchHash, checkpoint);
           }
-          patchOffset += insertLength;
-        } else {
-          throw new Error(`Unknown instruction type: ${instrType}`);
-        }
+        } while (paused);
 
         // Throttled progress updates
         const now = Date.now();
         if (now - lastProgressUpdate >= PROGRESS_INTERVAL_MS) {
-          const totalWritten = bytesWritten + outputBufferPos;
           send({
             type: "progress",
             stage: "Writing output",
-            percent: 45 + (totalWritten / headerInfo.targetSize) * 50,
-            detail: `${formatSize(totalWritten)} / ${formatSize(headerInfo.targetSize)}`,
+            percent: 45 + (outputOffset / headerInfo.targetSize) * 50,
+            detail: `${formatSize(outputOffset)} / ${formatSize(headerInfo.targetSize)}`,
           });
           lastProgressUpdate = now;
         }
       }
 
-      await flushOutputBuffer();
+      applier.finish();
+      if (decoder) {
+        const tail = decoder.finish();
+        outputHandle.write(tail, { at: outputOffset });
+        outputOffset += tail.length;
+      }
+      if (outputOffset !== headerInfo.targetSize) {
+        throw new Error(
+          `Target size mismatch. Expected ${formatSize(headerInfo.targetSize)}, got ${formatSize(outputOffset)}`,
+        );
+      }
+      outputHandle.flush();
     } finally {
+      applier?.free();
+      decoder?.free();
+      checkpointHandle.close();
       sourceHandle.close();
       patchHandle.close();
-      await outputWritable.close();
+      outputHandle.close();
     }
 
     // Phase 5: Cleanup (98-100%)
     send({ type: "progress", stage: "Cleaning up", percent: 98 });
     await safeDeleteOpfsFile(TEMP_FILES.SOURCE);
     await safeDeleteOpfsFile(TEMP_FILES.PATCH);
+    await safeDeleteOpfsFile(TEMP_FILES.CHECKPOINT);
 
     send({ type: "progress", stage: "Complete", percent: 100 });
     send({ type: "complete", outputName, size: headerInfo.targetSize });
@@ -483,7 +660,12 @@ self.onmessage = async (event: MessageEvent<WorkerMessage>): Promise<void> => {
       break;
 
     case "createPatch":
-      await createPatch(msg.sourceFile, msg.targetFile, msg.outputName);
+      await createPatch(
+        msg.sourceFile,
+        msg.targetFile,
+        msg.outputName,
+        msg.options,
+      );
       break;
 
     case "applyPatch":
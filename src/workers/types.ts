@@ -11,12 +11,22 @@ export interface InitMessage {
   type: 'init';
 }
 
+/** Options for patch creation. */
+export interface CreatePatchOptions {
+  /**
+   * Record the file names and creation time in the patch header. Off by
+   * default: it makes the patch version 2 and embeds local file names.
+   */
+  includeMetadata?: boolean;
+}
+
 /** Create a patch from source and target files. */
 export interface CreatePatchMessage {
   type: 'createPatch';
   sourceFile: File;
   targetFile: File;
   outputName: string;
+  options?: CreatePatchOptions;
 }
 
 /** Apply a patch to a source file. */